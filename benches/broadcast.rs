@@ -0,0 +1,105 @@
+// 广播吞吐基准: 在进程内启动服务器, 通过内存管道连接 N 个客户端,
+// 由第一个客户端连续广播 MESSAGES 条消息, 测量所有客户端都收到全部消息所需的时间
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::{SinkExt, StreamExt};
+use futures::stream::{SplitSink, SplitStream};
+use rustchat::common::codec::LengthCodec;
+use rustchat::common::{ClientMessage, Message, ServerMessage};
+use rustchat::server::{handle_client, ServerState};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::DuplexStream;
+use tokio::sync::Mutex;
+use tokio_util::codec::Framed;
+
+const MESSAGES: usize = 100;
+
+type ClientSink = SplitSink<Framed<DuplexStream, LengthCodec>, Message>;
+type ClientStream = SplitStream<Framed<DuplexStream, LengthCodec>>;
+
+// 连接并注册一个客户端, 等到收到 Welcome 为止
+async fn connect(name: String, state: &Arc<Mutex<ServerState>>) -> (ClientSink, ClientStream) {
+    let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+    let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    tokio::spawn(handle_client(server_io, addr, state.clone()));
+
+    let mut framed = Framed::new(client_io, LengthCodec::new());
+    framed.send(Message::Clientmsg(ClientMessage::Register { name, capabilities: vec![], public_key: None, encryption_key: None, session: None })).await.unwrap();
+    while let Some(Ok(msg)) = framed.next().await {
+        if let Message::Servermsg(ServerMessage::Welcome { .. }) = msg {
+            break;
+        }
+    }
+    framed.split()
+}
+
+// 读取直到收到 count 条广播
+async fn drain_broadcasts(stream: &mut ClientStream, count: usize) {
+    let mut seen = 0;
+    while seen < count {
+        match stream.next().await {
+            Some(Ok(Message::Servermsg(ServerMessage::BroadcastMessage { .. }))) => seen += 1,
+            Some(Ok(_)) => {}
+            _ => panic!("connection closed after {} of {} broadcasts", seen, count),
+        }
+    }
+}
+
+async fn run(clients: usize) -> Duration {
+    let state = Arc::new(Mutex::new(ServerState::default()));
+    let mut sinks = Vec::new();
+    let mut streams = Vec::new();
+    for i in 0..clients {
+        let (sink, stream) = connect(format!("user{}", i), &state).await;
+        sinks.push(sink);
+        streams.push(stream);
+    }
+
+    // 先发一条广播并等所有人收到, 把加入通知等握手阶段的消息排空
+    let sender = &mut sinks[0];
+    let mut id = 0;
+    let mut send = |content: String| {
+        id += 1;
+        Message::Clientmsg(ClientMessage::Broadcast { content, id, ephemeral: false, ttl_secs: None, reply_to: None, attachments: Vec::new() })
+    };
+    sender.send(send("warmup".into())).await.unwrap();
+    for stream in streams.iter_mut() {
+        drain_broadcasts(stream, 1).await;
+    }
+
+    let start = Instant::now();
+    let readers: Vec<_> = streams
+        .into_iter()
+        .map(|mut stream| tokio::spawn(async move { drain_broadcasts(&mut stream, MESSAGES).await }))
+        .collect();
+    for i in 0..MESSAGES {
+        sender.send(send(format!("message {}", i))).await.unwrap();
+    }
+    for reader in readers {
+        reader.await.unwrap();
+    }
+    start.elapsed()
+}
+
+fn broadcast_throughput(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("broadcast");
+    for clients in [1, 10, 50] {
+        // 吞吐量按投递次数计: 每条广播投递给每个客户端
+        group.throughput(Throughput::Elements((MESSAGES * clients) as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(clients), &clients, |b, &clients| {
+            b.to_async(&rt).iter_custom(|iters| async move {
+                let mut total = Duration::ZERO;
+                for _ in 0..iters {
+                    total += run(clients).await;
+                }
+                total
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, broadcast_throughput);
+criterion_main!(benches);
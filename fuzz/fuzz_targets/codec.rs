@@ -0,0 +1,31 @@
+// LengthCodec 解码的模糊测试
+// 输入的第一个字节决定是否开启压缩以及每次喂给解码器的字节数, 其余字节按块追加到缓冲区,
+// 模拟数据从网络中分批到达。每追加一块就反复解码直到 Ok(None) 或 Err, 要求全程不 panic
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use rustchat::common::codec::LengthCodec;
+use tokio_util::codec::Decoder;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&control, rest)) = data.split_first() else {
+        return;
+    };
+    let mut codec = LengthCodec::new();
+    codec.set_compression(control & 0x80 != 0);
+    let chunk = usize::from(control & 0x7f).max(1);
+
+    let mut buf = BytesMut::new();
+    for piece in rest.chunks(chunk) {
+        buf.extend_from_slice(piece);
+        loop {
+            match codec.decode(&mut buf) {
+                Ok(Some(_)) => continue,
+                Ok(None) => break,
+                // Framed 在解码出错后会结束流, 这里同样停止
+                Err(_) => return,
+            }
+        }
+    }
+});
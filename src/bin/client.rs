@@ -1,27 +1,170 @@
-use tokio::net::TcpStream;
-use tokio_util::codec::Framed;                
-use futures::{SinkExt, StreamExt};            
-use std::io::{stdin, stdout, Write};        
+use futures::StreamExt;
+use std::io::{stdin, stdout, BufRead, Write};        
 use anyhow::Result;
 use config::{Config, File};
 use serde::Deserialize;
-use rustchat::common::{Message, ServerMessage, ClientMessage};
-use rustchat::common::codec::LengthCodec;
+use rustchat::common::{Message, ServerMessage, ClientMessage, ShutdownReason, CAP_COMPRESS, CAP_E2E};
+use rustchat::common::codec::{self, MAX_FRAME_LEN};
+use rustchat::common::command::{self, Command, NameColor};
+use rustchat::common::export::{ExportedMessage, HistoryExport};
+use rustchat::common::files::{human_size, AttachmentMeta, FileMeta, MAX_ATTACHMENTS};
+use rustchat::common::signing::{Identity, KeyPins, Verdict};
+use rustchat::client::{ChatClient, ChatSender, Incoming, Refused};
+use rustchat::server::MAX_NAME_LEN;
 use crossterm::event::{self, Event, KeyCode}; 
+use crossterm::style::{Color, Stylize};
+use crossterm::{cursor, execute, terminal};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::TcpStream;
+use tokio_util::either::Either;
 
 #[derive(Debug, Deserialize)]
 struct ClientConfig {
     host: String,
     port: u16,
+    unix_socket: String,        // 服务器的本地套接字路径, 设置后不用 host 和 port, 通过它连接
+    outbox_capacity: usize,     // 断线期间最多缓存多少条待发送消息
+    read_receipts: bool,        // 是否在显示私聊后向发送方回执已读
+    compression: bool,          // 是否向服务器声明支持压缩
+    prompt: String,             // 输入提示符
+    status_line: String,        // 状态栏模板, 可用 {name} {server} {state} {users} {unread}, 为空则不显示
+    json: bool,                 // JSON 模式, 也可以用 --json 参数开启
+    signing: bool,              // 是否为发出的私聊签名
+    signing_key_file: String,   // 签名密钥文件, 设置后总是签名并在每次启动时使用同一个密钥, 不存在时自动生成
+    encryption: bool,           // 是否对私聊做端到端加密
+    plaintext_fallback: bool,   // 开启加密时, 是否把私聊以明文发给还没有公钥或公钥变过的接收者; 默认不发
+    command_prefix: String,     // 指令前缀, 需与服务器一致
+    name: String,               // 用户名, 也可以用 --name 参数给出; 都没有时启动后询问, JSON 模式下读 stdin 第一行
+    auto_suffix_name: bool,     // 用户名被占用时自动加上 _2、_3 这样的后缀重试, 供无人值守的机器人使用
+    auto_suffix_attempts: usize,    // 自动加后缀最多重试几次
 }
 
-// 读入名字
-fn name_prompt(msg: &str) -> std::io::Result<String> {
-    print!("{}", msg);
-    stdout().flush()?; 
-    let mut s = String::new();
-    stdin().read_line(&mut s)?;
-    Ok(s.trim().to_string())
+// 接收任务与输入循环共享的客户端状态
+#[derive(Clone)]
+struct Shared {
+    name: String,
+    dnd: Arc<AtomicBool>,                   // 免打扰模式开关, 由输入循环切换、接收任务读取
+    pending: Arc<Mutex<HashSet<u64>>>,      // 已在本地显示、尚未收到服务器回显的广播 id
+    connected: Arc<AtomicBool>,             // 接收任务发现连接断开后置为 false
+    receipts: Option<mpsc::UnboundedSender<u64>>,   // 开启已读回执时, 接收任务把已显示的私聊 id 交给输入循环发送
+    unread: Arc<AtomicUsize>,               // 上次输入之后收到的私聊数
+    users: Arc<AtomicU64>,                  // 服务器推送的在线人数
+    prompt: Arc<str>,
+    status_line: Arc<str>,
+    server_name: Arc<Mutex<String>>,        // 当前连接的服务器名字, 重连后更新
+    keys: Arc<Mutex<KeyPins>>,              // 每个发送者第一次签名有效时的公钥, 之后的签名都按它核对
+    colors: Arc<Mutex<HashMap<String, NameColor>>>, // 服务器告知的其他用户的名字颜色
+    recent: Arc<Mutex<VecDeque<Seen>>>,     // 最近收到的群发和私聊, 供 /reply 和回复的引用使用
+    retry_at: Arc<Mutex<Option<Instant>>>,  // 服务器因重启或维护关闭时, 输入循环在这个时间自动重连
+    export: Arc<Mutex<Option<PendingExport>>>,  // 正在进行的 /export, 收齐服务器的回复后写入文件
+}
+
+// 一次 /export: 保存的路径和已经收到的消息
+struct PendingExport {
+    path: PathBuf,
+    messages: Vec<ExportedMessage>,
+}
+
+// 最多记住多少条最近收到的消息
+const RECENT_MESSAGES: usize = 200;
+// 显示被回复的消息时最多引用多少个字符
+const QUOTE_CHARS: usize = 40;
+// 收到的消息按 1..=SHORT_INDEXES 循环编号, 用 /reply 3 代替服务器分配的长 id
+const SHORT_INDEXES: usize = 99;
+// /recent 列出的消息条数
+const RECENT_LISTED: usize = 10;
+// 服务器重启时等多久再第一次重连
+const RESTART_DELAY: Duration = Duration::from_secs(2);
+// 维护结束时间再远, 最多等这么久就试着重连
+const MAX_MAINTENANCE_WAIT: Duration = Duration::from_secs(24 * 3600);
+// 自动重连失败后每隔多久再试一次
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+// 收到的一条群发或私聊
+struct Seen {
+    index: usize,           // 显示在消息末尾的短编号, 循环使用, 重复时以最近的一条为准
+    message_id: u64,
+    from: String,
+    content: String,
+    private: bool,
+}
+
+// 是否进入了备用屏幕, 进入时置为 true, 恢复终端时据此决定是否离开
+static ALTERNATE_SCREEN: AtomicBool = AtomicBool::new(false);
+
+// 把终端恢复到正常状态: 关闭 raw mode、显示光标, 需要时离开备用屏幕
+fn restore_terminal() {
+    let _ = terminal::disable_raw_mode();
+    if ALTERNATE_SCREEN.swap(false, Ordering::Relaxed) {
+        let _ = execute!(stdout(), terminal::LeaveAlternateScreen);
+    }
+    let _ = execute!(stdout(), cursor::Show);
+}
+
+// 终端状态守卫: 离开作用域时恢复终端, 覆盖 main 正常返回和 ? 提前返回的情况
+// panic 由 install_panic_hook 处理, std::process::exit 不会运行析构函数, 调用前需手动 restore_terminal
+struct TerminalGuard;
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        restore_terminal();
+    }
+}
+
+// panic 时先恢复终端再打印 panic 信息, 否则信息会在 raw mode 下错乱
+fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        restore_terminal();
+        default_hook(info);
+    }));
+}
+
+// 读入名字, 空行时重新提示; 输入结束(EOF)时返回 None, 不能拿空名字去注册
+fn name_prompt(msg: &str, input: &mut impl BufRead) -> std::io::Result<Option<String>> {
+    loop {
+        print!("{}", msg);
+        stdout().flush()?;
+        let mut s = String::new();
+        if input.read_line(&mut s)? == 0 {
+            println!();
+            return Ok(None);
+        }
+        if !s.trim().is_empty() {
+            return Ok(Some(s.trim().to_string()));
+        }
+        println!("[错误] Name cannot be empty");
+    }
+}
+
+// 命令行参数 flag 后面的值, 例如 --name alice
+fn arg_value(args: &[String], flag: &str) -> Option<String> {
+    args.iter().position(|arg| arg == flag).and_then(|i| args.get(i + 1)).cloned()
+}
+
+/* 读取签名密钥文件, 文件不存在时生成一个新密钥写进去(仅本人可读写)
+    每次启动都用同一个密钥, 服务器据此认出配置了公钥的管理员, 其他人也能一直认出同一个发送者
+*/
+fn load_identity(path: &Path) -> Result<Identity> {
+    match std::fs::read_to_string(path) {
+        Ok(secret) => Identity::from_secret(&secret).ok_or_else(|| anyhow::anyhow!("{} does not contain a signing key", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let identity = Identity::generate();
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            options.open(path)?.write_all(identity.secret().as_bytes())?;
+            Ok(identity)
+        }
+        Err(e) => Err(e.into()),
+    }
 }
 
 // 读入一行消息
@@ -31,109 +174,1112 @@ fn read_line() -> std::io::Result<String> {
     Ok(s.trim().to_string())
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    
-    let name = name_prompt("Enter your name: ")?;
+/* 粘贴模式下读入多行, 直到单独一行 "." 为止, 保留每行的缩进
+    要发送以 "." 开头的行时多写一个点, 例如 ".." 发出 ".", 和 SMTP 的做法一样
+*/
+fn read_block() -> std::io::Result<String> {
+    let mut lines = Vec::new();
+    loop {
+        let mut s = String::new();
+        // stdin 结束时也当作结束
+        if stdin().read_line(&mut s)? == 0 {
+            break;
+        }
+        let line = s.trim_end_matches(['\r', '\n']);
+        if line == "." {
+            break;
+        }
+        lines.push(line.strip_prefix('.').filter(|rest| rest.starts_with('.')).unwrap_or(line).to_string());
+    }
+    Ok(lines.join("\n"))
+}
 
-    // 客户端连接到服务器，一样的逻辑
-    let settings = Config::builder()
-        .set_default("host", "127.0.0.1")?
-        .set_default("port", 8080)?
-        .add_source(File::with_name("Config").required(false))
-        .build()?;
+// 多行消息的后续行缩进显示, 和第一行区分开
+fn indent_continuation(text: &str) -> String {
+    text.replace('\n', "\n    ")
+}
 
-    let cfg: ClientConfig = settings.try_deserialize()?;
-    let server_addr = format!("{}:{}", cfg.host, cfg.port);
-    println!("Connecting to server at {}", server_addr);
+// 超过服务器的帧长度上限时提醒用户缩短, 否则服务器会断开连接
+fn too_long(msg: ClientMessage) -> bool {
+    let len = codec::payload_len(&Message::Clientmsg(msg));
+    if len > MAX_FRAME_LEN {
+        println!("[错误] Message is too long ({} bytes, the limit is {} bytes), please shorten it", len, MAX_FRAME_LEN);
+    }
+    len > MAX_FRAME_LEN
+}
 
-    // 客户端，启动
-    let socket = TcpStream::connect(&server_addr).await?;
-    println!("✅ Successfully Connected!");
+// 按模板填入状态栏内容
+fn status_line(shared: &Shared) -> String {
+    let state = if shared.connected.load(Ordering::Relaxed) { "online" } else { "offline" };
+    shared.status_line
+        .replace("{name}", &shared.name)
+        .replace("{server}", &shared.server_name.lock().unwrap())
+        .replace("{state}", state)
+        .replace("{unread}", &shared.unread.load(Ordering::Relaxed).to_string())
+        .replace("{users}", &shared.users.load(Ordering::Relaxed).to_string())
+}
+
+// 输入提示符, 上方显示状态栏, 免打扰模式下带上 [DND] 标记
+fn prompt(shared: &Shared) -> std::io::Result<()> {
+    if !shared.status_line.is_empty() {
+        println!("{}", status_line(shared).reverse());
+    }
+    if shared.dnd.load(Ordering::Relaxed) {
+        print!("[DND] {}", shared.prompt);
+    } else {
+        print!("{}", shared.prompt);
+    }
+    stdout().flush()
+}
+
+// 消息内容是否提到了自己(@name)
+fn is_mention(content: &str, name: &str) -> bool {
+    content.contains(&format!("@{}", name))
+}
+
+// 找出文本中的 URL, 返回每个 URL 的字节区间
+// 以 http:// 、https:// 或 www. 开头(不区分大小写)、到空白字符为止的片段视为 URL,
+// 包裹 URL 的括号、引号以及末尾的标点不计入 URL, 只有前缀没有内容的片段不算
+fn find_urls(text: &str) -> Vec<(usize, usize)> {
+    let mut urls = Vec::new();
+    let mut start = None;
+    // 末尾补一个虚拟空白, 让最后一个片段也能被处理
+    for (i, c) in text.char_indices().chain(std::iter::once((text.len(), ' '))) {
+        match (c.is_whitespace(), start) {
+            (false, None) => start = Some(i),
+            (true, Some(s)) => {
+                if let Some((from, to)) = url_span(&text[s..i]) {
+                    urls.push((s + from, s + to));
+                }
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    urls
+}
+
+// 判断一个不含空白的片段是否是 URL, 是则返回去掉首尾括号、标点后 URL 在片段中的区间
+fn url_span(token: &str) -> Option<(usize, usize)> {
+    let body = token.trim_start_matches(|c| "([{<'\"".contains(c));
+    let lead = token.len() - body.len();
+    let lower = body.to_ascii_lowercase();
+    let prefix = ["http://", "https://", "www."].into_iter().find(|p| lower.starts_with(p))?;
+    let trimmed = body.trim_end_matches(|c| ".,;:!?)]}>'\"".contains(c));
+    (trimmed.len() > prefix.len()).then_some((lead, lead + trimmed.len()))
+}
 
-    let mut framed = Framed::new(socket, LengthCodec);
+// 给文本中的 URL 加上下划线高亮
+fn highlight_urls(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut last = 0;
+    for (s, e) in find_urls(text) {
+        out.push_str(&text[last..s]);
+        out.push_str(&text[s..e].underlined().cyan().to_string());
+        last = e;
+    }
+    out.push_str(&text[last..]);
+    out
+}
 
-    // 向服务器注册
-    let join_msg = Message::Clientmsg(ClientMessage::Register { name: name.clone() });
-    framed.send(join_msg).await?;
+// 打印一行消息, 免打扰模式下非提及消息以暗色显示
+fn show(line: String, dim: bool) {
+    if dim {
+        println!("{}", line.dim());
+    } else {
+        println!("{}", line);
+    }
+}
 
-    // 分离编码与解码：Sink 用于编码，Stream 用于解码
-    let (mut sink, mut stream) = framed.split();
+// 到服务器的连接: TCP, 或服务器地址以 UNIX_PREFIX 开头时的本地套接字
+#[cfg(unix)]
+type Stream = Either<TcpStream, tokio::net::UnixStream>;
+#[cfg(not(unix))]
+type Stream = Either<TcpStream, TcpStream>;
 
-   
-    let name_for_recv = name.clone();
+// 服务器地址写成 "unix://<path>" 时通过本地套接字连接, 例如 unix:///tmp/rustchat.sock
+const UNIX_PREFIX: &str = "unix://";
+
+async fn open(server_addr: &str) -> Result<Stream> {
+    let Some(path) = server_addr.strip_prefix(UNIX_PREFIX) else {
+        return Ok(Either::Left(TcpStream::connect(server_addr).await?));
+    };
+    #[cfg(unix)]
+    return Ok(Either::Right(tokio::net::UnixStream::connect(path).await?));
+    #[cfg(not(unix))]
+    anyhow::bail!("cannot connect to {}: unix sockets are not supported on this platform", path);
+}
+
+// 连接服务器并注册, 返回分离后的发送端和消息流; 给出 identity 时为私聊签名
+// 有会话 token 时尝试恢复会话, 服务器会补发断线期间错过的消息
+async fn connect(server_addr: &str, name: &str, capabilities: &[String], identity: Option<&Identity>, session: Option<&str>) -> Result<(ChatSender<Stream>, Incoming<Stream>)> {
+    let socket = open(server_addr).await?;
+    let client = match (session, identity) {
+        (Some(session), _) => ChatClient::handshake_resume(socket, name, capabilities, identity.cloned(), session).await?,
+        (None, Some(identity)) => ChatClient::handshake_signed(socket, name, capabilities, identity.clone()).await?,
+        (None, None) => ChatClient::handshake(socket, name, capabilities).await?,
+    };
+    Ok(client.split())
+}
+
+// 用户名被占用时依次尝试 alice_2、alice_3 ...; 加上后缀超出长度上限时截短原名
+fn suffixed_name(name: &str, n: usize) -> String {
+    let suffix = format!("_{}", n);
+    let keep = MAX_NAME_LEN.saturating_sub(suffix.len());
+    format!("{}{}", name.chars().take(keep).collect::<String>(), suffix)
+}
+
+/* 第一次连接服务器, 用户名被占用时换成带后缀的名字重试, 最多重试 attempts 次, 为 0 时不重试
+    实际注册的名字用 sink.name() 取得; 之后断线重连沿用这个名字, 会话也属于它
+*/
+async fn connect_first(server_addr: &str, name: &str, capabilities: &[String], identity: Option<&Identity>, attempts: usize) -> Result<(ChatSender<Stream>, Incoming<Stream>)> {
+    let mut n = 1;
+    loop {
+        let candidate = if n == 1 { name.to_string() } else { suffixed_name(name, n) };
+        match connect(server_addr, &candidate, capabilities, identity, None).await {
+            Err(e) if n <= attempts && e.downcast_ref::<Refused>().is_some_and(Refused::is_name_taken) => {
+                n += 1;
+                eprintln!("[系统] Name {} is taken, trying {}", candidate, suffixed_name(name, n));
+            }
+            result => return result,
+        }
+    }
+}
 
-    // tokio::spawn 一个任务循环打印所有到来的消息，根据消息类型格式化输出
+// 私聊的签名标记: 没有签名时为空; 公钥和之前见过的不同时不论签名如何都提示公钥已变更, 不算验证通过
+fn signature_tag(verdict: Option<Verdict>) -> &'static str {
+    match verdict {
+        None => "",
+        Some(Verdict::Verified) => "[已验证]",
+        Some(Verdict::Invalid) => "[签名无效]",
+        Some(Verdict::KeyChanged) => "[公钥已变更]",
+    }
+}
+
+// 按用户选择的颜色显示名字, 没有选择时原样返回
+fn paint(name: &str, shared: &Shared) -> String {
+    let color = match shared.colors.lock().unwrap().get(name) {
+        Some(NameColor::Red) => Color::Red,
+        Some(NameColor::Green) => Color::Green,
+        Some(NameColor::Yellow) => Color::Yellow,
+        Some(NameColor::Blue) => Color::Blue,
+        Some(NameColor::Magenta) => Color::Magenta,
+        Some(NameColor::Cyan) => Color::Cyan,
+        None => return name.to_string(),
+    };
+    name.with(color).to_string()
+}
+
+// 记下收到的消息并分配短编号, 超出 RECENT_MESSAGES 时丢弃最旧的; 返回短编号
+fn remember(message_id: u64, from: &str, content: &str, private: bool, shared: &Shared) -> usize {
+    let mut recent = shared.recent.lock().unwrap();
+    let index = recent.back().map_or(1, |last| last.index % SHORT_INDEXES + 1);
+    if recent.len() >= RECENT_MESSAGES {
+        recent.pop_front();
+    }
+    recent.push_back(Seen { index, message_id, from: from.to_string(), content: content.to_string(), private });
+    index
+}
+
+/* /reply 的回复对象: 短编号(例如 3)或以 # 开头的消息 id(例如 #1024)
+    返回消息 id, 以及私聊时的发送者; 短编号找不到时返回 None, 消息 id 总是可以用
+*/
+fn reply_target(recent: &VecDeque<Seen>, arg: &str) -> Option<(u64, Option<String>)> {
+    let seen = match arg.strip_prefix('#') {
+        Some(id) => {
+            let message_id = id.parse().ok()?;
+            match recent.iter().rev().find(|m| m.message_id == message_id) {
+                Some(seen) => seen,
+                None => return Some((message_id, None)),
+            }
+        }
+        None => {
+            let index: usize = arg.parse().ok()?;
+            recent.iter().rev().find(|m| m.index == index)?
+        }
+    };
+    Some((seen.message_id, seen.private.then(|| seen.from.clone())))
+}
+
+// 群发附件逐个列在消息下面, 编号从 1 开始, 例如 "    [附件 1] plan.pdf (2.0 KiB, application/pdf)"
+fn attachment_lines(attachments: &[AttachmentMeta]) -> Vec<String> {
+    attachments.iter().enumerate()
+        .map(|(i, a)| format!("    [附件 {}] {} ({}, {})", i + 1, a.name, human_size(a.size), a.content_type))
+        .collect()
+}
+
+// 被回复消息的摘要: 只取第一行, 超过 QUOTE_CHARS 个字符时截断
+fn snippet(content: &str) -> String {
+    let first = content.lines().next().unwrap_or("");
+    if first.chars().count() > QUOTE_CHARS || content.contains('\n') {
+        format!("{}…", first.chars().take(QUOTE_CHARS).collect::<String>())
+    } else {
+        first.to_string()
+    }
+}
+
+/* 回复上方的引用行; 终端里话题只能平铺显示, 所以只引用直接回复的那一条, 不展开更上层的消息
+    被回复的消息不在最近收到的消息里时只显示它的 id
+*/
+fn reply_line(reply_to: u64, shared: &Shared) -> String {
+    let recent = shared.recent.lock().unwrap();
+    match recent.iter().rev().find(|m| m.message_id == reply_to) {
+        Some(m) => format!("  ↳ replying to {}: \"{}\"", m.from, snippet(&m.content)),
+        None => format!("  ↳ replying to #{}", reply_to),
+    }
+}
+
+// /export 不指定路径时保存到当前目录, 文件名带上用户名和时间, 不会覆盖之前的导出
+fn default_export_path(name: &str, now: SystemTime) -> PathBuf {
+    let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    PathBuf::from(format!("{}-history-{}.json", name, secs))
+}
+
+// 把导出的历史以缩进的 JSON 写入 path
+fn save_export(path: &Path, export: &HistoryExport) -> std::io::Result<()> {
+    std::fs::write(path, serde_json::to_string_pretty(export)?)
+}
+
+// 服务器关闭通知的提示, 以及多久之后自动重连; 停止服务时返回 None, 客户端随之退出
+fn exit_notice(reason: ShutdownReason, now: SystemTime) -> (String, Option<Duration>) {
+    match reason {
+        ShutdownReason::Shutdown => ("The server is shutting down and the client is about to exit".to_string(), None),
+        ShutdownReason::Restart => ("The server is restarting, the client will reconnect automatically".to_string(), Some(RESTART_DELAY)),
+        ShutdownReason::Maintenance { until } => {
+            let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            let wait = until.saturating_sub(now).min(MAX_MAINTENANCE_WAIT.as_secs());
+            let notice = format!("The server is down for maintenance, the client will reconnect in about {} min", wait.div_ceil(60));
+            (notice, Some(Duration::from_secs(wait)))
+        }
+    }
+}
+
+// tokio::spawn 一个任务循环打印所有到来的消息，根据消息类型格式化输出
+fn spawn_receiver(mut stream: Incoming<Stream>, shared: Shared) {
+    shared.connected.store(true, Ordering::Relaxed);
     tokio::spawn(async move {
-        while let Some(Ok(Message::Servermsg(msg))) = stream.next().await {
+        loop {
+            // 区分服务器正常关闭连接(EOF)和读取/解码出错, 分别提示
+            let msg = match stream.next().await {
+                Some(Ok(msg)) => msg,
+                Some(Err(e)) => {
+                    println!("[错误] Connection to server failed: {}", e);
+                    break;
+                }
+                None => {
+                    println!("[系统] Disconnected from server");
+                    break;
+                }
+            };
+            let dnd = shared.dnd.load(Ordering::Relaxed);
+            let verdict = shared.keys.lock().unwrap().check(&msg);
             match msg {
-                ServerMessage::BroadcastMessage { from, content } => {
-                    println!("[{}] {}", from, content);
+                ServerMessage::BroadcastMessage { from, content, id, message_id, reply_to, attachments } => {
+                    // 自己的广播已经预先显示过, 回显到达时不再重复打印, 但要记下 id 供别人回复时引用
+                    let own = from == shared.name && shared.pending.lock().unwrap().remove(&id);
+                    let quote = reply_to.map(|parent| reply_line(parent, &shared));
+                    let index = remember(message_id, &from, &content, false, &shared);
+                    if own {
+                        continue;
+                    }
+                    let dim = dnd && !is_mention(&content, &shared.name);
+                    if let Some(quote) = quote {
+                        show(quote.dark_grey().to_string(), dim);
+                    }
+                    let tag = format!("({})", index).dark_grey();
+                    show(format!("[{}] {} {}", paint(&from, &shared), highlight_urls(&indent_continuation(&content)), tag), dim);
+                    for line in attachment_lines(&attachments) {
+                        show(line, dim);
+                    }
+                }
+                ServerMessage::PrivateMessage { from, to, content, message_id, encrypted, reply_to, .. } if to == shared.name => {
+                    let tag = signature_tag(verdict);
+                    let lock = if encrypted { "[加密]" } else { "" };
+                    if let Some(parent) = reply_to {
+                        println!("{}", reply_line(parent, &shared).dark_grey());
+                    }
+                    let index = remember(message_id, &from, &content, true, &shared);
+                    let index_tag = format!("({})", index).dark_grey();
+                    println!("[私聊]{}{}[{} → you] {} {}", lock, tag, paint(&from, &shared), highlight_urls(&indent_continuation(&content)), index_tag);
+                    if let Some(receipts) = &shared.receipts {
+                        let _ = receipts.send(message_id);
+                    }
+                    shared.unread.fetch_add(1, Ordering::Relaxed);
+                    let _ = prompt(&shared);
                 }
-                ServerMessage::PrivateMessage { from, to, content } if to == name_for_recv => {
-                    println!("[私聊][{} → you] {}", from, content);
+                ServerMessage::Read { message_id, by } => {
+                    show(format!("[私聊] ✓ {} read message #{}", by, message_id), dnd);
                 }
-                ServerMessage::UserList { content, to } if to == name_for_recv => {
+                ServerMessage::UserList { content, to } if to == shared.name => {
                     println!("[系统] Userlist:\n {:?}", content);
                 }
-                ServerMessage::History { content, to} if to == name_for_recv => {
+                ServerMessage::History { content, to} if to == shared.name => {
                     println!("[系统] Histroy:\n {}", content);
                 }
-                ServerMessage::Error { content, to } if to == name_for_recv => {
+                ServerMessage::Error { content, to } if to == shared.name => {
                     println!("[错误] {}", content);
                 }
                 ServerMessage::System { content } => {
-                    println!("[系统] {}", content);
+                    show(format!("[系统] {}", content), dnd);
+                    // 上下线等系统通知之后刷新状态栏
+                    let _ = prompt(&shared);
+                }
+                ServerMessage::FileOffer { from, file } => {
+                    show(format!("[文件] {} offers {} ({}, sha256 {})", paint(&from, &shared), file.name, human_size(file.size), file.sha256), dnd);
+                }
+                ServerMessage::Throttle { retry_after_ms } => {
+                    show(format!("[系统] The server asked to slow down, the next message waits {} ms", retry_after_ms), true);
+                }
+                // 人数随上下线通知一起到达, 只记下来, 显示通知后刷新的状态栏会带上它
+                ServerMessage::UserCount { count } => {
+                    shared.users.store(count, Ordering::Relaxed);
+                }
+                ServerMessage::Deleted { message_id } => {
+                    show(format!("[系统] Message #{} has expired", message_id), true);
+                }
+                ServerMessage::NameColor { name, color } => {
+                    let mut colors = shared.colors.lock().unwrap();
+                    match color {
+                        Some(color) => colors.insert(name, color),
+                        None => colors.remove(&name),
+                    };
+                }
+                ServerMessage::Export { messages, done, blocked } => {
+                    let mut export = shared.export.lock().unwrap();
+                    let Some(pending) = export.as_mut() else { continue };
+                    pending.messages.extend(messages);
+                    if !done {
+                        continue;
+                    }
+                    let PendingExport { path, messages } = export.take().expect("checked above");
+                    // 屏蔽列表只在最后一帧中, 随历史一起保存, 导入时恢复
+                    let count = messages.len();
+                    let export = HistoryExport {
+                        user: shared.name.clone(),
+                        server: shared.server_name.lock().unwrap().clone(),
+                        exported_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+                        messages,
+                        blocked,
+                    };
+                    match save_export(&path, &export) {
+                        Ok(()) => println!("[系统] Saved {} messages to {}", count, path.display()),
+                        Err(e) => println!("[错误] Cannot write {}: {}", path.display(), e),
+                    }
+                    let _ = prompt(&shared);
                 }
-                ServerMessage::Exit => {
-                    println!("[系统] The server is shutting down and the client is about to exit");
-                    std::process::exit(0);
+                ServerMessage::Exit { reason } => {
+                    let (notice, retry_in) = exit_notice(reason, SystemTime::now());
+                    println!("[系统] {}", notice);
+                    let Some(retry_in) = retry_in else {
+                        restore_terminal();
+                        std::process::exit(0);
+                    };
+                    *shared.retry_at.lock().unwrap() = Instant::now().checked_add(retry_in);
                 }
                 _ => {}
             }
         }
+        // 通知输入循环连接已断开, 由它负责重连
+        shared.connected.store(false, Ordering::Relaxed);
+        let _ = prompt(&shared);
     });
+}
+
+// 到服务器的发送端: 断线后 sink 置为 None, 期间的消息先放入 outbox, 重连成功后按顺序补发
+struct Link {
+    sink: Option<ChatSender<Stream>>,
+    outbox: VecDeque<ClientMessage>,
+    capacity: usize,
+    server_addr: String,
+    capabilities: Vec<String>,
+    identity: Option<Identity>,
+    session: String,            // 最近一次连接的会话 token, 重连时带上
+    plaintext_fallback: bool,   // 每次连接后交给发送端, 见 ChatSender::allow_plaintext
+}
+
+impl Link {
+    // 发送一条消息, 断线时先尝试重连; 重连失败则放入待发送队列
+    async fn send(&mut self, msg: ClientMessage, shared: &Shared) {
+        if !shared.connected.load(Ordering::Relaxed) {
+            self.sink = None;
+        }
+        if self.sink.is_none() && !self.reconnect(shared).await {
+            println!("[系统] Still disconnected, message queued");
+        }
+
+        if self.outbox.len() >= self.capacity {
+            println!("[错误] Outgoing queue is full ({} messages), message dropped", self.capacity);
+        } else {
+            self.outbox.push_back(msg);
+        }
+
+        self.flush().await;
+    }
+
+    // 通知服务器自己退出; 已断线时不再重连, 直接放弃
+    async fn quit(&mut self, shared: &Shared) {
+        if let Some(sink) = self.sink.take()
+            && shared.connected.load(Ordering::Relaxed) {
+            let _ = sink.quit(None).await;
+        }
+    }
+
+    // 重新连接并注册, 成功后启动新的接收任务
+    async fn reconnect(&mut self, shared: &Shared) -> bool {
+        let session = (!self.session.is_empty()).then_some(self.session.as_str());
+        match connect(&self.server_addr, &shared.name, &self.capabilities, self.identity.as_ref(), session).await {
+            Ok((mut sink, stream)) => {
+                sink.allow_plaintext(self.plaintext_fallback);
+                *shared.retry_at.lock().unwrap() = None;
+                self.session = sink.session().to_string();
+                println!("[系统] Reconnected, flushing {} queued message(s)", self.outbox.len());
+                *shared.server_name.lock().unwrap() = sink.server_name().to_string();
+                spawn_receiver(stream, shared.clone());
+                self.sink = Some(sink);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    // 按顺序发出待发送队列中的消息
+    async fn flush(&mut self) {
+        if let Some(sink) = self.sink.as_mut() {
+            while let Some(queued) = self.outbox.pop_front() {
+                match sink.send(queued.clone()).await {
+                    Ok(()) => {}
+                    // 加密后超过帧长度上限, 重发也没有用, 直接丢弃
+                    Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => println!("[错误] {}, message dropped", e),
+                    Err(_) => {
+                        // 发送失败, 放回队首等待下次重连
+                        self.outbox.push_front(queued);
+                        self.sink = None;
+                        println!("[系统] Disconnected from server, message queued");
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/* JSON 模式, 供脚本和机器人使用
+    没有用 --name 或配置给出用户名时, stdin 第一行为用户名; 之后每行是一条 JSON 格式的 ClientMessage, 例如 {"Raw":{"text":"hi"}}
+    收到的每条 ServerMessage 以一行 JSON 输出到 stdout, 其他提示都写到 stderr
+    stdin 结束时通知服务器退出, 服务器断开或关闭时程序结束
+*/
+async fn run_json(server_addr: &str, name: Option<String>, capabilities: &[String], identity: Option<&Identity>, suffix_attempts: usize, plaintext_fallback: bool) -> Result<()> {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let name = match name {
+        Some(name) => name,
+        None => match lines.next_line().await? {
+            Some(line) if !line.trim().is_empty() => line.trim().to_string(),
+            Some(_) => anyhow::bail!("the first line of input must be the user name"),
+            None => anyhow::bail!("input ended before a user name was given"),
+        },
+    };
+    let (mut sink, mut stream) = connect_first(server_addr, &name, capabilities, identity, suffix_attempts).await?;
+    sink.allow_plaintext(plaintext_fallback);
+    if sink.name() != name {
+        eprintln!("registered as {}", sink.name());
+    }
+
+    loop {
+        tokio::select! {
+            msg = stream.next() => match msg {
+                Some(Ok(msg)) => {
+                    println!("{}", serde_json::to_string(&msg)?);
+                    if matches!(msg, ServerMessage::Exit { .. }) {
+                        break;
+                    }
+                }
+                Some(Err(e)) => anyhow::bail!("connection to server failed: {}", e),
+                None => break,
+            },
+            line = lines.next_line() => match line? {
+                Some(line) if line.trim().is_empty() => {}
+                Some(line) => match serde_json::from_str::<ClientMessage>(&line) {
+                    Ok(msg) => match sink.send(msg).await {
+                        Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => eprintln!("message not sent: {}", e),
+                        result => result?,
+                    },
+                    Err(e) => eprintln!("invalid message: {}", e),
+                },
+                None => {
+                    sink.quit(None).await?;
+                    break;
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // 客户端连接到服务器，一样的逻辑
+    let settings = Config::builder()
+        .set_default("host", "127.0.0.1")?
+        .set_default("port", 8080)?
+        .set_default("unix_socket", "")?
+        .set_default("outbox_capacity", 100)?
+        .set_default("read_receipts", false)?
+        .set_default("compression", true)?
+        .set_default("prompt", "> ")?
+        .set_default("status_line", "{name}@{server} | {state} | {users} online | {unread} unread")?
+        .set_default("json", false)?
+        .set_default("signing", false)?
+        .set_default("signing_key_file", "")?
+        .set_default("encryption", false)?
+        .set_default("plaintext_fallback", false)?
+        .set_default("command_prefix", command::DEFAULT_PREFIX.to_string())?
+        .set_default("name", "")?
+        .set_default("auto_suffix_name", false)?
+        .set_default("auto_suffix_attempts", 10)?
+        .add_source(File::with_name("Config").required(false))
+        .build()?;
+
+    let cfg: ClientConfig = settings.try_deserialize()?;
+    let server_addr = match cfg.unix_socket.as_str() {
+        "" => format!("{}:{}", cfg.host, cfg.port),
+        path => format!("{}{}", UNIX_PREFIX, path),
+    };
+    let command_prefix = command::parse_prefix(&cfg.command_prefix)?;
+    let mut capabilities: Vec<String> = if cfg.compression { vec![CAP_COMPRESS.to_string()] } else { Vec::new() };
+    if cfg.encryption {
+        capabilities.push(CAP_E2E.to_string());
+    }
+    // 签名密钥在本次运行中保持不变, 重连后接收者仍能认出同一个公钥; 配置了密钥文件时跨运行也不变
+    let identity = match cfg.signing_key_file.as_str() {
+        "" => cfg.signing.then(Identity::generate),
+        path => Some(load_identity(Path::new(path))?),
+    };
+    let args: Vec<String> = std::env::args().collect();
+    // --public-key 只打印签名公钥后退出, 用来填写服务器的 admins 配置
+    if args.iter().any(|arg| arg == "--public-key") {
+        match &identity {
+            Some(identity) => println!("{}", identity.public_key()),
+            None => anyhow::bail!("set `signing_key_file` in Config.toml to get a public key that stays the same"),
+        }
+        return Ok(());
+    }
+    // 用户名: --name 参数优先, 其次是配置
+    let preset_name = arg_value(&args, "--name").or((!cfg.name.trim().is_empty()).then(|| cfg.name.trim().to_string()));
+    let suffix_attempts = if cfg.auto_suffix_name { cfg.auto_suffix_attempts } else { 0 };
+
+    // JSON 模式不使用终端界面, stdout 只输出 JSON
+    if cfg.json || args.iter().any(|arg| arg == "--json") {
+        return run_json(&server_addr, preset_name, &capabilities, identity.as_ref(), suffix_attempts, cfg.plaintext_fallback).await;
+    }
+
+    install_panic_hook();
+    let _terminal = TerminalGuard;
+
+    let name = match preset_name {
+        Some(name) => name,
+        None => match name_prompt("Enter your name: ", &mut stdin().lock())? {
+            Some(name) => name,
+            None => anyhow::bail!("input ended before a name was entered; pass --name <name> or set `name` in Config.toml to run without a terminal"),
+        },
+    };
+    println!("Connecting to server at {}", server_addr);
+
+    // 客户端，启动
+    let (mut sink, stream) = connect_first(&server_addr, &name, &capabilities, identity.as_ref(), suffix_attempts).await?;
+    sink.allow_plaintext(cfg.plaintext_fallback);
+    println!("✅ Successfully Connected to {}!", sink.server_name());
+    let name = sink.name().to_string();
+
+    // 已读回执由接收任务产生, 在输入循环中发送
+    let (receipts_tx, mut receipts_rx) = mpsc::unbounded_channel();
+    let shared = Shared {
+        name: name.clone(),
+        dnd: Arc::new(AtomicBool::new(false)),
+        pending: Arc::new(Mutex::new(HashSet::new())),
+        connected: Arc::new(AtomicBool::new(true)),
+        receipts: cfg.read_receipts.then_some(receipts_tx),
+        unread: Arc::new(AtomicUsize::new(0)),
+        users: Arc::new(AtomicU64::new(0)),
+        prompt: cfg.prompt.into(),
+        status_line: cfg.status_line.into(),
+        server_name: Arc::new(Mutex::new(sink.server_name().to_string())),
+        keys: Arc::new(Mutex::new(KeyPins::new())),
+        colors: Arc::new(Mutex::new(HashMap::new())),
+        recent: Arc::new(Mutex::new(VecDeque::new())),
+        retry_at: Arc::new(Mutex::new(None)),
+        export: Arc::new(Mutex::new(None)),
+    };
+    spawn_receiver(stream, shared.clone());
+
+    // 输入循环和 Ctrl+C 处理任务共用同一个发送端
+    let session = sink.session().to_string();
+    let signing = identity.is_some();
+    let link = Arc::new(tokio::sync::Mutex::new(Link {
+        sink: Some(sink),
+        outbox: VecDeque::new(),
+        capacity: cfg.outbox_capacity,
+        server_addr,
+        capabilities,
+        identity,
+        session,
+        plaintext_fallback: cfg.plaintext_fallback,
+    }));
+    let mut next_id: u64 = 0;
+
+    // Ctrl+C: 输入循环可能正阻塞在读取输入上, 由单独的任务通知服务器后退出, 避免服务器留下已断开的用户
+    {
+        let link = link.clone();
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                println!("\n[系统] Ctrl+C received, leaving the chat");
+                link.lock().await.quit(&shared).await;
+                restore_terminal();
+                std::process::exit(0);
+            }
+        });
+    }
 
     /* 在主线程里循环监听按键，
-        按 q 退出，
-        /w <user> <msg>（私聊）
+        /quit [reason] 退出聊天, reason 会附在离开通知中; Esc 为紧急退出
+        /w <user>[,<user>...] <msg>（私聊, 可同时发给多人）
         /users 请求当前用户列表
-        /history 请求历史聊天记录, 只能看见广播的消息、自己的请求和与自己相关的私聊消息
+        /whois <user> 查询用户是否在线、最近上线时间
+        /seen <user> 查询用户最近一次发言的时间
+        /history [page] 分页请求历史聊天记录, 只能看见广播的消息、自己的请求和与自己相关的私聊消息
+        /block <user> 屏蔽某个用户的私聊, /unblock <user> 取消屏蔽
+        /roll [NdM+K] 掷骰子, 结果所有人可见
+        /poll "<question>" <option>... 发起投票, /vote <id> <option> 投票, /poll-close <id> 结束并公布结果
+        /dnd 切换免打扰模式(仅本地生效)
+        /share <path> 计算本地文件的大小和 SHA-256, 把文件名和这些信息分享给所有人(不发送文件内容)
+        /paste 进入粘贴模式, 之后的多行输入直到单独一行 "." 为止作为一条群发发出
+        /reply <n|#id> <msg> 回复消息末尾显示的短编号 (n), 也可以用服务器的消息 id; 回复别人发给你的私聊时私聊回去, 否则群发
+        /recent 列出最近收到的 10 条消息和它们的短编号
+        /attach <path>[,<path>...] [msg] 群发一条带附件的消息, 只发送文件名、大小和类型, 文件由接收者另行获取
+        /export [path] 把自己能看到的历史(所在房间的广播、自己的私聊和指令)保存为 JSON 文件, 默认保存到当前目录
+        /quiet-joins 切换是否接收其他用户的上下线通知(由服务器过滤)
+        /color <颜色|none> 选择其他人看到的自己名字的颜色(由服务器保存)
+        /whoami 查看服务器记录的自己的用户名、房间和状态
+        /afk [msg] 标记为暂时离开, 私聊你的人会收到一次留言; 下次发送任何内容时自动取消
+        /stats 管理员查看每个用户的发送队列和群发的积压
+        /dumpstate [json|pretty] 管理员查看服务器状态快照
+        /o <msg> 群发一条不记入历史的消息
+        /ttl <secs> <msg> 群发一条 secs 秒后自动删除的消息
+        //<msg> 群发一条以 / 开头的消息, 例如 //tmp 发送 /tmp
         默认群发
-        通过 sink.send 发送给服务器
+        除 /dnd、/share、/attach、/paste、/recent、/reply 和 /export 外, 输入原样发给服务器, 由服务器解析指令; 开启签名或加密时 /w 在本地解析, 以便对内容签名、加密
+        通过 sink.send 发送给服务器, 断线时暂存并在重连后补发
     */
+    prompt(&shared)?;
     loop {
+        {
+            let mut link = link.lock().await;
+            // 发送积累的已读回执
+            while let Ok(message_id) = receipts_rx.try_recv() {
+                let receipt = ClientMessage::ReadReceipt { message_id };
+                link.send(receipt, &shared).await;
+            }
+            // 确认已显示的消息, 断线重连恢复会话时服务器只补发这之后的
+            if shared.connected.load(Ordering::Relaxed)
+                && let Some(sink) = link.sink.as_mut() {
+                let _ = sink.acknowledge().await;
+            }
+
+            // 接收任务报告连接断开: 立即尝试重连一次, 失败则之后的消息先排队, 发送时再重连
+            // 服务器说明了在重启或维护时不立即重连, 等到它给出的时间
+            let retry_at = *shared.retry_at.lock().unwrap();
+            if link.sink.is_some() && !shared.connected.load(Ordering::Relaxed) {
+                link.sink = None;
+                if retry_at.is_none() {
+                    if link.reconnect(&shared).await {
+                        link.flush().await;
+                    } else {
+                        println!("[系统] Reconnect failed, messages will be queued until the server is back");
+                    }
+                }
+                prompt(&shared)?;
+            }
+            // 到了自动重连的时间, 失败则过 RETRY_INTERVAL 再试
+            if link.sink.is_none() && retry_at.is_some_and(|at| Instant::now() >= at) {
+                if link.reconnect(&shared).await {
+                    link.flush().await;
+                } else {
+                    *shared.retry_at.lock().unwrap() = Some(Instant::now() + RETRY_INTERVAL);
+                }
+                prompt(&shared)?;
+            }
+        }
+
         // 每 500ms 检测一次键盘事件
-        if event::poll(std::time::Duration::from_millis(500))? {
-            if let Event::Key(key_event) = event::read()? {
-                
-                if key_event.code == KeyCode::Char('q') {
-                    break;
+        if event::poll(std::time::Duration::from_millis(500))?
+            && let Event::Key(key_event) = event::read()? {
+            
+            // Esc 作为紧急退出, 不通知服务器
+            if key_event.code == KeyCode::Esc {
+                break;
+            }
+            
+            let input = read_line()?;
+            // 用户回到输入, 之前收到的私聊视为已读
+            shared.unread.store(0, Ordering::Relaxed);
+
+            // 粘贴的大段文字超过服务器的帧长度上限时会被断开连接, 发送前先提醒用户
+            if too_long(ClientMessage::Raw { text: input.clone(), id: 0 }) {
+                prompt(&shared)?;
+                continue;
+            }
+
+            let cmd = command::parse_with(&input, command_prefix);
+
+            // 免打扰模式只影响本地显示, 不发送给服务器
+            if cmd.is_some_and(|c| c.keyword() == "dnd") {
+                let on = !shared.dnd.fetch_xor(true, Ordering::Relaxed);
+                println!("[系统] Do-not-disturb {}", if on { "on" } else { "off" });
+                prompt(&shared)?;
+                continue;
+            }
+
+            // 分享文件只发送元数据, 由客户端读取文件计算; 读文件和算哈希放到阻塞线程池, 不卡住输入循环
+            if let Some(cmd) = cmd.filter(|c| c.keyword() == "share") {
+                let path = cmd.rest;
+                if path.is_empty() {
+                    println!("[错误] Usage: /share <path>");
+                } else {
+                    let owned = path.to_string();
+                    let meta = tokio::task::spawn_blocking(move || FileMeta::from_path(owned)).await;
+                    match meta.map_err(std::io::Error::other).and_then(|meta| meta) {
+                        Ok(file) => link.lock().await.send(ClientMessage::FileOffer { file }, &shared).await,
+                        Err(e) => println!("[错误] Cannot share {}: {}", path, e),
+                    }
                 }
-                
-                let input = read_line()?;
-                
-                let msg = if input.starts_with("/w ") {
-                    let parts: Vec<&str> = input[3..].splitn(2, ' ').collect();
-                    Message::Clientmsg(ClientMessage::Private {
-                        from: name.clone(),
-                        to: parts[0].to_string(),
-                        content: parts[1].to_string(),
-                    })
-                } else if input == "/users"{
-                    Message::Clientmsg(ClientMessage::Command { from: name.clone(), command: "/users".to_string()})
-                } else if input == "/history"{
-                    Message::Clientmsg(ClientMessage::Command { from: name.clone(), command: "/history".to_string()})
-                } else{
-                    Message::Clientmsg(ClientMessage::Broadcast { from: name.clone(), content: input })
+                prompt(&shared)?;
+                continue;
+            }
+
+            // 带附件的群发: 读取本地文件的名字、大小和类型, 和消息一起发出
+            if let Some(cmd) = cmd.filter(|c| c.keyword() == "attach") {
+                let Some((paths, content)) = cmd.first_arg() else {
+                    println!("[错误] Usage: /attach <path>[,<path>...] [message]");
+                    prompt(&shared)?;
+                    continue;
                 };
-                // 发送消息
-                if sink.send(msg).await.is_err() {
-                    break;
+                let attachments = paths.split(',').map(str::trim).filter(|p| !p.is_empty())
+                    .map(|path| AttachmentMeta::from_path(path).map_err(|e| format!("Cannot attach {}: {}", path, e)))
+                    .collect::<Result<Vec<_>, _>>();
+                match attachments {
+                    Err(e) => println!("[错误] {}", e),
+                    Ok(attachments) if attachments.len() > MAX_ATTACHMENTS => println!("[错误] At most {} attachments per message", MAX_ATTACHMENTS),
+                    Ok(attachments) => {
+                        next_id += 1;
+                        shared.pending.lock().unwrap().insert(next_id);
+                        println!("{}", format!("[{}] {} (sending...)", name, content).dark_grey());
+                        for line in attachment_lines(&attachments) {
+                            println!("{}", line.dark_grey());
+                        }
+                        let msg = ClientMessage::Broadcast {
+                            content: content.to_string(), id: next_id, ephemeral: false, ttl_secs: None, reply_to: None, attachments,
+                        };
+                        link.lock().await.send(msg, &shared).await;
+                    }
+                }
+                prompt(&shared)?;
+                continue;
+            }
+
+            // 粘贴模式: 多行内容作为一条群发发出, 不经过服务器的指令解析
+            if cmd.is_some_and(|c| c.keyword() == "paste") {
+                println!("[系统] Paste mode, end with a line containing only \".\"");
+                let content = read_block()?;
+                if content.trim().is_empty() {
+                    println!("[系统] Nothing to send");
+                } else if !too_long(ClientMessage::Broadcast { content: content.clone(), id: 0, ephemeral: false, ttl_secs: None, reply_to: None, attachments: Vec::new() }) {
+                    next_id += 1;
+                    shared.pending.lock().unwrap().insert(next_id);
+                    println!("{}", format!("[{}] {} (sending...)", name, indent_continuation(&content)).dark_grey());
+                    let msg = ClientMessage::Broadcast { content, id: next_id, ephemeral: false, ttl_secs: None, reply_to: None, attachments: Vec::new() };
+                    link.lock().await.send(msg, &shared).await;
+                }
+                prompt(&shared)?;
+                continue;
+            }
+
+            // 列出最近收到的消息和它们的短编号
+            if cmd.is_some_and(|c| c.keyword() == "recent") {
+                let recent = shared.recent.lock().unwrap();
+                if recent.is_empty() {
+                    println!("[系统] No messages yet");
+                }
+                for m in recent.iter().skip(recent.len().saturating_sub(RECENT_LISTED)) {
+                    let kind = if m.private { "[私聊]" } else { "" };
+                    println!("({}) {}[{}] {}", m.index, kind, m.from, snippet(&m.content));
+                }
+                drop(recent);
+                prompt(&shared)?;
+                continue;
+            }
+
+            // 导出历史: 记下保存的路径, 服务器分几帧发回的消息由接收任务收齐后写入文件
+            if let Some(cmd) = cmd.filter(|c| c.keyword() == "export") {
+                let path = match cmd.rest {
+                    "" => default_export_path(&name, SystemTime::now()),
+                    path => PathBuf::from(path),
+                };
+                *shared.export.lock().unwrap() = Some(PendingExport { path, messages: Vec::new() });
+                link.lock().await.send(ClientMessage::Command { command: Command::Export }, &shared).await;
+                prompt(&shared)?;
+                continue;
+            }
+
+            // 回复: 回复别人发给自己的私聊时私聊回去, 其余作为群发回复
+            if let Some(cmd) = cmd.filter(|c| c.keyword() == "reply") {
+                let args = cmd.first_arg().filter(|(_, content)| !content.is_empty());
+                let target = args.as_ref().map(|(arg, content)| (reply_target(&shared.recent.lock().unwrap(), arg), *content));
+                match target {
+                    None => println!("[错误] Usage: /reply <n|#id> <message>"),
+                    Some((None, _)) => println!("[错误] No recent message ({}), see /recent", args.map(|(arg, _)| arg).unwrap_or_default()),
+                    Some((Some((reply_to, private_from)), content)) => {
+                        let msg = match private_from {
+                            Some(from) => ClientMessage::Private {
+                                to: vec![from], content: content.to_string(), ephemeral: false, ttl_secs: None,
+                                signature: None, encrypted: false, reply_to: Some(reply_to),
+                            },
+                            None => {
+                                next_id += 1;
+                                shared.pending.lock().unwrap().insert(next_id);
+                                println!("{}", format!("{}\n[{}] {} (sending...)", reply_line(reply_to, &shared), name, content).dark_grey());
+                                ClientMessage::Broadcast { content: content.to_string(), id: next_id, ephemeral: false, ttl_secs: None, reply_to: Some(reply_to), attachments: Vec::new() }
+                            }
+                        };
+                        link.lock().await.send(msg, &shared).await;
+                    }
+                }
+                prompt(&shared)?;
+                continue;
+            }
+
+            // 指令、私聊等都由服务器解析, 客户端原样发送整行输入
+            // 普通群发先在本地以灰色显示, 等服务器回显后再对应上
+            next_id += 1;
+            if cmd.is_none() {
+                shared.pending.lock().unwrap().insert(next_id);
+                let text = command::unescape(&input, command_prefix);
+                println!("{}", format!("[{}] {} (sending...)", name, text).dark_grey());
+            }
+            let quit = cmd.is_some_and(|c| c.keyword() == "quit");
+            let whisper = cmd.filter(|c| c.keyword() == "w" && (signing || cfg.encryption)).and_then(|c| c.whisper());
+            let mut link = link.lock().await;
+            let msg = match whisper {
+                Some((to, content)) => {
+                    // 允许退回明文时, 发给没有可信公钥的接收者前提醒; 不允许时由发送端拒绝, 整条不发
+                    if cfg.encryption && cfg.plaintext_fallback && let Some(sink) = &link.sink {
+                        let plain = to.iter().filter(|t| !sink.can_encrypt_to(t)).cloned().collect::<Vec<_>>();
+                        if !plain.is_empty() {
+                            println!("[系统] No encryption key for {}, sending unencrypted", plain.join(", "));
+                        }
+                    }
+                    ClientMessage::Private {
+                        to, content: content.to_string(), ephemeral: false, ttl_secs: None, signature: None, encrypted: false, reply_to: None,
+                    }
                 }
+                None => ClientMessage::Raw { text: input, id: next_id },
+            };
+            // 发送消息
+            link.send(msg, &shared).await;
+            drop(link);
+            // /quit 发出后直接退出, 由服务器向其他人广播离开通知
+            if quit {
+                break;
             }
+            prompt(&shared)?;
         }
     }
     println!("{} exit", name);
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn urls(text: &str) -> Vec<&str> {
+        find_urls(text).into_iter().map(|(s, e)| &text[s..e]).collect()
+    }
+
+    #[test]
+    fn attachments_are_listed_one_per_line() {
+        let attachments = [
+            AttachmentMeta { name: "plan.pdf".into(), size: 2048, content_type: "application/pdf".into() },
+            AttachmentMeta { name: "cat.png".into(), size: 12, content_type: "image/png".into() },
+        ];
+        assert_eq!(attachment_lines(&attachments), [
+            "    [附件 1] plan.pdf (2.0 KiB, application/pdf)",
+            "    [附件 2] cat.png (12 B, image/png)",
+        ]);
+        assert!(attachment_lines(&[]).is_empty());
+    }
+
+    #[test]
+    fn name_prompt_skips_blank_lines_and_stops_at_eof() {
+        let mut input = std::io::Cursor::new("\n  \n alice \n");
+        assert_eq!(name_prompt("", &mut input).unwrap().as_deref(), Some("alice"));
+        assert_eq!(name_prompt("", &mut std::io::Cursor::new("")).unwrap(), None);
+        assert_eq!(name_prompt("", &mut std::io::Cursor::new("\n")).unwrap(), None);
+    }
+
+    #[test]
+    fn taken_names_get_a_numbered_suffix_within_the_length_limit() {
+        assert_eq!(suffixed_name("alice", 2), "alice_2");
+        assert_eq!(suffixed_name("alice", 10), "alice_10");
+        let long = "a".repeat(MAX_NAME_LEN);
+        assert_eq!(suffixed_name(&long, 3), format!("{}_3", "a".repeat(MAX_NAME_LEN - 2)));
+        assert_eq!(suffixed_name("爱丽丝", 2), "爱丽丝_2");
+    }
+
+    #[test]
+    fn flag_values_follow_the_flag() {
+        let args: Vec<String> = ["client", "--json", "--name", "bob"].map(String::from).into();
+        assert_eq!(arg_value(&args, "--name").as_deref(), Some("bob"));
+        assert_eq!(arg_value(&args, "--identity"), None);
+        assert_eq!(arg_value(&args[..3], "--name"), None);
+    }
+
+    #[test]
+    fn signing_keys_are_created_once_and_reused() {
+        let path = std::env::temp_dir().join(format!("rustchat-key-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let created = load_identity(&path).unwrap();
+        assert_eq!(load_identity(&path).unwrap().public_key(), created.public_key());
+        #[cfg(unix)]
+        assert_eq!(std::os::unix::fs::PermissionsExt::mode(&std::fs::metadata(&path).unwrap().permissions()) & 0o777, 0o600);
+        std::fs::write(&path, "not a key").unwrap();
+        assert!(load_identity(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn export_is_saved_as_json_that_reads_back() {
+        let path = std::env::temp_dir().join(format!("rustchat-export-{}.json", std::process::id()));
+        let export = HistoryExport {
+            user: "alice".into(),
+            server: "rustchat".into(),
+            exported_at: 1_700_000_000,
+            messages: vec![ExportedMessage {
+                message_id: 3, sent_at: 1_699_999_999, kind: rustchat::common::export::ExportKind::Private,
+                from: "bob".into(), to: vec!["alice".into()], content: "hi \"there\"\n".into(), reply_to: Some(1),
+                attachments: Vec::new(),
+            }],
+            blocked: vec!["mallory".into()],
+        };
+        save_export(&path, &export).unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(serde_json::from_str::<HistoryExport>(&saved).unwrap(), export);
+        assert!(saved.contains("\"kind\": \"Private\""), "{}", saved);
+
+        let at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(default_export_path("alice", at), PathBuf::from("alice-history-1700000000.json"));
+    }
+
+    #[test]
+    fn reply_targets_resolve_short_indexes_and_message_ids() {
+        let seen = |index, message_id, private| Seen { index, message_id, from: "bob".into(), content: "hi".into(), private };
+        let recent = VecDeque::from([seen(3, 40, false), seen(4, 41, true), seen(3, 90, false)]);
+        // 短编号重复时取最近的一条
+        assert_eq!(reply_target(&recent, "3"), Some((90, None)));
+        assert_eq!(reply_target(&recent, "4"), Some((41, Some("bob".into()))));
+        assert_eq!(reply_target(&recent, "#41"), Some((41, Some("bob".into()))));
+        assert_eq!(reply_target(&recent, "#7"), Some((7, None)));
+        assert_eq!(reply_target(&recent, "5"), None);
+        assert_eq!(reply_target(&recent, "x"), None);
+    }
+
+    #[test]
+    fn reply_quotes_are_cut_to_the_first_line() {
+        assert_eq!(snippet("short"), "short");
+        assert_eq!(snippet("line one\nline two"), "line one…");
+        assert_eq!(snippet(&"好".repeat(50)), format!("{}…", "好".repeat(QUOTE_CHARS)));
+    }
+
+    #[test]
+    fn continuation_lines_are_indented() {
+        assert_eq!(indent_continuation("fn main() {\n    run();\n}"), "fn main() {\n        run();\n    }");
+        assert_eq!(indent_continuation("one line"), "one line");
+    }
+
+    #[test]
+    fn finds_urls_by_prefix() {
+        assert_eq!(
+            urls("see https://rust-lang.org and http://a.b/c?d=1 or www.example.com"),
+            vec!["https://rust-lang.org", "http://a.b/c?d=1", "www.example.com"]
+        );
+    }
+
+    #[test]
+    fn prefix_match_ignores_case() {
+        assert_eq!(urls("HTTPS://EXAMPLE.COM"), vec!["HTTPS://EXAMPLE.COM"]);
+    }
+
+    #[test]
+    fn trailing_punctuation_is_not_part_of_url() {
+        assert_eq!(urls("go to https://x.io/a)."), vec!["https://x.io/a"]);
+        assert_eq!(urls("(https://x.io), ok"), vec!["https://x.io"]);
+        assert_eq!(urls("<www.x.io>"), vec!["www.x.io"]);
+    }
+
+    #[test]
+    fn bare_prefix_and_plain_words_are_not_urls() {
+        assert!(urls("http:// www. hello httpfoo").is_empty());
+        assert!(urls("").is_empty());
+    }
+
+    #[test]
+    fn byte_ranges_are_correct_after_multibyte_text() {
+        assert_eq!(urls("你好 https://例子.cn/路径 世界"), vec!["https://例子.cn/路径"]);
+    }
+
+    #[test]
+    fn status_line_fills_in_the_template() {
+        let shared = Shared {
+            name: "alice".into(),
+            dnd: Arc::new(AtomicBool::new(false)),
+            pending: Arc::new(Mutex::new(HashSet::new())),
+            connected: Arc::new(AtomicBool::new(true)),
+            receipts: None,
+            unread: Arc::new(AtomicUsize::new(3)),
+            users: Arc::new(AtomicU64::new(2)),
+            prompt: "> ".into(),
+            status_line: "{name}@{server} | {state} | {users} online | {unread} unread | {other}".into(),
+            server_name: Arc::new(Mutex::new("lab".into())),
+            keys: Arc::new(Mutex::new(KeyPins::new())),
+            colors: Arc::new(Mutex::new(HashMap::new())),
+            recent: Arc::new(Mutex::new(VecDeque::new())),
+            retry_at: Arc::new(Mutex::new(None)),
+            export: Arc::new(Mutex::new(None)),
+        };
+        assert_eq!(status_line(&shared), "alice@lab | online | 2 online | 3 unread | {other}");
+        shared.connected.store(false, Ordering::Relaxed);
+        assert!(status_line(&shared).contains("offline"));
+    }
+
+    #[test]
+    fn only_a_plain_shutdown_stops_the_client() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000);
+        assert_eq!(exit_notice(ShutdownReason::Shutdown, now).1, None);
+        assert_eq!(exit_notice(ShutdownReason::Restart, now).1, Some(RESTART_DELAY));
+        let (notice, retry_in) = exit_notice(ShutdownReason::Maintenance { until: 1_000 + 90 }, now);
+        assert_eq!(retry_in, Some(Duration::from_secs(90)));
+        assert!(notice.contains("about 2 min"), "{notice}");
+        // 维护结束的时间已经过去时马上重连
+        assert_eq!(exit_notice(ShutdownReason::Maintenance { until: 10 }, now).1, Some(Duration::ZERO));
+        // 远得离谱的结束时间按最长等待计
+        let (notice, retry_in) = exit_notice(ShutdownReason::Maintenance { until: u64::MAX }, now);
+        assert_eq!(retry_in, Some(MAX_MAINTENANCE_WAIT));
+        assert!(notice.contains("about 1440 min"), "{notice}");
+    }
+
+    #[test]
+    fn highlight_leaves_non_url_text_unchanged() {
+        assert_eq!(highlight_urls("no links here"), "no links here");
+        assert!(highlight_urls("a https://x.io b").contains("https://x.io"));
+    }
+}
@@ -1,279 +1,480 @@
-use tokio::{net::{TcpListener, TcpStream}, sync::Mutex};
-use tokio_util::codec::Framed;                
-use futures::{SinkExt, StreamExt};          
-use anyhow::Result;                           
-use std::{sync::Arc, collections::HashMap};
-use std::collections::VecDeque;
-use tokio::sync::mpsc;
-use config::{Config, File};
+use anyhow::{anyhow, bail, Context, Result};
+use config::builder::{ConfigBuilder, DefaultState};
+use config::{Config, ConfigError, File, Map, Source, Value};
 use serde::Deserialize;                        
-use rustchat::common::{Message, ServerMessage, ClientMessage};
-use rustchat::common::codec::LengthCodec;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+use rustchat::client::ChatClient;
+use rustchat::common::ServerMessage;
+use rustchat::common::export::HistoryExport;
+use rustchat::common::command::Command;
+use rustchat::common::command;
+use rustchat::server::{ActionCommand, ContentFilter, ControlChars, Drain, DumpFormat, Echo, FilterPolicy, HelpBot, HistoryFormat, IpAccess, Locale, PresenceFormat, Server, ServerBuilder, ShutdownReason, SpamPolicy, DEFAULT_BROADCAST_CAPACITY, DEFAULT_DEDUP_WINDOW, DEFAULT_FRAME_TIMEOUT, DEFAULT_JOIN_HISTORY, DEFAULT_MAX_HISTORY_BYTES, DEFAULT_MAX_HISTORY_SIZE, DEFAULT_RESERVED_NAMES, DEFAULT_SESSION_TTL, DEFAULT_SERVER_NAME, DEFAULT_WRITE_TIMEOUT};
 
-const MAX_HISTORY_SIZE: usize = 100;
-
-/* 共享服务器状态
-    clients: 所有已连接的客户端维护“用户名 -> 发送通道”的映射，用于确定消息的接收方
-    broadcast_history: 所有广播的消息
-    private_history: 私聊消息, 且按客户分开存放
-*/
-struct ServerState {
-    clients: HashMap<String, mpsc::Sender<Message>>,
-    broadcast_history: VecDeque<String>, 
-    private_history: HashMap<String, VecDeque<String>>,
-}
-impl Default for ServerState {
-    fn default() -> Self { ServerState { 
-        clients: HashMap::new(),
-        broadcast_history: VecDeque::with_capacity(MAX_HISTORY_SIZE),
-        private_history: HashMap::new()
-    } }
-}
-
-// 服务器的监听地址和段靠谱
+// 服务器的监听地址、端口和其他配置
 #[derive(Debug, Deserialize)]
 struct ServerConfig {
     host: String,
     port: u16,
+    unix_socket: String,            // 另外监听的 Unix 本地套接字路径, 为空则只监听 TCP; 客户端设置同一项时通过它连接
+    filter_words: Vec<String>,      // 过滤词列表, 为空则不过滤
+    filter_policy: FilterPolicy,    // "reject" 或 "mask"
+    control_chars: ControlChars,    // 消息中的控制字符: "strip" 删除或 "escape" 显示为 \x1b 形式
+    max_names_per_ip: usize,        // 每个 IP 同时最多注册的用户名数量, 0 表示不限制
+    max_connections: usize,         // 同时处理的连接数上限, 包括还没有注册的连接, 0 表示不限制
+    compression: bool,              // 是否允许与客户端协商压缩
+    join_history: usize,            // 新用户加入时补发最近多少条广播, 0 表示不补发
+    max_broadcast_history: usize,   // 每个房间最多保留多少条广播, 必须大于 0
+    max_private_history: usize,     // 每个用户最多保留多少条私聊, 必须大于 0
+    max_history_bytes: usize,       // 全部历史合计最多占用的字节数, 0 表示不限制
+    max_history_age_secs: u64,      // 历史最多保留多少秒, 更早的消息被清理, 0 表示不限制
+    rate_limit: usize,              // rate_window_secs 秒内最多群发多少条, 0 表示不限制
+    rate_window_secs: u64,
+    spam_max_repeats: usize,        // 最多连续发送多少条相同内容, 超出时禁言, 0 表示不检查
+    spam_max_strikes: usize,        // 超出速率限制多少次后禁言, 0 表示只丢弃不禁言
+    mute_secs: u64,                 // 禁言时长
+    allow_ips: Vec<String>,         // 只接受这些网段的连接, 为空则不限制
+    deny_ips: Vec<String>,          // 拒绝这些网段的连接, 优先于 allow_ips
+    history_broadcast_format: String,   // 历史记录模板, 见 HistoryFormat
+    history_sent_format: String,
+    history_received_format: String,
+    join_format: String,            // 上下线通知的模板, 见 PresenceFormat; 为空则使用 locale 的默认文字
+    leave_format: String,
+    leave_reason_format: String,
+    greeting: String,               // 新用户加入时发给他的欢迎语, 可用 {name} {count} {server}; 为空则不发
+    locale: Locale,                 // 系统消息的语言, "en" 或 "zh"
+    command_prefix: String,         // 指令前缀, 与客户端共用同一项配置
+    echo_users: Vec<String>,        // 启动时注册的回声服务用户, 私聊它会收到同样的内容
+    helpbot: bool,                  // 是否启动帮助机器人 helpbot
+    server_name: String,            // 服务器的名字, 注册时告诉客户端
+    heartbeat_log_secs: u64,        // 每隔多少秒打印一次当前连接数, 0 表示不打印
+    broadcast_capacity: usize,      // 群发通道的容量, 接收太慢落后更多的用户会错过最旧的消息
+    session_ttl_secs: u64,          // 断线后会话保留多少秒, 期间重连可以补收错过的消息
+    dedup_window: usize,            // 每个会话记住最近多少个客户端消息 id, 重复发送的消息只处理一次, 0 表示不去重
+    write_timeout_secs: u64,        // 发送一帧最多等多少秒, 超时的客户端被断开, 0 表示一直等
+    frame_timeout_secs: u64,        // 收齐一帧最多等多少秒, 停在半帧上的客户端被断开, 0 表示一直等
+    admins: BTreeMap<String, String>,   // 可以使用管理指令的用户名 -> 签名公钥(hex), 只能用这个密钥注册
+    import_history: Vec<String>,    // 启动时导入的历史文件(/export 的格式), 用于从旧服务器迁移
+    import_dir: String,             // /import 只能读取这个目录中的文件, 为空则不允许 /import
+    reserved_names: Vec<String>,    // 普通用户不能注册的用户名, 不区分大小写
+    dumpstate_format: DumpFormat,   // /dumpstate 默认的输出格式, "pretty" 或 "json"
+    actions: BTreeMap<String, ActionCommand>,   // 动作指令, 指令名 -> 模板和参数个数, 见 ActionCommand
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // 服务器绑定 TCP 接口
-    let settings = Config::builder()
+// 配置项的默认值, 也用来判断 Config.toml 中哪些是服务器认识的配置项
+fn defaults() -> Result<ConfigBuilder<DefaultState>> {
+    let history_format = HistoryFormat::default();
+    Ok(Config::builder()
         // 默认IP和端口
         .set_default("host", "0.0.0.0")?
         .set_default("port", 8080)?
-        //再看当前目录下是否有 Config.toml（可选）去合并
-        .add_source(File::with_name("Config").required(false))
-        .build()?;
+        .set_default("unix_socket", "")?
+        .set_default("filter_words", Vec::<String>::new())?
+        .set_default("filter_policy", "mask")?
+        .set_default("control_chars", "strip")?
+        .set_default("max_names_per_ip", 0)?
+        .set_default("max_connections", 0)?
+        .set_default("compression", true)?
+        .set_default("join_history", DEFAULT_JOIN_HISTORY as u64)?
+        .set_default("max_broadcast_history", DEFAULT_MAX_HISTORY_SIZE as u64)?
+        .set_default("max_private_history", DEFAULT_MAX_HISTORY_SIZE as u64)?
+        .set_default("max_history_bytes", DEFAULT_MAX_HISTORY_BYTES as u64)?
+        .set_default("max_history_age_secs", 0)?
+        .set_default("rate_limit", 0)?
+        .set_default("rate_window_secs", 10)?
+        .set_default("spam_max_repeats", 0)?
+        .set_default("spam_max_strikes", 3)?
+        .set_default("mute_secs", 60)?
+        .set_default("allow_ips", Vec::<String>::new())?
+        .set_default("deny_ips", Vec::<String>::new())?
+        .set_default("history_broadcast_format", history_format.broadcast)?
+        .set_default("history_sent_format", history_format.private_sent)?
+        .set_default("history_received_format", history_format.private_received)?
+        .set_default("join_format", "")?
+        .set_default("leave_format", "")?
+        .set_default("leave_reason_format", "")?
+        .set_default("greeting", "")?
+        .set_default("locale", "en")?
+        .set_default("command_prefix", command::DEFAULT_PREFIX.to_string())?
+        .set_default("echo_users", Vec::<String>::new())?
+        .set_default("helpbot", false)?
+        .set_default("server_name", DEFAULT_SERVER_NAME)?
+        .set_default("heartbeat_log_secs", 60)?
+        .set_default("broadcast_capacity", DEFAULT_BROADCAST_CAPACITY as u64)?
+        .set_default("session_ttl_secs", DEFAULT_SESSION_TTL.as_secs())?
+        .set_default("dedup_window", DEFAULT_DEDUP_WINDOW as u64)?
+        .set_default("write_timeout_secs", DEFAULT_WRITE_TIMEOUT.as_secs())?
+        .set_default("frame_timeout_secs", DEFAULT_FRAME_TIMEOUT.as_secs())?
+        .set_default("admins", Map::<String, Value>::new())?
+        .set_default("import_history", Vec::<String>::new())?
+        .set_default("import_dir", "")?
+        .set_default("reserved_names", DEFAULT_RESERVED_NAMES.to_vec())?
+        .set_default("dumpstate_format", "pretty")?
+        .set_default("actions", Map::<String, Value>::new())?)
+}
 
-    let cfg: ServerConfig = settings.try_deserialize()?;
-    let bind_addr = format!("{}:{}", cfg.host, cfg.port);
+// 这些配置项关系到访问控制和内容过滤, 写错时拒绝启动, 不悄悄换成默认值
+const STRICT_KEYS: &[&str] = &[
+    "allow_ips", "deny_ips", "admins", "reserved_names", "filter_words", "filter_policy", "control_chars",
+];
 
-    // 服务器，启动
-    let listener = TcpListener::bind(&bind_addr).await?;
-    println!("Server is up on {}", bind_addr);
+// 客户端读取的配置项, 出现在同一个 Config.toml 中不算写错
+const CLIENT_KEYS: &[&str] = &[
+    "outbox_capacity", "read_receipts", "prompt", "status_line", "json", "signing", "signing_key_file", "encryption", "plaintext_fallback", "name",
+    "auto_suffix_name", "auto_suffix_attempts",
+];
 
-    let state = Arc::new(Mutex::new(ServerState::default()));
+// 出错的配置项名, "filter_words[0]" 这样的路径只取最前面的名字
+fn bad_key(err: &ConfigError) -> Option<String> {
+    let key = match err {
+        ConfigError::Type { key: Some(key), .. } | ConfigError::At { key: Some(key), .. } => key,
+        _ => return None,
+    };
+    key.split(['.', '[']).next().map(str::to_string)
+}
 
-    // 服务器关闭信号：Ctrl+C
-    let shutdown = tokio::signal::ctrl_c();
-    tokio::pin!(shutdown);
+// 合并默认值和 source 并解析配置: 不认识的配置项和类型写错的普通配置项只产生警告,
+// 后者换成默认值; 无法解析的文件和写错的 STRICT_KEYS 返回指出配置项的错误
+fn load_config<S>(source: S) -> Result<(ServerConfig, Vec<String>)>
+where
+    S: Source + Clone + Send + Sync + 'static,
+{
+    let fallback = defaults()?.build()?;
+    let known = fallback.collect()?;
+    let values = source.collect().context("Config.toml could not be parsed")?;
+    let mut warnings = Vec::new();
+    let mut unknown: Vec<_> = values
+        .keys()
+        .filter(|key| !known.contains_key(*key) && !CLIENT_KEYS.contains(&key.as_str()))
+        .collect();
+    unknown.sort();
+    for key in unknown {
+        warnings.push(format!("unknown key `{key}` in Config.toml is ignored"));
+    }
 
-    /* 接受新信号：
-        如果是新连接，则用 tokio::spawn 为每个客户端开一个任务
-        如果是Ctrl+C，则关闭服务器
-    */
+    let mut builder = defaults()?.add_source(source);
+    let mut replaced = Vec::new();
     loop {
-        tokio::select! {
-            accept_res = listener.accept() => {
-                match accept_res {
-                    Ok((socket, addr)) => {
-                        println!("New connection: {}", addr);
-                        let state = state.clone();
-                        tokio::spawn(async move {
-                            if let Err(e) = handle_client(socket, state).await {
-                                eprintln!("Client handle error: {}", e);
-                            }
-                        });
-                    }
-                    Err(e) => eprintln!("Accept error: {}", e),
-                }
-            }
-            _ = &mut shutdown => {
-                println!("Ctrl+C received, shutting down server...");
-
-                let clients = state.lock().await.clients.clone();
-                for (_name, tx) in clients {
-                    let shutdown_msg = Message::Servermsg(ServerMessage::Exit);
-                    let _ = tx.send(shutdown_msg).await;
-                }
-                
-                // 清空 clients，使写任务自然终止
-                state.lock().await.clients.clear();
-                
-                // 等待一小段时间，确保通知下发完毕
-                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-
-                break;
-            }
+        let err = match builder.build_cloned()?.try_deserialize::<ServerConfig>() {
+            Ok(cfg) => return Ok((cfg, warnings)),
+            Err(err) => err,
+        };
+        let Some(key) = bad_key(&err) else {
+            bail!("invalid Config.toml: {err}");
+        };
+        // 同一项换成默认值后仍然出错时不再重试, 避免死循环
+        if STRICT_KEYS.contains(&key.as_str()) || replaced.contains(&key) {
+            bail!("invalid value for `{key}` in Config.toml: {err}");
         }
-    }  
-    Ok(())
+        warnings.push(format!("{err}; using the default"));
+        builder = builder.set_override(key.as_str(), fallback.get::<Value>(&key)?)?;
+        replaced.push(key);
+    }
 }
 
-// 处理单个客户端连接
-async fn handle_client(socket: TcpStream, state: Arc<Mutex<ServerState>>) -> Result<()> {
-    // 使用在common.rs中定义的编解码器
-    let mut framed = Framed::new(socket, LengthCodec);
+// 按配置组装服务器, 还没有绑定端口
+fn server_builder(cfg: ServerConfig) -> Result<ServerBuilder> {
+    let bind_addr = format!("{}:{}", cfg.host, cfg.port);
+    let ip_access = IpAccess::parse(&cfg.allow_ips, &cfg.deny_ips)
+        .context("invalid `allow_ips` or `deny_ips` in Config.toml")?;
+    let command_prefix = command::parse_prefix(&cfg.command_prefix)
+        .context("invalid `command_prefix` in Config.toml")?;
+    let history_format = HistoryFormat {
+        broadcast: cfg.history_broadcast_format,
+        private_sent: cfg.history_sent_format,
+        private_received: cfg.history_received_format,
+    };
+    let template = |format: String| (!format.is_empty()).then_some(format);
+    let presence_format = PresenceFormat {
+        joined: template(cfg.join_format),
+        left: template(cfg.leave_format),
+        left_with_reason: template(cfg.leave_reason_format),
+    };
 
-    if let Some(Ok(msg)) = framed.next().await {
-        // 独立处理第一则消息，因此第一次通信是 Reegister 消息，用存储册用户名和发送通道
-        if let Message::Clientmsg(ClientMessage::Register { name }) = msg {
-            // 注册用户，并在服务器中储存发送端tx
-            let (tx, mut rx) = mpsc::channel(100);
-            state.lock().await.clients.insert(name.clone(), tx);
-            // 广播“某用户”加入聊天的消息
-            register(&name, &state).await;
-            // 分离编码与解码：Sink 用于编码，Stream 用于解码
-            let (mut sink, mut stream) = framed.split();
-            // rx.recv() 接收该客户端消息并发送给特定的客户端
-            tokio::spawn(async move {
-                while let Some(msg) = rx.recv().await {
-                    if sink.send(msg).await.is_err() {
-                        break; 
-                    }
-                }
-            });
+    let mut builder = Server::builder();
+    for name in cfg.echo_users {
+        builder = builder.service(name, Echo);
+    }
+    if cfg.helpbot {
+        builder = builder.service("helpbot", HelpBot::new(cfg.locale, command_prefix));
+    }
+    if !cfg.unix_socket.is_empty() {
+        builder = builder.unix_socket(cfg.unix_socket);
+    }
+    for (name, action) in cfg.actions {
+        builder = builder.action(name, action);
+    }
+    if !cfg.import_dir.is_empty() {
+        builder = builder.import_dir(cfg.import_dir);
+    }
+    if !cfg.greeting.is_empty() {
+        builder = builder.greeting(cfg.greeting);
+    }
+    for path in &cfg.import_history {
+        let export = read_history(path).with_context(|| format!("history file {path} could not be imported"))?;
+        builder = builder.import_history(export);
+    }
+    Ok(builder
+        .bind(bind_addr)
+        .filter(ContentFilter { words: cfg.filter_words, policy: cfg.filter_policy })
+        .control_chars(cfg.control_chars)
+        .max_names_per_ip(cfg.max_names_per_ip)
+        .max_connections(cfg.max_connections)
+        .compression(cfg.compression)
+        .join_history(cfg.join_history)
+        .max_broadcast_history(cfg.max_broadcast_history)
+        .max_private_history(cfg.max_private_history)
+        .max_history_bytes(cfg.max_history_bytes)
+        .max_history_age(Duration::from_secs(cfg.max_history_age_secs))
+        .spam_policy(SpamPolicy {
+            rate_limit: cfg.rate_limit,
+            rate_window: Duration::from_secs(cfg.rate_window_secs),
+            max_repeats: cfg.spam_max_repeats,
+            max_strikes: cfg.spam_max_strikes,
+            mute_duration: Duration::from_secs(cfg.mute_secs),
+        })
+        .ip_access(ip_access)
+        .history_format(history_format)
+        .presence_format(presence_format)
+        .locale(cfg.locale)
+        .command_prefix(command_prefix)
+        .server_name(cfg.server_name)
+        .heartbeat_log(Duration::from_secs(cfg.heartbeat_log_secs))
+        .broadcast_capacity(cfg.broadcast_capacity)
+        .session_ttl(Duration::from_secs(cfg.session_ttl_secs))
+        .dedup_window(cfg.dedup_window)
+        .write_timeout(Duration::from_secs(cfg.write_timeout_secs))
+        .frame_timeout(Duration::from_secs(cfg.frame_timeout_secs))
+        .admins(cfg.admins)
+        .reserved_names(cfg.reserved_names)
+        .dump_format(cfg.dumpstate_format))
+}
 
-            // 读取循环：接收该客户端发来的消息并处理
-            while let Some(Ok(Message::Clientmsg(msg))) = stream.next().await {
-                match &msg {
-                    ClientMessage::Broadcast { .. } => broadcast(msg, &state).await,
-                    ClientMessage::Private { .. }   => dispatch(msg, &state).await,
-                    ClientMessage::Command { .. }   => command(msg, &state).await,
-                    _ => (),
-                }
-            }
+// 读取并检查一个 /export 导出的历史文件
+fn read_history(path: &str) -> Result<HistoryExport> {
+    let export: HistoryExport = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    export.validate()?;
+    Ok(export)
+}
+
+// --self-test 中等待每条回复的最长时间
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(5);
 
-            // 客户端断开，移除状态并广播离开通知(系统消息)
-            state.lock().await.clients.remove(&name);
-            let leave_msg = Message::Servermsg(ServerMessage::System { content: name.clone() + " leave the chat" });
-            for (_name, tx) in state.lock().await.clients.clone() {
-                let _ = tx.send(leave_msg.clone()).await;
+// 读取消息直到满足条件, 超时或连接关闭时返回错误
+async fn expect(client: &mut ChatClient, pred: impl Fn(&ServerMessage) -> bool) -> Result<ServerMessage> {
+    let wait = async {
+        loop {
+            match client.next_message().await {
+                Some(Ok(msg)) if pred(&msg) => return Ok(msg),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+                None => bail!("connection closed"),
             }
         }
-    }
+    };
+    tokio::time::timeout(SELF_TEST_TIMEOUT, wait).await.map_err(|_| anyhow!("timed out waiting for a reply"))?
+}
+
+// 运行一步自检并打印结果, 出错时带上这一步的名字
+async fn step(name: &str, work: impl Future<Output = Result<()>>) -> Result<()> {
+    work.await.with_context(|| format!("{name} failed"))?;
+    println!("self-test: {name} ok");
     Ok(())
 }
 
-// 广播消息给所有在线客户端
-async fn broadcast(msg: ClientMessage, state: &Arc<Mutex<ServerState>>) {
-    if let ClientMessage::Broadcast { from , content } = &msg{
-        // 记录客户发言
-        {
-            let mut st = state.lock().await;
-            st.broadcast_history.push_back(format!("{} broadcast: {}", from, content));
-            if st.broadcast_history.len() > MAX_HISTORY_SIZE {
-                st.broadcast_history.pop_front();
-            }
+// 两个客户端连接 addr 上的服务器, 依次检查注册、群发、私聊、/users 和 /history
+async fn self_test(addr: SocketAddr) -> Result<()> {
+    let (a, b) = ("selftest-a", "selftest-b");
+    let (broadcast, private) = ("self-test broadcast", "self-test private");
+    let mut alice = None;
+    let mut bob = None;
+    step("register", async {
+        alice = Some(ChatClient::connect(addr, a).await?);
+        bob = Some(ChatClient::connect(addr, b).await?);
+        Ok(())
+    })
+    .await?;
+    let (mut alice, mut bob) = (alice.unwrap(), bob.unwrap());
+
+    step("broadcast", async {
+        let id = alice.send_broadcast(broadcast).await?;
+        expect(&mut alice, |m| matches!(m, ServerMessage::BroadcastMessage { id: got, .. } if *got == id)).await?;
+        expect(&mut bob, |m| matches!(m, ServerMessage::BroadcastMessage { from, content, .. } if from == a && content == broadcast)).await?;
+        Ok(())
+    })
+    .await?;
+    step("private", async {
+        alice.send_private(&[b], private).await?;
+        expect(&mut bob, |m| matches!(m, ServerMessage::PrivateMessage { from, content, .. } if from == a && content == private)).await?;
+        Ok(())
+    })
+    .await?;
+    step("/users", async {
+        alice.send_command(Command::Users).await?;
+        let users = expect(&mut alice, |m| matches!(m, ServerMessage::UserList { .. })).await?;
+        if !matches!(&users, ServerMessage::UserList { content, .. } if content.iter().any(|n| n == a) && content.iter().any(|n| n == b)) {
+            bail!("user list is missing {a} or {b}: {users:?}");
         }
-        
-        // 将广播消息放入mpsc::channel中
-        let reply_msg = Message::Servermsg(ServerMessage::BroadcastMessage { from: from.clone(), content: content.clone() });
-        let clients = state.lock().await.clients.clone();
-        for (_name, tx) in clients {
-            let _ = tx.send(reply_msg.clone()).await;
+        Ok(())
+    })
+    .await?;
+    step("/history", async {
+        bob.send_command(Command::History { page: None, since: None }).await?;
+        let history = expect(&mut bob, |m| matches!(m, ServerMessage::History { .. })).await?;
+        if !matches!(&history, ServerMessage::History { content, .. } if content.contains(broadcast) && content.contains(private)) {
+            bail!("history is missing the test messages: {history:?}");
         }
+        Ok(())
+    })
+    .await?;
+    alice.quit(None).await?;
+    bob.quit(None).await?;
+    Ok(())
+}
+
+// 等待 SIGTERM; 不支持的平台上永远不返回
+async fn terminated() {
+    #[cfg(unix)]
+    if let Ok(mut term) = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+        term.recv().await;
+        return;
     }
+    std::future::pending::<()>().await
 }
 
-// 私聊仅发送给指定目标用户
-async fn dispatch(msg: ClientMessage, state: &Arc<Mutex<ServerState>>) {
-    if let ClientMessage::Private { from, to, content} = &msg {
-        // 记录客户发言(自己发送的 + 送向自己的)
-        {
-            let mut st = state.lock().await;
-            let entry_from = st.private_history
-                .entry(from.clone())
-                .or_default();
-            entry_from.push_back(format!("You → {}: {}", to, content));
-            if entry_from.len() > MAX_HISTORY_SIZE {
-                entry_from.pop_front();
-            }
-            let entry_to = st.private_history
-                .entry(to.clone())
-                .or_default();
-            entry_to.push_back(format!("{} → You: {}", from, content));
-            if entry_to.len() > MAX_HISTORY_SIZE {
-                entry_to.pop_front();
-            }
+#[tokio::main]
+async fn main() -> Result<()> {
+    // --check-config: 只检查 Config.toml, 有警告或错误时以非零状态退出
+    // --self-test: 按 Config.toml 在随机端口上启动服务器, 跑一遍 self_test 后退出, 失败时以非零状态退出
+    let check_only = std::env::args().skip(1).any(|arg| arg == "--check-config");
+    let self_test_only = std::env::args().skip(1).any(|arg| arg == "--self-test");
+    //再看当前目录下是否有 Config.toml（可选）去合并
+    let (cfg, warnings) = load_config(File::with_name("Config").required(false))?;
+    for warning in &warnings {
+        eprintln!("Warning: {warning}");
+    }
+    let builder = server_builder(cfg)?;
+    if check_only {
+        builder.check().context("invalid Config.toml")?;
+        if !warnings.is_empty() {
+            std::process::exit(1);
+        }
+        println!("Config.toml is valid");
+        return Ok(());
+    }
+    if self_test_only {
+        let handle = builder.bind("127.0.0.1:0").heartbeat_log(Duration::ZERO).run().await?;
+        let result = self_test(handle.local_addr()).await;
+        handle.shutdown().await;
+        if let Err(e) = result {
+            eprintln!("Self-test failed: {e:#}");
+            std::process::exit(1);
         }
+        println!("Self-test passed");
+        return Ok(());
+    }
 
-        // 将私聊消息放入mpsc::channel中
-        let reply_msg = Message::Servermsg(ServerMessage::PrivateMessage { from: from.clone(), to: to.clone(), content: content.clone() });
-        if let Some(tx) = state.lock().await.clients.get(to) {
-            let _ = tx.send(reply_msg.clone()).await;
-        }else{  // 如果找不到私聊对象, 向该客户端返回一个错误消息
-            if let Some(tx) = state.lock().await.clients.get(from){
-                let private_error_msg = Message::Servermsg(ServerMessage::Error { content: "Private object is not online or the name is incorrect ".to_string(), to: from.to_string()});
-                let _ = tx.send(private_error_msg).await;
-            }
+    // 服务器，启动
+    let handle = builder.run().await?;
+    let connected = handle.state().lock().await.clients.len();
+    println!("Server is up on {} ({} clients connected)", handle.local_addr(), connected);
+    if let Some(path) = handle.unix_socket() {
+        println!("Also listening on unix socket {}", path.display());
+    }
+
+    // 服务器关闭信号：Ctrl+C 停止服务, 客户端随之退出;
+    // SIGTERM 通常来自服务管理器的重启, 通知客户端稍后自动重连
+    let reason = tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            result?;
+            println!("Ctrl+C received, shutting down server...");
+            ShutdownReason::Shutdown
         }
+        _ = terminated() => {
+            println!("SIGTERM received, restarting: clients will reconnect...");
+            ShutdownReason::Restart
+        }
+    };
+    match handle.shutdown_with(reason).await {
+        Drain::Complete => println!("All pending messages delivered"),
+        Drain::TimedOut => println!("Timed out while delivering pending messages"),
     }
+    Ok(())
 }
 
-// 命令
-async fn command(msg: ClientMessage, state: &Arc<Mutex<ServerState>>) {
-    if let ClientMessage::Command { from, command } = &msg {
-        if command == "/users" {
-            // 记录客户这次请求
-            {
-                let mut st = state.lock().await;
-                let entry_from = st.private_history
-                    .entry(from.clone())
-                    .or_default();
-                entry_from.push_back(format!("You issued: {}", command));
-                if entry_from.len() > MAX_HISTORY_SIZE {
-                    entry_from.pop_front();
-                }
-            }
-            
-            // 从 clients 整理得到用户列表 user_list, 放入 mpsc::channel 中
-            let clients = state.lock().await.clients.clone();
-            let mut user_list: Vec<String> = Vec::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::FileFormat;
+    use rustchat::common::signing::Identity;
 
-            for (name, _tx) in clients {
-                user_list.push(name.clone());
-            }
+    fn load(toml: &str) -> Result<(ServerConfig, Vec<String>)> {
+        load_config(File::from_str(toml, FileFormat::Toml))
+    }
 
-            let reply_msg = if user_list.is_empty() {
-                Message::Servermsg(ServerMessage::System { content: "No User Online".to_string() })
-            } else {
-                Message::Servermsg(ServerMessage::UserList { content: user_list, to: from.to_string()})
-            };
+    #[test]
+    fn mistyped_keys_fall_back_to_defaults_with_a_warning() {
+        let (cfg, warnings) = load("port = \"eighty\"\nhelpbot = \"maybe\"\njoin_history = 5\nprompt = \"$ \"").unwrap();
+        assert_eq!(cfg.port, 8080);
+        assert!(!cfg.helpbot);
+        assert_eq!(cfg.join_history, 5);
+        assert_eq!(warnings.len(), 2);
+        for key in ["`port`", "`helpbot`"] {
+            assert!(warnings.iter().any(|w| w.contains(key) && w.ends_with("using the default")), "{warnings:?}");
+        }
+    }
 
-            if let Some(tx) = state.lock().await.clients.get(from) {
-                let _ = tx.send(reply_msg).await;
-            }
-        }else if command == "/history" {
-            let mut st = state.lock().await;
-            // 记录客户这次请求
-            st.private_history
-                .entry(from.clone())
-                .or_default()
-                .push_back(format!("You issued: {}", command));
-            // 收集历史: 广播 + 自己的私聊
-            let mut lines = Vec::new();
-            lines.push("=== Broadcast History ===".into());
-            lines.extend(st.broadcast_history.iter().cloned());
-            lines.push("=== Your Private History ===".into());
-            if let Some(priv_h) = st.private_history.get(from) {
-                lines.extend(priv_h.iter().cloned());
-            }
-            let history_txt = lines.join("\n");
+    #[test]
+    fn unknown_keys_are_reported_but_client_keys_are_not() {
+        let (_, warnings) = load("prot = 9000\nread_receipts = true\nlocale = \"zh\"").unwrap();
+        assert_eq!(warnings, ["unknown key `prot` in Config.toml is ignored"]);
+    }
 
-            if let Some(tx) = st.clients.get(from) {
-                let _ = tx.send(Message::Servermsg(ServerMessage::History {
-                    content: history_txt,
-                    to: from.to_string(),
-                })).await;
-            }
-        }else{
-            let userlist_error_msg = Message::Servermsg(ServerMessage::Error { content: "No User Online".to_string(), to: from.to_string()});
-            if let Some(tx) = state.lock().await.clients.get(from) {
-                let _ = tx.send(userlist_error_msg).await;
-            }
-        }
+    #[test]
+    fn actions_are_read_as_a_table() {
+        let toml = "[actions.hug]\ntemplate = \"{name} hugs {1}\"\nargs = 1\n\n[actions.highfive]\ntemplate = \"{name} high-fives {1} and {2}\"\nargs = 2";
+        let (cfg, warnings) = load(toml).unwrap();
+        assert!(warnings.is_empty(), "{warnings:?}");
+        assert_eq!(cfg.actions["hug"], ActionCommand { template: "{name} hugs {1}".into(), args: 1 });
+        assert_eq!(cfg.actions["highfive"].args, 2);
+        assert!(server_builder(cfg).unwrap().check().is_ok());
+
+        let (cfg, _) = load("[actions.users]\ntemplate = \"{name} waves\"\nargs = 0").unwrap();
+        assert!(server_builder(cfg).unwrap().check().is_err());
+    }
+
+    #[test]
+    fn admins_are_read_as_names_with_signing_keys() {
+        let key = Identity::generate().public_key();
+        let (cfg, warnings) = load(&format!("[admins]\nroot = \"{key}\"")).unwrap();
+        assert!(warnings.is_empty(), "{warnings:?}");
+        assert_eq!(cfg.admins["root"], key);
+        assert!(server_builder(cfg).unwrap().check().is_ok());
+
+        let (cfg, _) = load("[admins]\nroot = \"not a key\"").unwrap();
+        let err = server_builder(cfg).unwrap().check().unwrap_err().to_string();
+        assert!(err.contains("root"), "{err}");
+        // 旧的名字列表写法没有密钥, 不能再用
+        let err = load("admins = [\"root\"]").unwrap_err().to_string();
+        assert!(err.contains("`admins`"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn self_test_passes_against_a_default_server() {
+        let handle = Server::builder().bind("127.0.0.1:0").run().await.unwrap();
+        self_test(handle.local_addr()).await.unwrap();
+        handle.shutdown().await;
     }
-}
 
-// 注册, 以系统消息形式通知某位客户端上线
-async fn register(name: &String, state: &Arc<Mutex<ServerState>>) {
-    let clients = state.lock().await.clients.clone();
-    let reply_msg = Message::Servermsg(ServerMessage::System {content : name.to_string() + " join the chat"});
-    for (_name, tx) in clients {
-        let _ = tx.send(reply_msg.clone()).await;
+    #[test]
+    fn bad_security_keys_and_broken_files_stop_the_server() {
+        let err = load("deny_ips = \"10.0.0.1\"").unwrap_err().to_string();
+        assert!(err.contains("`deny_ips`"), "{err}");
+        let err = load("filter_policy = \"shout\"").unwrap_err().to_string();
+        assert!(err.contains("`filter_policy`"), "{err}");
+        let err = format!("{:#}", load("port = ").unwrap_err());
+        assert!(err.contains("could not be parsed"), "{err}");
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,510 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio_util::codec::Framed;
+use futures::{SinkExt, Stream, StreamExt};
+use futures::stream::{SplitSink, SplitStream};
+use anyhow::Result;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::{HashMap, HashSet};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::Instant;
+use crate::common::{Message, ServerMessage, ClientMessage, CAP_COMPRESS, CAP_E2E, CAP_SIGN};
+pub use crate::common::RefuseReason;
+use crate::common::signing::Identity;
+use crate::common::encryption::{self, EncryptionKey};
+use crate::common::codec::{self, LengthCodec, MAX_FRAME_LEN};
+use crate::common::files::FileMeta;
+use crate::common::command::Command;
+
+// 服务器要求等待的时间最多按这么久计, 异常的大值不会让发送端一直停住
+const MAX_THROTTLE: Duration = Duration::from_secs(60);
+
+/* 客户端库, 供机器人和其他程序复用连接、注册和收发消息的逻辑
+
+    let mut client = ChatClient::connect("127.0.0.1:8080", "bot").await?;
+    client.send_broadcast("hello").await?;
+    while let Some(msg) = client.next_message().await { ... }
+
+    需要同时收发时用 split() 拆成发送端 ChatSender 和消息流 Incoming, 分别交给不同的任务
+    用 connect_signed 连接时, 服务器同意签名后发出的私聊都会自动签名
+    声明 e2e 能力时, 发给已知公钥用户的私聊自动加密, 收到的加密私聊在 Incoming 中自动解密;
+    每个用户第一次出现的公钥被记下, 之后换了公钥的用户和没有公钥的用户一样, 默认拒绝以明文发给他们, 见 allow_plaintext
+    Incoming 收到服务器的 Throttle 后, 发送端的下一条消息会等到服务器要求的时间之后再发
+    断线后用 connect_resume 带上 session() 重连, 服务器补发最后一次 acknowledge() 之后错过的消息
+*/
+pub struct ChatClient<S = TcpStream> {
+    sender: ChatSender<S>,
+    incoming: Incoming<S>,
+}
+
+impl ChatClient<TcpStream> {
+    // 连接服务器并注册, 默认声明支持压缩
+    pub async fn connect(addr: impl ToSocketAddrs, name: &str) -> Result<Self> {
+        Self::connect_with(addr, name, &[CAP_COMPRESS.to_string()]).await
+    }
+
+    // 连接服务器并注册, 声明指定的能力
+    pub async fn connect_with(addr: impl ToSocketAddrs, name: &str, capabilities: &[String]) -> Result<Self> {
+        let socket = TcpStream::connect(addr).await?;
+        Self::handshake(socket, name, capabilities).await
+    }
+
+    // 连接服务器并注册, 用 identity 为之后的私聊签名
+    pub async fn connect_signed(addr: impl ToSocketAddrs, name: &str, capabilities: &[String], identity: Identity) -> Result<Self> {
+        let socket = TcpStream::connect(addr).await?;
+        Self::handshake_signed(socket, name, capabilities, identity).await
+    }
+
+    // 断线后重新连接, 带上之前的会话 token; 会话仍然有效时服务器会补发错过的消息
+    pub async fn connect_resume(addr: impl ToSocketAddrs, name: &str, capabilities: &[String], identity: Option<Identity>, session: &str) -> Result<Self> {
+        let socket = TcpStream::connect(addr).await?;
+        Self::handshake_resume(socket, name, capabilities, identity, session).await
+    }
+}
+
+// 服务器配置了 unix_socket 时, 同一台机器上的程序可以通过本地套接字连接, 不经过 TCP
+#[cfg(unix)]
+impl ChatClient<tokio::net::UnixStream> {
+    pub async fn connect_unix(path: impl AsRef<std::path::Path>, name: &str, capabilities: &[String]) -> Result<Self> {
+        let socket = tokio::net::UnixStream::connect(path).await?;
+        Self::handshake(socket, name, capabilities).await
+    }
+}
+
+// 健康检查: 连接后只发送 Health, 服务器回复 Healthy 时返回它的名字; 不注册用户, 服务器随后关闭连接
+pub async fn check_health(addr: impl ToSocketAddrs) -> Result<String> {
+    let socket = TcpStream::connect(addr).await?;
+    check_health_on(socket).await
+}
+
+// 在已建立的连接上做健康检查, 见 check_health
+pub async fn check_health_on<S: AsyncRead + AsyncWrite + Unpin>(io: S) -> Result<String> {
+    let mut framed = Framed::new(io, LengthCodec::new());
+    framed.send(Message::Clientmsg(ClientMessage::Health)).await?;
+    match framed.next().await {
+        Some(Ok(Message::Servermsg(ServerMessage::Healthy { server_name }))) => Ok(server_name),
+        Some(Ok(other)) => anyhow::bail!("unexpected reply to health check: {:?}", other),
+        Some(Err(e)) => Err(e.into()),
+        None => anyhow::bail!("server closed the connection during health check"),
+    }
+}
+
+// 服务器拒绝注册时连接函数返回的错误, 用 err.downcast_ref::<Refused>() 取出
+// reason 供程序判断; message 是服务器按自己的语言写的说明, 旧版服务器只回复 Error 时 reason 为 Other
+#[derive(Debug)]
+pub struct Refused {
+    pub reason: RefuseReason,
+    pub message: String,
+}
+
+impl Refused {
+    // 是否因为用户名已被占用而被拒绝
+    pub fn is_name_taken(&self) -> bool {
+        self.reason == RefuseReason::NameTaken
+    }
+}
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "registration refused: {}", self.message)
+    }
+}
+
+impl std::error::Error for Refused {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> ChatClient<S> {
+    // 在已建立的连接上完成注册握手, 服务器拒绝注册时返回错误
+    // capabilities 中包含 sign 时生成一个新的密钥对用于签名, 包含 e2e 时生成一个新的加密密钥对
+    pub async fn handshake(io: S, name: &str, capabilities: &[String]) -> Result<Self> {
+        let identity = capabilities.iter().any(|c| c == CAP_SIGN).then(Identity::generate);
+        Self::register(io, name, capabilities, identity, None).await
+    }
+
+    // 在已建立的连接上完成注册握手, 附上 identity 的公钥并声明 sign 能力
+    pub async fn handshake_signed(io: S, name: &str, capabilities: &[String], identity: Identity) -> Result<Self> {
+        Self::register(io, name, capabilities, Some(identity), None).await
+    }
+
+    // 在已建立的连接上完成注册握手并尝试恢复会话, 会话已过期时和普通注册一样, 得到一个新的会话
+    // 没有给出 identity 时与 handshake 相同, 按 capabilities 决定是否生成签名密钥
+    pub async fn handshake_resume(io: S, name: &str, capabilities: &[String], identity: Option<Identity>, session: &str) -> Result<Self> {
+        let identity = identity.or_else(|| capabilities.iter().any(|c| c == CAP_SIGN).then(Identity::generate));
+        Self::register(io, name, capabilities, identity, Some(session.to_string())).await
+    }
+
+    async fn register(io: S, name: &str, capabilities: &[String], identity: Option<Identity>, session: Option<String>) -> Result<Self> {
+        let mut framed = Framed::new(io, LengthCodec::new());
+
+        // 向服务器注册, 并声明支持的能力
+        let mut capabilities = capabilities.to_vec();
+        if identity.is_some() && !capabilities.iter().any(|c| c == CAP_SIGN) {
+            capabilities.push(CAP_SIGN.to_string());
+        }
+        let public_key = identity.as_ref().map(Identity::public_key);
+        let e2e = capabilities.iter().any(|c| c == CAP_E2E).then(|| Arc::new(E2e { key: EncryptionKey::generate(), peers: Mutex::default(), changed: Mutex::default() }));
+        let encryption_key = e2e.as_ref().map(|e2e| e2e.key.public_key());
+        let join_msg = Message::Clientmsg(ClientMessage::Register { name: name.to_string(), capabilities, public_key, encryption_key, session });
+        framed.send(join_msg).await?;
+
+        // 等待服务器的 Welcome, 按协商结果决定之后的帧是否压缩、私聊是否签名
+        let (identity, e2e, server_name, session) = match framed.next().await {
+            Some(Ok(Message::Servermsg(ServerMessage::Welcome { capabilities, server_name, session }))) => {
+                let agreed = |cap: &str| capabilities.iter().any(|c| c == cap);
+                framed.codec_mut().set_compression(agreed(CAP_COMPRESS));
+                (identity.filter(|_| agreed(CAP_SIGN)), e2e.filter(|_| agreed(CAP_E2E)), server_name, session)
+            }
+            Some(Ok(Message::Servermsg(ServerMessage::RegisterRefused { reason, content }))) => {
+                return Err(Refused { reason, message: content }.into());
+            }
+            Some(Ok(Message::Servermsg(ServerMessage::Error { content, .. }))) => {
+                return Err(Refused { reason: RefuseReason::Other, message: content }.into());
+            }
+            Some(Err(e)) => return Err(e.into()),
+            _ => anyhow::bail!("server closed the connection during registration"),
+        };
+
+        // 分离编码与解码：Sink 用于编码，Stream 用于解码
+        let (sink, stream) = framed.split();
+        let resume_at = Arc::new(Mutex::new(None));
+        let received = Arc::new(AtomicU64::new(0));
+        Ok(ChatClient {
+            sender: ChatSender {
+                name: name.to_string(), server_name, session, sink, next_id: 0, identity, e2e: e2e.clone(), plaintext_fallback: false,
+                resume_at: resume_at.clone(), received: received.clone(), acked: 0,
+            },
+            incoming: Incoming { stream, e2e, resume_at, received },
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.sender.name
+    }
+
+    // 服务器在 Welcome 中告知的名字, 旧版服务器不提供时为空
+    pub fn server_name(&self) -> &str {
+        &self.sender.server_name
+    }
+
+    // 本次连接的会话 token, 重连时交给 connect_resume; 旧版服务器不提供时为空
+    pub fn session(&self) -> &str {
+        &self.sender.session
+    }
+
+    pub fn is_signing(&self) -> bool {
+        self.sender.is_signing()
+    }
+
+    pub fn can_encrypt_to(&self, name: &str) -> bool {
+        self.sender.can_encrypt_to(name)
+    }
+
+    pub fn allow_plaintext(&mut self, allow: bool) {
+        self.sender.allow_plaintext(allow)
+    }
+
+    // 拆分为发送端和消息流
+    pub fn split(self) -> (ChatSender<S>, Incoming<S>) {
+        (self.sender, self.incoming)
+    }
+
+    // 等待下一条服务器消息, 连接关闭时返回 None
+    pub async fn next_message(&mut self) -> Option<io::Result<ServerMessage>> {
+        self.incoming.next().await
+    }
+
+    pub async fn send_broadcast(&mut self, content: &str) -> io::Result<u64> {
+        self.sender.send_broadcast(content).await
+    }
+
+    pub async fn send_reply(&mut self, message_id: u64, content: &str) -> io::Result<u64> {
+        self.sender.send_reply(message_id, content).await
+    }
+
+    pub async fn send_private(&mut self, to: &[&str], content: &str) -> io::Result<()> {
+        self.sender.send_private(to, content).await
+    }
+
+    pub async fn send_raw(&mut self, text: &str) -> io::Result<u64> {
+        self.sender.send_raw(text).await
+    }
+
+    pub async fn send_command(&mut self, command: Command) -> io::Result<()> {
+        self.sender.send_command(command).await
+    }
+
+    pub async fn acknowledge(&mut self) -> io::Result<()> {
+        self.sender.acknowledge().await
+    }
+
+    pub async fn share_file(&mut self, path: impl AsRef<std::path::Path>) -> io::Result<FileMeta> {
+        self.sender.share_file(path).await
+    }
+
+    pub async fn send(&mut self, msg: ClientMessage) -> io::Result<()> {
+        self.sender.send(msg).await
+    }
+
+    pub async fn quit(self, reason: Option<&str>) -> io::Result<()> {
+        self.sender.quit(reason).await
+    }
+}
+
+// 发送端, 服务器以注册时的用户名作为发出消息的发送者
+pub struct ChatSender<S = TcpStream> {
+    name: String,
+    server_name: String,
+    session: String,
+    sink: SplitSink<Framed<S, LengthCodec>, Message>,
+    next_id: u64,               // 群发的 id, 服务器回显时原样带回
+    identity: Option<Identity>, // 服务器同意签名时为私聊签名
+    e2e: Option<Arc<E2e>>,      // 服务器同意加密时与 Incoming 共用的密钥
+    plaintext_fallback: bool,   // 开启加密时, 是否允许把私聊以明文发给没有可信公钥的接收者
+    resume_at: Arc<Mutex<Option<Instant>>>, // 与 Incoming 共用: 服务器要求等到这个时间再发
+    received: Arc<AtomicU64>,   // 与 Incoming 共用: 已收到的最大消息 id
+    acked: u64,                 // 已向服务器确认的最大消息 id
+}
+
+// 端到端加密的密钥: 自己的解密密钥, 以及 Incoming 收到的其他用户的公钥
+// 每个用户只记第一次收到的公钥, 之后收到不同公钥的用户记入 changed, 不再给他们加密
+struct E2e {
+    key: EncryptionKey,
+    peers: Mutex<HashMap<String, String>>,
+    changed: Mutex<HashSet<String>>,
+}
+
+impl E2e {
+    // 记下 name 的公钥; 与已记下的不同时不替换, 把 name 标为公钥已变更
+    fn pin(&self, name: &str, key: &str) {
+        let mut peers = self.peers.lock().unwrap();
+        match peers.get(name) {
+            Some(pinned) if pinned != key => {
+                self.changed.lock().unwrap().insert(name.to_string());
+            }
+            Some(_) => {}
+            None => {
+                peers.insert(name.to_string(), key.to_string());
+            }
+        }
+    }
+
+    // 可以用来加密的公钥: 已经记下并且没有变过
+    fn trusted(&self, name: &str) -> Option<String> {
+        if self.changed.lock().unwrap().contains(name) {
+            return None;
+        }
+        self.peers.lock().unwrap().get(name).cloned()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> ChatSender<S> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn server_name(&self) -> &str {
+        &self.server_name
+    }
+
+    pub fn session(&self) -> &str {
+        &self.session
+    }
+
+    // 发出的私聊是否会被签名
+    pub fn is_signing(&self) -> bool {
+        self.identity.is_some()
+    }
+
+    // 发给 name 的私聊是否会被加密: 需要开启加密, 已经收到对方的公钥, 并且公钥没有变过
+    pub fn can_encrypt_to(&self, name: &str) -> bool {
+        self.e2e.as_ref().is_some_and(|e2e| e2e.trusted(name).is_some())
+    }
+
+    /* 开启加密时, 没有可信公钥的接收者默认收不到私聊: send 返回 InvalidInput, 一条也不发送
+        allow_plaintext(true) 后改为把明文合并发给他们, 签名照常附上; 没有开启加密时不受影响
+    */
+    pub fn allow_plaintext(&mut self, allow: bool) {
+        self.plaintext_fallback = allow;
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    // 群发, 返回本条消息的 id, 回显的 BroadcastMessage 带有相同的 id
+    pub async fn send_broadcast(&mut self, content: &str) -> io::Result<u64> {
+        let id = self.next_id();
+        let msg = ClientMessage::Broadcast { content: content.to_string(), id, ephemeral: false, ttl_secs: None, reply_to: None, attachments: Vec::new() };
+        self.send(msg).await?;
+        Ok(id)
+    }
+
+    // 群发一条对 message_id 的回复, 返回值同 send_broadcast; 私聊回复用 send 发出带 reply_to 的 Private
+    pub async fn send_reply(&mut self, message_id: u64, content: &str) -> io::Result<u64> {
+        let id = self.next_id();
+        let msg = ClientMessage::Broadcast { content: content.to_string(), id, ephemeral: false, ttl_secs: None, reply_to: Some(message_id), attachments: Vec::new() };
+        self.send(msg).await?;
+        Ok(id)
+    }
+
+    // 私聊, 可以同时发给多个用户
+    pub async fn send_private(&mut self, to: &[&str], content: &str) -> io::Result<()> {
+        let to = to.iter().map(|t| t.to_string()).collect();
+        self.send(ClientMessage::Private { to, content: content.to_string(), ephemeral: false, ttl_secs: None, signature: None, encrypted: false, reply_to: None }).await
+    }
+
+    // 发送一行原始输入, 由服务器解析为指令、私聊或群发; 返回的 id 在解析为群发时随回显带回
+    pub async fn send_raw(&mut self, text: &str) -> io::Result<u64> {
+        let id = self.next_id();
+        self.send(ClientMessage::Raw { text: text.to_string(), id }).await?;
+        Ok(id)
+    }
+
+    // 发送指令, 不需要按服务器的指令前缀拼出一行输入
+    pub async fn send_command(&mut self, command: Command) -> io::Result<()> {
+        self.send(ClientMessage::Command { command }).await
+    }
+
+    // 向服务器确认已从 Incoming 取出的消息, 重连恢复会话时不再补发它们; 没有新消息时不发送
+    pub async fn acknowledge(&mut self) -> io::Result<()> {
+        let message_id = self.received.load(Ordering::Relaxed);
+        if message_id > self.acked {
+            self.send(ClientMessage::Ack { message_id }).await?;
+            self.acked = message_id;
+        }
+        Ok(())
+    }
+
+    // 读取文件计算元数据并分享给所有人, 只发送元数据, 返回发出的元数据
+    pub async fn share_file(&mut self, path: impl AsRef<std::path::Path>) -> io::Result<FileMeta> {
+        let path = path.as_ref().to_path_buf();
+        let file = tokio::task::spawn_blocking(move || FileMeta::from_path(path)).await.map_err(io::Error::other)??;
+        self.send(ClientMessage::FileOffer { file: file.clone() }).await?;
+        Ok(file)
+    }
+
+    /* 发送任意消息
+        开启签名时为还没有签名的私聊补上签名, 签名针对明文;
+        开启加密时, 私聊按接收者拆开, 已知公钥的接收者各收到一条单独加密的消息;
+        其余接收者在 allow_plaintext(true) 后合并发送明文, 否则返回 InvalidInput;
+        任何一条超过服务器的帧长度上限时返回 InvalidInput, 一条也不发送, 连接仍然可用
+    */
+    pub async fn send(&mut self, mut msg: ClientMessage) -> io::Result<()> {
+        let resume_at = self.resume_at.lock().unwrap().take();
+        if let Some(at) = resume_at {
+            tokio::time::sleep_until(at).await;
+        }
+        if let ClientMessage::Private { content, signature: signature @ None, .. } = &mut msg
+            && let Some(identity) = &self.identity {
+            *signature = Some(identity.sign(&self.name, content));
+        }
+        let frames: Vec<Message> = self.encrypt(msg)?.into_iter().map(Message::Clientmsg).collect();
+        if let Some(len) = frames.iter().map(codec::payload_len).find(|len| *len > MAX_FRAME_LEN) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("message of {} bytes exceeds the limit of {} bytes", len, MAX_FRAME_LEN),
+            ));
+        }
+        for frame in frames {
+            self.sink.send(frame).await?;
+        }
+        Ok(())
+    }
+
+    fn encrypt(&self, msg: ClientMessage) -> io::Result<Vec<ClientMessage>> {
+        let (Some(e2e), ClientMessage::Private { to, content, ephemeral, ttl_secs, signature, encrypted: false, reply_to }) = (&self.e2e, &msg) else {
+            return Ok(vec![msg]);
+        };
+        let mut out = Vec::new();
+        let mut plain = Vec::new();
+        for name in to {
+            match e2e.trusted(name).and_then(|key| encryption::encrypt(&key, content)) {
+                Some(ciphertext) => out.push(ClientMessage::Private {
+                    to: vec![name.clone()], content: ciphertext,
+                    ephemeral: *ephemeral, ttl_secs: *ttl_secs, signature: signature.clone(), encrypted: true, reply_to: *reply_to,
+                }),
+                None => plain.push(name.clone()),
+            }
+        }
+        if !plain.is_empty() && !self.plaintext_fallback {
+            let changed = e2e.changed.lock().unwrap();
+            let (changed, unknown): (Vec<&String>, Vec<&String>) = plain.iter().partition(|name| changed.contains(*name));
+            let mut reasons = Vec::new();
+            if !unknown.is_empty() {
+                reasons.push(format!("no encryption key for {}", unknown.iter().map(|n| n.as_str()).collect::<Vec<_>>().join(", ")));
+            }
+            if !changed.is_empty() {
+                reasons.push(format!("the encryption key of {} changed", changed.iter().map(|n| n.as_str()).collect::<Vec<_>>().join(", ")));
+            }
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{}, not sending unencrypted", reasons.join("; "))));
+        }
+        if !plain.is_empty() {
+            out.push(ClientMessage::Private {
+                to: plain, content: content.clone(),
+                ephemeral: *ephemeral, ttl_secs: *ttl_secs, signature: signature.clone(), encrypted: false, reply_to: *reply_to,
+            });
+        }
+        Ok(out)
+    }
+
+    // 通知服务器退出, reason 会附在离开通知中
+    pub async fn quit(mut self, reason: Option<&str>) -> io::Result<()> {
+        let msg = ClientMessage::Quit { reason: reason.map(String::from) };
+        self.send(msg).await?;
+        self.sink.close().await
+    }
+}
+
+/* 服务器消息流, 连接关闭时结束; 服务器不应发来的 ClientMessage 帧被跳过
+    开启加密时记下 EncryptionKey 中其他用户的公钥, 并把加密的私聊解密成明文(encrypted 仍为 true),
+    无法解密时内容替换为提示文字; 收到 Throttle 时记下恢复发送的时间, 由发送端遵守;
+    取出的群发和私聊的消息 id 记下最大值, 由发送端的 acknowledge 确认
+*/
+pub struct Incoming<S = TcpStream> {
+    stream: SplitStream<Framed<S, LengthCodec>>,
+    e2e: Option<Arc<E2e>>,
+    resume_at: Arc<Mutex<Option<Instant>>>,
+    received: Arc<AtomicU64>,
+}
+
+impl<S> Incoming<S> {
+    fn receive(&self, mut msg: ServerMessage) -> ServerMessage {
+        if let ServerMessage::BroadcastMessage { message_id, .. } | ServerMessage::PrivateMessage { message_id, .. } = &msg {
+            self.received.fetch_max(*message_id, Ordering::Relaxed);
+        }
+        if let ServerMessage::Throttle { retry_after_ms } = &msg
+            && let Some(until) = Instant::now().checked_add(Duration::from_millis(*retry_after_ms).min(MAX_THROTTLE)) {
+            let mut resume_at = self.resume_at.lock().unwrap();
+            *resume_at = Some(resume_at.map_or(until, |at| at.max(until)));
+        }
+        let Some(e2e) = &self.e2e else { return msg };
+        match &mut msg {
+            ServerMessage::EncryptionKey { name, key } => e2e.pin(name, key),
+            ServerMessage::PrivateMessage { content, encrypted: true, .. } => {
+                *content = e2e.key.decrypt(content).unwrap_or_else(|| "(unable to decrypt message)".to_string());
+            }
+            _ => {}
+        }
+        msg
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Stream for Incoming<S> {
+    type Item = io::Result<ServerMessage>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            return match futures::ready!(self.stream.poll_next_unpin(cx)) {
+                Some(Ok(Message::Servermsg(msg))) => Poll::Ready(Some(Ok(self.receive(msg)))),
+                Some(Ok(Message::Clientmsg(_))) => continue,
+                Some(Err(e)) => Poll::Ready(Some(Err(e))),
+                None => Poll::Ready(None),
+            };
+        }
+    }
+}
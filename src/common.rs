@@ -1,36 +1,103 @@
 use serde::{Serialize, Deserialize};
 
-// 客户端发给服务器的消息类型枚举
-#[derive(Serialize, Deserialize, Debug, Clone)]
+// 握手时可协商的能力: 双方都声明 compress 后, 之后的帧使用 deflate 压缩
+pub const CAP_COMPRESS: &str = "compress";
+// 私聊签名: 客户端注册时附上 Ed25519 公钥, 之后发出的私聊带签名, 服务器原样转给接收者验证
+pub const CAP_SIGN: &str = "sign";
+// 私聊端到端加密: 客户端注册时附上 X25519 公钥, 服务器把在线用户的公钥推送给其他开启加密的客户端
+pub const CAP_E2E: &str = "e2e";
+
+/* 客户端发给服务器的消息类型枚举
+    消息中不带发送者, 服务器以连接注册时的名字作为发送者; 旧客户端附带的 from 字段在解码时被忽略
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum ClientMessage {
-    Broadcast {             // 群发
-        from: String,
+    Broadcast {             // 群发, id 由客户端生成, 用于和服务器的回显对应
         content: String,
+        id: u64,
+        #[serde(default)]
+        ephemeral: bool,    // 为 true 时服务器只转发, 不记入历史
+        #[serde(default)]
+        ttl_secs: Option<u64>,  // 设置后消息在 ttl 秒后从历史中删除
+        #[serde(default)]
+        reply_to: Option<u64>,  // 回复的消息 id(服务器分配的 message_id), 构成一个话题
+        #[serde(default)]
+        attachments: Vec<files::AttachmentMeta>,    // 附件的元数据, 文件内容由接收者另行获取
     },
-    Private {               // 私聊
-        from: String,
-        to: String,
+    Private {               // 私聊, 可以同时发给多个用户
+        to: Vec<String>,
         content: String,
+        #[serde(default)]
+        ephemeral: bool,
+        #[serde(default)]
+        ttl_secs: Option<u64>,
+        #[serde(default)]
+        signature: Option<String>,  // 发送者对自己的名字和 content 的签名(hex), 见 signing 模块
+        #[serde(default)]
+        encrypted: bool,            // content 是用接收者公钥加密的密文, 见 encryption 模块
+        #[serde(default)]
+        reply_to: Option<u64>,
     },
-    Command {               // 指令, "/users", "/history"
-        from: String,
-        command: String, 
+    Command {               // 指令, 见 command::Command; 也可以发送 Raw 由服务器从输入的一行解析
+        command: command::Command,
     },
-    Register {              // 注册
+    Register {              // 注册, capabilities 为客户端支持的能力
         name: String,
+        #[serde(default)]
+        capabilities: Vec<String>,
+        #[serde(default)]
+        public_key: Option<String>, // 声明 sign 能力时附上的 Ed25519 公钥(hex)
+        #[serde(default)]
+        encryption_key: Option<String>, // 声明 e2e 能力时附上的 X25519 公钥(hex)
+        #[serde(default)]
+        session: Option<String>,    // 上次 Welcome 中的会话 token, 有效时服务器补发断线期间错过的消息
+    },
+    ReadReceipt {           // 已读回执, 接收方显示私聊后发送(需在客户端配置中开启)
+        message_id: u64,
+    },
+    Ack {                   // 确认已收到 message_id 及之前的所有消息, 重连恢复会话时从这之后补发
+        message_id: u64,
+    },
+    Quit {                  // 主动退出, reason 会附在离开通知中告知其他人
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    Raw {                   // 用户输入的原始一行, 由服务器解析为指令、私聊或群发
+        text: String,
+        #[serde(default)]
+        id: u64,            // 解析为群发时作为 Broadcast 的 id 回显
     },
+    FileOffer {             // 向所有人分享一个文件的元数据(/share), 文件内容不经过服务器
+        file: files::FileMeta,
+    },
+    Health,                 // 健康检查, 只能作为连接的第一帧; 服务器回复 Healthy 后关闭连接, 不注册用户
 }
 // 服务器发给客户端的消息类型枚举
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum ServerMessage {
-    BroadcastMessage {      // 群发
+    BroadcastMessage {      // 群发, id 原样带回发送方生成的 id, message_id 由服务器分配; reply_to 为回复的消息 id
         from: String,
         content: String,
+        id: u64,
+        message_id: u64,
+        #[serde(default)]
+        reply_to: Option<u64>,
+        #[serde(default)]
+        attachments: Vec<files::AttachmentMeta>,
     },
-    PrivateMessage {        // 私聊
+    PrivateMessage {        // 私聊, 发送者签名时附上签名和发送者注册时的公钥
         from: String,
         to: String,
         content: String,
+        message_id: u64,
+        #[serde(default)]
+        signature: Option<String>,
+        #[serde(default)]
+        public_key: Option<String>,
+        #[serde(default)]
+        encrypted: bool,
+        #[serde(default)]
+        reply_to: Option<u64>,
     },
     UserList {              // 告知用户列表
         content: Vec<String>,
@@ -47,24 +114,1175 @@ pub enum ServerMessage {
         content: String,
         to: String,
     },
-    Exit,                   // 服务器关闭
+    Deleted {               // 消息已过期, 从历史中删除
+        message_id: u64,
+    },
+    Read {                  // 私聊已被 by 阅读, 只发给原发送者
+        message_id: u64,
+        by: String,
+    },
+    Welcome {               // 注册成功, capabilities 为双方都支持、本连接启用的能力, server_name 为服务器配置的名字
+        capabilities: Vec<String>,
+        #[serde(default)]
+        server_name: String,
+        #[serde(default)]
+        session: String,    // 会话 token, 断线后在 Register 中带上它重连; 旧版服务器不提供时为空
+    },
+    RegisterRefused {       // 拒绝注册, 随后服务器关闭连接; reason 供程序判断, content 是按服务器语言写给用户看的说明
+        reason: RefuseReason,
+        content: String,
+    },
+    EncryptionKey {         // 用户 name 的加密公钥, 只发给开启加密的客户端
+        name: String,
+        key: String,
+    },
+    FileOffer {             // from 分享的文件的元数据
+        from: String,
+        file: files::FileMeta,
+    },
+    Throttle {              // 服务器要求放慢发送: 至少等 retry_after_ms 毫秒再发下一条, 只发给被限制的用户
+        retry_after_ms: u64,
+    },
+    NameColor {             // 用户 name 选择的名字颜色(/color), None 为恢复默认; 新用户加入时收到在线用户已选的颜色
+        name: String,
+        color: Option<command::NameColor>,
+    },
+    Healthy {               // 对第一帧 Health 的回复, 随后服务器关闭连接
+        server_name: String,
+    },
+    Exit {                  // 服务器关闭, reason 告诉客户端是否应该自动重连
+        reason: ShutdownReason,
+    },
+    Export {                // /export 的结果, 较多时分成几帧发送, done 为最后一帧
+        messages: Vec<export::ExportedMessage>,
+        done: bool,
+        #[serde(default)]
+        blocked: Vec<String>,   // 导出者屏蔽的用户, 只在最后一帧中给出
+    },
+    UserCount {             // 房间当前的在线人数, 有人加入或离开时推送给房间里的所有人
+        count: u64,
+    },
+}
+
+// 服务器关闭的原因
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownReason {
+    Shutdown,               // 停止服务, 客户端不应重连
+    Restart,                // 马上重启, 客户端稍后自动重连
+    Maintenance {           // 停机维护, 客户端在 until(Unix 时间, 秒)之后重连
+        until: u64,
+    },
+}
+
+// 服务器拒绝注册的原因
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefuseReason {
+    InvalidName,            // 名字格式不对
+    NameReserved,           // 保留名, 或管理员名字没有配上对应的签名公钥
+    NameTaken,              // 名字已被占用
+    TooManyNames,           // 同一 IP 注册的用户名数量超出上限
+    #[serde(other)]
+    Other,                  // 较新的服务器给出的、本版本不认识的原因
 }
 // 聊天消息结构体
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum Message {
     Clientmsg(ClientMessage),
     Servermsg(ServerMessage),
 }
 
+// 指令解析: 客户端和服务器共用, 保证参数的切分方式一致
+pub mod command {
+    use std::fmt;
+    use serde::{Serialize, Deserialize};
+
+    // 默认的指令前缀, 可在配置中换成 ! 或 . 等字符, 客户端和服务器需使用相同的前缀
+    pub const DEFAULT_PREFIX: char = '/';
+
+    /* 一行以指令前缀开头的输入, 例如 `/w alice,bob hello there`
+        name 是指令名(含前缀), rest 是指令名之后的原始文本(已去掉首尾空白),
+        args() 按空白切分参数, 双引号括起的部分算作一个参数, 引号内可用 \" 和 \\ 转义
+    */
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct CommandLine<'a> {
+        pub name: &'a str,
+        pub rest: &'a str,
+    }
+
+    // 用默认前缀解析一行输入
+    pub fn parse(input: &str) -> Option<CommandLine<'_>> {
+        parse_with(input, DEFAULT_PREFIX)
+    }
+
+    // 解析一行输入, 不以 prefix 开头、只有 prefix 或以两个 prefix 开头(转义)时返回 None
+    pub fn parse_with(input: &str, prefix: char) -> Option<CommandLine<'_>> {
+        let input = input.trim();
+        let after = input.strip_prefix(prefix)?;
+        if after.is_empty() || after.starts_with(prefix) {
+            return None;
+        }
+        let (name, rest) = input.split_once(char::is_whitespace).unwrap_or((input, ""));
+        Some(CommandLine { name, rest: rest.trim() })
+    }
+
+    /* 不是指令的输入作为普通消息发送时的内容: 以两个 prefix 开头时去掉一个, 其余原样返回
+        例如 `//tmp/x` 发送 `/tmp/x`, `///` 发送 `//`
+    */
+    pub fn unescape(input: &str, prefix: char) -> &str {
+        let trimmed = input.trim_start();
+        match trimmed.strip_prefix(prefix) {
+            Some(rest) if rest.starts_with(prefix) => rest,
+            _ => input,
+        }
+    }
+
+    // 指令前缀必须是单个字符, 且不能是字母、数字、空白或控制字符
+    pub fn is_valid_prefix(prefix: char) -> bool {
+        !prefix.is_alphanumeric() && !prefix.is_whitespace() && !prefix.is_control()
+    }
+
+    // 解析配置中的指令前缀
+    pub fn parse_prefix(s: &str) -> anyhow::Result<char> {
+        let mut chars = s.chars();
+        match (chars.next(), chars.next()) {
+            (Some(prefix), None) if is_valid_prefix(prefix) => Ok(prefix),
+            _ => anyhow::bail!("invalid command prefix {:?}: must be a single non-alphanumeric character", s),
+        }
+    }
+
+    impl<'a> CommandLine<'a> {
+        // 去掉前缀的指令名, 例如 `!users` 的 keyword 是 `users`
+        pub fn keyword(&self) -> &'a str {
+            let mut chars = self.name.chars();
+            chars.next();
+            chars.as_str()
+        }
+
+        // 切分全部参数
+        pub fn args(&self) -> Vec<String> {
+            let mut args = Vec::new();
+            let mut rest = self.rest;
+            while let Some((arg, next)) = next_arg(rest) {
+                args.push(arg);
+                rest = next;
+            }
+            args
+        }
+
+        // 取出第一个参数, 其后的文本原样返回, 用于 `/w <user> <message>` 这类最后一段是自由文本的指令
+        pub fn first_arg(&self) -> Option<(String, &'a str)> {
+            next_arg(self.rest).map(|(arg, rest)| (arg, rest.trim_start()))
+        }
+
+        // 按 `/w <user>[,<user>...] <message>` 解析出接收者和内容, 缺少内容时返回 None
+        pub fn whisper(&self) -> Option<(Vec<String>, &'a str)> {
+            let (targets, content) = self.first_arg().filter(|(_, content)| !content.is_empty())?;
+            let to = targets.split(',').map(str::trim).filter(|t| !t.is_empty()).map(String::from).collect();
+            Some((to, content))
+        }
+    }
+
+    /* 协议中的指令, 客户端可以直接构造, 也可以由 Command::parse 从一行输入解析
+        解析时只检查参数的格式; 取值范围(骰子个数、投票选项数量等)和权限由服务器检查
+    */
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub enum Command {
+        Users,
+        History {
+            page: Option<usize>,    // 页码从 1 开始, None 为第一页
+            since: Option<u64>,     // 只看这个时间(Unix 秒)及之后的消息, 供重连的客户端补上错过的部分
+        },
+        Block(String),
+        Unblock(String),
+        Whois(String),
+        Seen(String),               // 查询用户最近一次发言的时间
+        Whoami,
+        Color(Option<NameColor>),   // None 为恢复默认颜色
+        Roll(Option<Dice>),         // None 为 1d6
+        Poll { question: String, options: Vec<String> },
+        Vote { poll_id: u64, choice: String },  // choice 是选项编号或选项文字
+        PollClose(u64),
+        QuietJoins,
+        Afk(Option<String>),        // 离开时的留言, 原样保留指令名之后的文本
+        Stats,
+        DumpState(Option<DumpFormat>),  // None 为服务器配置的格式
+        Export,                     // 导出自己能看到的全部历史, 回复 ServerMessage::Export
+        ClearDmHistory { confirm: bool },   // 删除服务器上自己的私聊历史; 先不带 confirm 发一次, 再在限定时间内带 confirm 确认
+        Import(String),             // 管理员从服务器上的文件导入 /export 导出的历史, 参数是文件路径
+        Action { name: String, args: Vec<String> },    // 服务器配置的动作指令, 例如 /slap bob; name 不含前缀
+    }
+
+    // 一行输入无法解析为 Command 的原因: 不认识的指令(附指令名, 含前缀), 或参数不符合该指令(附 keyword)
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum CommandError {
+        Unknown(String),
+        Usage(String),
+    }
+
+    impl Command {
+        // 动作指令由服务器配置, 这里不认识, 作为 Unknown 返回, 由服务器查表后构造 Command::Action
+        pub fn parse(cmd: &CommandLine) -> Result<Command, CommandError> {
+            let keyword = cmd.keyword();
+            let usage = || CommandError::Usage(keyword.to_string());
+            let args = cmd.args();
+            let single = || match args.as_slice() {
+                [arg] if !arg.is_empty() => Ok(arg.clone()),
+                _ => Err(usage()),
+            };
+            match keyword {
+                "users" => Ok(Command::Users),
+                "history" => {
+                    let (since, rest) = match args.as_slice() {
+                        [flag, secs, rest @ ..] if flag == "--since" => (Some(secs.parse().map_err(|_| usage())?), rest),
+                        rest => (None, rest),
+                    };
+                    let page = match rest {
+                        [] => None,
+                        [page] => Some(page.parse().ok().filter(|&p| p > 0).ok_or_else(usage)?),
+                        _ => return Err(usage()),
+                    };
+                    Ok(Command::History { page, since })
+                }
+                "block" => single().map(Command::Block),
+                "unblock" => single().map(Command::Unblock),
+                "whois" => single().map(Command::Whois),
+                "seen" => single().map(Command::Seen),
+                "whoami" => Ok(Command::Whoami),
+                "color" => match single()?.as_str() {
+                    "none" => Ok(Command::Color(None)),
+                    color => NameColor::parse(color).map(|c| Command::Color(Some(c))).ok_or_else(usage),
+                },
+                "roll" => match args.as_slice() {
+                    [] => Ok(Command::Roll(None)),
+                    [notation] => Dice::parse(notation).map(|dice| Command::Roll(Some(dice))).ok_or_else(usage),
+                    _ => Err(usage()),
+                },
+                "poll" => match args.split_first() {
+                    Some((question, options)) if !args.iter().any(String::is_empty) => {
+                        Ok(Command::Poll { question: question.clone(), options: options.to_vec() })
+                    }
+                    _ => Err(usage()),
+                },
+                "vote" => match args.as_slice() {
+                    [id, choice] => poll_id(id).map(|poll_id| Command::Vote { poll_id, choice: choice.clone() }).ok_or_else(usage),
+                    _ => Err(usage()),
+                },
+                "poll-close" => single().ok().and_then(|id| poll_id(&id)).map(Command::PollClose).ok_or_else(usage),
+                "quiet-joins" => Ok(Command::QuietJoins),
+                "afk" => Ok(Command::Afk((!cmd.rest.is_empty()).then(|| cmd.rest.to_string()))),
+                "stats" => Ok(Command::Stats),
+                "dumpstate" => match args.as_slice() {
+                    [] => Ok(Command::DumpState(None)),
+                    [format] => DumpFormat::parse(format).map(|f| Command::DumpState(Some(f))).ok_or_else(usage),
+                    _ => Err(usage()),
+                },
+                "export" => Ok(Command::Export),
+                "import" => single().map(Command::Import),
+                "dm-history" => match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+                    ["clear"] => Ok(Command::ClearDmHistory { confirm: false }),
+                    ["clear", "confirm"] => Ok(Command::ClearDmHistory { confirm: true }),
+                    _ => Err(usage()),
+                },
+                _ => Err(CommandError::Unknown(cmd.name.to_string())),
+            }
+        }
+
+        // 指令名(不含前缀)
+        pub fn keyword(&self) -> &str {
+            match self {
+                Command::Users => "users",
+                Command::History { .. } => "history",
+                Command::Block(_) => "block",
+                Command::Unblock(_) => "unblock",
+                Command::Whois(_) => "whois",
+                Command::Seen(_) => "seen",
+                Command::Whoami => "whoami",
+                Command::Color(_) => "color",
+                Command::Roll(_) => "roll",
+                Command::Poll { .. } => "poll",
+                Command::Vote { .. } => "vote",
+                Command::PollClose(_) => "poll-close",
+                Command::QuietJoins => "quiet-joins",
+                Command::Afk(_) => "afk",
+                Command::Stats => "stats",
+                Command::DumpState(_) => "dumpstate",
+                Command::Export => "export",
+                Command::Import(_) => "import",
+                Command::ClearDmHistory { .. } => "dm-history",
+                Command::Action { name, .. } => name,
+            }
+        }
+
+        // 还原成一行输入, 必要时给参数加上引号, 解析这一行会得到同样的指令
+        pub fn line(&self, prefix: char) -> String {
+            let args = match self {
+                Command::Users | Command::Whoami | Command::QuietJoins | Command::Stats | Command::Export
+                | Command::Roll(None) | Command::DumpState(None) | Command::Afk(None) => Vec::new(),
+                Command::Afk(Some(message)) => vec![message.clone()],
+                Command::History { page, since } => since.iter().flat_map(|secs| ["--since".to_string(), secs.to_string()])
+                    .chain(page.map(|p| p.to_string()))
+                    .collect(),
+                Command::Block(name) | Command::Unblock(name) | Command::Whois(name) | Command::Seen(name) | Command::Import(name) => vec![quote(name)],
+                Command::Roll(Some(dice)) => vec![dice.to_string()],
+                Command::Poll { question, options } => std::iter::once(question).chain(options).map(|a| quote(a)).collect(),
+                Command::Action { args, .. } => args.iter().map(|a| quote(a)).collect(),
+                Command::Vote { poll_id, choice } => vec![poll_id.to_string(), quote(choice)],
+                Command::PollClose(poll_id) => vec![poll_id.to_string()],
+                Command::Color(color) => vec![color.map_or("none", NameColor::as_str).to_string()],
+                Command::DumpState(Some(format)) => vec![format.as_str().to_string()],
+                Command::ClearDmHistory { confirm } => std::iter::once("clear").chain(confirm.then_some("confirm")).map(String::from).collect(),
+            };
+            std::iter::once(format!("{}{}", prefix, self.keyword())).chain(args).collect::<Vec<_>>().join(" ")
+        }
+    }
+
+    // 投票 id, 可以带 # 前缀, 例如 #3
+    fn poll_id(s: &str) -> Option<u64> {
+        s.trim_start_matches('#').parse().ok()
+    }
+
+    // 为空或含空白、引号的参数加上引号并转义, 其余原样返回
+    fn quote(arg: &str) -> String {
+        if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || c == '"') {
+            return arg.to_string();
+        }
+        format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+
+    // 掷骰表达式 NdM+K, 例如 2d6、d20、3d8-2; 个数省略时为 1
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+    pub struct Dice {
+        pub count: u32,
+        pub sides: u32,
+        pub modifier: i64,
+    }
+
+    impl Dice {
+        // 解析掷骰表达式, 只检查格式, 格式错误时返回 None
+        pub fn parse(notation: &str) -> Option<Dice> {
+            let (count, rest) = notation.split_once(['d', 'D'])?;
+            let count = if count.is_empty() { 1 } else { count.parse().ok()? };
+            let (sides, modifier) = match rest.find(['+', '-']) {
+                Some(i) => (&rest[..i], rest[i..].parse().ok()?),
+                None => (rest, 0),
+            };
+            Some(Dice { count, sides: sides.parse().ok()?, modifier })
+        }
+
+        // 结果描述, 例如 "2d6+1: 4, 2 +1 (total 7)"
+        pub fn describe(&self, rolls: &[u32]) -> String {
+            let total = rolls.iter().map(|&r| r as i64).sum::<i64>() + self.modifier;
+            let list = rolls.iter().map(u32::to_string).collect::<Vec<_>>().join(", ");
+            match self.modifier {
+                0 => format!("{}: {} (total {})", self, list, total),
+                m => format!("{}: {} {:+} (total {})", self, list, m, total),
+            }
+        }
+    }
+
+    impl fmt::Display for Dice {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self.modifier {
+                0 => write!(f, "{}d{}", self.count, self.sides),
+                m => write!(f, "{}d{}{:+}", self.count, self.sides, m),
+            }
+        }
+    }
+
+    // /dumpstate 的输出格式: 缩进排版的 JSON, 或适合复制给其他程序的单行 JSON
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+    #[serde(rename_all = "lowercase")]
+    pub enum DumpFormat {
+        #[default]
+        Pretty,
+        Json,
+    }
+
+    impl DumpFormat {
+        // 不区分大小写
+        pub fn parse(s: &str) -> Option<DumpFormat> {
+            [DumpFormat::Pretty, DumpFormat::Json].into_iter().find(|f| s.eq_ignore_ascii_case(f.as_str()))
+        }
+
+        pub fn as_str(self) -> &'static str {
+            match self {
+                DumpFormat::Pretty => "pretty",
+                DumpFormat::Json => "json",
+            }
+        }
+    }
+
+    // /color 可选的名字颜色, 客户端按终端的标准颜色显示
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+    #[serde(rename_all = "lowercase")]
+    pub enum NameColor {
+        Red,
+        Green,
+        Yellow,
+        Blue,
+        Magenta,
+        Cyan,
+    }
+
+    impl NameColor {
+        pub const ALL: [NameColor; 6] = [NameColor::Red, NameColor::Green, NameColor::Yellow, NameColor::Blue, NameColor::Magenta, NameColor::Cyan];
+
+        // 不区分大小写
+        pub fn parse(s: &str) -> Option<NameColor> {
+            NameColor::ALL.into_iter().find(|c| s.eq_ignore_ascii_case(c.as_str()))
+        }
+
+        pub fn as_str(self) -> &'static str {
+            match self {
+                NameColor::Red => "red",
+                NameColor::Green => "green",
+                NameColor::Yellow => "yellow",
+                NameColor::Blue => "blue",
+                NameColor::Magenta => "magenta",
+                NameColor::Cyan => "cyan",
+            }
+        }
+    }
+
+    // 从 s 开头读出一个参数, 返回参数和剩余文本; 没有参数时返回 None
+    fn next_arg(s: &str) -> Option<(String, &str)> {
+        let s = s.trim_start();
+        if s.is_empty() {
+            return None;
+        }
+        let mut arg = String::new();
+        let mut quoted = false;
+        let mut chars = s.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => quoted = !quoted,
+                '\\' if quoted => match chars.next() {
+                    Some((_, e @ ('"' | '\\'))) => arg.push(e),
+                    Some((_, e)) => { arg.push('\\'); arg.push(e); }
+                    None => arg.push('\\'),
+                },
+                c if c.is_whitespace() && !quoted => return Some((arg, &s[i..])),
+                c => arg.push(c),
+            }
+        }
+        // 未闭合的引号一直取到行尾
+        Some((arg, ""))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn args(input: &str) -> Vec<String> {
+            parse(input).unwrap().args()
+        }
+
+        #[test]
+        fn splits_name_and_rest() {
+            assert_eq!(parse("/users"), Some(CommandLine { name: "/users", rest: "" }));
+            assert_eq!(parse("  /users   "), Some(CommandLine { name: "/users", rest: "" }));
+            assert_eq!(parse("/history   2 "), Some(CommandLine { name: "/history", rest: "2" }));
+            assert_eq!(parse("/w\talice hi"), Some(CommandLine { name: "/w", rest: "alice hi" }));
+        }
+
+        #[test]
+        fn plain_text_is_not_a_command() {
+            assert_eq!(parse("hello /users"), None);
+            assert_eq!(parse("/"), None);
+            assert_eq!(parse(""), None);
+        }
+
+        #[test]
+        fn custom_prefix_marks_commands() {
+            assert_eq!(parse_with("!users", '!'), Some(CommandLine { name: "!users", rest: "" }));
+            assert_eq!(parse_with("!w bob hi", '!').unwrap().keyword(), "w");
+            assert_eq!(parse_with("/users", '!'), None);
+            assert_eq!(parse_with("!", '!'), None);
+            assert_eq!(parse_with("§roll 2d6", '§').unwrap().keyword(), "roll");
+        }
+
+        #[test]
+        fn doubled_prefix_is_plain_text() {
+            for (input, text) in [("/", "/"), ("//", "/"), ("///", "//"), ("//tmp/x y", "/tmp/x y"), ("hi /there", "hi /there")] {
+                assert_eq!(parse(input), None, "{}", input);
+                assert_eq!(unescape(input, '/'), text, "{}", input);
+            }
+            assert_eq!(parse_with("!!bang", '!'), None);
+            assert_eq!(unescape("!!bang", '!'), "!bang");
+            assert_eq!(unescape("//x", '!'), "//x");
+        }
+
+        #[test]
+        fn prefix_must_be_one_symbol() {
+            assert_eq!(parse_prefix("!").unwrap(), '!');
+            assert_eq!(parse_prefix(".").unwrap(), '.');
+            for bad in ["", "a", "7", " ", "\t", "!!", "//"] {
+                assert!(parse_prefix(bad).is_err(), "{:?}", bad);
+            }
+        }
+
+        #[test]
+        fn args_collapse_whitespace() {
+            assert_eq!(args("/kick  bob   being rude "), vec!["bob", "being", "rude"]);
+            assert!(args("/users").is_empty());
+        }
+
+        #[test]
+        fn quotes_group_and_escape() {
+            assert_eq!(args(r#"/nick "big bob" x"#), vec!["big bob", "x"]);
+            assert_eq!(args(r#"/a "say \"hi\"" c:\dir"#), vec![r#"say "hi""#, r"c:\dir"]);
+            assert_eq!(args(r#"/a pre"fix suf"fix"#), vec!["prefix suffix"]);
+            assert_eq!(args(r#"/a "" b"#), vec!["", "b"]);
+            assert_eq!(args(r#"/a "unterminated quote"#), vec!["unterminated quote"]);
+        }
+
+        #[test]
+        fn first_arg_keeps_the_remaining_text() {
+            let cmd = parse("/w alice,bob   hello   there ").unwrap();
+            assert_eq!(cmd.first_arg(), Some(("alice,bob".to_string(), "hello   there")));
+            assert_eq!(parse("/w alice").unwrap().first_arg(), Some(("alice".to_string(), "")));
+            assert_eq!(parse("/w").unwrap().first_arg(), None);
+            assert_eq!(parse(r#"/w "a b" hi"#).unwrap().first_arg(), Some(("a b".to_string(), "hi")));
+        }
+
+        #[test]
+        fn commands_parse_and_print_back() {
+            let parsed = |input: &str| Command::parse(&parse(input).unwrap());
+            assert_eq!(parsed("/history 2"), Ok(Command::History { page: Some(2), since: None }));
+            assert_eq!(parsed("/history --since 1700000000 3"), Ok(Command::History { page: Some(3), since: Some(1_700_000_000) }));
+            assert_eq!(parsed("/roll d20-1"), Ok(Command::Roll(Some(Dice { count: 1, sides: 20, modifier: -1 }))));
+            assert_eq!(parsed("/vote #3 yes"), Ok(Command::Vote { poll_id: 3, choice: "yes".into() }));
+            assert_eq!(parsed("/dumpstate JSON"), Ok(Command::DumpState(Some(DumpFormat::Json))));
+            assert_eq!(parsed("/color Cyan"), Ok(Command::Color(Some(NameColor::Cyan))));
+            assert_eq!(parsed(r#"/afk  back at "3pm" "#), Ok(Command::Afk(Some(r#"back at "3pm""#.into()))));
+            assert_eq!(parsed("/frobnicate now"), Err(CommandError::Unknown("/frobnicate".into())));
+            // 动作指令由服务器配置, 还原成一行时和其他指令一样
+            assert_eq!(Command::Action { name: "hug".into(), args: vec!["the team".into()] }.line('/'), r#"/hug "the team""#);
+            for bad in ["/history 0", "/history --since", "/history --since yesterday", "/history 1 2", "/block", "/seen", "/roll 2x6", "/poll-close soon", "/vote 1", "/dumpstate xml", "/color pink", "/color", "/import", r#"/poll "" a b"#, "/dm-history", "/dm-history wipe", "/dm-history clear now"] {
+                assert!(matches!(parsed(bad), Err(CommandError::Usage(_))), "{}", bad);
+            }
+
+            let commands = [
+                Command::Users,
+                Command::History { page: None, since: None },
+                Command::History { page: Some(2), since: Some(1_700_000_000) },
+                Command::Whois("a \\ b".into()),
+                Command::Seen("bob".into()),
+                Command::Whoami,
+                Command::Color(Some(NameColor::Magenta)),
+                Command::Color(None),
+                Command::Roll(Some(Dice { count: 2, sides: 6, modifier: 3 })),
+                Command::Poll { question: "lunch \"now\"?".into(), options: vec!["yes".into(), "not today".into()] },
+                Command::Vote { poll_id: 7, choice: "not today".into() },
+                Command::DumpState(Some(DumpFormat::Pretty)),
+                Command::Export,
+                Command::Import("old server/alice.json".into()),
+                Command::ClearDmHistory { confirm: false },
+                Command::ClearDmHistory { confirm: true },
+            ];
+            for command in commands {
+                let line = command.line('!');
+                assert_eq!(Command::parse(&parse_with(&line, '!').unwrap()), Ok(command), "{}", line);
+            }
+        }
+
+        #[test]
+        fn whisper_splits_recipients() {
+            let to = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+            assert_eq!(parse("/w alice,,bob,  hi there").unwrap().whisper(), Some((to(&["alice", "bob"]), "hi there")));
+            assert_eq!(parse("/w alice").unwrap().whisper(), None);
+        }
+    }
+}
+
+/* 私聊签名: 客户端持有 Ed25519 密钥对, 用私钥对 from 和 content 签名, 接收者用发送者的公钥验证
+    公钥和签名都以 hex 字符串传输; 签名不包含接收者和时间, 不能防止同一条消息被重放
+    服务器过滤词替换了内容时签名会验证失败
+*/
+pub mod signing {
+    use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+    use std::collections::HashMap;
+    use super::ServerMessage;
+
+    // 客户端的签名密钥
+    #[derive(Clone)]
+    pub struct Identity {
+        key: SigningKey,
+    }
+
+    impl Identity {
+        // 用系统熵源生成一个新的密钥对
+        pub fn generate() -> Self {
+            Identity { key: SigningKey::from_bytes(&rand::random()) }
+        }
+
+        // 从 secret() 保存的 hex 字符串恢复密钥, 格式不对时返回 None
+        pub fn from_secret(secret: &str) -> Option<Self> {
+            let bytes: [u8; 32] = hex::decode(secret.trim()).ok()?.try_into().ok()?;
+            Some(Identity { key: SigningKey::from_bytes(&bytes) })
+        }
+
+        // 私钥的 hex 编码, 只用于保存到本地文件
+        pub fn secret(&self) -> String {
+            hex::encode(self.key.to_bytes())
+        }
+
+        pub fn public_key(&self) -> String {
+            hex::encode(self.key.verifying_key().as_bytes())
+        }
+
+        pub fn sign(&self, from: &str, content: &str) -> String {
+            hex::encode(self.key.sign(&payload(from, content)).to_bytes())
+        }
+    }
+
+    // 公钥是否是合法的 hex 编码 Ed25519 公钥
+    pub fn is_valid_public_key(public_key: &str) -> bool {
+        parse_public_key(public_key).is_some()
+    }
+
+    // 验证签名, 公钥或签名格式错误时同样返回 false
+    pub fn verify(public_key: &str, from: &str, content: &str, signature: &str) -> bool {
+        let Some(key) = parse_public_key(public_key) else { return false };
+        let Some(signature) = hex::decode(signature).ok().and_then(|b| Signature::from_slice(&b).ok()) else { return false };
+        key.verify(&payload(from, content), &signature).is_ok()
+    }
+
+    // 对照 KeyPins 验证私聊的结果
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Verdict {
+        Verified,
+        Invalid,
+        KeyChanged,     // 服务器转来的公钥与记下的不同, 不论签名能否用新公钥验证都算失败
+    }
+
+    /* 每个发送者第一次签名有效时的公钥(首次使用时信任), 之后只用记下的公钥验证
+        服务器转来的公钥只在第一次时采用, 服务器换掉公钥也无法冒充已经见过的发送者
+    */
+    #[derive(Debug, Default, Clone)]
+    pub struct KeyPins {
+        keys: HashMap<String, String>,
+    }
+
+    impl KeyPins {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        // 验证收到的私聊; 不是私聊或没有签名时返回 None
+        pub fn check(&mut self, msg: &ServerMessage) -> Option<Verdict> {
+            let ServerMessage::PrivateMessage { from, content, signature: Some(signature), public_key, .. } = msg else { return None };
+            let verdict = match (self.keys.get(from), public_key) {
+                (Some(pinned), Some(key)) if key != pinned => Verdict::KeyChanged,
+                (Some(pinned), _) if verify(pinned, from, content, signature) => Verdict::Verified,
+                (Some(_), _) => Verdict::Invalid,
+                (None, Some(key)) if verify(key, from, content, signature) => {
+                    self.keys.insert(from.clone(), key.clone());
+                    Verdict::Verified
+                }
+                (None, _) => Verdict::Invalid,
+            };
+            Some(verdict)
+        }
+    }
+
+    // 只用消息中服务器转来的公钥验证收到的私聊, 确认发送者身份时用 KeyPins; 不是私聊或没有签名时返回 None
+    pub fn verify_message(msg: &ServerMessage) -> Option<bool> {
+        match msg {
+            ServerMessage::PrivateMessage { from, content, signature: Some(signature), public_key, .. } => {
+                Some(public_key.as_deref().is_some_and(|key| verify(key, from, content, signature)))
+            }
+            _ => None,
+        }
+    }
+
+    fn parse_public_key(public_key: &str) -> Option<VerifyingKey> {
+        let bytes: [u8; 32] = hex::decode(public_key).ok()?.try_into().ok()?;
+        VerifyingKey::from_bytes(&bytes).ok()
+    }
+
+    // 被签名的字节: 加上前缀区分用途, 用 \0 分隔避免 from 和 content 的边界被挪动
+    fn payload(from: &str, content: &str) -> Vec<u8> {
+        format!("rustchat-private\0{}\0{}", from, content).into_bytes()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn signature_round_trips() {
+            let id = Identity::generate();
+            let sig = id.sign("alice", "hi bob");
+            assert!(verify(&id.public_key(), "alice", "hi bob", &sig));
+        }
+
+        #[test]
+        fn tampered_messages_fail_verification() {
+            let id = Identity::generate();
+            let sig = id.sign("alice", "hi bob");
+            assert!(!verify(&id.public_key(), "alice", "hi bob!", &sig));
+            assert!(!verify(&id.public_key(), "mallory", "hi bob", &sig));
+            assert!(!verify(&Identity::generate().public_key(), "alice", "hi bob", &sig));
+        }
+
+        #[test]
+        fn malformed_keys_and_signatures_are_rejected() {
+            let id = Identity::generate();
+            let sig = id.sign("alice", "hi");
+            assert!(!verify("zz", "alice", "hi", &sig));
+            assert!(!verify(&id.public_key(), "alice", "hi", "00ff"));
+            assert!(is_valid_public_key(&id.public_key()));
+            assert!(!is_valid_public_key(&id.public_key()[2..]));
+        }
+
+        #[test]
+        fn saved_secrets_restore_the_same_identity() {
+            let id = Identity::generate();
+            let restored = Identity::from_secret(&format!("{}\n", id.secret())).unwrap();
+            assert_eq!(restored.public_key(), id.public_key());
+            assert!(verify(&id.public_key(), "alice", "hi", &restored.sign("alice", "hi")));
+            assert!(Identity::from_secret("zz").is_none());
+            assert!(Identity::from_secret(&id.secret()[2..]).is_none());
+        }
+
+        #[test]
+        fn pinned_keys_outrank_the_key_the_server_sends() {
+            let (alice, mallory) = (Identity::generate(), Identity::generate());
+            let private = |content: &str, id: &Identity| ServerMessage::PrivateMessage {
+                from: "alice".into(), to: "bob".into(), content: content.into(), message_id: 1,
+                signature: Some(id.sign("alice", content)), public_key: Some(id.public_key()), encrypted: false, reply_to: None,
+            };
+            let mut pins = KeyPins::new();
+            // 第一次无效的签名不会记下公钥
+            let mut forged = private("hi", &mallory);
+            if let ServerMessage::PrivateMessage { content, .. } = &mut forged {
+                content.push('!');
+            }
+            assert_eq!(pins.check(&forged), Some(Verdict::Invalid));
+            assert_eq!(pins.check(&private("hi", &alice)), Some(Verdict::Verified));
+            assert_eq!(pins.check(&private("hi again", &alice)), Some(Verdict::Verified));
+            // 服务器换上另一把公钥, 签名本身有效也不算通过
+            assert_eq!(pins.check(&private("trust me", &mallory)), Some(Verdict::KeyChanged));
+            let mut keyless = private("trust me", &mallory);
+            if let ServerMessage::PrivateMessage { public_key, .. } = &mut keyless {
+                *public_key = None;
+            }
+            assert_eq!(pins.check(&keyless), Some(Verdict::Invalid));
+        }
+
+        #[test]
+        fn unsigned_messages_are_not_verified() {
+            let msg = ServerMessage::PrivateMessage {
+                from: "alice".into(), to: "bob".into(), content: "hi".into(), message_id: 1, signature: None, public_key: None, encrypted: false, reply_to: None,
+            };
+            assert_eq!(verify_message(&msg), None);
+        }
+    }
+}
+
+/* 私聊端到端加密: 每个客户端持有一个 X25519 密钥对, 发送者用接收者的公钥把内容封装成 sealed box,
+    只有接收者的私钥能解开, 服务器只转发密文; 密文以 hex 字符串放在 content 中
+    sealed box 不带发送者身份, 需要确认发送者时配合 signing 模块使用
+*/
+pub mod encryption {
+    use crypto_box::{aead::OsRng, PublicKey, SecretKey};
+
+    // 客户端的解密密钥
+    pub struct EncryptionKey {
+        secret: SecretKey,
+    }
+
+    impl EncryptionKey {
+        pub fn generate() -> Self {
+            EncryptionKey { secret: SecretKey::generate(&mut OsRng) }
+        }
+
+        pub fn public_key(&self) -> String {
+            hex::encode(self.secret.public_key().as_bytes())
+        }
+
+        // 解密发给自己的密文, 密文损坏或不是发给自己的时返回 None
+        pub fn decrypt(&self, ciphertext: &str) -> Option<String> {
+            let plaintext = self.secret.unseal(&hex::decode(ciphertext).ok()?).ok()?;
+            String::from_utf8(plaintext).ok()
+        }
+    }
+
+    // 用接收者的公钥加密, 公钥格式错误时返回 None
+    pub fn encrypt(public_key: &str, plaintext: &str) -> Option<String> {
+        let ciphertext = parse_public_key(public_key)?.seal(&mut OsRng, plaintext.as_bytes()).ok()?;
+        Some(hex::encode(ciphertext))
+    }
+
+    pub fn is_valid_public_key(public_key: &str) -> bool {
+        parse_public_key(public_key).is_some()
+    }
+
+    fn parse_public_key(public_key: &str) -> Option<PublicKey> {
+        let bytes: [u8; 32] = hex::decode(public_key).ok()?.try_into().ok()?;
+        Some(PublicKey::from(bytes))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn only_the_recipient_can_decrypt() {
+            let bob = EncryptionKey::generate();
+            let ciphertext = encrypt(&bob.public_key(), "meet at 5, 五点见").unwrap();
+            assert!(!ciphertext.contains("meet"));
+            assert_eq!(bob.decrypt(&ciphertext), Some("meet at 5, 五点见".to_string()));
+            assert_eq!(EncryptionKey::generate().decrypt(&ciphertext), None);
+        }
+
+        #[test]
+        fn same_plaintext_encrypts_differently() {
+            let bob = EncryptionKey::generate();
+            assert_ne!(encrypt(&bob.public_key(), "hi"), encrypt(&bob.public_key(), "hi"));
+        }
+
+        #[test]
+        fn malformed_input_is_rejected() {
+            let bob = EncryptionKey::generate();
+            assert_eq!(encrypt("not hex", "hi"), None);
+            assert!(!is_valid_public_key("abcd"));
+            assert_eq!(bob.decrypt("zz"), None);
+            let mut ciphertext = encrypt(&bob.public_key(), "hi").unwrap();
+            ciphertext.replace_range(ciphertext.len() - 2.., "00");
+            assert_eq!(bob.decrypt(&ciphertext), None);
+        }
+    }
+}
+
+/* 文件分享的元数据: 文件名、大小和 SHA-256, 接收者据此知道对方要发什么文件, 拿到文件后可以核对内容
+    只交换元数据, 文件本身需要通过其他途径传输
+*/
+pub mod files {
+    use serde::{Deserialize, Serialize};
+    use sha2::{Digest, Sha256};
+    use std::fs::File;
+    use std::io::{self, Read};
+    use std::path::Path;
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub struct FileMeta {
+        pub name: String,       // 不带目录的文件名
+        pub size: u64,          // 字节数
+        pub sha256: String,     // 内容的 SHA-256, 小写 hex
+    }
+
+    impl FileMeta {
+        // 读取整个文件计算元数据, 路径不是普通文件时返回错误
+        pub fn from_path(path: impl AsRef<Path>) -> io::Result<Self> {
+            let path = path.as_ref();
+            let name = path.file_name().and_then(|n| n.to_str())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?
+                .to_string();
+            let mut file = File::open(path)?;
+            if !file.metadata()?.is_file() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a regular file"));
+            }
+            let mut hasher = Sha256::new();
+            let mut buf = [0u8; 64 * 1024];
+            let mut size = 0;
+            loop {
+                let n = file.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buf[..n]);
+                size += n as u64;
+            }
+            Ok(FileMeta { name, size, sha256: hex::encode(hasher.finalize()) })
+        }
+
+        // 文件名不为空且不含目录, 校验和是 64 位小写 hex; 服务器只转发合法的元数据
+        pub fn is_valid(&self) -> bool {
+            is_plain_file_name(&self.name)
+                && self.sha256.len() == 64
+                && self.sha256.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+        }
+    }
+
+    // 一条群发最多带多少个附件
+    pub const MAX_ATTACHMENTS: usize = 10;
+
+    // 文件名最多多少字节, 和常见文件系统的上限相同
+    pub const MAX_FILE_NAME_LEN: usize = 255;
+
+    /* 群发附带的附件: 只是对一个文件的引用, 文件内容不经过聊天连接, 由接收者另行获取
+        content_type 是 MIME 类型, 例如 image/png
+    */
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub struct AttachmentMeta {
+        pub name: String,
+        pub size: u64,
+        pub content_type: String,
+    }
+
+    impl AttachmentMeta {
+        // 从本地文件取文件名和大小, 类型按扩展名推断; 路径不是普通文件时返回错误
+        pub fn from_path(path: impl AsRef<Path>) -> io::Result<Self> {
+            let path = path.as_ref();
+            let name = path.file_name().and_then(|n| n.to_str())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?
+                .to_string();
+            let metadata = std::fs::metadata(path)?;
+            if !metadata.is_file() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a regular file"));
+            }
+            let content_type = content_type_of(&name).to_string();
+            Ok(AttachmentMeta { name, size: metadata.len(), content_type })
+        }
+
+        // 文件名规则同 FileMeta, 类型形如 type/subtype 且不含空白; 服务器只转发、保存合法的附件
+        pub fn is_valid(&self) -> bool {
+            let well_formed = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_graphic() && c != '/');
+            is_plain_file_name(&self.name)
+                && self.content_type.len() <= 127
+                && self.content_type.split_once('/').is_some_and(|(kind, sub)| well_formed(kind) && well_formed(sub))
+        }
+    }
+
+    // 常见扩展名对应的 MIME 类型, 不认识的按 application/octet-stream
+    pub fn content_type_of(name: &str) -> &'static str {
+        let extension = name.rsplit_once('.').map(|(_, ext)| ext.to_ascii_lowercase()).unwrap_or_default();
+        match extension.as_str() {
+            "txt" | "log" => "text/plain",
+            "md" => "text/markdown",
+            "html" | "htm" => "text/html",
+            "csv" => "text/csv",
+            "json" => "application/json",
+            "pdf" => "application/pdf",
+            "zip" => "application/zip",
+            "gz" => "application/gzip",
+            "png" => "image/png",
+            "jpg" | "jpeg" => "image/jpeg",
+            "gif" => "image/gif",
+            "svg" => "image/svg+xml",
+            "webp" => "image/webp",
+            "mp3" => "audio/mpeg",
+            "mp4" => "video/mp4",
+            _ => "application/octet-stream",
+        }
+    }
+
+    // 文件名不为空、不超过 MAX_FILE_NAME_LEN 字节、不是 . 或 .., 不含目录分隔符和控制字符
+    fn is_plain_file_name(name: &str) -> bool {
+        !name.is_empty()
+            && name.len() <= MAX_FILE_NAME_LEN
+            && name != "." && name != ".."
+            && !name.contains(['/', '\\'])
+            && !name.chars().any(char::is_control)
+    }
+
+    // 以 B / KiB / MiB / GiB 显示文件大小
+    pub fn human_size(bytes: u64) -> String {
+        const UNITS: [&str; 3] = ["KiB", "MiB", "GiB"];
+        if bytes < 1024 {
+            return format!("{} B", bytes);
+        }
+        let mut size = bytes as f64 / 1024.0;
+        let mut unit = 0;
+        while size >= 1024.0 && unit + 1 < UNITS.len() {
+            size /= 1024.0;
+            unit += 1;
+        }
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn metadata_is_computed_from_the_file() {
+            let path = std::env::temp_dir().join(format!("rustchat-share-{}.txt", std::process::id()));
+            std::fs::write(&path, b"abc").unwrap();
+            let meta = FileMeta::from_path(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(meta.name, path.file_name().unwrap().to_str().unwrap());
+            assert_eq!(meta.size, 3);
+            assert_eq!(meta.sha256, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+            assert!(meta.is_valid());
+            assert!(FileMeta::from_path(std::env::temp_dir()).is_err());
+        }
+
+        #[test]
+        fn names_with_directories_and_bad_checksums_are_invalid() {
+            let ok = FileMeta { name: "a.txt".into(), size: 0, sha256: "0".repeat(64) };
+            assert!(ok.is_valid());
+            for name in ["", "..", "dir/a.txt", "..\\a.txt", "a\nb"] {
+                assert!(!FileMeta { name: name.into(), ..ok.clone() }.is_valid(), "{:?}", name);
+            }
+            assert!(!FileMeta { sha256: "A".repeat(64), ..ok.clone() }.is_valid());
+            assert!(!FileMeta { sha256: "0".repeat(63), ..ok }.is_valid());
+        }
+
+        #[test]
+        fn attachments_serialize_and_validate() {
+            let meta = AttachmentMeta { name: "plan.pdf".into(), size: 2048, content_type: "application/pdf".into() };
+            let json = serde_json::to_string(&meta).unwrap();
+            assert_eq!(json, r#"{"name":"plan.pdf","size":2048,"content_type":"application/pdf"}"#);
+            assert_eq!(serde_json::from_str::<AttachmentMeta>(&json).unwrap(), meta);
+            assert!(meta.is_valid());
+            for content_type in ["", "pdf", "application/", "/pdf", "application/p df", "a/b/c"] {
+                assert!(!AttachmentMeta { content_type: content_type.into(), ..meta.clone() }.is_valid(), "{:?}", content_type);
+            }
+            assert!(!AttachmentMeta { name: "../plan.pdf".into(), ..meta }.is_valid());
+
+            // 旧版本发出的群发没有附件字段, 解码为空列表
+            let old = r#"{"Broadcast":{"content":"hi","id":1}}"#;
+            assert!(matches!(serde_json::from_str(old).unwrap(), crate::common::ClientMessage::Broadcast { attachments, .. } if attachments.is_empty()));
+        }
+
+        #[test]
+        fn attachment_metadata_is_read_from_the_file() {
+            let path = std::env::temp_dir().join(format!("rustchat-attach-{}.PNG", std::process::id()));
+            std::fs::write(&path, [0u8; 10]).unwrap();
+            let meta = AttachmentMeta::from_path(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!((meta.size, meta.content_type.as_str()), (10, "image/png"));
+            assert_eq!(content_type_of("notes"), "application/octet-stream");
+            assert!(AttachmentMeta::from_path(std::env::temp_dir()).is_err());
+        }
+
+        #[test]
+        fn sizes_are_shown_in_binary_units() {
+            assert_eq!(human_size(512), "512 B");
+            assert_eq!(human_size(1536), "1.5 KiB");
+            assert_eq!(human_size(5 * 1024 * 1024), "5.0 MiB");
+            assert_eq!(human_size(3 << 40), "3072.0 GiB");
+        }
+    }
+}
+
+/* /export 的导出格式
+    服务器用 ServerMessage::Export 发回 ExportedMessage, 客户端收齐后加上用户名、服务器名和导出时间, 作为 HistoryExport 以 JSON 保存
+*/
+pub mod export {
+    use super::files::{AttachmentMeta, MAX_ATTACHMENTS};
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashSet;
+    use std::time::{Duration, SystemTime};
+
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ExportKind {
+        Broadcast,      // 所在房间的群发
+        Private,        // 自己发出或收到的私聊
+        Command,        // 自己发出的指令, 例如 /history
+    }
+
+    // 导出的一条历史; 加密私聊没有明文, content 为服务器记录的占位文字, 过长的内容以 … 截断
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub struct ExportedMessage {
+        pub message_id: u64,
+        pub sent_at: u64,           // 服务器收到的时间, Unix 秒
+        pub kind: ExportKind,
+        pub from: String,
+        pub to: Vec<String>,        // 私聊的接收者, 群发和指令为空
+        pub content: String,
+        #[serde(default)]
+        pub reply_to: Option<u64>,
+        #[serde(default)]
+        pub attachments: Vec<AttachmentMeta>,  // 群发附带的附件
+    }
+
+    // 导出文件的内容, messages 按 message_id 从旧到新排列
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub struct HistoryExport {
+        pub user: String,
+        pub server: String,
+        pub exported_at: u64,       // Unix 秒
+        pub messages: Vec<ExportedMessage>,
+        #[serde(default)]
+        pub blocked: Vec<String>,   // user 屏蔽的用户, 导入时恢复
+    }
+
+    impl HistoryExport {
+        /* 导入前的检查: id 不为 0、不是 u64::MAX 且不重复, 时间能表示成 SystemTime, 有发送者, 私聊有接收者, 群发和指令没有接收者, 附件合法
+            导入后新消息的 id 从最大的 id 加一开始, 所以 id 不能是 u64::MAX
+            有屏蔽列表时必须有 user, 屏蔽的用户名不能为空
+        */
+        pub fn validate(&self) -> anyhow::Result<()> {
+            if !self.blocked.is_empty() && self.user.is_empty() {
+                anyhow::bail!("the blocklist has no user");
+            }
+            if self.blocked.iter().any(String::is_empty) {
+                anyhow::bail!("the blocklist contains an empty name");
+            }
+            let mut ids = HashSet::new();
+            for m in &self.messages {
+                let problem = if m.message_id == 0 {
+                    "the message id must not be 0"
+                } else if m.message_id == u64::MAX {
+                    "the message id is too large"
+                } else if SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(m.sent_at)).is_none() {
+                    "the time is out of range"
+                } else if !ids.insert(m.message_id) {
+                    "the message id appears more than once"
+                } else if m.from.is_empty() {
+                    "the sender is missing"
+                } else if m.kind == ExportKind::Private && (m.to.is_empty() || m.to.iter().any(String::is_empty)) {
+                    "a private message needs recipients"
+                } else if m.kind != ExportKind::Private && !m.to.is_empty() {
+                    "only private messages have recipients"
+                } else if m.attachments.len() > MAX_ATTACHMENTS || !m.attachments.iter().all(AttachmentMeta::is_valid) {
+                    "the attachments are invalid"
+                } else {
+                    continue;
+                };
+                anyhow::bail!("message #{}: {}", m.message_id, problem);
+            }
+            Ok(())
+        }
+    }
+}
+
 // Codec 模块：基于长度前缀的编码器和解码器
 pub mod codec {
     use super::Message;
     use bytes::{BytesMut, Buf, BufMut};
+    use flate2::{Compression, read::DeflateDecoder, write::DeflateEncoder};
     use serde_json;
+    use std::io::{Error, ErrorKind, Read, Write};
     use tokio_util::codec::{Decoder, Encoder};
 
-    // 自定义长度前缀编码器
-    pub struct LengthCodec;
+    // 单帧负载(长度前缀之后的部分)的最大长度, 超过时直接报错, 不再等待其余字节
+    // 压缩的帧解压后也不能超过它, 所以同一条消息不论是否压缩都受同样的限制, 也不会有压缩炸弹
+    pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+    // 消息不压缩时的帧负载长度; 压缩只会让帧更短, 所以不超过 MAX_FRAME_LEN 的消息在任何连接上都能发出
+    pub fn payload_len(msg: &Message) -> usize {
+        serde_json::to_vec(msg).map_or(0, |data| data.len())
+    }
+
+    /* 自定义长度前缀编码器
+        每个连接持有自己的 codec, 默认不压缩。握手阶段(Register / Welcome)的帧总是明文,
+        双方在握手完成后调用 set_compression(true), 此后的帧负载改为 deflate(JSON), 长度前缀仍是明文。
+        服务器在发送 Welcome 之后、客户端在收到 Welcome 之后切换, 因此两端切换的位置正好对齐
+    */
+    #[derive(Debug, Default)]
+    pub struct LengthCodec {
+        compress: bool,
+    }
+
+    impl LengthCodec {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        // 握手完成后开启或关闭压缩
+        pub fn set_compression(&mut self, on: bool) {
+            self.compress = on;
+        }
+
+        pub fn is_compressed(&self) -> bool {
+            self.compress
+        }
+    }
 
     impl Decoder for LengthCodec {
         type Item = Message;
@@ -75,10 +1293,28 @@ pub mod codec {
             //每一帧消息长度必须大于等于4且实际长度与长度前缀相匹配(保证取出来的是正确且完整的消息)
             if src.len() < 4 { return Ok(None); }             
             let len = u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize;          
+            if len > MAX_FRAME_LEN {
+                return Err(Error::new(ErrorKind::InvalidData, format!("frame of {} bytes exceeds the limit of {} bytes", len, MAX_FRAME_LEN)));
+            }
             if src.len() < 4 + len { return Ok(None); }
            
             src.advance(4);
-            let data = src.split_to(len);          
+            let mut data = src.split_to(len).to_vec();
+            // 整帧已经从 buf 中取出, 即使内容有误也不影响后续帧的解码
+            if self.compress {
+                let mut inflated = Vec::new();
+                DeflateDecoder::new(&data[..]).take(MAX_FRAME_LEN as u64 + 1).read_to_end(&mut inflated)?;
+                if inflated.len() > MAX_FRAME_LEN {
+                    return Err(Error::new(ErrorKind::InvalidData, format!("decompressed frame exceeds the limit of {} bytes", MAX_FRAME_LEN)));
+                }
+                data = inflated;
+            }
+            if let Err(e) = std::str::from_utf8(&data) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("frame payload is not valid UTF-8 (at byte {})", e.valid_up_to()),
+                ));
+            }
             let msg: Message = serde_json::from_slice(&data)?;
             Ok(Some(msg))
         }
@@ -88,11 +1324,117 @@ pub mod codec {
         type Error = std::io::Error;
 
         // 编码：将 message 序列化并前置长度，储存于 BytesMut 中
+        // 负载(压缩前)超过 MAX_FRAME_LEN 时对方一定会拒收, 直接返回 InvalidData, dst 不做任何改动
         fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), std::io::Error> {
-            let data = serde_json::to_vec(&item)?;   
+            let mut data = serde_json::to_vec(&item)?;   
+            if data.len() > MAX_FRAME_LEN {
+                return Err(Error::new(ErrorKind::InvalidData, format!("frame of {} bytes exceeds the limit of {} bytes", data.len(), MAX_FRAME_LEN)));
+            }
+            if self.compress {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(&data)?;
+                data = encoder.finish()?;
+            }
             dst.put_u32(data.len() as u32);        
             dst.extend_from_slice(&data);    
             Ok(())
         }
     }
-}
\ No newline at end of file
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::common::{ClientMessage, Message, ServerMessage, CAP_COMPRESS};
+
+        fn frame(payload: &[u8]) -> BytesMut {
+            let mut buf = BytesMut::new();
+            buf.put_u32(payload.len() as u32);
+            buf.extend_from_slice(payload);
+            buf
+        }
+
+        #[test]
+        fn invalid_utf8_frame_is_a_distinct_recoverable_error() {
+            let mut codec = LengthCodec::new();
+            let mut buf = frame(b"{\"Clientmsg\":\xff\xfe}");
+            let valid = Message::Clientmsg(ClientMessage::Register { name: "alice".into(), capabilities: vec![], public_key: None, encryption_key: None, session: None });
+            codec.encode(valid, &mut buf).unwrap();
+
+            let err = codec.decode(&mut buf).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            assert!(err.to_string().contains("UTF-8"), "{}", err);
+
+            // 损坏的帧已被完整丢弃, 下一帧仍能正常解码
+            match codec.decode(&mut buf).unwrap() {
+                Some(Message::Clientmsg(ClientMessage::Register { name, .. })) => assert_eq!(name, "alice"),
+                other => panic!("unexpected decode result: {:?}", other),
+            }
+            assert!(buf.is_empty());
+        }
+
+        #[test]
+        fn oversized_length_prefix_is_rejected_before_the_payload_arrives() {
+            let mut buf = BytesMut::new();
+            buf.put_u32(MAX_FRAME_LEN as u32 + 1);
+            let err = LengthCodec::new().decode(&mut buf).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            assert!(err.to_string().contains("exceeds the limit"), "{}", err);
+        }
+
+        #[test]
+        fn oversized_messages_are_not_encoded() {
+            let mut buf = BytesMut::new();
+            let err = LengthCodec::new().encode(Message::Servermsg(ServerMessage::System { content: "a".repeat(MAX_FRAME_LEN) }), &mut buf).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            assert!(err.to_string().contains("exceeds the limit"), "{}", err);
+            assert!(buf.is_empty());
+        }
+
+        #[test]
+        fn compressed_frames_round_trip_and_differ_from_plain() {
+            let msg = Message::Servermsg(ServerMessage::System { content: "hello ".repeat(100) });
+            let mut plain = BytesMut::new();
+            LengthCodec::new().encode(msg.clone(), &mut plain).unwrap();
+
+            let mut codec = LengthCodec::new();
+            codec.set_compression(true);
+            let mut packed = BytesMut::new();
+            codec.encode(msg, &mut packed).unwrap();
+            assert!(packed.len() < plain.len());
+
+            match codec.decode(&mut packed).unwrap() {
+                Some(Message::Servermsg(ServerMessage::System { content })) => assert_eq!(content, "hello ".repeat(100)),
+                other => panic!("unexpected decode result: {:?}", other),
+            }
+        }
+
+        #[test]
+        fn compressed_frames_that_inflate_past_the_limit_are_rejected() {
+            // encode 不会产生这样的帧, 按线上格式手工压缩
+            let json = serde_json::to_vec(&Message::Servermsg(ServerMessage::System { content: "a".repeat(MAX_FRAME_LEN) })).unwrap();
+            let mut deflate = DeflateEncoder::new(Vec::new(), Compression::default());
+            deflate.write_all(&json).unwrap();
+            let mut packed = frame(&deflate.finish().unwrap());
+            assert!(packed.len() < MAX_FRAME_LEN);
+            let mut codec = LengthCodec::new();
+            codec.set_compression(true);
+            let err = codec.decode(&mut packed).unwrap_err();
+            assert!(err.to_string().contains("decompressed frame exceeds the limit"), "{}", err);
+        }
+
+        #[test]
+        fn handshake_then_switch_keeps_both_sides_aligned() {
+            // 握手帧明文, 切换后压缩: 接收方在同一位置切换即可正确解码
+            let mut sender = LengthCodec::new();
+            let mut receiver = LengthCodec::new();
+            let mut wire = BytesMut::new();
+            sender.encode(Message::Servermsg(ServerMessage::Welcome { capabilities: vec![CAP_COMPRESS.into()], server_name: "rustchat".into(), session: String::new() }), &mut wire).unwrap();
+            sender.set_compression(true);
+            sender.encode(Message::Servermsg(ServerMessage::System { content: "after".into() }), &mut wire).unwrap();
+
+            assert!(matches!(receiver.decode(&mut wire).unwrap(), Some(Message::Servermsg(ServerMessage::Welcome { .. }))));
+            receiver.set_compression(true);
+            assert!(matches!(receiver.decode(&mut wire).unwrap(), Some(Message::Servermsg(ServerMessage::System { .. }))));
+        }
+    }
+}
@@ -1 +1,5 @@
-pub mod common;
\ No newline at end of file
+pub mod common;
+pub mod server;
+pub mod client;
+#[cfg(feature = "testing")]
+pub mod testing;
@@ -0,0 +1,4523 @@
+use tokio::{io::{AsyncRead, AsyncWrite}, net::TcpListener, sync::{watch, Mutex, Semaphore}, task::JoinHandle};
+use tokio_util::codec::{Framed, FramedRead, FramedWrite};                
+use futures::{SinkExt, StreamExt};          
+use anyhow::Result;                           
+use std::{sync::Arc, collections::HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::time::{Duration, Instant, SystemTime};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::task::Context;
+use tokio::sync::{broadcast as fanout, mpsc};
+use serde::{Deserialize, Serialize};
+use rand::{rngs::StdRng, RngExt};
+use ipnet::IpNet;                        
+use crate::common::{Message, ServerMessage, ClientMessage, RefuseReason, CAP_COMPRESS, CAP_E2E, CAP_SIGN};
+use crate::common::{encryption, signing};
+use crate::common::export::{ExportKind, ExportedMessage, HistoryExport};
+use crate::common::files::{human_size, AttachmentMeta, MAX_ATTACHMENTS};
+use crate::common::codec::{self, LengthCodec, MAX_FRAME_LEN};
+use crate::common::command::{self as cmdline, Command, CommandError, Dice, NameColor};
+pub use crate::common::command::DumpFormat;
+pub use crate::common::ShutdownReason;
+
+mod catalog;
+mod service;
+pub use catalog::{Catalog, Locale};
+pub use service::{spawn_service, Echo, HelpBot, Service};
+use catalog::fill;
+
+// 每个房间默认保留的广播条数和每个用户默认保留的私聊条数
+pub const DEFAULT_MAX_HISTORY_SIZE: usize = 100;
+// 所有历史(各房间的广播和各用户的私聊)合计最多占用的字节数, 超过时从全局最旧的消息开始丢弃
+pub const DEFAULT_MAX_HISTORY_BYTES: usize = 64 * 1024 * 1024;
+// 默认房间; 还没有切换房间的指令, 所有人都在这个房间里
+const DEFAULT_ROOM: &str = "lobby";
+// 新用户加入时默认补发的广播条数
+pub const DEFAULT_JOIN_HISTORY: usize = 10;
+// /history 每页最多返回的行数, 避免单个 History 帧过大
+const HISTORY_PAGE_SIZE: usize = 20;
+// 一页历史 JSON 转义后的最大字节数, 为 History 帧的其他字段和翻页提示留出余量, 保证整帧不超过 MAX_FRAME_LEN
+const HISTORY_PAGE_MAX_BYTES: usize = MAX_FRAME_LEN / 2;
+// 单行历史的最大字节数(同样按转义后计算), 超长的行截断显示, 保证每页至少放得下几行
+const HISTORY_LINE_MAX_BYTES: usize = HISTORY_PAGE_MAX_BYTES / 4;
+// 历史中回复最多缩进到第几层, 更深的回复和这一层对齐
+const MAX_THREAD_DEPTH: usize = 3;
+// 最多跟踪多少条等待已读回执的私聊, 超出时丢弃最旧的
+const MAX_PENDING_RECEIPTS: usize = 1000;
+// 私聊的接收者队列已满时, 要求发送者等待的时长
+const QUEUE_FULL_RETRY: Duration = Duration::from_millis(500);
+// 消息 ttl 的上限, 更长的按上限算; 否则过大的 ttl 在计算到期时间时溢出
+const MAX_TTL: Duration = Duration::from_secs(365 * 24 * 3600);
+// /import 读取的文件大小上限
+const MAX_IMPORT_BYTES: u64 = 16 * 1024 * 1024;
+// /dm-history clear 之后多久内可以确认
+const CLEAR_CONFIRM_WINDOW: Duration = Duration::from_secs(60);
+// /whois 和 /seen 记录的上线、发言时间最多保留多久, 最多保留多少个用户, 由清理任务删除更早的
+const PRESENCE_RETENTION: Duration = Duration::from_secs(30 * 24 * 3600);
+const MAX_PRESENCE_ENTRIES: usize = 10_000;
+// 过期消息清理任务的运行间隔, 也就是消息实际删除时间的误差上限
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+// 每个投票允许的选项数量
+const MIN_POLL_OPTIONS: usize = 2;
+const MAX_POLL_OPTIONS: usize = 10;
+// 同时进行的投票数量上限: 每个用户和整个服务器; 发起者断开连接时他的投票随即结束
+const MAX_OPEN_POLLS_PER_USER: usize = 3;
+const MAX_OPEN_POLLS: usize = 100;
+// /roll 的上限: 骰子个数、面数和修正值的绝对值
+const MAX_DICE: u32 = 100;
+const MAX_SIDES: u32 = 1000;
+const MAX_MODIFIER: i64 = 10_000;
+// 没有配置 server_name 时使用的服务器名字
+pub const DEFAULT_SERVER_NAME: &str = "rustchat";
+// 关闭服务器时等待写任务发完积压消息的默认时长
+pub const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+// 连接断开后等待写任务发完积压消息的时长, 超时后取消写任务
+const WRITER_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+// 写一帧的默认最长时间, 超过时认为客户端已经不再读取, 断开它
+pub const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+// 一帧开始到达后默认最多等多久收齐, 超过时认为对方故意停在半帧上(slow loris), 断开它
+pub const DEFAULT_FRAME_TIMEOUT: Duration = Duration::from_secs(30);
+// 群发通道默认最多保留多少条未被所有人取走的消息, 落后更多的用户会错过最旧的消息
+pub const DEFAULT_BROADCAST_CAPACITY: usize = 1024;
+// 用户名最多多少个字符
+pub const MAX_NAME_LEN: usize = 32;
+// 默认保留的用户名, 普通用户不能注册, 避免冒充系统消息或内置机器人
+pub const DEFAULT_RESERVED_NAMES: &[&str] = &["system", "server", "admin", "helpbot"];
+// 断线后会话默认保留的时长, 期间带着会话 token 重连可以补收错过的消息
+pub const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(600);
+// 每个会话默认记住最近多少个客户端消息 id, 用于丢弃重复发送的消息
+pub const DEFAULT_DEDUP_WINDOW: usize = 64;
+
+/* 共享服务器状态
+    clients: 所有已连接的客户端维护“用户名 -> 发送通道”的映射，用于确定消息的接收方
+    everyone: 发给所有在线用户的消息(群发、公告、删除通知), 每个用户注册时订阅, 入队一次即可送达所有人
+    broadcast_capacity: everyone 的容量, 落后更多的用户会跳过最旧的群发
+    missed_broadcasts: 启动以来所有用户因落后而跳过的群发总数, 由各自的 Inbox 累加
+    broadcast_history: 广播的消息, 按房间分开存放, 每个房间最多保留 max_broadcast_history 条
+    private_history: 私聊消息, 且按客户分开存放, 每个用户最多保留 max_private_history 条
+    max_broadcast_history / max_private_history: 上面两类历史各自的条数上限, 必须大于 0
+    history_bytes: 上面两类历史当前合计占用的字节数(估算), 写入和删除历史时同步更新
+    max_history_bytes: history_bytes 的上限, 超过时不论哪个房间或用户, 先丢弃最旧的消息; 0 表示不限制
+    max_history_age: 历史保留的最长时间, 更早的消息由清理任务删除, 即使条数和字节数都没有超出上限; 0 表示不限制
+    filter: 消息内容过滤规则
+    next_message_id: 下一条消息的 id
+    pending_receipts: 等待已读回执的私聊, 按消息 id 排序
+    blocked: 每个用户屏蔽的用户, 被屏蔽者无法向其发送私聊; 随 /export 写入导出文件, 导入时恢复
+    quiet_joins: 不接收上下线通知的用户, 和 blocked 一样按用户名保存, 重连后仍然有效
+    afk: 用 /afk 标记为暂时离开的在线用户, 发送任何内容或断开连接时清除
+    clear_requests: 发出了 /dm-history clear、还在等确认的用户和发出的时间, 确认或断开连接时清除
+    colors: 用户用 /color 选择的名字颜色, 和 quiet_joins 一样按用户名保存, 重连后仍然有效
+    registering: 通过了注册检查、还在等 Welcome 发出的用户名; 写任务启动后才移入 clients, 期间同名注册同样被拒绝
+    names_by_ip: 每个 IP 当前注册的用户名
+    max_names_per_ip: 每个 IP 同时最多注册的用户名数量, 0 表示不限制
+    compression: 是否允许与客户端协商压缩
+    join_history: 新用户加入时补发最近多少条广播, 0 表示不补发
+    shutting_down: 服务器正在关闭, 不再处理客户端发来的消息
+    writers: 每个写任务持有一个订阅, 全部写任务结束后 closed() 返回, 用于关闭时等待积压消息发完
+    rng: 随机数生成器, 启动时从系统熵源取种子
+    polls: 进行中的投票, 按投票 id 存放
+    last_seen: 每个用户最近一次断开连接的时间
+    last_spoke: 每个用户最近一次被接受的群发或私聊的时间, 断开后仍然保留, 供 /seen 查询
+    last_seen 和 last_spoke 各自最多保留 PRESENCE_RETENTION 内的 MAX_PRESENCE_ENTRIES 个用户, 见 trim_presence
+    spam_policy: 群发的速率限制和自动禁言规则
+    spam: 每个用户的群发记录和禁言截止时间, 断开重连后仍然保留
+    ip_access: 允许或拒绝连接的 IP 网段
+    public_keys: 开启签名的在线用户注册时提供的公钥, 随私聊转给接收者
+    encryption_keys: 开启加密的在线用户的加密公钥, 推送给其他开启加密的用户
+    history_format: 写入历史记录时使用的文本模板
+    presence_format: 上下线通知的文本模板, 未设置的使用当前语言的默认文字
+    control_chars: 广播和私聊内容中控制字符与 ANSI 转义序列的处理方式, 在过滤词之前生效
+    locale: 发给用户的系统消息使用的语言
+    command_prefix: 指令前缀, 以它开头的输入按指令处理
+    server_name: 服务器的名字, 注册成功时告诉客户端, 用于区分连接的是哪一台服务器
+    admins: 可以使用管理指令(例如 /dumpstate)的用户名 -> 签名公钥(hex); 这些名字只能用对应的签名密钥注册, 否则当作保留名拒绝
+    reserved_names: 普通用户不能注册的用户名, 按小写保存, 比较时不区分大小写; 服务用户不受限制
+    sessions: 每个用户名当前的会话, 注册时发出 token, 记录客户端确认收到的最大消息 id
+    session_ttl: 断线后会话保留多久, 超时由清理任务删除; 在线期间不会过期
+    dedup_window: 每个会话记住最近多少个客户端消息 id, 同一会话中 id 重复的群发和原始输入被丢弃; 0 表示不去重
+    write_timeout: 写任务发送一帧最多等多久, 对方一直不读、socket 缓冲区写满时超时断开; Duration::ZERO 表示一直等
+    frame_timeout: 收到一帧的开头后最多等多久收齐, 以及新连接最多等多久发来第一帧; Duration::ZERO 表示一直等
+    dump_format: /dumpstate 不带参数时的输出格式
+    actions: 配置的动作指令, 指令名(不含前缀) -> 模板, 见 ActionCommand; 内置的 /slap 可以被同名配置覆盖
+    greeting: 新用户加入时单独发给他的欢迎语, 见 Greeting; None 表示不发
+    import_dir: /import 只能读取这个目录中的文件; None 表示不允许 /import
+*/
+pub struct ServerState {
+    pub clients: HashMap<String, mpsc::Sender<Message>>,
+    everyone: fanout::Sender<Message>,
+    broadcast_capacity: usize,
+    missed_broadcasts: Arc<AtomicU64>,
+    broadcast_history: HashMap<String, VecDeque<StoredMessage>>,
+    private_history: HashMap<String, VecDeque<StoredMessage>>,
+    pub max_broadcast_history: usize,
+    pub max_private_history: usize,
+    history_bytes: usize,
+    pub max_history_bytes: usize,
+    pub max_history_age: Duration,
+    pub filter: ContentFilter,
+    pub control_chars: ControlChars,
+    next_message_id: u64,
+    pending_receipts: BTreeMap<u64, PendingReceipt>,
+    blocked: HashMap<String, HashSet<String>>,
+    quiet_joins: HashSet<String>,
+    afk: HashMap<String, Afk>,
+    clear_requests: HashMap<String, Instant>,
+    colors: HashMap<String, NameColor>,
+    registering: HashSet<String>,
+    names_by_ip: HashMap<IpAddr, HashSet<String>>,
+    pub max_names_per_ip: usize,
+    pub compression: bool,
+    pub join_history: usize,
+    shutting_down: bool,
+    writers: Arc<watch::Sender<()>>,
+    rng: StdRng,
+    polls: HashMap<u64, Poll>,
+    next_poll_id: u64,
+    last_seen: HashMap<String, SystemTime>,
+    last_spoke: HashMap<String, SystemTime>,
+    pub spam_policy: SpamPolicy,
+    spam: HashMap<String, SpamTracker>,
+    pub ip_access: IpAccess,
+    public_keys: HashMap<String, String>,
+    encryption_keys: HashMap<String, String>,
+    pub history_format: HistoryFormat,
+    pub presence_format: PresenceFormat,
+    pub locale: Locale,
+    pub command_prefix: char,
+    pub server_name: String,
+    pub admins: HashMap<String, String>,
+    pub dump_format: DumpFormat,
+    pub reserved_names: HashSet<String>,
+    sessions: HashMap<String, Session>,
+    pub session_ttl: Duration,
+    pub dedup_window: usize,
+    pub write_timeout: Duration,
+    pub frame_timeout: Duration,
+    pub actions: BTreeMap<String, ActionCommand>,
+    pub greeting: Option<Greeting>,
+    pub import_dir: Option<PathBuf>,
+}
+impl Default for ServerState {
+    fn default() -> Self { ServerState { 
+        clients: HashMap::new(),
+        everyone: fanout::Sender::new(DEFAULT_BROADCAST_CAPACITY),
+        broadcast_capacity: DEFAULT_BROADCAST_CAPACITY,
+        missed_broadcasts: Arc::new(AtomicU64::new(0)),
+        broadcast_history: HashMap::new(),
+        private_history: HashMap::new(),
+        max_broadcast_history: DEFAULT_MAX_HISTORY_SIZE,
+        max_private_history: DEFAULT_MAX_HISTORY_SIZE,
+        history_bytes: 0,
+        max_history_bytes: DEFAULT_MAX_HISTORY_BYTES,
+        max_history_age: Duration::ZERO,
+        filter: ContentFilter::default(),
+        control_chars: ControlChars::default(),
+        next_message_id: 1,
+        pending_receipts: BTreeMap::new(),
+        blocked: HashMap::new(),
+        quiet_joins: HashSet::new(),
+        afk: HashMap::new(),
+        clear_requests: HashMap::new(),
+        colors: HashMap::new(),
+        registering: HashSet::new(),
+        names_by_ip: HashMap::new(),
+        max_names_per_ip: 0,
+        compression: true,
+        join_history: DEFAULT_JOIN_HISTORY,
+        shutting_down: false,
+        writers: Arc::new(watch::Sender::new(())),
+        rng: rand::make_rng(),
+        polls: HashMap::new(),
+        next_poll_id: 1,
+        last_seen: HashMap::new(),
+        last_spoke: HashMap::new(),
+        spam_policy: SpamPolicy::default(),
+        spam: HashMap::new(),
+        ip_access: IpAccess::default(),
+        public_keys: HashMap::new(),
+        encryption_keys: HashMap::new(),
+        history_format: HistoryFormat::default(),
+        presence_format: PresenceFormat::default(),
+        locale: Locale::default(),
+        command_prefix: cmdline::DEFAULT_PREFIX,
+        server_name: DEFAULT_SERVER_NAME.to_string(),
+        admins: HashMap::new(),
+        dump_format: DumpFormat::default(),
+        reserved_names: DEFAULT_RESERVED_NAMES.iter().map(|n| n.to_string()).collect(),
+        sessions: HashMap::new(),
+        session_ttl: DEFAULT_SESSION_TTL,
+        dedup_window: DEFAULT_DEDUP_WINDOW,
+        write_timeout: DEFAULT_WRITE_TIMEOUT,
+        frame_timeout: DEFAULT_FRAME_TIMEOUT,
+        actions: BTreeMap::new(),
+        greeting: None,
+        import_dir: None,
+    } }
+}
+impl ServerState {
+    // 分配一个新的消息 id
+    fn next_message_id(&mut self) -> u64 {
+        let id = self.next_message_id;
+        self.next_message_id += 1;
+        id
+    }
+
+    // 当前语言的消息文字
+    fn text(&self) -> &'static Catalog {
+        self.locale.catalog()
+    }
+
+    // 离线用户的最近上线时间描述, 例如 "last seen 2h ago"; 在线或从未出现过时返回 None
+    fn last_seen(&self, name: &str) -> Option<String> {
+        if self.clients.contains_key(name) {
+            return None;
+        }
+        let elapsed = self.last_seen.get(name)?.elapsed().unwrap_or_default();
+        let text = self.text();
+        Some(fill(text.last_seen, &[("ago", &ago(elapsed, text))]))
+    }
+
+    // 群发前的反刷屏检查, 不允许发送时返回拒绝的原因
+    fn check_spam(&mut self, from: &str, content: &str, now: Instant) -> Result<(), SpamRefusal> {
+        let policy = self.spam_policy;
+        self.spam.entry(from.to_string()).or_default().check(&policy, content, now)
+    }
+
+    /* 把私聊的接收者解析成在线用户的全名
+        与某个在线用户名完全相同(区分大小写)时原样使用; 否则 by_prefix 为 true 时不区分大小写地按前缀匹配在线用户,
+        只有一个匹配时换成它的全名, 有多个匹配时返回按名字排序的候选; 没有匹配时原样返回, 之后按不在线处理
+        离线的已知用户(来过或会话还在)的名字同样原样使用, 不会被发给恰好以它开头的另一个在线用户
+    */
+    fn resolve_recipient(&self, name: &str, by_prefix: bool) -> Result<String, Vec<String>> {
+        let known = self.is_taken(name) || self.last_seen.contains_key(name) || self.sessions.contains_key(name);
+        if !by_prefix || name.is_empty() || known {
+            return Ok(name.to_string());
+        }
+        let prefix = name.to_lowercase();
+        let mut matches: Vec<&String> = self.clients.keys().filter(|online| online.to_lowercase().starts_with(&prefix)).collect();
+        match matches.len() {
+            0 => Ok(name.to_string()),
+            1 => Ok(matches[0].clone()),
+            _ => {
+                matches.sort();
+                Err(matches.into_iter().cloned().collect())
+            }
+        }
+    }
+
+    // 名字已被在线用户或正在注册的连接占用
+    pub(crate) fn is_taken(&self, name: &str) -> bool {
+        self.clients.contains_key(name) || self.registering.contains(name)
+    }
+
+    // 注册中途放弃(发送 Welcome 失败或服务器正在关闭)时归还名字和 IP 名额, 会话留待重连
+    fn cancel_registration(&mut self, name: &str, ip: IpAddr) {
+        self.registering.remove(name);
+        self.release_ip_slot(ip, name);
+        self.suspend_session(name, Instant::now());
+    }
+
+    // 为该 IP 占用一个用户名名额, 超出上限时返回 false
+    fn reserve_ip_slot(&mut self, ip: IpAddr, name: &str) -> bool {
+        let names = self.names_by_ip.entry(ip).or_default();
+        if self.max_names_per_ip > 0 && names.len() >= self.max_names_per_ip {
+            return false;
+        }
+        names.insert(name.to_string());
+        true
+    }
+
+    // 客户端断开时归还名额
+    fn release_ip_slot(&mut self, ip: IpAddr, name: &str) {
+        if let Some(names) = self.names_by_ip.get_mut(&ip) {
+            names.remove(name);
+            if names.is_empty() {
+                self.names_by_ip.remove(&ip);
+            }
+        }
+    }
+
+    // 订阅发给所有人的消息, 在 clients 中插入 name 的发送通道时用它和通道的接收端组成收件箱
+    pub fn inbox(&self, name: &str, direct: mpsc::Receiver<Message>) -> Inbox {
+        Inbox { name: name.to_string(), direct, everyone: self.everyone.subscribe(), missed: self.missed_broadcasts.clone(), text: self.text() }
+    }
+
+    // 发给所有在线用户, 只入队一次, 不等待任何人
+    fn send_to_everyone(&self, msg: Message) {
+        // 没有任何订阅者时发送失败, 忽略即可
+        let _ = self.everyone.send(msg);
+    }
+
+    /* 每个客户端发送队列中积压的消息数和队列容量, 按积压数从多到少排列
+        积压数由通道剩余容量推算, 写任务跟不上时积压会一直增长, 队列满后发给它的消息会阻塞发送方
+    */
+    // 只统计发给个人的队列, 群发消息在共享通道中排队, 不计入
+    pub fn queue_depths(&self) -> Vec<(String, usize, usize)> {
+        let mut depths = self.clients.iter()
+            .map(|(name, tx)| (name.clone(), tx.max_capacity() - tx.capacity(), tx.max_capacity()))
+            .collect::<Vec<_>>();
+        depths.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        depths
+    }
+
+    // 群发通道的积压: 落后最多的用户还有多少条没读、通道容量, 以及启动以来因落后被跳过的群发总数
+    pub fn broadcast_lag(&self) -> (usize, usize, u64) {
+        (self.everyone.len(), self.broadcast_capacity, self.missed_broadcasts.load(Ordering::Relaxed))
+    }
+
+    // 在线人数变化后推送给房间里的所有人, 和 /users 一样计数; 目前只有一个房间, 就是全部在线用户
+    // 不受 /quiet-joins 影响: 这是状态而不是通知, 客户端只用它更新状态栏
+    fn push_user_count(&self) {
+        let count = self.clients.len() as u64;
+        self.send_to_everyone(Message::Servermsg(ServerMessage::UserCount { count }));
+    }
+
+    // 接收上下线通知的客户端, 跳过开启了 /quiet-joins 的用户
+    fn presence_listeners(&self) -> Vec<mpsc::Sender<Message>> {
+        self.clients.iter()
+            .filter(|(name, _)| !self.quiet_joins.contains(*name))
+            .map(|(_, tx)| tx.clone())
+            .collect()
+    }
+
+    // 接收 name 状态变化通知的用户: 没有关闭上下线通知的人, 以及 name 自己
+    fn status_listeners(&self, name: &str) -> Vec<mpsc::Sender<Message>> {
+        self.clients.iter()
+            .filter(|(n, _)| *n == name || !self.quiet_joins.contains(*n))
+            .map(|(_, tx)| tx.clone())
+            .collect()
+    }
+
+    // from 私聊暂时离开的 to 时回复的留言; 同一次离开期间每个发送者只收到一次
+    fn afk_reply(&mut self, to: &str, from: &str) -> Option<String> {
+        let text = self.text();
+        let afk = self.afk.get_mut(to)?;
+        afk.replied.insert(from.to_string()).then(|| away_notice(to, afk.message.as_deref(), text))
+    }
+
+    // owner 是否屏蔽了 sender
+    fn has_blocked(&self, owner: &str, sender: &str) -> bool {
+        self.blocked.get(owner).is_some_and(|b| b.contains(sender))
+    }
+
+    // 按指令名查动作指令, 配置的优先, 其次是内置的 /slap
+    fn action(&self, name: &str) -> Option<ActionCommand> {
+        self.actions.get(name).cloned()
+            .or_else(|| (name == "slap").then(|| ActionCommand { template: self.text().slapped.to_string(), args: 1 }))
+    }
+
+    // 名字在 admins 中, 并且本次连接注册时用的签名公钥就是配置的那个
+    fn is_admin(&self, name: &str) -> bool {
+        self.admins.get(name).is_some_and(|key| self.public_keys.get(name) == Some(key))
+    }
+
+    // 管理员的名字只能由持有对应签名密钥的连接注册
+    fn admin_key_mismatch(&self, name: &str, public_key: Option<&str>) -> bool {
+        self.admins.get(name).is_some_and(|key| public_key != Some(key.as_str()))
+    }
+
+    // 用户写下、要发给其他人的文字: 清理控制字符后做内容过滤, 被过滤规则拒绝时返回 None
+    fn clean(&self, text: &str) -> Option<String> {
+        self.filter.apply(&self.control_chars.sanitize(text))
+    }
+
+    fn is_reserved(&self, name: &str) -> bool {
+        self.reserved_names.contains(&name.to_lowercase())
+    }
+
+    // 用于调试的状态快照, 不包含发送通道等无法序列化的部分
+    pub fn snapshot(&self) -> StateSnapshot {
+        let mut clients: Vec<String> = self.clients.keys().cloned().collect();
+        clients.sort();
+        let mut rooms: BTreeMap<String, Vec<String>> = self.broadcast_history.keys().map(|room| (room.clone(), Vec::new())).collect();
+        for name in &clients {
+            rooms.entry(self.room_of(name).to_string()).or_default().push(name.clone());
+        }
+        let mut quiet_joins: Vec<String> = self.quiet_joins.iter().cloned().collect();
+        quiet_joins.sort();
+        StateSnapshot {
+            server_name: self.server_name.clone(),
+            clients,
+            rooms,
+            broadcast_history: self.broadcast_history.iter().map(|(room, h)| (room.clone(), h.len())).collect(),
+            private_history: self.private_history.iter().map(|(name, h)| (name.clone(), h.len())).collect(),
+            history_bytes: self.history_bytes,
+            pending_receipts: self.pending_receipts.len(),
+            open_polls: self.polls.len(),
+            blocked: self.blocked.iter().map(|(name, b)| (name.clone(), b.iter().cloned().collect::<BTreeSet<_>>())).collect(),
+            quiet_joins,
+        }
+    }
+
+    // 结束 owner 发起的所有投票, 按 id 顺序把结果通知所有人
+    fn close_polls_of(&mut self, owner: &str) {
+        let mut ids: Vec<u64> = self.polls.iter().filter(|(_, poll)| poll.owner == owner).map(|(&id, _)| id).collect();
+        ids.sort_unstable();
+        for id in ids {
+            let poll = self.polls.remove(&id).unwrap();
+            let content = poll.results(id, self.text());
+            self.send_to_everyone(Message::Servermsg(ServerMessage::System { content }));
+        }
+    }
+
+    // 用户当前所在的房间
+    fn room_of(&self, _name: &str) -> &'static str {
+        DEFAULT_ROOM
+    }
+
+    // 记录一条房间内的广播, 超过上限时丢弃该房间最旧的一条
+    fn record_broadcast(&mut self, room: &str, entry: StoredMessage) {
+        self.history_bytes += entry.size();
+        self.history_bytes -= push_history(self.broadcast_history.entry(room.to_string()).or_default(), entry, self.max_broadcast_history);
+        self.enforce_history_cap();
+    }
+
+    // 在 owner 的私聊历史中记录一条, 超过上限时丢弃该用户最旧的一条
+    fn record_private(&mut self, owner: &str, entry: StoredMessage) {
+        self.history_bytes += entry.size();
+        self.history_bytes -= push_history(self.private_history.entry(owner.to_string()).or_default(), entry, self.max_private_history);
+        self.enforce_history_cap();
+    }
+
+    /* 合并 /export 导出的历史, 返回导入的消息条数; 格式不对时不做任何改动
+        消息保留原来的 id、时间和发送者, 已有同一 id 的跳过(同一条私聊会出现在双方的导出中), 新消息的 id 从导入的最大 id 之后分配;
+        群发进入默认房间, 私聊进入发送者和每个接收者的历史, 指令进入发送者的历史, 之后照常执行条数和字节上限;
+        导出者的屏蔽列表合并进 blocked
+    */
+    pub fn import_history(&mut self, export: &HistoryExport) -> Result<usize> {
+        export.validate()?;
+        let mut messages: Vec<&ExportedMessage> = export.messages.iter().collect();
+        messages.sort_by_key(|m| m.message_id);
+        let mut imported = 0;
+        for m in messages {
+            let sent_at = SystemTime::UNIX_EPOCH + Duration::from_secs(m.sent_at);
+            let entry = |text: String, to: Vec<String>| {
+                StoredMessage { sent_at, ..StoredMessage::new(m.message_id, text, None) }
+                    .with_origin(m.kind, &m.from, to, &m.content, m.reply_to)
+            };
+            let added = match m.kind {
+                ExportKind::Broadcast => {
+                    let text = self.history_format.broadcast(&m.from, &m.content);
+                    let entry = entry(text, Vec::new()).with_attachments(m.attachments.clone(), self.text());
+                    self.import_entry(None, entry)
+                }
+                ExportKind::Private => {
+                    let sent = self.history_format.private_sent(&m.to.join(", "), &m.content);
+                    let received = self.history_format.private_received(&m.from, &m.content);
+                    let mut added = self.import_entry(Some(&m.from), entry(sent, m.to.clone()));
+                    for name in &m.to {
+                        added |= self.import_entry(Some(name), entry(received.clone(), vec![name.clone()]));
+                    }
+                    added
+                }
+                ExportKind::Command => {
+                    let text = fill(self.text().issued, &[("command", &m.content)]);
+                    self.import_entry(Some(&m.from), entry(text, Vec::new()))
+                }
+            };
+            imported += usize::from(added);
+            self.next_message_id = self.next_message_id.max(m.message_id + 1);
+        }
+        self.enforce_history_cap();
+        if !export.blocked.is_empty() {
+            let blocked = self.blocked.entry(export.user.clone()).or_default();
+            blocked.extend(export.blocked.iter().filter(|name| **name != export.user).cloned());
+        }
+        Ok(imported)
+    }
+
+    // 把导入的一条历史按 id 插入 owner 的私聊历史, owner 为 None 时插入默认房间; 已有同一 id 时跳过, 超过条数上限时丢弃最旧的
+    fn import_entry(&mut self, owner: Option<&str>, entry: StoredMessage) -> bool {
+        let (history, cap) = match owner {
+            Some(owner) => (self.private_history.entry(owner.to_string()).or_default(), self.max_private_history),
+            None => (self.broadcast_history.entry(DEFAULT_ROOM.to_string()).or_default(), self.max_broadcast_history),
+        };
+        let at = history.partition_point(|m| m.id < entry.id);
+        if history.get(at).is_some_and(|m| m.id == entry.id) {
+            return false;
+        }
+        let reply_to = entry.origin.as_ref().and_then(|o| o.reply_to);
+        let entry = entry.in_thread(reply_depth(Some(history), reply_to));
+        self.history_bytes += entry.size();
+        history.insert(at, entry);
+        while history.len() > cap {
+            self.history_bytes -= history.pop_front().map_or(0, |m| m.size());
+        }
+        true
+    }
+
+    // 历史合计超过 max_history_bytes 时, 在所有房间和用户的历史中反复丢弃 id 最小(最旧)的一条
+    fn enforce_history_cap(&mut self) {
+        while self.max_history_bytes > 0 && self.history_bytes > self.max_history_bytes {
+            let oldest = self.broadcast_history.values_mut()
+                .chain(self.private_history.values_mut())
+                .filter(|h| !h.is_empty())
+                .min_by_key(|h| h[0].id);
+            let Some(entry) = oldest.and_then(|h| h.pop_front()) else { break };
+            self.history_bytes -= entry.size();
+        }
+    }
+
+    /* 为刚注册的 name 打开会话, 返回会话 token 和需要补发的起点
+        token 与 name 当前未过期的会话一致时恢复该会话, 返回 Some(已确认的最大消息 id);
+        否则发一个新 token 替换旧会话, 新会话从现在开始计, 返回 None
+    */
+    fn open_session(&mut self, name: &str, token: Option<&str>, now: Instant) -> (String, Option<u64>) {
+        if let Some(session) = self.sessions.get_mut(name)
+            && token == Some(session.token.as_str())
+            && session.expires_at.is_none_or(|t| t > now) {
+            session.expires_at = None;
+            return (session.token.clone(), Some(session.acked));
+        }
+        let token = format!("{:032x}", self.rng.random::<u128>());
+        let acked = self.next_message_id - 1;
+        self.sessions.insert(name.to_string(), Session { token: token.clone(), acked, expires_at: None, recent_ids: VecDeque::new() });
+        (token, None)
+    }
+
+    // 客户端确认收到 message_id 及之前的消息; 只前进不后退, 也不超过已分配的 id
+    fn ack(&mut self, name: &str, message_id: u64) {
+        let last = self.next_message_id - 1;
+        if let Some(session) = self.sessions.get_mut(name) {
+            session.acked = session.acked.max(message_id.min(last));
+        }
+    }
+
+    /* 记下 name 发来的客户端消息 id, 最近 dedup_window 个中已经有这个 id 时返回 true
+        重连后补发的消息和客户端误发两次的消息因此只处理一次; 恢复的会话保留记录, 新会话从头记起
+        id 为 0 表示客户端没有编号, 没有会话的服务用户也不去重
+    */
+    fn is_duplicate(&mut self, name: &str, id: u64) -> bool {
+        let Some(session) = self.sessions.get_mut(name).filter(|_| id != 0 && self.dedup_window > 0) else {
+            return false;
+        };
+        // 最近用过的排到最后, 超出窗口时淘汰最久没出现的
+        let seen = match session.recent_ids.iter().position(|&recent| recent == id) {
+            Some(i) => session.recent_ids.remove(i).is_some(),
+            None => false,
+        };
+        session.recent_ids.push_back(id);
+        while session.recent_ids.len() > self.dedup_window {
+            session.recent_ids.pop_front();
+        }
+        seen
+    }
+
+    // 断线后开始计算会话的过期时间
+    fn suspend_session(&mut self, name: &str, now: Instant) {
+        if let Some(session) = self.sessions.get_mut(name) {
+            session.expires_at = Some(now + self.session_ttl);
+        }
+    }
+
+    // 删除断线超过 session_ttl 的会话, 返回被删除会话的用户名
+    fn sweep_sessions(&mut self, now: Instant) -> Vec<String> {
+        let expired: Vec<String> = self.sessions.iter()
+            .filter(|(_, s)| s.expires_at.is_some_and(|t| t <= now))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in &expired {
+            self.sessions.remove(name);
+        }
+        expired
+    }
+
+    // name 在 acked 之后错过的消息: 所在房间的广播和自己的私聊, 按 id 从旧到新
+    fn missed_since(&self, name: &str, acked: u64) -> Vec<String> {
+        let private = self.private_history.get(name).into_iter().flatten();
+        let mut missed: Vec<&StoredMessage> = self.room_history(self.room_of(name))
+            .chain(private)
+            .filter(|m| m.id > acked)
+            .collect();
+        missed.sort_by_key(|m| m.id);
+        missed.into_iter().map(StoredMessage::line).collect()
+    }
+
+    // 所有历史当前合计占用的字节数(估算)
+    pub fn history_bytes(&self) -> usize {
+        self.history_bytes
+    }
+
+    // 房间的广播历史, 从旧到新
+    fn room_history(&self, room: &str) -> impl DoubleEndedIterator<Item = &StoredMessage> + ExactSizeIterator {
+        self.broadcast_history.get(room).map(|h| h.iter()).unwrap_or_default()
+    }
+
+    // 删除所有在 now 之前到期的历史消息, 返回需要通知客户端删除的消息
+    fn sweep_expired(&mut self, now: Instant) -> Expired {
+        let mut expired = Expired::default();
+        let mut freed = 0;
+        for history in self.broadcast_history.values_mut() {
+            history.retain(|m| {
+                let keep = !m.is_expired(now);
+                if !keep {
+                    expired.broadcast.push(m.id);
+                    freed += m.size();
+                }
+                keep
+            });
+        }
+        for (owner, history) in self.private_history.iter_mut() {
+            history.retain(|m| {
+                let keep = !m.is_expired(now);
+                if !keep {
+                    expired.private.push((owner.clone(), m.id));
+                    freed += m.size();
+                }
+                keep
+            });
+        }
+        self.history_bytes -= freed;
+        expired
+    }
+
+    // 删除服务器收到时间早于 now - max_history_age 的历史, 返回删除的条数; 只是清理存储, 不通知客户端
+    fn trim_old_history(&mut self, now: SystemTime) -> usize {
+        if self.max_history_age.is_zero() {
+            return 0;
+        }
+        let Some(cutoff) = now.checked_sub(self.max_history_age) else { return 0 };
+        let (mut trimmed, mut freed) = (0, 0);
+        for history in self.broadcast_history.values_mut().chain(self.private_history.values_mut()) {
+            // 导入的消息按 id 插入, 缓冲区不一定按 sent_at 排序, 所以逐条检查
+            history.retain(|m| {
+                let keep = m.sent_at >= cutoff;
+                if !keep {
+                    freed += m.size();
+                    trimmed += 1;
+                }
+                keep
+            });
+        }
+        self.history_bytes -= freed;
+        trimmed
+    }
+
+    // 删除早于 now - PRESENCE_RETENTION 的上线和发言时间, 仍然太多时从最旧的删起, 只保留 MAX_PRESENCE_ENTRIES 个
+    fn trim_presence(&mut self, now: SystemTime) {
+        let cutoff = now.checked_sub(PRESENCE_RETENTION).unwrap_or(SystemTime::UNIX_EPOCH);
+        for times in [&mut self.last_seen, &mut self.last_spoke] {
+            times.retain(|_, at| *at >= cutoff);
+            if times.len() > MAX_PRESENCE_ENTRIES {
+                let mut all: Vec<SystemTime> = times.values().copied().collect();
+                let excess = all.len() - MAX_PRESENCE_ENTRIES;
+                let (_, &mut oldest_kept, _) = all.select_nth_unstable(excess);
+                times.retain(|_, at| *at >= oldest_kept);
+            }
+        }
+    }
+}
+
+// 历史记录中的一条消息
+#[derive(Debug, Clone)]
+struct StoredMessage {
+    id: u64,                        // 服务器分配的消息 id, 同一条私聊在双方历史中共用一个 id
+    text: String,                   // /history 中显示的文本
+    expires_at: Option<Instant>,    // 设置了 ttl 的消息到期后会被清理
+    sent_at: SystemTime,            // 服务器收到的时间, 用于 /history --since
+    depth: usize,                   // 在话题中的层级, 0 为不是回复的消息
+    origin: Option<Origin>,         // 原始的发送者、接收者和内容, 供 /export 使用
+    attachments: Vec<AttachmentMeta>, // 群发附带的附件, text 中已列出, 原样保留供 /export 使用
+}
+
+// 一条历史的原始内容; 服务器写入的历史都带有它, /export 只导出带有它的消息
+#[derive(Debug, Clone)]
+struct Origin {
+    kind: ExportKind,
+    from: String,
+    to: Vec<String>,
+    content: String,
+    reply_to: Option<u64>,
+}
+
+impl StoredMessage {
+    fn new(id: u64, text: String, expires_at: Option<Instant>) -> Self {
+        StoredMessage { id, text, expires_at, sent_at: SystemTime::now(), depth: 0, origin: None, attachments: Vec::new() }
+    }
+
+    // 附上附件, 同时在显示的文本下面逐个列出, 回放和 /history 都能看到
+    fn with_attachments(mut self, attachments: Vec<AttachmentMeta>, text: &Catalog) -> Self {
+        for (i, a) in attachments.iter().enumerate() {
+            let line = fill(text.attachment_line, &[("n", &(i + 1).to_string()), ("name", &a.name), ("size", &human_size(a.size)), ("type", &a.content_type)]);
+            self.text = format!("{}\n{}", self.text, line);
+        }
+        self.attachments = attachments;
+        self
+    }
+
+    fn in_thread(mut self, depth: usize) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    fn with_origin(mut self, kind: ExportKind, from: &str, to: Vec<String>, content: &str, reply_to: Option<u64>) -> Self {
+        self.origin = Some(Origin { kind, from: from.to_string(), to, content: content.to_string(), reply_to });
+        self
+    }
+
+    // 导出为结构化的记录, 内容过长时和 /history 一样截断
+    fn export(&self) -> Option<ExportedMessage> {
+        let origin = self.origin.as_ref()?;
+        let sent_at = self.sent_at.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        Some(ExportedMessage {
+            message_id: self.id, sent_at, kind: origin.kind, from: origin.from.clone(), to: origin.to.clone(),
+            content: clip_line(&origin.content, HISTORY_LINE_MAX_BYTES), reply_to: origin.reply_to, attachments: self.attachments.clone(),
+        })
+    }
+
+    /* 历史中显示的一行: 回复以 ↳ 开头, 每深一层多缩进两格
+        终端里只能平铺显示, 超过 MAX_THREAD_DEPTH 层的回复按最深一层缩进, 避免整行被挤到右边
+    */
+    fn line(&self) -> String {
+        match self.depth {
+            0 => self.text.clone(),
+            depth => format!("{}↳ {}", "  ".repeat(depth.min(MAX_THREAD_DEPTH) - 1), self.text),
+        }
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|t| t <= now)
+    }
+
+    // 这条消息大约占用的内存: 结构体本身加上文本、原始内容和附件
+    fn size(&self) -> usize {
+        let origin = self.origin.as_ref().map_or(0, |o| {
+            o.from.len() + o.content.len() + o.to.iter().map(String::len).sum::<usize>()
+        });
+        let attachments = self.attachments.iter().map(|a| std::mem::size_of::<AttachmentMeta>() + a.name.len() + a.content_type.len()).sum::<usize>();
+        std::mem::size_of::<Self>() + self.text.len() + origin + attachments
+    }
+}
+
+// 回复 reply_to 的消息在话题中的层级: 在 history 中找到被回复的消息时比它深一层, 找不到(已被清理)时按第一层
+fn reply_depth(history: Option<&VecDeque<StoredMessage>>, reply_to: Option<u64>) -> usize {
+    let Some(parent) = reply_to else { return 0 };
+    history.and_then(|h| h.iter().rev().find(|m| m.id == parent)).map_or(1, |m| m.depth + 1)
+}
+
+// 暂时离开的状态: 留言, 以及这次离开期间已经收到过自动回复的发送者
+#[derive(Debug, Default)]
+struct Afk {
+    message: Option<String>,
+    replied: HashSet<String>,
+}
+
+// 一个用户的会话: 发给客户端的 token 和客户端确认收到的最大消息 id
+#[derive(Debug)]
+struct Session {
+    token: String,
+    acked: u64,
+    expires_at: Option<Instant>,    // 断线时设置, 重连恢复后清除
+    recent_ids: VecDeque<u64>,      // 最近收到的客户端消息 id, 最久没出现的在前, 见 is_duplicate
+}
+
+// 一条等待已读回执的私聊: 原发送者和尚未回执的接收者
+#[derive(Debug)]
+struct PendingReceipt {
+    sender: String,
+    unread: HashSet<String>,
+}
+
+// 一个进行中的投票, votes 记录每个用户选择的选项下标, 重复投票时覆盖
+#[derive(Debug)]
+struct Poll {
+    owner: String,
+    question: String,
+    options: Vec<String>,
+    votes: HashMap<String, usize>,
+}
+
+impl Poll {
+    // 按编号(从 1 开始)或选项文字找到选项下标
+    fn option_index(&self, choice: &str) -> Option<usize> {
+        match choice.parse::<usize>() {
+            Ok(n) => (1..=self.options.len()).contains(&n).then(|| n - 1),
+            Err(_) => self.options.iter().position(|o| o.eq_ignore_ascii_case(choice)),
+        }
+    }
+
+    // 每个选项的得票数
+    fn tally(&self) -> Vec<usize> {
+        let mut counts = vec![0; self.options.len()];
+        for &choice in self.votes.values() {
+            counts[choice] += 1;
+        }
+        counts
+    }
+
+    // 结束投票时通知所有人的结果
+    fn results(&self, id: u64, text: &Catalog) -> String {
+        let results = self.options.iter().zip(self.tally())
+            .map(|(option, votes)| format!("{}: {}", option, votes))
+            .collect::<Vec<_>>()
+            .join(", ");
+        fill(text.poll_closed, &[
+            ("id", &id.to_string()), ("question", &self.question), ("results", &results), ("votes", &self.votes.len().to_string()),
+        ])
+    }
+}
+
+/* 群发的速率限制和自动禁言规则, 各项为 0 时表示不启用
+    rate_limit: rate_window 内最多群发多少条, 超出的消息被丢弃并记一次警告
+    max_repeats: 最多连续发送多少条相同的内容, 超出时直接禁言
+    max_strikes: 警告累计多少次后禁言
+    mute_duration: 禁言时长, 期间的群发被丢弃
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpamPolicy {
+    pub rate_limit: usize,
+    pub rate_window: Duration,
+    pub max_repeats: usize,
+    pub max_strikes: usize,
+    pub mute_duration: Duration,
+}
+impl Default for SpamPolicy {
+    fn default() -> Self {
+        SpamPolicy { rate_limit: 0, rate_window: Duration::from_secs(10), max_repeats: 0, max_strikes: 3, mute_duration: Duration::from_secs(60) }
+    }
+}
+
+// 一个用户的群发记录
+#[derive(Debug, Default)]
+struct SpamTracker {
+    recent: VecDeque<Instant>,      // rate_window 内每条群发的时间
+    last_content: String,           // 上一条群发的内容
+    repeats: usize,                 // last_content 连续出现的次数
+    strikes: usize,                 // 超出速率限制的次数
+    muted_until: Option<Instant>,
+}
+
+impl SpamTracker {
+    // 记录一条群发, 不允许发送时返回拒绝的原因
+    fn check(&mut self, policy: &SpamPolicy, content: &str, now: Instant) -> Result<(), SpamRefusal> {
+        if let Some(until) = self.muted_until {
+            if now < until {
+                return Err(SpamRefusal::Muted(until - now));
+            }
+            self.muted_until = None;
+        }
+
+        while self.recent.front().is_some_and(|&t| now.duration_since(t) >= policy.rate_window) {
+            self.recent.pop_front();
+        }
+        if policy.rate_limit > 0 && self.recent.len() >= policy.rate_limit {
+            self.strikes += 1;
+            if policy.max_strikes > 0 && self.strikes >= policy.max_strikes {
+                return Err(self.mute(policy, now));
+            }
+            // 窗口内最早的一条移出窗口后才能再发
+            let oldest = self.recent.front().copied().unwrap_or(now);
+            return Err(SpamRefusal::TooFast(policy.rate_window.saturating_sub(now.duration_since(oldest))));
+        }
+        self.recent.push_back(now);
+
+        if self.last_content == content {
+            self.repeats += 1;
+        } else {
+            self.last_content = content.to_string();
+            self.repeats = 1;
+        }
+        if policy.max_repeats > 0 && self.repeats > policy.max_repeats {
+            return Err(self.mute(policy, now));
+        }
+        Ok(())
+    }
+
+    // 禁言并清空记录, 禁言结束后重新计数
+    fn mute(&mut self, policy: &SpamPolicy, now: Instant) -> SpamRefusal {
+        *self = SpamTracker { muted_until: Some(now + policy.mute_duration), ..SpamTracker::default() };
+        SpamRefusal::Muted(policy.mute_duration)
+    }
+}
+
+// 群发被拒绝的原因: 超出速率限制(附还要等多久才能再发), 或处于禁言中(附剩余时间)
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SpamRefusal {
+    TooFast(Duration),
+    Muted(Duration),
+}
+impl SpamRefusal {
+    // 发送者至少要等这么久, 随 Throttle 告诉客户端
+    fn retry_after(self) -> Duration {
+        match self {
+            SpamRefusal::TooFast(wait) | SpamRefusal::Muted(wait) => wait,
+        }
+    }
+
+    // 给发送者的提示, 剩余时间向上取整到秒
+    fn describe(self, text: &Catalog) -> String {
+        match self {
+            SpamRefusal::TooFast(_) => text.rate_limited.to_string(),
+            SpamRefusal::Muted(remaining) => {
+                let secs = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+                fill(text.muted, &[("secs", &secs.to_string())])
+            }
+        }
+    }
+}
+
+/* 消息投递模型: 每个在线用户(包括服务用户)有一个收件箱, 由两个来源组成
+    - 个人通道: clients 中的 mpsc 发送端, 只发给这一个人的消息走这里:
+      私聊、已读回执、指令的回复和报错、加入时补发的历史、加密公钥, 以及按 /quiet-joins 逐个过滤的上下线通知;
+      队列有上限, 满了以后发送方等待, 积压情况见 /stats
+    - 群发通道: ServerState 中唯一的 everyone, 发给所有人的消息走这里:
+      群发、/roll 和投票等公告、过期消息的删除通知; 入队一次即可, 不等待任何接收者
+    两个来源中优先取群发, 先入队的群发不会排到之后的个人消息(例如关闭通知)后面
+    群发落后超过群发通道的容量(见 ServerBuilder::broadcast_capacity)时最旧的消息被覆盖, 改为告诉用户错过了多少条, 同时在服务器日志中记录
+    个人通道关闭(已下线或服务器关闭)后先发完已经排队的消息, 然后返回 None
+*/
+pub struct Inbox {
+    name: String,
+    direct: mpsc::Receiver<Message>,
+    everyone: fanout::Receiver<Message>,
+    missed: Arc<AtomicU64>,
+    text: &'static Catalog,
+}
+
+impl Inbox {
+    pub async fn recv(&mut self) -> Option<Message> {
+        if self.direct.is_closed() {
+            return match self.everyone.try_recv() {
+                Ok(msg) => Some(msg),
+                Err(fanout::error::TryRecvError::Lagged(n)) => Some(self.missed(n)),
+                Err(_) => self.direct.recv().await,
+            };
+        }
+        tokio::select! {
+            biased;
+            res = self.everyone.recv() => match res {
+                Ok(msg) => Some(msg),
+                Err(fanout::error::RecvError::Lagged(n)) => Some(self.missed(n)),
+                Err(fanout::error::RecvError::Closed) => self.direct.recv().await,
+            },
+            msg = self.direct.recv() => msg,
+        }
+    }
+
+    fn missed(&self, n: u64) -> Message {
+        eprintln!("{} fell behind and missed {} broadcasts", self.name, n);
+        self.missed.fetch_add(n, Ordering::Relaxed);
+        Message::Servermsg(ServerMessage::System { content: fill(self.text.missed_broadcasts, &[("n", &n.to_string())]) })
+    }
+}
+
+// 一次清理中被删除的消息: 广播消息通知所有人, 私聊消息只通知历史的主人
+#[derive(Debug, Default)]
+struct Expired {
+    broadcast: Vec<u64>,
+    private: Vec<(String, u64)>,
+}
+
+// 追加一条历史, 超过 cap 条时丢弃最旧的, 返回丢弃的消息占用的字节数
+fn push_history(history: &mut VecDeque<StoredMessage>, entry: StoredMessage, cap: usize) -> usize {
+    history.push_back(entry);
+    let mut dropped = 0;
+    while history.len() > cap {
+        dropped += history.pop_front().map_or(0, |m| m.size());
+    }
+    dropped
+}
+
+/* 历史记录的文本模板, 占位符在写入历史时替换
+    broadcast: 广播, 必须包含 {from} 和 {content}
+    private_sent: 自己发出的私聊, 必须包含 {to} 和 {content}
+    private_received: 收到的私聊, 必须包含 {from} 和 {content}
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryFormat {
+    pub broadcast: String,
+    pub private_sent: String,
+    pub private_received: String,
+}
+impl Default for HistoryFormat {
+    fn default() -> Self {
+        HistoryFormat {
+            broadcast: "{from} broadcast: {content}".to_string(),
+            private_sent: "You → {to}: {content}".to_string(),
+            private_received: "{from} → You: {content}".to_string(),
+        }
+    }
+}
+impl HistoryFormat {
+    // 检查每个模板都包含必需的占位符
+    pub fn validate(&self) -> Result<()> {
+        let checks = [
+            ("broadcast", &self.broadcast, ["{from}", "{content}"]),
+            ("private_sent", &self.private_sent, ["{to}", "{content}"]),
+            ("private_received", &self.private_received, ["{from}", "{content}"]),
+        ];
+        for (name, template, required) in checks {
+            if let Some(missing) = required.iter().find(|p| !template.contains(*p)) {
+                anyhow::bail!("history format {:?} must contain {}", name, missing);
+            }
+        }
+        Ok(())
+    }
+
+    fn broadcast(&self, from: &str, content: &str) -> String {
+        fill(&self.broadcast, &[("from", from), ("content", &indent_continuation(content))])
+    }
+
+    fn private_sent(&self, to: &str, content: &str) -> String {
+        fill(&self.private_sent, &[("to", to), ("content", &indent_continuation(content))])
+    }
+
+    fn private_received(&self, from: &str, content: &str) -> String {
+        fill(&self.private_received, &[("from", from), ("content", &indent_continuation(content))])
+    }
+}
+
+/* 上下线通知的文本模板, 为 None 时使用当前语言的默认文字
+    joined: 加入, 必须包含 {name}
+    left: 离开, 必须包含 {name}
+    left_with_reason: 用 /quit <reason> 离开, 必须包含 {name} 和 {reason}
+*/
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PresenceFormat {
+    pub joined: Option<String>,
+    pub left: Option<String>,
+    pub left_with_reason: Option<String>,
+}
+impl PresenceFormat {
+    // 检查设置了的模板都包含必需的占位符
+    pub fn validate(&self) -> Result<()> {
+        let checks = [
+            ("joined", &self.joined, &["{name}"][..]),
+            ("left", &self.left, &["{name}"][..]),
+            ("left_with_reason", &self.left_with_reason, &["{name}", "{reason}"][..]),
+        ];
+        for (name, template, required) in checks {
+            if let Some(template) = template
+                && let Some(missing) = required.iter().find(|p| !template.contains(*p)) {
+                anyhow::bail!("presence format {:?} must contain {}", name, missing);
+            }
+        }
+        Ok(())
+    }
+
+    fn joined(&self, name: &str, text: &Catalog) -> String {
+        fill(self.joined.as_deref().unwrap_or(text.joined), &[("name", name)])
+    }
+
+    fn left(&self, name: &str, reason: Option<&str>, text: &Catalog) -> String {
+        match reason {
+            Some(reason) => fill(self.left_with_reason.as_deref().unwrap_or(text.left_with_reason), &[("name", name), ("reason", reason)]),
+            None => fill(self.left.as_deref().unwrap_or(text.left), &[("name", name)]),
+        }
+    }
+}
+
+// parse_raw 自己处理的指令, 不经过 Command::parse
+const RAW_KEYWORDS: [&str; 4] = ["w", "o", "ttl", "quit"];
+
+/* 动作指令: 用户输入 /<name> <arg>... 时, 服务器把参数填入模板后通知所有人
+    模板中 {name} 是发送者, {1}、{2}... 依次是参数, 例如 "{name} slaps {1} around with a large trout"
+    args: 参数个数, 用户给出的参数个数必须正好相同; 含空格的参数用引号括起
+*/
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ActionCommand {
+    pub template: String,
+    pub args: usize,
+}
+impl ActionCommand {
+    // 检查指令名不与内置指令冲突, 模板中的参数编号都在 1..=args 之内
+    pub fn validate(&self, name: &str, prefix: char) -> Result<()> {
+        let line = format!("{}{}", prefix, name);
+        let builtin = match cmdline::parse_with(&line, prefix) {
+            Some(cmd) if !name.is_empty() && cmd.keyword() == name && cmd.rest.is_empty() => {
+                RAW_KEYWORDS.contains(&name) || !matches!(Command::parse(&cmd), Err(CommandError::Unknown(_)))
+            }
+            _ => anyhow::bail!("invalid action name {:?}", name),
+        };
+        if builtin {
+            anyhow::bail!("action {:?} would replace a built-in command", name);
+        }
+        for placeholder in placeholders(&self.template) {
+            if let Ok(n) = placeholder.parse::<usize>()
+                && !(1..=self.args).contains(&n) {
+                anyhow::bail!("action {:?} uses {{{}}} but takes {} argument(s)", name, n, self.args);
+            }
+        }
+        Ok(())
+    }
+
+    fn render(&self, from: &str, args: &[String]) -> String {
+        let keys: Vec<String> = (1..=args.len()).map(|i| i.to_string()).collect();
+        let values: Vec<(&str, &str)> = std::iter::once(("name", from))
+            .chain(keys.iter().map(String::as_str).zip(args.iter().map(String::as_str)))
+            .collect();
+        fill(&self.template, &values)
+    }
+}
+
+/* 新用户加入时只发给他的欢迎语, 例如 "Welcome {name}, {count} users online"
+    {name} 是用户名, {count} 是算上他在内的在线人数(和 /users 一样计数), {server} 是服务器名字
+    只能用这三个占位符, 写错时启动前就报错; 恢复会话的重连不算加入, 不再发送
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Greeting {
+    pub template: String,
+}
+impl Greeting {
+    const PLACEHOLDERS: [&str; 3] = ["name", "count", "server"];
+
+    // 检查模板只用了认识的占位符
+    pub fn validate(&self) -> Result<()> {
+        if let Some(unknown) = placeholders(&self.template).find(|p| !Self::PLACEHOLDERS.contains(p)) {
+            anyhow::bail!("greeting uses unknown placeholder {{{}}}, only {{name}}, {{count}} and {{server}} are available", unknown);
+        }
+        Ok(())
+    }
+
+    pub fn render(&self, name: &str, count: usize, server: &str) -> String {
+        fill(&self.template, &[("name", name), ("count", &count.to_string()), ("server", server)])
+    }
+}
+
+// 模板中 {...} 里的占位符名
+fn placeholders(template: &str) -> impl Iterator<Item = &str> {
+    template.split('{').skip(1).filter_map(|s| s.split_once('}')).map(|(k, _)| k)
+}
+
+// 多行消息(例如 /paste 发出的代码)在历史中把后续行缩进, 和下一条记录区分开
+fn indent_continuation(content: &str) -> String {
+    content.replace('\n', "\n    ")
+}
+
+/* ServerState 的调试快照, 由 /dumpstate 输出
+    rooms: 房间 -> 在线成员; broadcast_history / private_history: 每个房间、每个用户的历史条数
+    history_bytes: 全部历史合计占用的字节数(估算)
+*/
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct StateSnapshot {
+    pub server_name: String,
+    pub clients: Vec<String>,
+    pub rooms: BTreeMap<String, Vec<String>>,
+    pub broadcast_history: BTreeMap<String, usize>,
+    pub private_history: BTreeMap<String, usize>,
+    pub history_bytes: usize,
+    pub pending_receipts: usize,
+    pub open_polls: usize,
+    pub blocked: BTreeMap<String, BTreeSet<String>>,
+    pub quiet_joins: Vec<String>,
+}
+
+impl StateSnapshot {
+    pub fn render(&self, format: DumpFormat) -> String {
+        let rendered = match format {
+            DumpFormat::Pretty => serde_json::to_string_pretty(self),
+            DumpFormat::Json => serde_json::to_string(self),
+        };
+        // 只含字符串、数字和以字符串为键的映射, 序列化不会失败
+        rendered.expect("state snapshot is always serializable")
+    }
+}
+
+// 命中过滤词时的处理方式: 拒绝整条消息, 或把命中的词替换为 ***
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterPolicy {
+    Reject,
+    #[default]
+    Mask,
+}
+
+// 消息内容过滤器, 在存储和转发之前对广播与私聊内容生效
+#[derive(Debug, Default)]
+pub struct ContentFilter {
+    pub words: Vec<String>,
+    pub policy: FilterPolicy,
+}
+impl ContentFilter {
+    // 返回过滤后的内容; 策略为 Reject 且命中过滤词时返回 None
+    // 匹配不区分 ASCII 大小写
+    pub fn apply(&self, content: &str) -> Option<String> {
+        let mut out = content.to_string();
+        for word in self.words.iter().filter(|w| !w.is_empty()) {
+            let needle = word.to_ascii_lowercase();
+            let mut start = 0;
+            while let Some(pos) = out.to_ascii_lowercase()[start..].find(&needle) {
+                if self.policy == FilterPolicy::Reject {
+                    return None;
+                }
+                let pos = start + pos;
+                out.replace_range(pos..pos + needle.len(), "***");
+                start = pos + 3;
+            }
+        }
+        Some(out)
+    }
+}
+
+/* 消息内容中控制字符的处理方式, 防止恶意内容破坏其他用户的终端
+    换行统一为 \n, \r\n 和单独的 \r 都算一次换行; \n 和 \t 保留, 其余控制字符(包括 C1 控制字符)按策略处理:
+    Strip 删除, ANSI 转义序列(CSI、OSC 等)整段删除; Escape 替换为可见的 \x1b 形式, 序列的其余部分原样保留
+*/
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ControlChars {
+    #[default]
+    Strip,
+    Escape,
+}
+impl ControlChars {
+    pub fn sanitize(self, content: &str) -> String {
+        let mut out = String::with_capacity(content.len());
+        let mut chars = content.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\r' => {
+                    chars.next_if_eq(&'\n');
+                    out.push('\n');
+                }
+                '\n' | '\t' => out.push(c),
+                '\u{1b}' if self == ControlChars::Strip => skip_escape_sequence(&mut chars),
+                c if c.is_control() => {
+                    if self == ControlChars::Escape {
+                        out.push_str(&format!("\\x{:02x}", c as u32));
+                    }
+                }
+                c => out.push(c),
+            }
+        }
+        out
+    }
+}
+
+// 跳过 ESC 之后的转义序列: CSI(ESC [ ... 终止字节)、OSC/DCS 等字符串序列(以 BEL 或 ESC \ 结束), 其余只跳过一个字符
+fn skip_escape_sequence(chars: &mut std::iter::Peekable<std::str::Chars>) {
+    match chars.next() {
+        Some('[') => {
+            // 参数和中间字节之后是 0x40..=0x7e 的终止字节
+            for c in chars.by_ref() {
+                if ('\u{40}'..='\u{7e}').contains(&c) {
+                    break;
+                }
+            }
+        }
+        Some(']' | 'P' | 'X' | '^' | '_') => {
+            while let Some(c) = chars.next() {
+                if c == '\u{7}' || (c == '\u{1b}' && chars.next_if_eq(&'\\').is_some()) {
+                    break;
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/* 按 IP 网段限制连接
+    deny 中的网段总是被拒绝; allow 不为空时, 只接受 allow 中的网段
+    两者都为空时接受所有连接
+*/
+#[derive(Debug, Default, Clone)]
+pub struct IpAccess {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+impl IpAccess {
+    // 从配置中的字符串解析, 可以是 CIDR 网段(10.0.0.0/8)或单个地址(10.0.0.1)
+    pub fn parse(allow: &[String], deny: &[String]) -> Result<Self> {
+        let nets = |list: &[String]| list.iter().map(|s| parse_net(s)).collect::<Result<Vec<_>>>();
+        Ok(IpAccess { allow: nets(allow)?, deny: nets(deny)? })
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        // 双栈监听时 IPv4 客户端的地址形如 ::ffff:1.2.3.4, 先还原成 IPv4
+        let ip = ip.to_canonical();
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+fn parse_net(s: &str) -> Result<IpNet> {
+    let s = s.trim();
+    s.parse::<IpNet>()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| anyhow::anyhow!("invalid IP range: {}", s))
+}
+
+// 骰子个数、面数和修正值都在上限以内
+fn dice_in_range(dice: &Dice) -> bool {
+    (1..=MAX_DICE).contains(&dice.count)
+        && (1..=MAX_SIDES).contains(&dice.sides)
+        && (-MAX_MODIFIER..=MAX_MODIFIER).contains(&dice.modifier)
+}
+
+// 处理单个客户端连接
+// socket 可以是任意双向字节流(TCP 连接、内存管道等), addr 为对端地址
+pub async fn handle_client<S>(socket: S, addr: SocketAddr, state: Arc<Mutex<ServerState>>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // 使用在common.rs中定义的编解码器
+    let mut framed = Framed::new(socket, LengthCodec::new());
+
+    // 单独处理第一条消息: 第一次通信是 Register 消息, 用于登记用户名和发送通道
+    // 还没注册的连接也占着连接名额, 在 frame_timeout 内没有发来完整的第一帧就断开
+    let frame_timeout = state.lock().await.frame_timeout;
+    let first = if frame_timeout.is_zero() {
+        framed.next().await
+    } else {
+        match tokio::time::timeout(frame_timeout, framed.next()).await {
+            Ok(first) => first,
+            Err(_) => {
+                eprintln!("{} sent no complete frame within {:?}, disconnecting", addr, frame_timeout);
+                return Ok(());
+            }
+        }
+    };
+    // 健康检查: 第一帧是 Health 时回复 Healthy 后直接关闭连接, 不注册用户, 也不碰 clients
+    if let Some(Ok(Message::Clientmsg(ClientMessage::Health))) = first {
+        let server_name = state.lock().await.server_name.clone();
+        framed.send(Message::Servermsg(ServerMessage::Healthy { server_name })).await?;
+        return Ok(());
+    }
+    if let Some(Ok(Message::Clientmsg(ClientMessage::Register { name, capabilities, public_key, encryption_key, session }))) = first {
+        // 先占住名字和 IP 名额并打开会话, 但还不放进 clients: 写任务启动前没有人能往它的通道里发消息,
+        // 客户端在收到 Welcome 前断开时也不会有消息落进一个没人读的通道
+        // 名字格式不对、是保留名、已被占用, 或同一 IP 注册的用户名数量超出上限时拒绝注册; 检查与占用在同一次加锁中完成
+        let (tx, rx) = mpsc::channel(100);
+        // 声明了 sign 能力且公钥有效时才启用签名
+        let public_key = public_key
+            .filter(|key| capabilities.iter().any(|c| c == CAP_SIGN) && signing::is_valid_public_key(key));
+        let encryption_key = encryption_key
+            .filter(|key| capabilities.iter().any(|c| c == CAP_E2E) && encryption::is_valid_public_key(key));
+        let reserved = {
+            let mut st = state.lock().await;
+            if !is_valid_name(&name) {
+                Err((RefuseReason::InvalidName, fill(st.text().invalid_name, &[("max", &MAX_NAME_LEN.to_string())])))
+            } else if st.is_reserved(&name) || st.admin_key_mismatch(&name, public_key.as_deref()) {
+                Err((RefuseReason::NameReserved, st.text().name_reserved.to_string()))
+            } else if st.is_taken(&name) {
+                Err((RefuseReason::NameTaken, st.text().name_taken.to_string()))
+            } else if !st.reserve_ip_slot(addr.ip(), &name) {
+                Err((RefuseReason::TooManyNames, st.text().too_many_names.to_string()))
+            } else {
+                st.registering.insert(name.clone());
+                Ok(st.open_session(&name, session.as_deref(), Instant::now()))
+            }
+        };
+        let (session, resumed) = match reserved {
+            Ok(reserved) => reserved,
+            Err((reason, content)) => {
+                framed.send(Message::Servermsg(ServerMessage::RegisterRefused { reason, content })).await?;
+                return Ok(());
+            }
+        };
+
+        // 协商能力: Welcome 本身仍以明文发送, 之后的帧才按协商结果压缩
+        let (compression, server_name) = {
+            let st = state.lock().await;
+            (st.compression, st.server_name.clone())
+        };
+        let compress = compression && capabilities.iter().any(|c| c == CAP_COMPRESS);
+        let mut agreed = Vec::new();
+        if compress {
+            agreed.push(CAP_COMPRESS.to_string());
+        }
+        if public_key.is_some() {
+            agreed.push(CAP_SIGN.to_string());
+        }
+        if encryption_key.is_some() {
+            agreed.push(CAP_E2E.to_string());
+        }
+        if let Err(e) = framed.send(Message::Servermsg(ServerMessage::Welcome { capabilities: agreed, server_name, session })).await {
+            state.lock().await.cancel_registration(&name, addr.ip());
+            return Err(e.into());
+        }
+        framed.codec_mut().set_compression(compress);
+
+        // 分离编码与解码：Sink 用于编码，Stream 用于解码
+        // 读写两半各用一个编解码器, 这样读取循环可以查看读缓冲里是否停着半帧; 已经读进来的字节原样交给读的一半
+        let parts = framed.into_parts();
+        let (reader, writer) = tokio::io::split(parts.io);
+        let mut stream = FramedRead::new(reader, parts.codec);
+        stream.read_buffer_mut().extend_from_slice(&parts.read_buf);
+        let mut encoder = LengthCodec::new();
+        encoder.set_compression(compress);
+        let mut sink = FramedWrite::new(writer, encoder);
+        // 启动写任务后在同一次加锁中把发送通道放进 clients, 同时订阅群发, 之后的群发不会漏掉
+        // 写任务从收件箱取出发给该客户端的消息并发送, 发送通道关闭后先发完积压的消息再结束
+        let mut writer = {
+            let mut st = state.lock().await;
+            if st.shutting_down {
+                st.cancel_registration(&name, addr.ip());
+                return Ok(());
+            }
+            st.registering.remove(&name);
+            let mut inbox = st.inbox(&name, rx);
+            let writer_alive = st.writers.subscribe();
+            // 一帧在 write_timeout 内没有写完说明对方不再读取, 结束写任务, 读取循环随之结束并断开连接
+            let (write_timeout, writer_name) = (st.write_timeout, name.clone());
+            let writer = tokio::spawn(async move {
+                let _writer_alive = writer_alive;
+                while let Some(msg) = inbox.recv().await {
+                    let send = sink.send(msg);
+                    let sent = if write_timeout.is_zero() { Ok(send.await) } else { tokio::time::timeout(write_timeout, send).await };
+                    match sent {
+                        Ok(Ok(())) => {}
+                        // 超过帧长度上限的消息没有写出, 连接仍然可用, 只记录下来
+                        Ok(Err(e)) if e.kind() == std::io::ErrorKind::InvalidData => {
+                            eprintln!("Dropped a message to {}: {}", writer_name, e);
+                        }
+                        Ok(Err(_)) => break,
+                        Err(_) => {
+                            eprintln!("{} has not read anything for {:?}, disconnecting", writer_name, write_timeout);
+                            break;
+                        }
+                    }
+                }
+            });
+            st.clients.insert(name.clone(), tx);
+            if let Some(key) = &public_key {
+                st.public_keys.insert(name.clone(), key.clone());
+            }
+            if let Some(key) = &encryption_key {
+                st.encryption_keys.insert(name.clone(), key.clone());
+            }
+            writer
+        };
+
+        // 广播“某用户”加入聊天的消息
+        register(&name, resumed, &state).await;
+        println!("{} joined from {} ({} clients connected)", name, addr, state.lock().await.clients.len());
+        if let Some(key) = encryption_key {
+            exchange_keys(&name, key, &state).await;
+        }
+        share_colors(&name, &state).await;
+
+        // 读取循环：接收该客户端发来的消息并处理, 解码出错时记录原因后断开
+        // 服务器移除了该客户端的发送通道(例如关闭服务器)时写任务结束, 读取循环随之结束, 连接关闭
+        // 每隔 frame_timeout 的四分之一检查一次读缓冲: 同一个半帧停留超过 frame_timeout 时断开
+        let mut quit_reason = None;
+        let mut writer_done = false;
+        let mut check = tokio::time::interval((frame_timeout / 4).max(Duration::from_millis(1)));
+        check.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut partial_since = None;
+        loop {
+            let next = tokio::select! {
+                next = stream.next() => next,
+                _ = &mut writer => {
+                    writer_done = true;
+                    break;
+                }
+                now = check.tick(), if !frame_timeout.is_zero() => {
+                    if stream.read_buffer().is_empty() {
+                        partial_since = None;
+                    } else if now - *partial_since.get_or_insert(now) >= frame_timeout {
+                        eprintln!("{} ({}) has not finished a frame for {:?}, disconnecting", name, addr, frame_timeout);
+                        break;
+                    }
+                    continue;
+                }
+            };
+            partial_since = None;
+            let msg = match next {
+                Some(Ok(Message::Clientmsg(msg))) => msg,
+                Some(Ok(Message::Servermsg(_))) => continue,
+                Some(Err(e)) => {
+                    eprintln!("Decode error from {} ({}): {}", name, addr, e);
+                    break;
+                }
+                None => break,
+            };
+            // 服务器关闭期间不再处理新消息
+            if state.lock().await.shutting_down {
+                break;
+            }
+            if let ControlFlow::Break(reason) = route(&name, msg, &state).await {
+                quit_reason = reason;
+                break;
+            }
+        }
+
+        // 客户端断开，移除状态并广播离开通知(系统消息), 主动退出时附上退出原因
+        unregister(&name, addr, &state).await;
+        // 退出原因和消息内容一样清理控制字符, 以免借离开通知改写别人的终端
+        let content = {
+            let st = state.lock().await;
+            let reason = quit_reason.map(|reason| st.control_chars.sanitize(&reason));
+            st.presence_format.left(&name, reason.as_deref(), st.text())
+        };
+        let leave_msg = Message::Servermsg(ServerMessage::System { content });
+        let listeners = state.lock().await.presence_listeners();
+        for tx in listeners {
+            let _ = tx.send(leave_msg.clone()).await;
+        }
+
+        // 发送通道已从 clients 中移除, 写任务发完积压的消息后就会结束; 在这里等它结束,
+        // 别处还留着通道的副本或对方一直不读时超时取消, 保证 handle_client 返回时写任务也已结束
+        if !writer_done && tokio::time::timeout(WRITER_DRAIN_TIMEOUT, &mut writer).await.is_err() {
+            writer.abort();
+            let _ = writer.await;
+        }
+    }
+    Ok(())
+}
+
+// 用户名不能为空或超过 MAX_NAME_LEN 个字符, 不能包含空白、控制字符和逗号(/w 用逗号分隔多个接收者)
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.chars().count() <= MAX_NAME_LEN
+        && !name.chars().any(|c| c.is_whitespace() || c.is_control() || c == ',')
+}
+
+// 按消息类型交给对应的处理函数, from 是连接注册时的名字; 客户端主动退出时返回 Break(退出原因)
+pub(crate) async fn route(from: &str, msg: ClientMessage, state: &Arc<Mutex<ServerState>>) -> ControlFlow<Option<String>> {
+    // 原始输入先解析成具体的消息
+    // 同一会话中已经处理过的 id 说明是重复发送, 直接丢弃
+    let client_id = match &msg {
+        ClientMessage::Broadcast { id, .. } | ClientMessage::Raw { id, .. } => *id,
+        _ => 0,
+    };
+    let parsed = {
+        let mut st = state.lock().await;
+        if st.is_duplicate(from, client_id) {
+            return ControlFlow::Continue(());
+        }
+        parse_raw(from, msg, st.text(), st.command_prefix, |keyword| st.action(keyword).is_some())
+    };
+    let msg = match parsed {
+        Ok(msg) => msg,
+        Err(error) => {
+            if let ServerMessage::Error { to, .. } = &*error {
+                let to = to.clone();
+                send_to(&to, Message::Servermsg(*error), state).await;
+            }
+            return ControlFlow::Continue(());
+        }
+    };
+    // 有了动静就取消暂时离开; 已读回执和确认由客户端自动发出, /whoami 只是查看状态, 都不算
+    let active = match &msg {
+        ClientMessage::Command { command: Command::Afk(_) | Command::Whoami } => false,
+        ClientMessage::Broadcast { .. } | ClientMessage::Private { .. } | ClientMessage::Command { .. } | ClientMessage::FileOffer { .. } => true,
+        _ => false,
+    };
+    if active {
+        back(from, state).await;
+    }
+    match &msg {
+        ClientMessage::Broadcast { .. } => broadcast(from, msg, state).await,
+        ClientMessage::Private { .. }   => dispatch(from, msg, state).await,
+        ClientMessage::Command { .. }   => command(from, msg, state).await,
+        ClientMessage::ReadReceipt { .. } => read_receipt(from, msg, state).await,
+        ClientMessage::Ack { message_id } => state.lock().await.ack(from, *message_id),
+        ClientMessage::FileOffer { .. } => file_offer(from, msg, state).await,
+        ClientMessage::Quit { reason, .. } => return ControlFlow::Break(reason.clone()),
+        _ => (),
+    }
+    ControlFlow::Continue(())
+}
+
+/* 把客户端原样发来的一行输入解析成具体的消息, 输入格式有误时返回给发送者的用法提示, 其他消息原样返回
+    /w <user>[,<user>...] <msg>  私聊, 可同时发给多人
+    /o <msg>                     群发一条不记入历史的消息
+    /ttl <secs> <msg>            群发一条 secs 秒后自动删除的消息
+    /quit [reason]               退出聊天, reason 附在离开通知中
+    其他以指令前缀开头的输入解析为 Command, is_action 认识的指令名解析为 Command::Action
+    其余的都是普通群发; 上面的 / 代表配置的前缀
+    以两个前缀开头的输入是转义, 去掉一个前缀后作为普通群发, 例如 //tmp 发送 /tmp
+*/
+fn parse_raw(
+    from: &str, msg: ClientMessage, catalog: &Catalog, prefix: char, is_action: impl Fn(&str) -> bool,
+) -> Result<ClientMessage, Box<ServerMessage>> {
+    let ClientMessage::Raw { text, id } = msg else { return Ok(msg) };
+    let usage = |content: &str| Box::new(ServerMessage::Error { content: content.to_string(), to: from.to_string() });
+    let broadcast = |content: &str, ephemeral, ttl_secs| ClientMessage::Broadcast {
+        content: content.to_string(), id, ephemeral, ttl_secs, reply_to: None, attachments: Vec::new(),
+    };
+    let Some(cmd) = cmdline::parse_with(&text, prefix) else {
+        return Ok(broadcast(cmdline::unescape(&text, prefix), false, None));
+    };
+    match cmd.keyword() {
+        "w" => {
+            let (to, content) = cmd.whisper().ok_or_else(|| usage(catalog.usage_whisper))?;
+            Ok(ClientMessage::Private { to, content: content.to_string(), ephemeral: false, ttl_secs: None, signature: None, encrypted: false, reply_to: None })
+        }
+        "o" if !cmd.rest.is_empty() => Ok(broadcast(cmd.rest, true, None)),
+        "o" => Err(usage(catalog.usage_off_record)),
+        "ttl" => {
+            let (secs, content) = cmd.first_arg()
+                .and_then(|(secs, content)| Some((secs.parse::<u64>().ok()?, content)))
+                .filter(|(_, content)| !content.is_empty())
+                .ok_or_else(|| usage(catalog.usage_ttl))?;
+            Ok(broadcast(content, false, Some(secs)))
+        }
+        "quit" => Ok(ClientMessage::Quit { reason: (!cmd.rest.is_empty()).then(|| cmd.rest.to_string()) }),
+        _ => match Command::parse(&cmd) {
+            Ok(command) => Ok(ClientMessage::Command { command }),
+            Err(CommandError::Usage(keyword)) => Err(usage(&usage_of(&keyword, catalog))),
+            Err(CommandError::Unknown(_)) if is_action(cmd.keyword()) => {
+                Ok(ClientMessage::Command { command: Command::Action { name: cmd.keyword().to_string(), args: cmd.args() } })
+            }
+            Err(CommandError::Unknown(name)) => Err(usage(&fill(catalog.unknown_command, &[("command", &name)]))),
+        },
+    }
+}
+
+// 移除已注册的客户端并归还 IP 名额
+async fn unregister(name: &String, addr: SocketAddr, state: &Arc<Mutex<ServerState>>) {
+    let mut st = state.lock().await;
+    st.clients.remove(name);
+    st.release_ip_slot(addr.ip(), name);
+    st.public_keys.remove(name);
+    st.encryption_keys.remove(name);
+    st.afk.remove(name);
+    st.clear_requests.remove(name);
+    st.close_polls_of(name);
+    st.last_seen.insert(name.clone(), SystemTime::now());
+    st.suspend_session(name, Instant::now());
+    st.push_user_count();
+    println!("{} disconnected ({} clients connected)", name, st.clients.len());
+}
+
+// 广播消息给所有在线客户端
+async fn broadcast(from: &str, msg: ClientMessage, state: &Arc<Mutex<ServerState>>) {
+    if let ClientMessage::Broadcast { content, id, ephemeral, ttl_secs, reply_to, attachments } = &msg {
+        // 附件过多或元数据不合法时整条消息不发, 只通知发送者
+        if attachments.len() > MAX_ATTACHMENTS || !attachments.iter().all(AttachmentMeta::is_valid) {
+            let content = fill(state.lock().await.text().invalid_attachments, &[("max", &MAX_ATTACHMENTS.to_string())]);
+            send_to(from, Message::Servermsg(ServerMessage::Error { content, to: from.to_string() }), state).await;
+            return;
+        }
+
+        // 刷屏检查, 被限制或禁言时消息直接丢弃, 只通知发送者
+        let checked = state.lock().await.check_spam(from, content, Instant::now());
+        if let Err(refusal) = checked {
+            refuse_spam(from, refusal, state).await;
+            return;
+        }
+
+        // 清理控制字符后做内容过滤, 被拒绝时只通知发送者
+        let filtered = state.lock().await.clean(content);
+        let Some(content) = filtered else {
+            reject(from, state).await;
+            return;
+        };
+
+        // 服务器转发时会加上发送者和消息 id, 加上后超过单帧上限的消息接收者无法解码, 只通知发送者
+        let probe = ServerMessage::BroadcastMessage {
+            from: from.to_string(), content: content.clone(), id: *id, message_id: u64::MAX, reply_to: *reply_to, attachments: attachments.clone(),
+        };
+        if !fits_in_frame(probe) {
+            refuse_too_large(from, state).await;
+            return;
+        }
+
+        // 记录客户发言, 阅后即焚的消息不记录; 回复的 id 必须是已经分配过的, 否则当作不是回复
+        // 通过了所有检查才算发言, 记下时间供 /seen 查询
+        let (message_id, reply_to) = {
+            let mut st = state.lock().await;
+            st.last_spoke.insert(from.to_string(), SystemTime::now());
+            let message_id = st.next_message_id();
+            let reply_to = reply_to.filter(|&parent| parent < message_id);
+            if !ephemeral {
+                let text = st.history_format.broadcast(from, &content);
+                let room = st.room_of(from);
+                let depth = reply_depth(st.broadcast_history.get(room), reply_to);
+                let entry = StoredMessage::new(message_id, text, expiry(*ttl_secs)).in_thread(depth)
+                    .with_origin(ExportKind::Broadcast, from, Vec::new(), &content, reply_to)
+                    .with_attachments(attachments.clone(), st.text());
+                st.record_broadcast(room, entry);
+            }
+            (message_id, reply_to)
+        };
+        
+        // 将广播消息放入共享的群发通道
+        let reply_msg = Message::Servermsg(ServerMessage::BroadcastMessage {
+            from: from.to_string(), content, id: *id, message_id, reply_to, attachments: attachments.clone(),
+        });
+        state.lock().await.send_to_everyone(reply_msg);
+    }
+}
+
+// 把文件分享的元数据转发给所有人; 和群发一样受反刷屏限制, 元数据不合法时只通知发送者
+async fn file_offer(from: &str, msg: ClientMessage, state: &Arc<Mutex<ServerState>>) {
+    let ClientMessage::FileOffer { file } = msg else { return };
+    if !file.is_valid() {
+        let content = state.lock().await.text().invalid_file_offer.to_string();
+        send_to(from, Message::Servermsg(ServerMessage::Error { content, to: from.to_string() }), state).await;
+        return;
+    }
+    let checked = {
+        let mut st = state.lock().await;
+        let checked = st.check_spam(from, &file.sha256, Instant::now());
+        if checked.is_ok() {
+            st.send_to_everyone(Message::Servermsg(ServerMessage::FileOffer { from: from.to_string(), file }));
+        }
+        checked
+    };
+    if let Err(refusal) = checked {
+        refuse_spam(from, refusal, state).await;
+    }
+}
+
+// 通知发送者消息被反刷屏规则拒绝, 并用 Throttle 告诉它至少要等多久再发
+async fn refuse_spam(from: &str, refusal: SpamRefusal, state: &Arc<Mutex<ServerState>>) {
+    let content = refusal.describe(state.lock().await.text());
+    send_to(from, Message::Servermsg(ServerMessage::Error { content, to: from.to_string() }), state).await;
+    send_to(from, throttle(refusal.retry_after()), state).await;
+}
+
+// 要求客户端至少等待 wait 再发下一条, 不足一毫秒的部分向上取整
+fn throttle(wait: Duration) -> Message {
+    Message::Servermsg(ServerMessage::Throttle { retry_after_ms: wait.as_micros().div_ceil(1000) as u64 })
+}
+
+// 转发的消息按最长的消息 id 估算后是否仍在单帧上限之内; 压缩的帧解压后同样受这个限制
+fn fits_in_frame(msg: ServerMessage) -> bool {
+    codec::payload_len(&Message::Servermsg(msg)) <= MAX_FRAME_LEN
+}
+
+// 通知发送者消息转发时会超过单帧上限, 没有发出
+async fn refuse_too_large(from: &str, state: &Arc<Mutex<ServerState>>) {
+    let content = fill(state.lock().await.text().too_large, &[("max", &human_size(MAX_FRAME_LEN as u64))]);
+    send_to(from, Message::Servermsg(ServerMessage::Error { content, to: from.to_string() }), state).await;
+}
+
+// 通知发送者消息因包含过滤词被拒绝
+async fn reject(from: &str, state: &Arc<Mutex<ServerState>>) {
+    let content = state.lock().await.text().filtered.to_string();
+    send_to(from, Message::Servermsg(ServerMessage::Error { content, to: from.to_string() }), state).await;
+}
+
+/* 发给一个在线用户, 不在线时什么也不做
+    先取出发送通道的副本、释放锁再发送: 接收者的队列满时只有这次发送在等, 其他连接照常处理
+    持有 state 的锁时不要 await 有界通道的 send, 一律经过这里或先取出副本
+*/
+async fn send_to(name: &str, msg: Message, state: &Arc<Mutex<ServerState>>) {
+    let tx = state.lock().await.clients.get(name).cloned();
+    if let Some(tx) = tx {
+        let _ = tx.send(msg).await;
+    }
+}
+
+// 私聊仅发送给指定目标用户, 可以同时发给多个用户
+async fn dispatch(from: &str, msg: ClientMessage, state: &Arc<Mutex<ServerState>>) {
+    if let ClientMessage::Private { to, content, ephemeral, ttl_secs, signature, encrypted, reply_to } = &msg {
+        // 清理控制字符后做内容过滤, 被拒绝时只通知发送者; 密文无法过滤, 原样转发, 由接收的客户端解密后自行处理
+        let filtered = if *encrypted {
+            Some(content.clone())
+        } else {
+            state.lock().await.clean(content)
+        };
+        let Some(content) = filtered else {
+            reject(from, state).await;
+            return;
+        };
+
+        // 接收者可以只写名字的前缀, 见 resolve_recipient; 有歧义时整条消息都不发出, 告诉发送者有哪些候选
+        // 密文是按客户端写下的名字对应的公钥加密的, 只接受完整的名字
+        // 按前缀换成全名的接收者事后告诉发送者, 以免发错了人还不知道
+        let mut expanded = Vec::new();
+        let resolved: Result<Vec<String>, String> = {
+            let st = state.lock().await;
+            to.iter()
+                .map(|name| match st.resolve_recipient(name, !*encrypted) {
+                    Ok(full) => {
+                        if full != *name && !expanded.contains(&full) {
+                            expanded.push(full.clone());
+                        }
+                        Ok(full)
+                    }
+                    Err(candidates) => Err(fill(st.text().ambiguous_recipient, &[("prefix", name), ("names", &candidates.join(", "))])),
+                })
+                .collect()
+        };
+        let resolved = match resolved {
+            Ok(resolved) => resolved,
+            Err(content) => {
+                send_to(from, Message::Servermsg(ServerMessage::Error { content, to: from.to_string() }), state).await;
+                return;
+            }
+        };
+
+        // 去掉重复的接收者, 保持原有顺序
+        let mut recipients: Vec<&String> = Vec::new();
+        for name in &resolved {
+            if !recipients.contains(&name) {
+                recipients.push(name);
+            }
+        }
+        let to_list = recipients.iter().map(|n| n.as_str()).collect::<Vec<_>>().join(", ");
+
+        // 屏蔽了发送者的接收者直接跳过, 对发送者而言与不在线无异
+        let mut offline = Vec::new();
+        {
+            let st = state.lock().await;
+            recipients.retain(|name| {
+                let blocked = st.has_blocked(name, from);
+                if blocked {
+                    offline.push(name.as_str());
+                }
+                !blocked
+            });
+        }
+
+        // 用所有接收者名字连起来的长度估算, 不短于发给任何一个接收者的那一帧
+        let public_key = signature.as_ref().and(state.lock().await.public_keys.get(from).cloned());
+        let probe = ServerMessage::PrivateMessage {
+            from: from.to_string(), to: to_list.clone(), content: content.clone(), message_id: u64::MAX,
+            signature: signature.clone(), public_key: public_key.clone(), encrypted: *encrypted, reply_to: *reply_to,
+        };
+        if !fits_in_frame(probe) {
+            refuse_too_large(from, state).await;
+            return;
+        }
+
+        // 记录客户发言(自己发送的 + 送向自己的), 阅后即焚的消息不记录
+        // 通过了所有检查、至少有一个没有屏蔽发送者的接收者才算发言, 记下时间供 /seen 查询
+        let (message_id, reply_to) = {
+            let mut st = state.lock().await;
+            if !recipients.is_empty() {
+                st.last_spoke.insert(from.to_string(), SystemTime::now());
+            }
+            let message_id = st.next_message_id();
+            let reply_to = reply_to.filter(|&parent| parent < message_id);
+            if !ephemeral {
+                let expires_at = expiry(*ttl_secs);
+                // 历史中不保存密文, 只记下有过一条加密消息
+                let content = if *encrypted { st.text().encrypted_placeholder } else { content.as_str() };
+                let sent = st.history_format.private_sent(&to_list, content);
+                let received = st.history_format.private_received(from, content);
+                // 回复的层级按发送者自己的私聊历史计算, 双方看到的缩进相同
+                let depth = reply_depth(st.private_history.get(from), reply_to);
+                let entry = StoredMessage::new(message_id, sent, expires_at).in_thread(depth)
+                    .with_origin(ExportKind::Private, from, recipients.iter().map(|n| n.to_string()).collect(), content, reply_to);
+                st.record_private(from, entry);
+                for name in &recipients {
+                    let entry = StoredMessage::new(message_id, received.clone(), expires_at).in_thread(depth)
+                        .with_origin(ExportKind::Private, from, vec![name.to_string()], content, reply_to);
+                    st.record_private(name, entry);
+                }
+            }
+            (message_id, reply_to)
+        };
+
+        // 将私聊消息逐个放入接收者的 mpsc::channel 中, 并收集不在线的接收者
+        // 签名连同发送者的公钥原样转发, 由接收者验证
+        // 接收者的队列已满时发送要等对方取走消息, 事后用 Throttle 让发送者放慢
+        let mut delivered = HashSet::new();
+        let mut congested = false;
+        for name in recipients {
+            let reply_msg = Message::Servermsg(ServerMessage::PrivateMessage {
+                from: from.to_string(), to: name.clone(), content: content.clone(), message_id,
+                signature: signature.clone(), public_key: public_key.clone(), encrypted: *encrypted, reply_to,
+            });
+            let tx = state.lock().await.clients.get(name).cloned();
+            if let Some(tx) = tx {
+                congested |= tx.capacity() == 0;
+                let _ = tx.send(reply_msg).await;
+                delivered.insert(name.clone());
+            } else {
+                offline.push(name.as_str());
+            }
+        }
+
+        if congested {
+            send_to(from, throttle(QUEUE_FULL_RETRY), state).await;
+        }
+
+        if !expanded.is_empty() {
+            let content = fill(state.lock().await.text().resolved_recipients, &[("names", &expanded.join(", "))]);
+            send_to(from, Message::Servermsg(ServerMessage::System { content }), state).await;
+        }
+
+        // 暂时离开的接收者替他们回复一次留言
+        let replies: Vec<String> = {
+            let mut st = state.lock().await;
+            delivered.iter().filter_map(|name| st.afk_reply(name, from)).collect()
+        };
+        for content in replies {
+            send_to(from, Message::Servermsg(ServerMessage::System { content }), state).await;
+        }
+
+        // 记录已送达的接收者, 等待他们的已读回执
+        if !delivered.is_empty() {
+            let mut st = state.lock().await;
+            st.pending_receipts.insert(message_id, PendingReceipt { sender: from.to_string(), unread: delivered });
+            if st.pending_receipts.len() > MAX_PENDING_RECEIPTS {
+                st.pending_receipts.pop_first();
+            }
+        }
+
+        // 如果有找不到的私聊对象, 向该客户端返回一个汇总的错误消息
+        // 已经离线的用户附上最近上线时间
+        if !offline.is_empty() {
+            let content = {
+                let st = state.lock().await;
+                let offline = offline.iter()
+                    .map(|name| match st.last_seen(name) {
+                        Some(seen) => format!("{} ({})", name, seen),
+                        None => name.to_string(),
+                    })
+                    .collect::<Vec<_>>();
+                fill(st.text().not_online, &[("names", &offline.join(", "))])
+            };
+            let private_error_msg = Message::Servermsg(ServerMessage::Error { content, to: from.to_string()});
+            send_to(from, private_error_msg, state).await;
+        }
+    }
+}
+
+// 已读回执: 只接受该私聊真正的接收者发来的回执, 转发给原发送者
+async fn read_receipt(from: &str, msg: ClientMessage, state: &Arc<Mutex<ServerState>>) {
+    if let ClientMessage::ReadReceipt { message_id } = msg {
+        let mut st = state.lock().await;
+        let Some(pending) = st.pending_receipts.get_mut(&message_id) else {
+            return;
+        };
+        if !pending.unread.remove(from) {
+            return;
+        }
+        let sender = pending.sender.clone();
+        if pending.unread.is_empty() {
+            st.pending_receipts.remove(&message_id);
+        }
+        drop(st);
+        send_to(&sender, Message::Servermsg(ServerMessage::Read { message_id, by: from.to_string() }), state).await;
+    }
+}
+
+// 命令: 交给对应的处理函数, 把结果回复给发送者; 新增指令时在 Command 中加一个成员并在这里处理
+async fn command(from: &str, msg: ClientMessage, state: &Arc<Mutex<ServerState>>) {
+    let ClientMessage::Command { command } = msg else { return };
+    let reply = match command {
+        Command::Users => cmd_users(from, state).await,
+        Command::History { page, since } => cmd_history(from, page, since, state).await,
+        Command::Block(target) => cmd_block(from, target, state).await,
+        Command::Unblock(target) => cmd_unblock(from, target, state).await,
+        Command::Whois(target) => cmd_whois(target, state).await,
+        Command::Seen(target) => cmd_seen(target, state).await,
+        Command::Whoami => cmd_whoami(from, state).await,
+        Command::Color(color) => cmd_color(from, color, state).await,
+        Command::Roll(dice) => cmd_roll(from, dice, state).await,
+        Command::Poll { question, options } => cmd_poll(from, question, options, state).await,
+        Command::Vote { poll_id, choice } => cmd_vote(from, poll_id, choice, state).await,
+        Command::PollClose(poll_id) => cmd_poll_close(from, poll_id, state).await,
+        Command::QuietJoins => cmd_quiet_joins(from, state).await,
+        Command::Afk(message) => cmd_afk(from, message, state).await,
+        Command::Stats => cmd_stats(from, state).await,
+        Command::DumpState(format) => cmd_dumpstate(from, format, state).await,
+        Command::Export => cmd_export(from, state).await,
+        Command::Import(path) => cmd_import(from, path, state).await,
+        Command::ClearDmHistory { confirm } => cmd_clear_dm_history(from, confirm, state).await,
+        Command::Action { name, args } => cmd_action(from, name, args, state).await,
+    };
+    if let Some(reply) = reply {
+        send_to(from, reply, state).await;
+    }
+}
+
+// 在发送者的私聊历史中记录这次请求, 记录的是还原后的一行输入
+fn record_command(st: &mut ServerState, from: &str, command: &Command) {
+    let message_id = st.next_message_id();
+    let line = command.line(st.command_prefix);
+    let text = fill(st.text().issued, &[("command", &line)]);
+    st.record_private(from, StoredMessage::new(message_id, text, None).with_origin(ExportKind::Command, from, Vec::new(), &line, None));
+}
+
+// 指令参数有误时回复的用法提示, keyword 不含前缀
+fn usage_of(keyword: &str, text: &Catalog) -> String {
+    let template = match keyword {
+        "users" => text.usage_users,
+        "history" => text.usage_history,
+        "block" => text.usage_block,
+        "unblock" => text.usage_unblock,
+        "whois" => text.usage_whois,
+        "seen" => text.usage_seen,
+        "whoami" => text.usage_whoami,
+        "color" => text.usage_color,
+        "roll" => text.usage_roll,
+        "poll" => text.usage_poll,
+        "vote" => text.usage_vote,
+        "poll-close" => text.usage_poll_close,
+        "quiet-joins" => text.usage_quiet_joins,
+        "afk" => text.usage_afk,
+        "stats" => text.usage_stats,
+        "dumpstate" => text.usage_dumpstate,
+        "export" => text.usage_export,
+        "import" => text.usage_import,
+        "dm-history" => text.usage_dm_history,
+        _ => return fill(text.unknown_command, &[("command", keyword)]),
+    };
+    usage_text(template)
+}
+
+// 填入用法提示中的上限, 例如 /roll 的骰子个数和 /poll 的选项数量
+fn usage_text(template: &str) -> String {
+    fill(template, &[
+        ("dice", &MAX_DICE.to_string()), ("sides", &MAX_SIDES.to_string()), ("modifier", &MAX_MODIFIER.to_string()),
+        ("min", &MIN_POLL_OPTIONS.to_string()), ("max", &MAX_POLL_OPTIONS.to_string()),
+    ])
+}
+
+fn error_reply(to: &str, content: &str) -> Option<Message> {
+    Some(Message::Servermsg(ServerMessage::Error { content: content.to_string(), to: to.to_string() }))
+}
+
+fn system_reply(content: String) -> Option<Message> {
+    Some(Message::Servermsg(ServerMessage::System { content }))
+}
+
+// /users: 当前在线的用户列表
+async fn cmd_users(from: &str, state: &Arc<Mutex<ServerState>>) -> Option<Message> {
+    let mut st = state.lock().await;
+    record_command(&mut st, from, &Command::Users);
+
+    let user_list: Vec<String> = st.clients.keys().cloned().collect();
+    if user_list.is_empty() {
+        system_reply(st.text().no_user_online.to_string())
+    } else {
+        Some(Message::Servermsg(ServerMessage::UserList { content: user_list, to: from.to_string()}))
+    }
+}
+
+// /history [--since <unix_secs>] [page]: 分页返回广播历史和自己的私聊历史, 页码从 1 开始; 带 --since 时只返回该时间及之后的消息
+async fn cmd_history(from: &str, page: Option<usize>, since: Option<u64>, state: &Arc<Mutex<ServerState>>) -> Option<Message> {
+    let mut st = state.lock().await;
+    let text = st.text();
+    record_command(&mut st, from, &Command::History { page, since });
+    let page = page.unwrap_or(1);
+    let cutoff = since.map_or(SystemTime::UNIX_EPOCH, |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+    let is_recent = |m: &&StoredMessage| m.sent_at >= cutoff;
+    // 收集历史: 所在房间的广播 + 自己的私聊
+    let mut lines = Vec::new();
+    lines.push(text.broadcast_history.to_string());
+    lines.extend(st.room_history(st.room_of(from)).filter(is_recent).map(StoredMessage::line));
+    lines.push(text.private_history.to_string());
+    if let Some(priv_h) = st.private_history.get(from) {
+        lines.extend(priv_h.iter().filter(is_recent).map(StoredMessage::line));
+    }
+
+    match history_page(&lines, page, since, text) {
+        Some(content) => Some(Message::Servermsg(ServerMessage::History { content, to: from.to_string() })),
+        None => error_reply(from, &fill(text.no_history_page, &[("page", &page.to_string())])),
+    }
+}
+
+// /export: 按 message_id 的顺序导出所在房间的广播和自己的私聊, 较多时分成几帧, 最后一帧 done 为 true 并带上屏蔽列表
+async fn cmd_export(from: &str, state: &Arc<Mutex<ServerState>>) -> Option<Message> {
+    let (frames, mut blocked, tx) = {
+        let mut st = state.lock().await;
+        record_command(&mut st, from, &Command::Export);
+        let mut messages: Vec<ExportedMessage> = st.room_history(st.room_of(from))
+            .chain(st.private_history.get(from).into_iter().flatten())
+            .filter_map(StoredMessage::export)
+            .collect();
+        messages.sort_by_key(|m| m.message_id);
+        let blocked: Vec<String> = st.blocked.get(from).into_iter().flatten().cloned().collect();
+        (export_frames(messages), blocked, st.clients.get(from).cloned())
+    };
+    let tx = tx?;
+    blocked.sort();
+    let last = frames.len() - 1;
+    for (i, messages) in frames.into_iter().enumerate() {
+        let blocked = if i == last { std::mem::take(&mut blocked) } else { Vec::new() };
+        let _ = tx.send(Message::Servermsg(ServerMessage::Export { messages, done: i == last, blocked })).await;
+    }
+    None
+}
+
+/* /dm-history clear [confirm]: 删除服务器上自己的私聊历史, 包括记下的指令; 对方历史中的同一条私聊不受影响
+    不带 confirm 时只说明会删除多少条并记下请求, CLEAR_CONFIRM_WINDOW 内带 confirm 再发一次才真正删除
+    删除后历史为空, 这次指令本身也不记录
+*/
+async fn cmd_clear_dm_history(from: &str, confirm: bool, state: &Arc<Mutex<ServerState>>) -> Option<Message> {
+    let mut st = state.lock().await;
+    let text = st.text();
+    let prefix = st.command_prefix;
+    let line = |confirm| Command::ClearDmHistory { confirm }.line(prefix);
+    let secs = CLEAR_CONFIRM_WINDOW.as_secs().to_string();
+    if !confirm {
+        let count = st.private_history.get(from).map_or(0, VecDeque::len).to_string();
+        let content = fill(text.dm_history_confirm, &[("count", &count), ("command", &line(true)), ("secs", &secs)]);
+        st.clear_requests.insert(from.to_string(), Instant::now());
+        return system_reply(content);
+    }
+    let requested = st.clear_requests.remove(from).is_some_and(|at| at.elapsed() <= CLEAR_CONFIRM_WINDOW);
+    if !requested {
+        return error_reply(from, &fill(text.dm_history_unconfirmed, &[("request", &line(false)), ("secs", &secs)]));
+    }
+    let removed = st.private_history.remove(from).unwrap_or_default();
+    st.history_bytes -= removed.iter().map(StoredMessage::size).sum::<usize>();
+    system_reply(fill(text.dm_history_cleared, &[("count", &removed.len().to_string())]))
+}
+
+// 把导出的消息按 JSON 长度分组, 每组不超过 HISTORY_PAGE_MAX_BYTES, 使每帧都在帧长度上限以内; 没有消息时也有一组
+fn export_frames(messages: Vec<ExportedMessage>) -> Vec<Vec<ExportedMessage>> {
+    let mut frames = vec![Vec::new()];
+    let mut bytes = 0;
+    for message in messages {
+        // 逗号分隔, 多算 1 字节
+        let len = serde_json::to_string(&message).map_or(0, |s| s.len()) + 1;
+        if bytes > 0 && bytes + len > HISTORY_PAGE_MAX_BYTES {
+            frames.push(Vec::new());
+            bytes = 0;
+        }
+        bytes += len;
+        frames.last_mut().expect("frames is never empty").push(message);
+    }
+    frames
+}
+
+// /block <user>: 屏蔽某个用户的私聊
+async fn cmd_block(from: &str, target: String, state: &Arc<Mutex<ServerState>>) -> Option<Message> {
+    let mut st = state.lock().await;
+    st.blocked.entry(from.to_string()).or_default().insert(target.clone());
+    system_reply(fill(st.text().blocked, &[("name", &target)]))
+}
+
+// /unblock <user>: 取消屏蔽
+async fn cmd_unblock(from: &str, target: String, state: &Arc<Mutex<ServerState>>) -> Option<Message> {
+    let mut st = state.lock().await;
+    let text = st.text();
+    let removed = st.blocked.get_mut(from).is_some_and(|b| b.remove(&target));
+    system_reply(fill(if removed { text.unblocked } else { text.not_blocked }, &[("name", &target)]))
+}
+
+// /quiet-joins: 切换是否接收其他用户的上下线通知
+async fn cmd_quiet_joins(from: &str, state: &Arc<Mutex<ServerState>>) -> Option<Message> {
+    let mut st = state.lock().await;
+    let quiet = !st.quiet_joins.remove(from);
+    if quiet {
+        st.quiet_joins.insert(from.to_string());
+    }
+    system_reply(if quiet { st.text().quiet_joins_on } else { st.text().quiet_joins_off }.to_string())
+}
+
+// /afk [message]: 标记为暂时离开并通知其他人, 私聊自己的人各收到一次留言; 下次发送任何内容时取消, 见 back
+async fn cmd_afk(from: &str, message: Option<String>, state: &Arc<Mutex<ServerState>>) -> Option<Message> {
+    let (content, listeners) = {
+        let mut st = state.lock().await;
+        // 留言会出现在别人的屏幕上, 和群发一样清理、过滤
+        let message = match message.map(|message| st.clean(&message)) {
+            Some(None) => return error_reply(from, st.text().filtered),
+            Some(cleaned) => cleaned,
+            None => None,
+        };
+        let content = away_notice(from, message.as_deref(), st.text());
+        st.afk.insert(from.to_string(), Afk { message, replied: HashSet::new() });
+        (content, st.status_listeners(from))
+    };
+    let notice = Message::Servermsg(ServerMessage::System { content });
+    for tx in listeners {
+        let _ = tx.send(notice.clone()).await;
+    }
+    None
+}
+
+// 暂时离开的用户有了动静: 取消离开状态并通知其他人; 不在离开状态时什么也不做
+async fn back(name: &str, state: &Arc<Mutex<ServerState>>) {
+    let (content, listeners) = {
+        let mut st = state.lock().await;
+        if st.afk.remove(name).is_none() {
+            return;
+        }
+        (fill(st.text().back, &[("name", name)]), st.status_listeners(name))
+    };
+    let notice = Message::Servermsg(ServerMessage::System { content });
+    for tx in listeners {
+        let _ = tx.send(notice.clone()).await;
+    }
+}
+
+// "alice is away" 或带留言的 "alice is away: lunch"
+fn away_notice(name: &str, message: Option<&str>, text: &Catalog) -> String {
+    match message {
+        Some(message) => fill(text.away_with_message, &[("name", name), ("message", message)]),
+        None => fill(text.away, &[("name", name)]),
+    }
+}
+
+// /roll [NdM+K]: 掷骰子并把结果广播给所有人, 默认 1d6
+async fn cmd_roll(from: &str, dice: Option<Dice>, state: &Arc<Mutex<ServerState>>) -> Option<Message> {
+    let dice = dice.unwrap_or(Dice { count: 1, sides: 6, modifier: 0 });
+    let text = state.lock().await.text();
+    if !dice_in_range(&dice) {
+        return error_reply(from, &usage_text(text.usage_roll));
+    }
+
+    let rolls: Vec<u32> = {
+        let mut st = state.lock().await;
+        (0..dice.count).map(|_| st.rng.random_range(1..=dice.sides)).collect()
+    };
+    announce(fill(text.rolled, &[("name", from), ("result", &dice.describe(&rolls))]), state).await;
+    None
+}
+
+// 动作指令, 例如 /slap bob: 检查参数个数, 把清理、过滤后的参数填入模板后通知所有人; 和群发一样受反刷屏限制
+async fn cmd_action(from: &str, name: String, args: Vec<String>, state: &Arc<Mutex<ServerState>>) -> Option<Message> {
+    let refusal = {
+        let mut st = state.lock().await;
+        let text = st.text();
+        let command = format!("{}{}", st.command_prefix, name);
+        let Some(action) = st.action(&name) else {
+            return error_reply(from, &fill(text.unknown_command, &[("command", &command)]));
+        };
+        if args.len() != action.args || args.iter().any(String::is_empty) {
+            return error_reply(from, &fill(text.action_args, &[("command", &command), ("count", &action.args.to_string())]));
+        }
+        let Some(args) = args.iter().map(|arg| st.clean(arg)).collect::<Option<Vec<_>>>() else {
+            return error_reply(from, text.filtered);
+        };
+        let content = action.render(from, &args);
+        match st.check_spam(from, &content, Instant::now()) {
+            Ok(()) => {
+                st.send_to_everyone(Message::Servermsg(ServerMessage::System { content }));
+                return None;
+            }
+            Err(refusal) => refusal,
+        }
+    };
+    refuse_spam(from, refusal, state).await;
+    None
+}
+
+// /stats: 管理员查看每个客户端发送队列和群发通道的积压情况, 用于找出读得太慢的客户端
+async fn cmd_stats(from: &str, state: &Arc<Mutex<ServerState>>) -> Option<Message> {
+    let mut st = state.lock().await;
+    let text = st.text();
+    if !st.is_admin(from) {
+        let name = format!("{}{}", st.command_prefix, Command::Stats.keyword());
+        return error_reply(from, &fill(text.admin_only, &[("command", &name)]));
+    }
+    record_command(&mut st, from, &Command::Stats);
+    let queues = st.queue_depths().iter()
+        .map(|(name, queued, capacity)| format!("{} {}/{}", name, queued, capacity))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let (queued, capacity, missed) = st.broadcast_lag();
+    system_reply(format!("{}\n{}", fill(text.queue_depths, &[("queues", &queues)]), fill(text.broadcast_lag, &[
+        ("queued", &queued.to_string()), ("capacity", &capacity.to_string()), ("missed", &missed.to_string()),
+    ])))
+}
+
+// /dumpstate [json|pretty]: 管理员查看服务器状态快照, 不带参数时使用配置的格式
+async fn cmd_dumpstate(from: &str, format: Option<DumpFormat>, state: &Arc<Mutex<ServerState>>) -> Option<Message> {
+    let mut st = state.lock().await;
+    let text = st.text();
+    if !st.is_admin(from) {
+        let name = format!("{}{}", st.command_prefix, Command::DumpState(None).keyword());
+        return error_reply(from, &fill(text.admin_only, &[("command", &name)]));
+    }
+    record_command(&mut st, from, &Command::DumpState(format));
+    let format = format.unwrap_or(st.dump_format);
+    system_reply(st.snapshot().render(format))
+}
+
+// /import <path>: 管理员把服务器上的导出文件合并进历史; 读文件时不持有锁
+async fn cmd_import(from: &str, path: String, state: &Arc<Mutex<ServerState>>) -> Option<Message> {
+    {
+        let mut st = state.lock().await;
+        if !st.is_admin(from) {
+            let name = format!("{}{}", st.command_prefix, Command::Import(String::new()).keyword());
+            return error_reply(from, &fill(st.text().admin_only, &[("command", &name)]));
+        }
+        record_command(&mut st, from, &Command::Import(path.clone()));
+    }
+    let (dir, text) = {
+        let st = state.lock().await;
+        (st.import_dir.clone(), st.text())
+    };
+    let Some(dir) = dir else {
+        return error_reply(from, text.import_disabled);
+    };
+    let export = match read_import(&dir, &path, text).await {
+        Ok(export) => export,
+        Err(reason) => return error_reply(from, &reason),
+    };
+    match state.lock().await.import_history(&export) {
+        Ok(count) => system_reply(fill(text.imported, &[("count", &count.to_string()), ("path", &path)])),
+        Err(e) => error_reply(from, &fill(text.import_failed, &[("path", &path), ("error", &e.to_string())])),
+    }
+}
+
+/* 读取 /import 指定的文件, 相对路径从 dir 算起
+    解析符号链接和 .. 之后必须仍在 dir 之内, 并且是不超过 MAX_IMPORT_BYTES 的普通文件
+    文件不存在、不可读或在目录之外时给出同样的回复, 不透露目录之外有哪些文件
+*/
+async fn read_import(dir: &Path, path: &str, text: &Catalog) -> Result<HistoryExport, String> {
+    use tokio::io::AsyncReadExt;
+    let not_found = || fill(text.import_not_found, &[("path", path)]);
+    let too_large = || fill(text.import_too_large, &[("path", path), ("max", &human_size(MAX_IMPORT_BYTES))]);
+    let dir = tokio::fs::canonicalize(dir).await.map_err(|_| not_found())?;
+    let file = tokio::fs::canonicalize(dir.join(path)).await.map_err(|_| not_found())?;
+    if !file.starts_with(&dir) {
+        return Err(not_found());
+    }
+    let file = tokio::fs::File::open(&file).await.map_err(|_| not_found())?;
+    let metadata = file.metadata().await.map_err(|_| not_found())?;
+    if !metadata.is_file() {
+        return Err(not_found());
+    }
+    if metadata.len() > MAX_IMPORT_BYTES {
+        return Err(too_large());
+    }
+    // 文件可能在检查之后变大, 读取时同样限制长度
+    let mut json = String::new();
+    file.take(MAX_IMPORT_BYTES + 1).read_to_string(&mut json).await
+        .map_err(|e| fill(text.import_failed, &[("path", path), ("error", &e.to_string())]))?;
+    if json.len() as u64 > MAX_IMPORT_BYTES {
+        return Err(too_large());
+    }
+    serde_json::from_str(&json).map_err(|e| fill(text.import_failed, &[("path", path), ("error", &e.to_string())]))
+}
+
+// /whois <user>: 查询用户是否在线, 离线时给出最近上线时间
+async fn cmd_whois(target: String, state: &Arc<Mutex<ServerState>>) -> Option<Message> {
+    let st = state.lock().await;
+    let text = st.text();
+    let content = if st.clients.contains_key(&target) {
+        fill(text.online, &[("name", &target)])
+    } else if let Some(seen) = st.last_seen(&target) {
+        fill(text.offline, &[("name", &target), ("seen", &seen)])
+    } else {
+        fill(text.never_seen, &[("name", &target)])
+    };
+    system_reply(content)
+}
+
+// /seen <user>: 查询用户最近一次发言的时间; 在线时就是现在, 没发过言时给出最近上线时间
+async fn cmd_seen(target: String, state: &Arc<Mutex<ServerState>>) -> Option<Message> {
+    let st = state.lock().await;
+    let text = st.text();
+    let content = if st.clients.contains_key(&target) {
+        fill(text.seen_now, &[("name", &target)])
+    } else if let Some(spoke) = st.last_spoke.get(&target) {
+        fill(text.seen_spoke, &[("name", &target), ("ago", &ago(spoke.elapsed().unwrap_or_default(), text))])
+    } else if let Some(seen) = st.last_seen(&target) {
+        fill(text.seen_silent, &[("name", &target), ("seen", &seen)])
+    } else {
+        fill(text.never_seen, &[("name", &target)])
+    };
+    system_reply(content)
+}
+
+// /whoami: 服务器记录的请求者的用户名、所在房间和状态, 用于确认重连后的身份
+async fn cmd_whoami(from: &str, state: &Arc<Mutex<ServerState>>) -> Option<Message> {
+    let st = state.lock().await;
+    let text = st.text();
+    let status = match st.afk.get(from) {
+        Some(Afk { message: Some(message), .. }) => fill(text.status_away_with_message, &[("message", message)]),
+        Some(Afk { message: None, .. }) => text.status_away.to_string(),
+        None => text.status_available.to_string(),
+    };
+    system_reply(fill(text.whoami, &[("name", from), ("room", st.room_of(from)), ("status", &status)]))
+}
+
+// /color <color|none>: 选择名字颜色并告诉所有在线用户, 由客户端按颜色显示
+async fn cmd_color(from: &str, color: Option<NameColor>, state: &Arc<Mutex<ServerState>>) -> Option<Message> {
+    let mut st = state.lock().await;
+    let content = match color {
+        Some(color) => {
+            st.colors.insert(from.to_string(), color);
+            fill(st.text().color_set, &[("color", color.as_str())])
+        }
+        None => {
+            st.colors.remove(from);
+            st.text().color_cleared.to_string()
+        }
+    };
+    st.send_to_everyone(Message::Servermsg(ServerMessage::NameColor { name: from.to_string(), color }));
+    system_reply(content)
+}
+
+// 给所有在线客户端发送一条系统消息
+async fn announce(content: String, state: &Arc<Mutex<ServerState>>) {
+    state.lock().await.send_to_everyone(Message::Servermsg(ServerMessage::System { content }));
+}
+
+// /poll "<question>" <option> <option>...: 发起投票并通知所有人, 含空格的问题或选项用引号括起
+async fn cmd_poll(from: &str, question: String, options: Vec<String>, state: &Arc<Mutex<ServerState>>) -> Option<Message> {
+    let text = state.lock().await.text();
+    // 问题和选项会发给所有人, 和群发一样清理、过滤; 清理后为空的也算没写
+    let cleaned = {
+        let st = state.lock().await;
+        st.clean(&question).zip(options.iter().map(|o| st.clean(o)).collect::<Option<Vec<_>>>())
+    };
+    let Some((question, options)) = cleaned else {
+        return error_reply(from, text.filtered);
+    };
+    if !(MIN_POLL_OPTIONS..=MAX_POLL_OPTIONS).contains(&options.len()) || question.is_empty() || options.iter().any(String::is_empty) {
+        return error_reply(from, &usage_text(text.usage_poll));
+    }
+
+    let listing = options.iter().enumerate().map(|(i, o)| format!("[{}] {}", i + 1, o)).collect::<Vec<_>>().join("  ");
+    let id = {
+        let mut st = state.lock().await;
+        if st.polls.values().filter(|poll| poll.owner == from).count() >= MAX_OPEN_POLLS_PER_USER {
+            return error_reply(from, &fill(text.too_many_own_polls, &[("max", &MAX_OPEN_POLLS_PER_USER.to_string())]));
+        }
+        if st.polls.len() >= MAX_OPEN_POLLS {
+            return error_reply(from, &fill(text.too_many_polls, &[("max", &MAX_OPEN_POLLS.to_string())]));
+        }
+        let id = st.next_poll_id;
+        st.next_poll_id += 1;
+        st.polls.insert(id, Poll { owner: from.to_string(), question: question.clone(), options, votes: HashMap::new() });
+        id
+    };
+    let id = id.to_string();
+    announce(fill(text.poll_started, &[("name", from), ("id", &id), ("question", &question), ("options", &listing)]), state).await;
+    None
+}
+
+// /vote <poll_id> <option>: 投票, 选项可以是编号或选项文字, 再次投票会覆盖之前的选择
+async fn cmd_vote(from: &str, poll_id: u64, choice: String, state: &Arc<Mutex<ServerState>>) -> Option<Message> {
+    let mut st = state.lock().await;
+    let text = st.text();
+    let Some(poll) = st.polls.get_mut(&poll_id) else {
+        return error_reply(from, &fill(text.no_open_poll, &[("id", &poll_id.to_string())]));
+    };
+    let Some(index) = poll.option_index(&choice) else {
+        return error_reply(from, &fill(text.no_such_option, &[("question", &poll.question), ("choice", &choice)]));
+    };
+    poll.votes.insert(from.to_string(), index);
+    system_reply(fill(text.voted, &[("option", &poll.options[index]), ("question", &poll.question)]))
+}
+
+// /poll-close <poll_id>: 发起者结束投票, 结果通知所有人
+async fn cmd_poll_close(from: &str, id: u64, state: &Arc<Mutex<ServerState>>) -> Option<Message> {
+    let text = state.lock().await.text();
+    let poll = {
+        let mut st = state.lock().await;
+        match st.polls.get(&id) {
+            None => return error_reply(from, &fill(text.no_open_poll, &[("id", &id.to_string())])),
+            Some(poll) if poll.owner != from => return error_reply(from, text.poll_owner_only),
+            Some(_) => st.polls.remove(&id).unwrap(),
+        }
+    };
+    announce(poll.results(id, text), state).await;
+    None
+}
+
+// 把一段时间描述为 "just now"、"5m ago"、"2h ago"、"3d ago"
+fn ago(elapsed: Duration, text: &Catalog) -> String {
+    let secs = elapsed.as_secs();
+    let (template, n) = match secs {
+        0..60 => return text.just_now.to_string(),
+        60..3600 => (text.minutes_ago, secs / 60),
+        3600..86400 => (text.hours_ago, secs / 3600),
+        _ => (text.days_ago, secs / 86400),
+    };
+    fill(template, &[("n", &n.to_string())])
+}
+
+// 定期打印当前连接数(包括服务用户), 供运维观察容量; 启动时不打印, 第一条在一个间隔之后
+async fn heartbeat_logger(state: Arc<Mutex<ServerState>>, interval: Duration) {
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        ticker.tick().await;
+        let count = state.lock().await.clients.len();
+        println!("{} clients connected", count);
+    }
+}
+
+// 根据 ttl 计算消息的到期时间, ttl 超过 MAX_TTL 时按 MAX_TTL 算
+fn expiry(ttl_secs: Option<u64>) -> Option<Instant> {
+    ttl_secs.and_then(|secs| Instant::now().checked_add(Duration::from_secs(secs).min(MAX_TTL)))
+}
+
+// 定期清理过期消息并通知客户端删除, 同时清理太旧的历史和断线太久的会话
+pub async fn expiry_sweeper(state: Arc<Mutex<ServerState>>) {
+    let mut ticker = tokio::time::interval(EXPIRY_SWEEP_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let (expired, clients) = {
+            let mut st = state.lock().await;
+            for name in st.sweep_sessions(Instant::now()) {
+                println!("Session of {} expired", name);
+            }
+            let expired = st.sweep_expired(Instant::now());
+            st.trim_old_history(SystemTime::now());
+            st.trim_presence(SystemTime::now());
+            for &message_id in &expired.broadcast {
+                st.send_to_everyone(Message::Servermsg(ServerMessage::Deleted { message_id }));
+            }
+            (expired, st.clients.clone())
+        };
+        for (owner, message_id) in expired.private {
+            if let Some(tx) = clients.get(&owner) {
+                let _ = tx.send(Message::Servermsg(ServerMessage::Deleted { message_id })).await;
+            }
+        }
+    }
+}
+
+/* 取出历史记录的第 page 页(从 1 开始), 后面还有内容时附上翻页提示(带上 since); 页码超出范围返回 None
+    每页最多 HISTORY_PAGE_SIZE 行, 行较长时提前分页, 使一页的 History 帧不超过帧长度上限
+*/
+fn history_page(lines: &[String], page: usize, since: Option<u64>, text: &Catalog) -> Option<String> {
+    let lines: Vec<String> = lines.iter().map(|line| clip_line(line, HISTORY_LINE_MAX_BYTES)).collect();
+    let mut start = 0;
+    for _ in 1..page {
+        start = page_end(&lines, start);
+        if start >= lines.len() {
+            return None;
+        }
+    }
+    let end = page_end(&lines, start);
+    let mut content = lines[start..end].join("\n");
+    let more = lines.len() - end;
+    if more > 0 {
+        content.push('\n');
+        // 翻页提示带上同样的 --since, 下一页仍是同一范围
+        let next = match since {
+            Some(secs) => format!("--since {} {}", secs, page + 1),
+            None => (page + 1).to_string(),
+        };
+        content.push_str(&fill(text.more_history, &[("more", &more.to_string()), ("next", &next)]));
+    }
+    Some(content)
+}
+
+// 把若干行按与 history_page 相同的规则分页, 不带翻页提示, 用于一次补发全部内容
+fn split_pages(lines: &[String]) -> Vec<String> {
+    let lines: Vec<String> = lines.iter().map(|line| clip_line(line, HISTORY_LINE_MAX_BYTES)).collect();
+    let mut pages = Vec::new();
+    let mut start = 0;
+    while start < lines.len() {
+        let end = page_end(&lines, start);
+        pages.push(lines[start..end].join("\n"));
+        start = end;
+    }
+    pages
+}
+
+// 从 start 开始的一页在哪一行结束(不含): 行数或字节数先达到上限为止, 至少包含一行
+fn page_end(lines: &[String], start: usize) -> usize {
+    let mut bytes = 0;
+    let mut end = start;
+    while end < lines.len() && end - start < HISTORY_PAGE_SIZE {
+        // 行尾的换行转义后占 2 字节
+        let len = escaped_len(&lines[end]) + 2;
+        if end > start && bytes + len > HISTORY_PAGE_MAX_BYTES {
+            break;
+        }
+        bytes += len;
+        end += 1;
+    }
+    end
+}
+
+// 字符在 JSON 字符串中转义后占用的字节数
+fn escaped_char_len(c: char) -> usize {
+    match c {
+        '"' | '\\' | '\n' | '\r' | '\t' | '\u{8}' | '\u{c}' => 2,
+        c if (c as u32) < 0x20 => 6,
+        c => c.len_utf8(),
+    }
+}
+
+fn escaped_len(s: &str) -> usize {
+    s.chars().map(escaped_char_len).sum()
+}
+
+// 转义后超过 max 字节的行截断到字符边界并以省略号结尾
+fn clip_line(line: &str, max: usize) -> String {
+    if escaped_len(line) <= max {
+        return line.to_string();
+    }
+    let budget = max - '…'.len_utf8();
+    let mut used = 0;
+    let mut clipped = String::new();
+    for c in line.chars() {
+        used += escaped_char_len(c);
+        if used > budget {
+            break;
+        }
+        clipped.push(c);
+    }
+    clipped.push('…');
+    clipped
+}
+
+/* 注册, 以系统消息形式通知某位客户端上线, 再给新用户补发最近的广播
+    恢复会话时(resumed 为已确认的最大消息 id)改为补发这之后错过的广播和私聊, 按页拆成多个 History 帧
+*/
+async fn register(name: &String, resumed: Option<u64>, state: &Arc<Mutex<ServerState>>) {
+    let (listeners, text, content) = {
+        let st = state.lock().await;
+        st.push_user_count();
+        (st.presence_listeners(), st.text(), st.presence_format.joined(name, st.text()))
+    };
+    let reply_msg = Message::Servermsg(ServerMessage::System { content });
+    for tx in listeners {
+        let _ = tx.send(reply_msg.clone()).await;
+    }
+
+    // 欢迎语只发给新加入的用户, 恢复会话的重连不算加入
+    if resumed.is_none() {
+        let content = {
+            let st = state.lock().await;
+            st.greeting.as_ref().map(|greeting| greeting.render(name, st.clients.len(), &st.server_name))
+        };
+        if let Some(content) = content {
+            send_to(name, Message::Servermsg(ServerMessage::System { content }), state).await;
+        }
+    }
+
+    if let Some(acked) = resumed {
+        let (missed, tx) = {
+            let st = state.lock().await;
+            (st.missed_since(name, acked), st.clients.get(name).cloned())
+        };
+        if let Some(tx) = tx {
+            let notice = fill(text.session_resumed, &[("count", &missed.len().to_string())]);
+            let _ = tx.send(Message::Servermsg(ServerMessage::System { content: notice })).await;
+            for content in split_pages(&missed) {
+                let _ = tx.send(Message::Servermsg(ServerMessage::History { content, to: name.clone() })).await;
+            }
+        }
+        return;
+    }
+
+    // 和恢复会话一样按帧长度上限分页, 较长的广播不会让补发超出单帧上限
+    let (recent, tx) = {
+        let st = state.lock().await;
+        let history = st.room_history(st.room_of(name));
+        let skip = history.len().saturating_sub(st.join_history);
+        (history.skip(skip).map(StoredMessage::line).collect::<Vec<_>>(), st.clients.get(name).cloned())
+    };
+    if let Some(tx) = tx {
+        for content in split_pages(&recent) {
+            let _ = tx.send(Message::Servermsg(ServerMessage::History { content, to: name.clone() })).await;
+        }
+    }
+}
+// 交换加密公钥: 把新用户的公钥发给其他开启加密的用户, 再把他们的公钥发给新用户
+async fn exchange_keys(name: &String, key: String, state: &Arc<Mutex<ServerState>>) {
+    let (listeners, known, tx) = {
+        let st = state.lock().await;
+        let others = st.encryption_keys.iter().filter(|(other, _)| *other != name);
+        let mut listeners = Vec::new();
+        let mut known = Vec::new();
+        for (other, other_key) in others {
+            listeners.extend(st.clients.get(other).cloned());
+            known.push(ServerMessage::EncryptionKey { name: other.clone(), key: other_key.clone() });
+        }
+        (listeners, known, st.clients.get(name).cloned())
+    };
+    for other in listeners {
+        let _ = other.send(Message::Servermsg(ServerMessage::EncryptionKey { name: name.clone(), key: key.clone() })).await;
+    }
+    if let Some(tx) = tx {
+        for msg in known {
+            let _ = tx.send(Message::Servermsg(msg)).await;
+        }
+    }
+}
+
+// 把新用户之前选的名字颜色告诉所有人, 再把其他在线用户的颜色发给新用户
+async fn share_colors(name: &String, state: &Arc<Mutex<ServerState>>) {
+    let known: Vec<ServerMessage> = {
+        let st = state.lock().await;
+        if let Some(&color) = st.colors.get(name) {
+            st.send_to_everyone(Message::Servermsg(ServerMessage::NameColor { name: name.clone(), color: Some(color) }));
+        }
+        st.colors.iter()
+            .filter(|(other, _)| *other != name && st.clients.contains_key(*other))
+            .map(|(other, &color)| ServerMessage::NameColor { name: other.clone(), color: Some(color) })
+            .collect()
+    };
+    for msg in known {
+        send_to(name, Message::Servermsg(msg), state).await;
+    }
+}
+
+/* 可嵌入的服务器
+    let handle = Server::builder().bind("127.0.0.1:0").compression(false).run().await?;
+    ... handle.local_addr() ...
+    handle.shutdown().await;
+    run() 绑定端口后在后台接受连接并立即返回 ServerHandle, 之后可以随时通过句柄关闭服务器
+*/
+pub struct Server;
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder {
+            addr: "0.0.0.0:8080".to_string(), unix_socket: None, state: ServerState::default(), services: Vec::new(), heartbeat: Duration::ZERO,
+            broadcast_capacity: DEFAULT_BROADCAST_CAPACITY, imports: Vec::new(), max_connections: 0,
+        }
+    }
+}
+
+pub struct ServerBuilder {
+    addr: String,
+    unix_socket: Option<PathBuf>,
+    state: ServerState,
+    services: Vec<(String, Box<dyn Service>)>,
+    heartbeat: Duration,
+    broadcast_capacity: usize,
+    imports: Vec<HistoryExport>,
+    max_connections: usize,
+}
+
+impl ServerBuilder {
+    // 监听地址, 端口为 0 时由系统分配, 实际地址用 ServerHandle::local_addr 查询
+    pub fn bind(mut self, addr: impl Into<String>) -> Self {
+        self.addr = addr.into();
+        self
+    }
+
+    /* 在 TCP 之外再监听一个 Unix 本地套接字, 供同一台机器上的程序连接; 只支持 Unix 平台
+        文件已存在但没有服务器在监听时当作上次留下的删掉, 关闭服务器时删除; 谁能连接由文件权限决定
+        通过它连接的客户端按 127.0.0.1 计算每个 IP 的用户名上限, 不受 allow_ips / deny_ips 限制
+    */
+    pub fn unix_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.unix_socket = Some(path.into());
+        self
+    }
+
+    pub fn filter(mut self, filter: ContentFilter) -> Self {
+        self.state.filter = filter;
+        self
+    }
+
+    // 消息中控制字符和 ANSI 转义序列的处理方式, 默认删除
+    pub fn control_chars(mut self, policy: ControlChars) -> Self {
+        self.state.control_chars = policy;
+        self
+    }
+
+    pub fn max_names_per_ip(mut self, max: usize) -> Self {
+        self.state.max_names_per_ip = max;
+        self
+    }
+
+    pub fn compression(mut self, on: bool) -> Self {
+        self.state.compression = on;
+        self
+    }
+
+    // 新用户加入时补发最近多少条广播, 0 表示不补发
+    pub fn join_history(mut self, count: usize) -> Self {
+        self.state.join_history = count;
+        self
+    }
+
+    pub fn spam_policy(mut self, policy: SpamPolicy) -> Self {
+        self.state.spam_policy = policy;
+        self
+    }
+
+    pub fn ip_access(mut self, access: IpAccess) -> Self {
+        self.state.ip_access = access;
+        self
+    }
+
+    pub fn history_format(mut self, format: HistoryFormat) -> Self {
+        self.state.history_format = format;
+        self
+    }
+
+    // 上下线通知的模板, 见 PresenceFormat
+    pub fn presence_format(mut self, format: PresenceFormat) -> Self {
+        self.state.presence_format = format;
+        self
+    }
+
+    pub fn locale(mut self, locale: Locale) -> Self {
+        self.state.locale = locale;
+        self
+    }
+
+    pub fn server_name(mut self, name: impl Into<String>) -> Self {
+        self.state.server_name = name.into();
+        self
+    }
+
+    pub fn command_prefix(mut self, prefix: char) -> Self {
+        self.state.command_prefix = prefix;
+        self
+    }
+
+    // 可以使用管理指令的用户名和各自的签名公钥(hex), 管理员需要用这个密钥签名注册
+    pub fn admins(mut self, admins: impl IntoIterator<Item = (String, String)>) -> Self {
+        self.state.admins = admins.into_iter().collect();
+        self
+    }
+
+    // 普通用户不能注册的用户名, 替换默认的 DEFAULT_RESERVED_NAMES; 不区分大小写
+    pub fn reserved_names(mut self, names: impl IntoIterator<Item = String>) -> Self {
+        self.state.reserved_names = names.into_iter().map(|n| n.to_lowercase()).collect();
+        self
+    }
+
+    // /import 只能读取这个目录中的文件, 不设置时 /import 不可用
+    pub fn import_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.state.import_dir = Some(dir.into());
+        self
+    }
+
+    pub fn dump_format(mut self, format: DumpFormat) -> Self {
+        self.state.dump_format = format;
+        self
+    }
+
+    // 增加一个动作指令, 见 ActionCommand; 与已有的同名时替换, 包括内置的 /slap
+    pub fn action(mut self, name: impl Into<String>, action: ActionCommand) -> Self {
+        self.state.actions.insert(name.into(), action);
+        self
+    }
+
+    // 新用户加入时发给他的欢迎语, 见 Greeting
+    pub fn greeting(mut self, template: impl Into<String>) -> Self {
+        self.state.greeting = Some(Greeting { template: template.into() });
+        self
+    }
+
+    // 启动时导入 /export 导出的历史, 例如从旧服务器迁移; 在其他设置生效后按加入的顺序导入
+    pub fn import_history(mut self, export: HistoryExport) -> Self {
+        self.imports.push(export);
+        self
+    }
+
+    // 每个房间最多保留多少条广播
+    pub fn max_broadcast_history(mut self, count: usize) -> Self {
+        self.state.max_broadcast_history = count;
+        self
+    }
+
+    // 每个用户最多保留多少条私聊
+    pub fn max_private_history(mut self, count: usize) -> Self {
+        self.state.max_private_history = count;
+        self
+    }
+
+    // 全部历史合计最多占用的字节数, 0 表示不限制
+    pub fn max_history_bytes(mut self, bytes: usize) -> Self {
+        self.state.max_history_bytes = bytes;
+        self
+    }
+
+    // 历史保留多久, 更早的消息被清理任务删除; Duration::ZERO 表示不限制
+    pub fn max_history_age(mut self, age: Duration) -> Self {
+        self.state.max_history_age = age;
+        self
+    }
+
+    // 发送一帧最多等多久, 超时的客户端被断开; Duration::ZERO 表示一直等
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.state.write_timeout = timeout;
+        self
+    }
+
+    // 一帧开始到达后最多等多久收齐, 新连接最多等多久发来第一帧; Duration::ZERO 表示一直等
+    pub fn frame_timeout(mut self, timeout: Duration) -> Self {
+        self.state.frame_timeout = timeout;
+        self
+    }
+
+    // 断线后会话保留多久, 期间重连可以补收错过的消息
+    pub fn session_ttl(mut self, ttl: Duration) -> Self {
+        self.state.session_ttl = ttl;
+        self
+    }
+
+    // 每个会话记住最近多少个客户端消息 id, 重复的丢弃; 0 表示不去重
+    pub fn dedup_window(mut self, window: usize) -> Self {
+        self.state.dedup_window = window;
+        self
+    }
+
+    // 群发通道的容量, 接收者落后超过这么多条时会错过最旧的消息, 必须大于 0
+    pub fn broadcast_capacity(mut self, capacity: usize) -> Self {
+        self.broadcast_capacity = capacity;
+        self
+    }
+
+    /* 同时处理的连接最多多少个, 包括还没有完成注册的连接和健康检查; 0 表示不限制(默认)
+        名额用完时不再接受新连接, 它们留在系统的等待队列里, 直到有连接结束
+    */
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max;
+        self
+    }
+
+    // 每隔 interval 打印一次当前连接数, 0 表示不打印(默认)
+    pub fn heartbeat_log(mut self, interval: Duration) -> Self {
+        self.heartbeat = interval;
+        self
+    }
+
+    // 启动时注册一个服务用户, 见 Service
+    pub fn service(mut self, name: impl Into<String>, service: impl Service) -> Self {
+        self.services.push((name.into(), Box::new(service)));
+        self
+    }
+
+    // 只检查配置而不绑定端口, run 启动前也会调用
+    pub fn check(&self) -> Result<()> {
+        self.state.history_format.validate()?;
+        self.state.presence_format.validate()?;
+        if !cmdline::is_valid_prefix(self.state.command_prefix) {
+            anyhow::bail!("invalid command prefix {:?}", self.state.command_prefix);
+        }
+        if self.broadcast_capacity == 0 {
+            anyhow::bail!("broadcast capacity must be greater than 0");
+        }
+        if self.state.max_broadcast_history == 0 {
+            anyhow::bail!("max_broadcast_history must be greater than 0");
+        }
+        if self.state.max_private_history == 0 {
+            anyhow::bail!("max_private_history must be greater than 0");
+        }
+        for (name, action) in &self.state.actions {
+            action.validate(name, self.state.command_prefix)?;
+        }
+        if let Some(greeting) = &self.state.greeting {
+            greeting.validate()?;
+        }
+        for (name, key) in &self.state.admins {
+            if !signing::is_valid_public_key(key) {
+                anyhow::bail!("admin {:?} needs a valid signing public key", name);
+            }
+        }
+        for export in &self.imports {
+            export.validate()?;
+        }
+        if self.unix_socket.is_some() && !cfg!(unix) {
+            anyhow::bail!("unix sockets are not supported on this platform");
+        }
+        Ok(())
+    }
+
+    // 检查配置后绑定端口, 启动接受连接和清理过期消息的后台任务
+    pub async fn run(mut self) -> Result<ServerHandle> {
+        self.check()?;
+        for export in &self.imports {
+            self.state.import_history(export)?;
+        }
+        self.state.everyone = fanout::Sender::new(self.broadcast_capacity);
+        self.state.broadcast_capacity = self.broadcast_capacity;
+        let listener = TcpListener::bind(&self.addr).await?;
+        let local_addr = listener.local_addr()?;
+        #[cfg(unix)]
+        let local_listener = match &self.unix_socket {
+            Some(path) => Some(bind_unix(path)?),
+            None => None,
+        };
+        let state = Arc::new(Mutex::new(self.state));
+        for (name, service) in self.services {
+            // 服务用户的名字同样保留, 其他人不能用只差大小写的名字冒充
+            state.lock().await.reserved_names.insert(name.to_lowercase());
+            spawn_service(&name, service, &state).await?;
+        }
+        let (stop_tx, stop_rx) = watch::channel(false);
+
+        let sweeper = tokio::spawn(expiry_sweeper(state.clone()));
+        // TCP 和本地套接字共用连接名额
+        let limit = (self.max_connections > 0).then(|| Arc::new(Semaphore::new(self.max_connections)));
+        let mut acceptors = vec![tokio::spawn(accept_loop(listener, state.clone(), limit.clone(), stop_rx.clone()))];
+        #[cfg(unix)]
+        if let Some(local_listener) = local_listener {
+            acceptors.push(tokio::spawn(accept_loop(local_listener, state.clone(), limit, stop_rx)));
+        }
+        let heartbeat = (!self.heartbeat.is_zero()).then(|| tokio::spawn(heartbeat_logger(state.clone(), self.heartbeat)));
+        Ok(ServerHandle { local_addr, unix_socket: self.unix_socket, state, stop_tx, acceptors, sweeper, heartbeat })
+    }
+}
+
+// 运行中的服务器, 用于查询地址、访问状态和关闭服务器
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    unix_socket: Option<PathBuf>,
+    state: Arc<Mutex<ServerState>>,
+    stop_tx: watch::Sender<bool>,
+    acceptors: Vec<JoinHandle<()>>,
+    sweeper: JoinHandle<()>,
+    heartbeat: Option<JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    // 监听的 Unix 本地套接字路径, 没有配置时为 None
+    pub fn unix_socket(&self) -> Option<&Path> {
+        self.unix_socket.as_deref()
+    }
+
+    pub fn state(&self) -> Arc<Mutex<ServerState>> {
+        self.state.clone()
+    }
+
+    // 关闭服务器, 最多等待 SHUTDOWN_DRAIN_TIMEOUT 让积压的消息发完
+    pub async fn shutdown(self) -> Drain {
+        self.shutdown_timeout(SHUTDOWN_DRAIN_TIMEOUT).await
+    }
+
+    // 同 shutdown, 关闭通知中带上 reason, 例如重启时让客户端自动重连
+    pub async fn shutdown_with(self, reason: ShutdownReason) -> Drain {
+        self.close(reason, SHUTDOWN_DRAIN_TIMEOUT).await
+    }
+
+    pub async fn shutdown_timeout(self, timeout: Duration) -> Drain {
+        self.close(ShutdownReason::Shutdown, timeout).await
+    }
+
+    /* 关闭服务器:
+        停止接受新连接, 不再处理客户端发来的消息,
+        在每个客户端的发送队列末尾追加关闭通知, 然后移除所有发送通道,
+        写任务发完队列中剩余的消息后结束; 最多等待 timeout, 返回是否全部发完
+    */
+    async fn close(self, reason: ShutdownReason, timeout: Duration) -> Drain {
+        let _ = self.stop_tx.send(true);
+        for acceptor in self.acceptors {
+            let _ = acceptor.await;
+        }
+        if let Some(path) = &self.unix_socket {
+            let _ = std::fs::remove_file(path);
+        }
+        self.sweeper.abort();
+        if let Some(heartbeat) = self.heartbeat {
+            heartbeat.abort();
+        }
+
+        let (clients, writers) = {
+            let mut st = self.state.lock().await;
+            st.shutting_down = true;
+            // 取走 clients，使写任务在发完积压消息后自然终止
+            (std::mem::take(&mut st.clients), st.writers.clone())
+        };
+        let drain = async {
+            for (_name, tx) in clients {
+                let shutdown_msg = Message::Servermsg(ServerMessage::Exit { reason });
+                let _ = tx.send(shutdown_msg).await;
+            }
+            writers.closed().await;
+        };
+        match tokio::time::timeout(timeout, drain).await {
+            Ok(()) => Drain::Complete,
+            Err(_) => Drain::TimedOut,
+        }
+    }
+}
+
+// 关闭时积压消息的发送结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drain {
+    Complete,       // 所有写任务都发完了积压的消息
+    TimedOut,       // 超时, 部分客户端可能没有收到全部消息
+}
+
+/* 监听端: TCP, 或 Unix 上的本地套接字
+    REMOTE 为 false 的监听端只接受本机的连接, 不检查 IP 访问规则; 对端没有 IP 地址, 按 127.0.0.1 计算
+*/
+trait Listen: Send + 'static {
+    type Io: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+    const REMOTE: bool;
+    fn poll_accept(&self, cx: &mut Context<'_>) -> std::task::Poll<std::io::Result<(Self::Io, SocketAddr)>>;
+}
+
+impl Listen for TcpListener {
+    type Io = tokio::net::TcpStream;
+    const REMOTE: bool = true;
+    fn poll_accept(&self, cx: &mut Context<'_>) -> std::task::Poll<std::io::Result<(Self::Io, SocketAddr)>> {
+        TcpListener::poll_accept(self, cx)
+    }
+}
+
+#[cfg(unix)]
+impl Listen for tokio::net::UnixListener {
+    type Io = tokio::net::UnixStream;
+    const REMOTE: bool = false;
+    fn poll_accept(&self, cx: &mut Context<'_>) -> std::task::Poll<std::io::Result<(Self::Io, SocketAddr)>> {
+        tokio::net::UnixListener::poll_accept(self, cx).map_ok(|(socket, _)| (socket, SocketAddr::from(([127, 0, 0, 1], 0))))
+    }
+}
+
+/* 绑定本地套接字; 路径上已有套接字文件时先试着连一下, 连得上说明另一个服务器正在使用, 连不上的是上次留下的, 删掉重建
+    已有的不是套接字(普通文件、目录、符号链接等)时拒绝启动, 不删除, 以免配错路径删掉别的文件
+*/
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            anyhow::bail!("{} exists and is not a unix socket; refusing to replace it", path.display());
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            anyhow::bail!("unix socket {} is already in use", path.display());
+        }
+        std::fs::remove_file(path)?;
+    }
+    Ok(tokio::net::UnixListener::bind(path)?)
+}
+
+/* 接受新连接：
+    有 limit 时先取得一个名额再接受, 名额在处理任务结束时归还
+    如果是新连接，先检查 IP 是否允许连接, 不允许的直接断开; 本地套接字的连接不检查
+    否则用 tokio::spawn 为每个客户端开一个任务
+    如果收到关闭信号，则停止接受
+*/
+async fn accept_loop<L: Listen>(listener: L, state: Arc<Mutex<ServerState>>, limit: Option<Arc<Semaphore>>, mut stop_rx: watch::Receiver<bool>) {
+    loop {
+        let permit = match &limit {
+            Some(limit) => tokio::select! {
+                permit = limit.clone().acquire_owned() => Some(permit.expect("the semaphore is never closed")),
+                _ = stop_rx.changed() => break,
+            },
+            None => None,
+        };
+        tokio::select! {
+            accept_res = std::future::poll_fn(|cx| listener.poll_accept(cx)) => {
+                match accept_res {
+                    Ok((socket, addr)) => {
+                        if L::REMOTE && !state.lock().await.ip_access.permits(addr.ip()) {
+                            println!("Refused connection: {}", addr);
+                            drop(socket);
+                            continue;
+                        }
+                        println!("New connection: {}", addr);
+                        let state = state.clone();
+                        tokio::spawn(async move {
+                            let _permit = permit;
+                            if let Err(e) = handle_client(socket, addr, state).await {
+                                eprintln!("Client handle error: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => eprintln!("Accept error: {}", e),
+                }
+            }
+            _ = stop_rx.changed() => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(words: &[&str], policy: FilterPolicy) -> ContentFilter {
+        ContentFilter { words: words.iter().map(|w| w.to_string()).collect(), policy }
+    }
+
+    // 通过内存管道连上 handle_client 并完成注册, 返回客户端一端和连接任务
+    async fn connect(name: &str, state: &Arc<Mutex<ServerState>>) -> (Framed<tokio::io::DuplexStream, LengthCodec>, JoinHandle<Result<()>>) {
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        let conn = tokio::spawn(handle_client(server_io, "127.0.0.1:40000".parse().unwrap(), state.clone()));
+        let mut client = Framed::new(client_io, LengthCodec::new());
+        let register = ClientMessage::Register { name: name.into(), capabilities: vec![], public_key: None, encryption_key: None, session: None };
+        client.send(Message::Clientmsg(register)).await.unwrap();
+        assert!(matches!(client.next().await, Some(Ok(Message::Servermsg(ServerMessage::Welcome { .. })))));
+        (client, conn)
+    }
+
+    #[tokio::test]
+    async fn writer_task_ends_with_the_connection() {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let (client, conn) = connect("alice", &state).await;
+        assert_eq!(state.lock().await.writers.receiver_count(), 1);
+
+        drop(client);
+        tokio::time::timeout(Duration::from_secs(2), conn).await.unwrap().unwrap().unwrap();
+        assert_eq!(state.lock().await.writers.receiver_count(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn client_that_never_reads_is_disconnected_after_the_write_timeout() {
+        let state = Arc::new(Mutex::new(ServerState { write_timeout: Duration::from_secs(10), ..ServerState::default() }));
+        let (client_io, server_io) = tokio::io::duplex(4096);
+        let conn = tokio::spawn(handle_client(server_io, "127.0.0.1:40000".parse().unwrap(), state.clone()));
+        let mut client = Framed::new(client_io, LengthCodec::new());
+        let register = ClientMessage::Register { name: "alice".into(), capabilities: vec![], public_key: None, encryption_key: None, session: None };
+        client.send(Message::Clientmsg(register)).await.unwrap();
+        while !state.lock().await.clients.contains_key("alice") {
+            tokio::task::yield_now().await;
+        }
+
+        // 之后客户端一直不读, 管道写满后写任务卡在发送上
+        let tx = state.lock().await.clients["alice"].clone();
+        for i in 0..10 {
+            let msg = Message::Servermsg(ServerMessage::System { content: format!("{} {}", i, "x".repeat(1000)) });
+            tx.send(msg).await.unwrap();
+        }
+        drop(tx);
+        tokio::time::sleep(Duration::from_secs(9)).await;
+        assert!(!conn.is_finished());
+        assert!(state.lock().await.clients.contains_key("alice"));
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        conn.await.unwrap().unwrap();
+        assert!(!state.lock().await.clients.contains_key("alice"));
+        assert_eq!(state.lock().await.writers.receiver_count(), 0);
+        drop(client);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn client_stuck_in_the_middle_of_a_frame_is_disconnected() {
+        use tokio::io::AsyncWriteExt;
+        let state = Arc::new(Mutex::new(ServerState { frame_timeout: Duration::from_secs(20), ..ServerState::default() }));
+
+        // 没注册的连接: 第一帧只发了长度就停住
+        let (mut silent, server_io) = tokio::io::duplex(4096);
+        let conn = tokio::spawn(handle_client(server_io, "127.0.0.1:40001".parse().unwrap(), state.clone()));
+        silent.write_all(&100u32.to_be_bytes()).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(21)).await;
+        conn.await.unwrap().unwrap();
+
+        let (client_io, server_io) = tokio::io::duplex(4096);
+        let conn = tokio::spawn(handle_client(server_io, "127.0.0.1:40000".parse().unwrap(), state.clone()));
+        let mut client = Framed::new(client_io, LengthCodec::new());
+        let register = ClientMessage::Register { name: "alice".into(), capabilities: vec![], public_key: None, encryption_key: None, session: None };
+        client.send(Message::Clientmsg(register)).await.unwrap();
+        while !state.lock().await.clients.contains_key("alice") {
+            tokio::task::yield_now().await;
+        }
+
+        // 空闲的连接不受影响, 只有停在半帧上的才会被断开
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert!(state.lock().await.clients.contains_key("alice"));
+        client.get_mut().write_all(&100u32.to_be_bytes()).await.unwrap();
+        client.get_mut().write_all(b"{\"Clientmsg\"").await.unwrap();
+        tokio::time::sleep(Duration::from_secs(19)).await;
+        assert!(!conn.is_finished());
+        tokio::time::sleep(Duration::from_secs(7)).await;
+        conn.await.unwrap().unwrap();
+        assert!(!state.lock().await.clients.contains_key("alice"));
+    }
+
+    #[tokio::test]
+    async fn name_is_held_but_unreachable_until_the_writer_runs() {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let register = |name: &str| Message::Clientmsg(ClientMessage::Register { name: name.into(), capabilities: vec![], public_key: None, encryption_key: None, session: None });
+        // 管道只有 8 个字节, 客户端不读时 Welcome 发不出去, 注册停在中途
+        let (client_io, server_io) = tokio::io::duplex(8);
+        let conn = tokio::spawn(handle_client(server_io, "127.0.0.1:40000".parse().unwrap(), state.clone()));
+        let mut stalled = Framed::new(client_io, LengthCodec::new());
+        stalled.send(register("alice")).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while !state.lock().await.registering.contains("alice") {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+        assert!(!state.lock().await.clients.contains_key("alice"));
+
+        // 名字已被占住, 同名注册被拒绝
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(handle_client(server_io, "127.0.0.1:40001".parse().unwrap(), state.clone()));
+        let mut other = Framed::new(client_io, LengthCodec::new());
+        other.send(register("alice")).await.unwrap();
+        assert!(matches!(other.next().await, Some(Ok(Message::Servermsg(ServerMessage::RegisterRefused { reason: RefuseReason::NameTaken, content }))) if content == "Name is already taken"));
+
+        // 收到 Welcome 之前断开: 名字和 IP 名额都归还, 没有留下发送通道
+        drop(stalled);
+        let _ = tokio::time::timeout(Duration::from_secs(2), conn).await.unwrap().unwrap();
+        {
+            let st = state.lock().await;
+            assert!(st.registering.is_empty() && st.clients.is_empty() && st.names_by_ip.is_empty());
+        }
+        let (_alice, _conn) = connect("alice", &state).await;
+        assert!(state.lock().await.clients.contains_key("alice"));
+    }
+
+    #[tokio::test]
+    async fn stats_report_backed_up_queues_first() {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let (alice_tx, mut alice_rx) = mpsc::channel(10);
+        let (bob_tx, _bob_rx) = mpsc::channel(10);
+        state.lock().await.clients.insert("alice".into(), alice_tx);
+        state.lock().await.clients.insert("bob".into(), bob_tx.clone());
+        for _ in 0..3 {
+            bob_tx.send(Message::Servermsg(ServerMessage::Exit { reason: ShutdownReason::Shutdown })).await.unwrap();
+        }
+
+        // 只有管理员可以查看
+        command("alice", ClientMessage::Command { command: Command::Stats }, &state).await;
+        assert!(matches!(alice_rx.try_recv(), Ok(Message::Servermsg(ServerMessage::Error { .. }))));
+
+        {
+            let mut st = state.lock().await;
+            st.admins.insert("alice".into(), "key".into());
+            st.public_keys.insert("alice".into(), "key".into());
+        }
+        // carol 一直不读群发, 落后超过容量后跳过最旧的两条
+        let (_carol_tx, carol_rx) = mpsc::channel(10);
+        let mut carol = state.lock().await.inbox("carol", carol_rx);
+        for _ in 0..DEFAULT_BROADCAST_CAPACITY + 2 {
+            state.lock().await.send_to_everyone(Message::Servermsg(ServerMessage::UserCount { count: 2 }));
+        }
+        assert_eq!(state.lock().await.broadcast_lag(), (DEFAULT_BROADCAST_CAPACITY, DEFAULT_BROADCAST_CAPACITY, 0));
+        assert!(matches!(carol.recv().await, Some(Message::Servermsg(ServerMessage::System { .. }))));
+        command("alice", ClientMessage::Command { command: Command::Stats }, &state).await;
+        match alice_rx.try_recv() {
+            Ok(Message::Servermsg(ServerMessage::System { content })) => assert_eq!(content, format!(
+                "Send queues (queued/capacity): bob 3/10, alice 0/10\nBroadcasts: slowest client {0}/{0} behind, 2 skipped since start", DEFAULT_BROADCAST_CAPACITY,
+            )),
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn writer_task_is_cancelled_when_a_sender_lingers() {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let (client, conn) = connect("alice", &state).await;
+        // 模拟别处留下的通道副本, 写任务不会因为通道关闭而结束
+        let lingering = state.lock().await.clients["alice"].clone();
+
+        drop(client);
+        conn.await.unwrap().unwrap();
+        assert_eq!(state.lock().await.writers.receiver_count(), 0);
+        assert!(lingering.is_closed());
+    }
+
+    #[test]
+    fn history_pages_are_bounded_and_point_to_the_next_page() {
+        let lines: Vec<String> = (1..=45).map(|i| format!("line {}", i)).collect();
+        let first = history_page(&lines, 1, None, &catalog::EN).unwrap();
+        assert_eq!(first.lines().count(), HISTORY_PAGE_SIZE + 1);
+        assert!(first.starts_with("line 1\n"));
+        assert!(first.ends_with("25 more, use /history 2"), "{}", first);
+
+        let last = history_page(&lines, 3, None, &catalog::EN).unwrap();
+        assert_eq!(last, "line 41\nline 42\nline 43\nline 44\nline 45");
+        assert_eq!(history_page(&lines, 4, None, &catalog::EN), None);
+        assert_eq!(history_page(&[], 1, None, &catalog::EN), Some(String::new()));
+    }
+
+    #[tokio::test]
+    async fn history_indents_replies_by_thread_depth() {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let (alice_tx, mut alice_rx) = mpsc::channel(10);
+        state.lock().await.clients.insert("alice".into(), alice_tx);
+        // 1 <- 2 <- 3 <- 4 <- 5 逐层回复, 6 回复一个不存在的 id
+        let chain = [(None, "root"), (Some(1), "one"), (Some(2), "two"), (Some(3), "three"), (Some(4), "four"), (Some(99), "future")];
+        for (reply_to, content) in chain {
+            let msg = ClientMessage::Broadcast { content: content.into(), id: 0, ephemeral: false, ttl_secs: None, reply_to, attachments: Vec::new() };
+            broadcast("bob", msg, &state).await;
+        }
+
+        command("alice", ClientMessage::Command { command: Command::History { page: None, since: None } }, &state).await;
+        let Ok(Message::Servermsg(ServerMessage::History { content, .. })) = alice_rx.try_recv() else { panic!("alice should get the history") };
+        let lines: Vec<&str> = content.lines().filter(|l| l.contains("bob broadcast")).collect();
+        assert_eq!(lines, [
+            "bob broadcast: root",
+            "↳ bob broadcast: one",
+            "  ↳ bob broadcast: two",
+            "    ↳ bob broadcast: three",
+            "    ↳ bob broadcast: four",
+            "bob broadcast: future",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn history_since_returns_only_messages_from_that_time_on() {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let (alice_tx, mut alice_rx) = mpsc::channel(10);
+        let since: u64 = 1_700_000_000;
+        let at = |offset: i64| SystemTime::UNIX_EPOCH + Duration::from_secs(since.checked_add_signed(offset).unwrap());
+        {
+            let mut st = state.lock().await;
+            st.clients.insert("alice".into(), alice_tx);
+            for (id, offset) in [(1, -1), (2, 0), (3, 1)] {
+                let msg = StoredMessage { sent_at: at(offset), ..StoredMessage::new(id, format!("bob: broadcast {}", offset), None) };
+                st.record_broadcast(DEFAULT_ROOM, msg);
+            }
+            for (id, offset) in [(4, -60), (5, 60)] {
+                let msg = StoredMessage { sent_at: at(offset), ..StoredMessage::new(id, format!("bob -> alice: private {}", offset), None) };
+                st.record_private("alice", msg);
+            }
+        }
+
+        command("alice", ClientMessage::Command { command: Command::History { page: None, since: Some(since) } }, &state).await;
+        let Ok(Message::Servermsg(ServerMessage::History { content, .. })) = alice_rx.try_recv() else { panic!("alice should get the history") };
+        for kept in ["broadcast 0", "broadcast 1", "private 60"] {
+            assert!(content.contains(kept), "{}", content);
+        }
+        for dropped in ["broadcast -1", "private -60"] {
+            assert!(!content.contains(dropped), "{}", content);
+        }
+
+        // 不带 --since 时全部返回
+        command("alice", ClientMessage::Command { command: Command::History { page: None, since: None } }, &state).await;
+        let Ok(Message::Servermsg(ServerMessage::History { content, .. })) = alice_rx.try_recv() else { panic!("alice should get the history") };
+        assert!(content.contains("broadcast -1") && content.contains("private -60"), "{}", content);
+    }
+
+    #[test]
+    fn repeated_client_ids_are_dropped_within_the_window() {
+        let mut st = ServerState { dedup_window: 2, ..ServerState::default() };
+        let now = Instant::now();
+        let (token, _) = st.open_session("alice", None, now);
+        assert!(!st.is_duplicate("alice", 1));
+        assert!(st.is_duplicate("alice", 1));
+        // 没有编号的消息和别人的同一个 id 都不算重复
+        assert!(!st.is_duplicate("alice", 0));
+        assert!(!st.is_duplicate("alice", 0));
+        st.open_session("bob", None, now);
+        assert!(!st.is_duplicate("bob", 1));
+
+        // 重复出现的 id 算最近用过, 窗口满时先淘汰 2
+        assert!(!st.is_duplicate("alice", 2));
+        assert!(st.is_duplicate("alice", 1));
+        assert!(!st.is_duplicate("alice", 3));
+        assert!(!st.is_duplicate("alice", 2));
+        assert!(st.is_duplicate("alice", 3));
+
+        // 恢复的会话保留记录, 新会话从头记起
+        st.suspend_session("alice", now);
+        st.open_session("alice", Some(&token), now);
+        assert!(st.is_duplicate("alice", 3));
+        st.open_session("alice", None, now);
+        assert!(!st.is_duplicate("alice", 3));
+
+        st.dedup_window = 0;
+        assert!(!st.is_duplicate("alice", 3));
+    }
+
+    #[test]
+    fn sessions_resume_with_the_right_token_until_they_expire() {
+        let mut st = ServerState { session_ttl: Duration::from_secs(60), ..ServerState::default() };
+        let now = Instant::now();
+        let (token, resumed) = st.open_session("alice", None, now);
+        assert_eq!(resumed, None);
+        let post = |st: &mut ServerState| {
+            let id = st.next_message_id();
+            st.record_broadcast(DEFAULT_ROOM, StoredMessage::new(id, format!("bob: {}", id), None));
+        };
+        (0..3).for_each(|_| post(&mut st));
+        // 确认不会超过已分配的 id, 也不会后退
+        st.ack("alice", 99);
+        st.ack("alice", 2);
+        assert_eq!(st.sessions["alice"].acked, 3);
+        (0..2).for_each(|_| post(&mut st));
+
+        st.suspend_session("alice", now);
+        assert_eq!(st.open_session("alice", Some(&token), now + Duration::from_secs(30)), (token.clone(), Some(3)));
+        assert_eq!(st.missed_since("alice", 3), vec!["bob: 4", "bob: 5"]);
+
+        // token 不对时换成新会话, 旧 token 作废
+        st.suspend_session("alice", now);
+        let (fresh, resumed) = st.open_session("alice", Some("guess"), now);
+        assert_eq!(resumed, None);
+        assert_ne!(fresh, token);
+        assert_eq!(st.open_session("alice", Some(&token), now).1, None);
+
+        let (token, _) = st.open_session("alice", None, now);
+        st.suspend_session("alice", now);
+        assert!(st.sweep_sessions(now + Duration::from_secs(59)).is_empty());
+        assert_eq!(st.sweep_sessions(now + Duration::from_secs(60)), vec!["alice".to_string()]);
+        assert_eq!(st.open_session("alice", Some(&token), now + Duration::from_secs(60)).1, None);
+    }
+
+    #[test]
+    fn export_is_split_into_frames_within_the_limit() {
+        let message = |id: u64| ExportedMessage {
+            message_id: id, sent_at: 0, kind: ExportKind::Broadcast, from: "bob".into(), to: Vec::new(),
+            content: "\"b\"".repeat(10_000), reply_to: None, attachments: Vec::new(),
+        };
+        let frames = export_frames((0..200).map(message).collect());
+        assert!(frames.len() > 1);
+        for messages in &frames {
+            let msg = Message::Servermsg(ServerMessage::Export { messages: messages.clone(), done: false, blocked: Vec::new() });
+            let mut frame = bytes::BytesMut::new();
+            tokio_util::codec::Encoder::encode(&mut LengthCodec::new(), msg, &mut frame).unwrap();
+            assert!(frame.len() - 4 <= MAX_FRAME_LEN, "frame is {} bytes", frame.len());
+        }
+        let ids: Vec<u64> = frames.into_iter().flatten().map(|m| m.message_id).collect();
+        assert_eq!(ids, (0..200).collect::<Vec<_>>());
+        assert_eq!(export_frames(Vec::new()), vec![Vec::new()]);
+    }
+
+    #[test]
+    fn history_page_hint_keeps_the_since_filter() {
+        let lines: Vec<String> = (1..=45).map(|i| format!("line {}", i)).collect();
+        let first = history_page(&lines, 1, Some(1_700_000_000), &catalog::EN).unwrap();
+        assert!(first.ends_with("use /history --since 1700000000 2"), "{}", first);
+    }
+
+    #[tokio::test]
+    async fn full_history_is_split_into_frames_within_the_limit() {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let (alice_tx, mut alice_rx) = mpsc::channel(10);
+        {
+            let mut st = state.lock().await;
+            st.clients.insert("alice".into(), alice_tx);
+            // 广播和私聊历史都写满, 每行约 40KB, 最后一条广播长到需要截断
+            for i in 0..DEFAULT_MAX_HISTORY_SIZE as u64 {
+                st.record_broadcast(DEFAULT_ROOM, StoredMessage::new(i, format!("bob: {}", "\"b\"".repeat(10_000)), None));
+                st.record_private("alice", StoredMessage::new(1000 + i, format!("carol -> alice: {}", "文".repeat(14_000)), None));
+            }
+            let last = st.broadcast_history.get_mut(DEFAULT_ROOM).unwrap().back_mut().unwrap();
+            last.text = "x".repeat(MAX_FRAME_LEN);
+        }
+
+        let (mut lines, mut clipped) = (0, false);
+        for page in 1.. {
+            command("alice", ClientMessage::Command { command: Command::History { page: Some(page), since: None } }, &state).await;
+            let msg = alice_rx.try_recv().unwrap();
+            let mut frame = bytes::BytesMut::new();
+            tokio_util::codec::Encoder::encode(&mut LengthCodec::new(), msg.clone(), &mut frame).unwrap();
+            assert!(frame.len() - 4 <= MAX_FRAME_LEN, "page {} is {} bytes", page, frame.len());
+            let Message::Servermsg(ServerMessage::History { content, .. }) = msg else {
+                panic!("unexpected message: {:?}", msg);
+            };
+            let more = content.ends_with(&format!("use /history {}", page + 1));
+            lines += content.lines().count() - usize::from(more);
+            clipped |= content.contains(&format!("{}…", "x".repeat(100)));
+            if !more {
+                break;
+            }
+        }
+        // 两个标题行加上全部历史, 每行恰好出现一次
+        assert_eq!(lines, 2 + 2 * DEFAULT_MAX_HISTORY_SIZE);
+        assert!(clipped);
+    }
+
+    #[tokio::test]
+    async fn join_replay_is_split_into_frames_within_the_limit() {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let (alice_tx, mut alice_rx) = mpsc::channel(64);
+        {
+            let mut st = state.lock().await;
+            st.clients.insert("alice".into(), alice_tx);
+            // 补发的每条广播约 200KB, 合起来远超单帧上限
+            for i in 0..st.join_history as u64 {
+                st.record_broadcast(DEFAULT_ROOM, StoredMessage::new(i, format!("bob: {}", "b".repeat(200_000)), None));
+            }
+        }
+
+        register(&"alice".to_string(), None, &state).await;
+        let (mut frames, mut lines) = (0, 0);
+        while let Ok(msg) = alice_rx.try_recv() {
+            let Message::Servermsg(ServerMessage::History { content, .. }) = &msg else { continue };
+            let mut frame = bytes::BytesMut::new();
+            tokio_util::codec::Encoder::encode(&mut LengthCodec::new(), msg.clone(), &mut frame).unwrap();
+            assert!(frame.len() - 4 <= MAX_FRAME_LEN, "replay frame is {} bytes", frame.len());
+            frames += 1;
+            lines += content.lines().count();
+        }
+        assert!(frames > 1);
+        assert_eq!(lines, DEFAULT_JOIN_HISTORY);
+    }
+
+    #[tokio::test]
+    async fn messages_that_would_outgrow_a_frame_when_relayed_are_refused() {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let mut alice = member("alice", &state).await;
+        let mut bob = member("bob", &state).await;
+        // 客户端发来的帧刚好在上限之内, 加上发送者和消息 id 后就超出了
+        let content = "a".repeat(MAX_FRAME_LEN - 120);
+        let sent = Message::Clientmsg(ClientMessage::Broadcast { content: content.clone(), id: 1, ephemeral: false, ttl_secs: None, reply_to: None, attachments: Vec::new() });
+        assert!(codec::payload_len(&sent) <= MAX_FRAME_LEN);
+        let refused = Some(Message::Servermsg(ServerMessage::Error { content: "Message not sent: it would exceed the 1.0 MiB frame limit when delivered".into(), to: "alice".into() }));
+
+        let Message::Clientmsg(msg) = sent else { unreachable!() };
+        broadcast("alice", msg, &state).await;
+        assert_eq!(pending(&mut alice), refused);
+        assert_eq!(pending(&mut bob), None);
+
+        let private = ClientMessage::Private { to: vec!["bob".into()], content, ephemeral: false, ttl_secs: None, signature: None, encrypted: false, reply_to: None };
+        dispatch("alice", private, &state).await;
+        assert_eq!(pending(&mut alice), refused);
+        assert_eq!(pending(&mut bob), None);
+        assert!(state.lock().await.private_history.get("bob").is_none_or(|h| h.is_empty()));
+    }
+
+    #[test]
+    fn control_chars_are_stripped_or_escaped() {
+        let crafted = "\u{1b}[2J\u{1b}[31mred\u{1b}[0m\r\nline\rtitle\u{1b}]0;pwned\u{7}\u{8}!\tend\u{9b}";
+        assert_eq!(ControlChars::Strip.sanitize(crafted), "red\nline\ntitle!\tend");
+        assert_eq!(
+            ControlChars::Escape.sanitize(crafted),
+            "\\x1b[2J\\x1b[31mred\\x1b[0m\nline\ntitle\\x1b]0;pwned\\x07\\x08!\tend\\x9b",
+        );
+        // OSC 也可以用 ESC \ 结束, 之后的内容保留
+        assert_eq!(ControlChars::Strip.sanitize("a\u{1b}]8;;http://x\u{1b}\\b"), "ab");
+        assert_eq!(ControlChars::Strip.sanitize("普通文字 🙂"), "普通文字 🙂");
+    }
+
+    #[test]
+    fn empty_filter_passes_content_through() {
+        assert_eq!(ContentFilter::default().apply("hello"), Some("hello".to_string()));
+    }
+
+    #[test]
+    fn mask_replaces_every_match_ignoring_case() {
+        let f = filter(&["darn"], FilterPolicy::Mask);
+        assert_eq!(f.apply("Darn it, darn"), Some("*** it, ***".to_string()));
+    }
+
+    #[test]
+    fn reject_refuses_matching_content() {
+        let f = filter(&["darn"], FilterPolicy::Reject);
+        assert_eq!(f.apply("oh DARN"), None);
+        assert_eq!(f.apply("all good"), Some("all good".to_string()));
+    }
+
+    #[test]
+    fn mask_keeps_non_ascii_text_intact() {
+        let f = filter(&["bad"], FilterPolicy::Mask);
+        assert_eq!(f.apply("你好 bad 世界"), Some("你好 *** 世界".to_string()));
+    }
+
+    #[test]
+    fn broadcast_history_is_kept_per_room() {
+        let mut st = ServerState::default();
+        for id in 0..DEFAULT_MAX_HISTORY_SIZE as u64 + 5 {
+            st.record_broadcast("a", StoredMessage::new(id, format!("in a {}", id), None));
+        }
+        st.record_broadcast("b", StoredMessage::new(1000, "in b".into(), None));
+
+        // 上限按房间计算, 一个房间写满不会挤掉另一个房间的历史
+        assert_eq!(st.room_history("a").len(), DEFAULT_MAX_HISTORY_SIZE);
+        assert_eq!(st.room_history("a").next().unwrap().id, 5);
+        let b: Vec<&str> = st.room_history("b").map(|m| m.text.as_str()).collect();
+        assert_eq!(b, vec!["in b"]);
+        assert!(st.room_history("a").all(|m| m.text.starts_with("in a")));
+        assert_eq!(st.room_history("c").len(), 0);
+    }
+
+    #[test]
+    fn broadcast_and_private_history_have_separate_caps() {
+        let mut st = ServerState { max_broadcast_history: 2, max_private_history: 3, ..ServerState::default() };
+        for id in 1..=5 {
+            st.record_broadcast("a", StoredMessage::new(id, format!("b{}", id), None));
+            st.record_private("alice", StoredMessage::new(id + 10, format!("p{}", id), None));
+        }
+
+        assert_eq!(st.room_history("a").map(|m| m.id).collect::<Vec<_>>(), vec![4, 5]);
+        assert_eq!(st.private_history["alice"].iter().map(|m| m.id).collect::<Vec<_>>(), vec![13, 14, 15]);
+        // 丢弃的消息同样从字节计数中扣除
+        let kept: usize = st.room_history("a").chain(st.private_history["alice"].iter()).map(|m| m.size()).sum();
+        assert_eq!(st.history_bytes, kept);
+    }
+
+    #[test]
+    fn zero_history_caps_are_rejected() {
+        assert!(Server::builder().max_broadcast_history(0).check().is_err());
+        assert!(Server::builder().max_private_history(0).check().is_err());
+        assert!(Server::builder().max_broadcast_history(1).max_private_history(1).check().is_ok());
+    }
+
+    #[test]
+    fn history_byte_cap_evicts_the_oldest_messages_across_rooms_and_users() {
+        let entry = |id: u64| StoredMessage::new(id, format!("{:>100}", id), None);
+        let size = entry(0).size();
+        let mut st = ServerState { max_history_bytes: 3 * size, ..ServerState::default() };
+        st.record_broadcast("a", entry(1));
+        st.record_private("alice", entry(2));
+        st.record_broadcast("b", entry(3));
+        assert_eq!(st.history_bytes(), 3 * size);
+
+        // 超出上限时丢弃的是全局最旧的一条, 不论它在哪个房间或谁的私聊里
+        st.record_private("bob", entry(4));
+        assert_eq!(st.room_history("a").len(), 0);
+        assert_eq!(st.history_bytes(), 3 * size);
+        st.record_broadcast("b", entry(5));
+        assert!(st.private_history["alice"].is_empty());
+        let b: Vec<u64> = st.room_history("b").map(|m| m.id).collect();
+        assert_eq!(b, vec![3, 5]);
+
+        // 过期清理同样会减少计数
+        st.record_private("bob", StoredMessage::new(6, "x".into(), Some(Instant::now())));
+        st.sweep_expired(Instant::now());
+        assert_eq!(st.history_bytes(), 2 * size);
+    }
+
+    #[test]
+    fn history_older_than_the_max_age_is_trimmed() {
+        let now = SystemTime::now();
+        let at = |id: u64, age_secs: u64| StoredMessage { sent_at: now - Duration::from_secs(age_secs), ..StoredMessage::new(id, id.to_string(), None) };
+        let mut st = ServerState::default();
+        st.record_broadcast("a", at(1, 7200));
+        st.record_private("alice", at(2, 7200));
+        st.record_broadcast("a", at(3, 60));
+        st.record_private("alice", at(4, 60));
+        let size = at(0, 0).size();
+
+        // 默认不按时间清理
+        assert_eq!(st.trim_old_history(now), 0);
+        st.max_history_age = Duration::from_secs(3600);
+        assert_eq!(st.trim_old_history(now), 2);
+        assert_eq!(st.room_history("a").map(|m| m.id).collect::<Vec<_>>(), vec![3]);
+        assert_eq!(st.private_history["alice"].iter().map(|m| m.id).collect::<Vec<_>>(), vec![4]);
+        assert_eq!(st.history_bytes(), 2 * size);
+    }
+
+    #[test]
+    fn old_imported_history_behind_recent_messages_is_trimmed() {
+        let now = SystemTime::now();
+        let mut st = ServerState { max_history_age: Duration::from_secs(3600), ..ServerState::default() };
+        let id = st.next_message_id();
+        st.record_broadcast(DEFAULT_ROOM, StoredMessage::new(id, "recent".into(), None));
+        let recent = st.history_bytes();
+        // 导入的消息 id 更大, 排在刚才那条后面, 但发送时间早得多
+        let old = HistoryExport {
+            user: "carol".into(), server: "old".into(), exported_at: 1_700_000_100,
+            messages: vec![ExportedMessage {
+                message_id: id + 1, sent_at: 1_700_000_000, kind: ExportKind::Broadcast, from: "carol".into(),
+                to: Vec::new(), content: "old".into(), reply_to: None, attachments: Vec::new(),
+            }],
+            blocked: Vec::new(),
+        };
+        assert_eq!(st.import_history(&old).unwrap(), 1);
+        assert_eq!(st.room_history(DEFAULT_ROOM).map(|m| m.id).collect::<Vec<_>>(), vec![id, id + 1]);
+
+        assert_eq!(st.trim_old_history(now), 1);
+        assert_eq!(st.room_history(DEFAULT_ROOM).map(|m| m.id).collect::<Vec<_>>(), vec![id]);
+        assert_eq!(st.history_bytes(), recent);
+    }
+
+    #[test]
+    fn imported_history_keeps_ids_times_and_authors() {
+        let message = |message_id, kind, from: &str, to: &[&str], content: &str| ExportedMessage {
+            message_id, sent_at: 1_700_000_000 + message_id, kind, from: from.into(),
+            to: to.iter().map(|t| t.to_string()).collect(), content: content.into(), reply_to: None, attachments: Vec::new(),
+        };
+        let alice = HistoryExport {
+            user: "alice".into(), server: "old".into(), exported_at: 1_700_000_100,
+            messages: vec![
+                message(5, ExportKind::Private, "alice", &["bob"], "for bob"),
+                message(2, ExportKind::Broadcast, "carol", &[], "hello"),
+                message(9, ExportKind::Command, "alice", &[], "/history"),
+            ],
+            blocked: vec!["mallory".into(), "alice".into()],
+        };
+        // bob 的导出和 alice 的有重叠, 重复的消息只保留一份
+        let bob = HistoryExport {
+            user: "bob".into(), server: "old".into(), exported_at: 1_700_000_100,
+            messages: vec![message(2, ExportKind::Broadcast, "carol", &[], "hello"), message(5, ExportKind::Private, "alice", &["bob"], "for bob")],
+            blocked: Vec::new(),
+        };
+        let mut st = ServerState { max_private_history: 2, ..ServerState::default() };
+        let id = st.next_message_id();
+        st.record_private("alice", StoredMessage::new(id, "already here".into(), None));
+
+        assert_eq!(st.import_history(&alice).unwrap(), 3);
+        assert_eq!(st.import_history(&bob).unwrap(), 0);
+        let lobby: Vec<_> = st.room_history(DEFAULT_ROOM).map(|m| (m.id, m.text.as_str())).collect();
+        assert_eq!(lobby, vec![(2, "carol broadcast: hello")]);
+        assert_eq!(st.room_history(DEFAULT_ROOM).next().unwrap().sent_at, SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_002));
+        // alice 的私聊历史按 id 排列, 超出上限时丢掉最旧的(她自己原有的 #1)
+        let texts = |name: &str| st.private_history[name].iter().map(|m| m.text.clone()).collect::<Vec<_>>();
+        assert_eq!(texts("alice"), ["You → bob: for bob", "You issued: /history"]);
+        assert_eq!(texts("bob"), ["alice → You: for bob"]);
+        assert_eq!(st.next_message_id(), 10);
+        // 屏蔽列表随导出恢复, 屏蔽自己的条目被忽略
+        assert!(st.has_blocked("alice", "mallory") && !st.has_blocked("alice", "alice"));
+        assert!(!st.blocked.contains_key("bob"));
+
+        let no_name = HistoryExport { blocked: vec![String::new()], ..bob.clone() };
+        assert_eq!(st.import_history(&no_name).unwrap_err().to_string(), "the blocklist contains an empty name");
+
+        let bad = HistoryExport { messages: vec![message(3, ExportKind::Private, "alice", &[], "to nobody")], ..bob };
+        assert_eq!(st.import_history(&bad).unwrap_err().to_string(), "message #3: a private message needs recipients");
+        assert!(Server::builder().import_history(bad.clone()).check().is_err());
+
+        // 会让 id 或时间溢出的消息整份拒绝
+        let last = ExportedMessage { message_id: u64::MAX, ..message(12, ExportKind::Broadcast, "carol", &[], "last") };
+        let huge_id = HistoryExport { messages: vec![last], ..bad.clone() };
+        assert_eq!(st.import_history(&huge_id).unwrap_err().to_string(), format!("message #{}: the message id is too large", u64::MAX));
+        let far_future = ExportedMessage { sent_at: u64::MAX, ..message(11, ExportKind::Broadcast, "carol", &[], "later") };
+        let huge_time = HistoryExport { messages: vec![far_future], ..bad };
+        assert_eq!(st.import_history(&huge_time).unwrap_err().to_string(), "message #11: the time is out of range");
+        assert_eq!(st.next_message_id(), 11);
+    }
+
+    #[test]
+    fn sweep_removes_only_expired_messages() {
+        let now = Instant::now();
+        let past = Some(now - Duration::from_secs(1));
+        let future = Some(now + Duration::from_secs(60));
+        let mut st = ServerState::default();
+        st.record_broadcast(DEFAULT_ROOM, StoredMessage::new(1, "old".into(), past));
+        st.record_broadcast(DEFAULT_ROOM, StoredMessage::new(2, "fresh".into(), future));
+        st.record_broadcast(DEFAULT_ROOM, StoredMessage::new(3, "forever".into(), None));
+        st.record_private("alice", StoredMessage::new(4, "You → bob: secret".into(), past));
+        st.record_private("bob", StoredMessage::new(4, "alice → You: secret".into(), past));
+        st.record_private("bob", StoredMessage::new(5, "carol → You: hi".into(), None));
+
+        let mut expired = st.sweep_expired(now);
+        expired.private.sort();
+        assert_eq!(expired.broadcast, vec![1]);
+        assert_eq!(expired.private, vec![("alice".to_string(), 4), ("bob".to_string(), 4)]);
+        let ids: Vec<u64> = st.room_history(DEFAULT_ROOM).map(|m| m.id).collect();
+        assert_eq!(ids, vec![2, 3]);
+        assert!(st.private_history["alice"].is_empty());
+        assert_eq!(st.private_history["bob"].len(), 1);
+
+        // 再次清理不会重复报告
+        let again = st.sweep_expired(now);
+        assert!(again.broadcast.is_empty() && again.private.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_full_recipient_queue_does_not_hold_the_state_lock() {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let mut alice = member("alice", &state).await;
+        let (bob_tx, mut bob_rx) = mpsc::channel(1);
+        bob_tx.try_send(Message::Servermsg(ServerMessage::System { content: "backlog".into() })).unwrap();
+        state.lock().await.clients.insert("bob".into(), bob_tx);
+
+        let private = ClientMessage::Private { to: vec!["bob".into()], content: "hi".into(), ephemeral: false, ttl_secs: None, signature: None, encrypted: false, reply_to: None };
+        let sending = tokio::spawn({
+            let state = state.clone();
+            async move { dispatch("alice", private, &state).await }
+        });
+        // 私聊在等 bob 的队列腾出位置, 其他连接仍然拿得到锁
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!sending.is_finished());
+        assert!(tokio::time::timeout(Duration::from_millis(100), state.lock()).await.is_ok());
+
+        assert!(matches!(bob_rx.recv().await, Some(Message::Servermsg(ServerMessage::System { .. }))));
+        assert!(matches!(bob_rx.recv().await, Some(Message::Servermsg(ServerMessage::PrivateMessage { .. }))));
+        sending.await.unwrap();
+        assert!(matches!(pending(&mut alice), Some(Message::Servermsg(ServerMessage::Throttle { .. }))));
+    }
+
+    #[tokio::test]
+    async fn read_receipt_is_routed_once_to_the_sender() {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let (alice_tx, mut alice_rx) = mpsc::channel(10);
+        let (bob_tx, mut bob_rx) = mpsc::channel(10);
+        state.lock().await.clients.insert("alice".into(), alice_tx);
+        state.lock().await.clients.insert("bob".into(), bob_tx);
+
+        let private = ClientMessage::Private { to: vec!["bob".into()], content: "hi".into(), ephemeral: false, ttl_secs: None, signature: None, encrypted: false, reply_to: None };
+        dispatch("alice", private, &state).await;
+        let Some(Message::Servermsg(ServerMessage::PrivateMessage { message_id, .. })) = bob_rx.recv().await else {
+            panic!("bob should receive the private message");
+        };
+
+        // 非接收者的回执被忽略
+        read_receipt("mallory", ClientMessage::ReadReceipt { message_id }, &state).await;
+        assert!(alice_rx.try_recv().is_err());
+
+        read_receipt("bob", ClientMessage::ReadReceipt { message_id }, &state).await;
+        match alice_rx.try_recv() {
+            Ok(Message::Servermsg(ServerMessage::Read { message_id: id, by })) => {
+                assert_eq!(id, message_id);
+                assert_eq!(by, "bob");
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+
+        // 重复的回执不会再次转发
+        read_receipt("bob", ClientMessage::ReadReceipt { message_id }, &state).await;
+        assert!(alice_rx.try_recv().is_err());
+        assert!(state.lock().await.pending_receipts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn blocked_sender_cannot_reach_recipient() {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let (alice_tx, mut alice_rx) = mpsc::channel(10);
+        let (bob_tx, mut bob_rx) = mpsc::channel(10);
+        state.lock().await.clients.insert("alice".into(), alice_tx);
+        state.lock().await.clients.insert("bob".into(), bob_tx);
+
+        command("bob", ClientMessage::Command { command: Command::Block("alice".into()) }, &state).await;
+        assert!(matches!(bob_rx.recv().await, Some(Message::Servermsg(ServerMessage::System { .. }))));
+
+        let private = ClientMessage::Private { to: vec!["bob".into()], content: "hi".into(), ephemeral: false, ttl_secs: None, signature: None, encrypted: false, reply_to: None };
+        dispatch("alice", private.clone(), &state).await;
+        assert!(bob_rx.try_recv().is_err());
+        assert!(!state.lock().await.private_history.contains_key("bob"));
+        // 发送者只收到与对方不在线相同的错误
+        assert!(matches!(alice_rx.try_recv(), Ok(Message::Servermsg(ServerMessage::Error { .. }))));
+
+        command("bob", ClientMessage::Command { command: Command::Unblock("alice".into()) }, &state).await;
+        let _ = bob_rx.recv().await;
+        dispatch("alice", private, &state).await;
+        assert!(matches!(bob_rx.try_recv(), Ok(Message::Servermsg(ServerMessage::PrivateMessage { .. }))));
+    }
+
+    #[test]
+    fn dice_notation_is_parsed_and_bounded() {
+        assert_eq!(Dice::parse("2d6"), Some(Dice { count: 2, sides: 6, modifier: 0 }));
+        assert_eq!(Dice::parse("d20"), Some(Dice { count: 1, sides: 20, modifier: 0 }));
+        assert_eq!(Dice::parse("3D8-2"), Some(Dice { count: 3, sides: 8, modifier: -2 }));
+        assert_eq!(Dice::parse("1d4+10"), Some(Dice { count: 1, sides: 4, modifier: 10 }));
+        for bad in ["", "6", "2x6", "d", "2d", "0d6", "2d0", "1000000d6", "1d100000", "1d6+", "1d6+99999", "-1d6", "1d6+2+3"] {
+            assert_eq!(Dice::parse(bad).filter(dice_in_range), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn dice_results_are_described_with_total() {
+        let dice = Dice { count: 2, sides: 6, modifier: 0 };
+        assert_eq!(dice.describe(&[4, 2]), "2d6: 4, 2 (total 6)");
+        let dice = Dice { count: 2, sides: 6, modifier: -3 };
+        assert_eq!(dice.describe(&[1, 1]), "2d6-3: 1, 1 -3 (total -1)");
+    }
+
+    // 不经过网络连接, 直接以 name 登记一个发送通道并返回它的收件箱
+    async fn member(name: &str, state: &Arc<Mutex<ServerState>>) -> Inbox {
+        let (tx, rx) = mpsc::channel(10);
+        let mut st = state.lock().await;
+        st.clients.insert(name.into(), tx);
+        st.inbox(name, rx)
+    }
+
+    // 收件箱中已经排队的下一条消息, 没有时返回 None
+    fn pending(inbox: &mut Inbox) -> Option<Message> {
+        futures::FutureExt::now_or_never(inbox.recv()).flatten()
+    }
+
+    #[tokio::test]
+    async fn slow_member_is_told_how_many_broadcasts_it_missed() {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let mut alice = member("alice", &state).await;
+        for n in 0..DEFAULT_BROADCAST_CAPACITY + 3 {
+            state.lock().await.send_to_everyone(Message::Servermsg(ServerMessage::System { content: n.to_string() }));
+        }
+        assert_eq!(pending(&mut alice), Some(Message::Servermsg(ServerMessage::System { content: "You fell behind and missed 3 messages".into() })));
+        assert_eq!(pending(&mut alice), Some(Message::Servermsg(ServerMessage::System { content: "3".into() })));
+    }
+
+    #[tokio::test]
+    async fn queued_broadcasts_are_delivered_before_the_inbox_closes() {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let mut alice = member("alice", &state).await;
+        state.lock().await.send_to_everyone(Message::Servermsg(ServerMessage::Deleted { message_id: 1 }));
+        let tx = state.lock().await.clients.remove("alice").unwrap();
+        tx.send(Message::Servermsg(ServerMessage::Exit { reason: ShutdownReason::Shutdown })).await.unwrap();
+        drop(tx);
+        state.lock().await.send_to_everyone(Message::Servermsg(ServerMessage::Deleted { message_id: 2 }));
+
+        // 先入队的群发排在关闭通知之前; 已下线后才发出的群发不再等待
+        assert_eq!(alice.recv().await, Some(Message::Servermsg(ServerMessage::Deleted { message_id: 1 })));
+        assert_eq!(alice.recv().await, Some(Message::Servermsg(ServerMessage::Deleted { message_id: 2 })));
+        assert_eq!(alice.recv().await, Some(Message::Servermsg(ServerMessage::Exit { reason: ShutdownReason::Shutdown })));
+        assert_eq!(alice.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn roll_is_broadcast_and_within_range() {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let mut alice = member("alice", &state).await;
+        let mut bob = member("bob", &state).await;
+
+        command("alice", ClientMessage::Command { command: Command::Roll(Some(Dice { count: 3, sides: 4, modifier: 0 })) }, &state).await;
+        let Some(Message::Servermsg(ServerMessage::System { content })) = pending(&mut bob) else {
+            panic!("bob should see the roll");
+        };
+        assert!(content.starts_with("alice rolled 3d4: "), "{}", content);
+        let rolls: Vec<u32> = content["alice rolled 3d4: ".len()..content.find(" (").unwrap()]
+            .split(", ").map(|r| r.parse().unwrap()).collect();
+        assert_eq!(rolls.len(), 3);
+        assert!(rolls.iter().all(|r| (1..=4).contains(r)));
+        assert!(matches!(pending(&mut alice), Some(Message::Servermsg(ServerMessage::System { .. }))));
+
+        command("alice", ClientMessage::Command { command: Command::Roll(Some(Dice { count: 1_000_000, sides: 6, modifier: 0 })) }, &state).await;
+        assert!(matches!(pending(&mut alice), Some(Message::Servermsg(ServerMessage::Error { .. }))));
+        assert!(pending(&mut bob).is_none());
+    }
+
+    #[tokio::test]
+    async fn poll_collects_votes_and_announces_results() {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let mut alice = member("alice", &state).await;
+        let mut bob = member("bob", &state).await;
+        let run = |from: &'static str, text: &str| input(from, raw(text), &state);
+
+        run("alice", r#"/poll "Lunch today?" pizza "ramen bar" salad"#).await;
+        let Some(Message::Servermsg(ServerMessage::System { content })) = pending(&mut bob) else { panic!("bob should see the poll") };
+        assert!(content.contains("poll #1: Lunch today?") && content.contains("[2] ramen bar"), "{}", content);
+        let _ = pending(&mut alice);
+
+        run("alice", "/vote 1 pizza").await;
+        run("bob", "/vote 1 2").await;
+        run("bob", r#"/vote 1 "Ramen Bar""#).await;     // 重复投票覆盖, 选项文字不区分大小写
+        run("bob", "/vote 1 9").await;
+        assert!(matches!(pending(&mut alice), Some(Message::Servermsg(ServerMessage::System { .. }))));
+        assert!(matches!(pending(&mut bob), Some(Message::Servermsg(ServerMessage::System { .. }))));
+        assert!(matches!(pending(&mut bob), Some(Message::Servermsg(ServerMessage::System { .. }))));
+        assert!(matches!(pending(&mut bob), Some(Message::Servermsg(ServerMessage::Error { .. }))));
+
+        // 只有发起者可以结束投票
+        run("bob", "/poll-close 1").await;
+        assert!(matches!(pending(&mut bob), Some(Message::Servermsg(ServerMessage::Error { .. }))));
+        run("alice", "/poll-close #1").await;
+        let Some(Message::Servermsg(ServerMessage::System { content })) = pending(&mut bob) else { panic!("bob should see the results") };
+        assert_eq!(content, "Poll #1 closed: Lunch today?  pizza: 1, ramen bar: 1, salad: 0  (2 votes)");
+        assert!(state.lock().await.polls.is_empty());
+
+        run("bob", "/vote 1 pizza").await;
+        assert!(matches!(pending(&mut bob), Some(Message::Servermsg(ServerMessage::Error { .. }))));
+
+        // 选项不足时拒绝创建
+        assert!(matches!(pending(&mut alice), Some(Message::Servermsg(ServerMessage::System { .. }))));
+        run("alice", "/poll lonely only-option").await;
+        assert!(matches!(pending(&mut alice), Some(Message::Servermsg(ServerMessage::Error { .. }))));
+        assert!(state.lock().await.polls.is_empty());
+    }
+
+    #[tokio::test]
+    async fn open_polls_are_capped_and_closed_when_owner_leaves() {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let mut alice = member("alice", &state).await;
+        let mut bob = member("bob", &state).await;
+
+        for n in 0..MAX_OPEN_POLLS_PER_USER {
+            input("alice", raw(&format!("/poll q{} yes no", n)), &state).await;
+            assert!(matches!(pending(&mut alice), Some(Message::Servermsg(ServerMessage::System { .. }))));
+            assert!(matches!(pending(&mut bob), Some(Message::Servermsg(ServerMessage::System { .. }))));
+        }
+        input("alice", raw("/poll one-more yes no"), &state).await;
+        assert!(matches!(pending(&mut alice), Some(Message::Servermsg(ServerMessage::Error { .. }))));
+        assert_eq!(state.lock().await.polls.len(), MAX_OPEN_POLLS_PER_USER);
+
+        // 服务器总数达到上限时, 其他人也不能再发起
+        {
+            let mut st = state.lock().await;
+            for id in 100..100 + (MAX_OPEN_POLLS - MAX_OPEN_POLLS_PER_USER) as u64 {
+                st.polls.insert(id, Poll { owner: "carol".into(), question: "q".into(), options: vec!["a".into(), "b".into()], votes: HashMap::new() });
+            }
+        }
+        input("bob", raw("/poll full yes no"), &state).await;
+        assert!(matches!(pending(&mut bob), Some(Message::Servermsg(ServerMessage::Error { .. }))));
+        state.lock().await.polls.retain(|_, poll| poll.owner != "carol");
+
+        // 发起者离开后他的投票按 id 顺序结束并公布结果
+        input("bob", raw("/vote 2 yes"), &state).await;
+        let _ = pending(&mut bob);
+        unregister(&"alice".to_string(), "127.0.0.1:1".parse().unwrap(), &state).await;
+        for n in 0..MAX_OPEN_POLLS_PER_USER {
+            let Some(Message::Servermsg(ServerMessage::System { content })) = pending(&mut bob) else { panic!("bob should see the results") };
+            assert!(content.starts_with(&format!("Poll #{} closed: q{}", n + 1, n)), "{}", content);
+        }
+        assert!(state.lock().await.polls.is_empty());
+    }
+
+    #[tokio::test]
+    async fn last_spoke_counts_only_accepted_messages() {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        state.lock().await.filter = filter(&["darn"], FilterPolicy::Reject);
+        let _alice = member("alice", &state).await;
+        let _bob = member("bob", &state).await;
+
+        // 被过滤的群发和发给屏蔽了自己的人的私聊都不算发言
+        input("alice", raw("darn it"), &state).await;
+        state.lock().await.blocked.entry("bob".into()).or_default().insert("alice".into());
+        input("alice", raw("/w bob hi"), &state).await;
+        assert!(!state.lock().await.last_spoke.contains_key("alice"));
+
+        state.lock().await.blocked.clear();
+        input("alice", raw("/w bob hi"), &state).await;
+        assert!(state.lock().await.last_spoke.contains_key("alice"));
+        input("bob", raw("hello"), &state).await;
+        assert!(state.lock().await.last_spoke.contains_key("bob"));
+    }
+
+    #[test]
+    fn presence_times_are_pruned_by_age_and_count() {
+        let now = SystemTime::now();
+        let mut st = ServerState::default();
+        st.last_seen.insert("old".into(), now - PRESENCE_RETENTION - Duration::from_secs(1));
+        st.last_spoke.insert("old".into(), now - PRESENCE_RETENTION - Duration::from_secs(1));
+        for i in 0..MAX_PRESENCE_ENTRIES + 5 {
+            st.last_seen.insert(format!("user{}", i), now - Duration::from_secs(i as u64));
+        }
+        st.trim_presence(now);
+        assert_eq!(st.last_seen.len(), MAX_PRESENCE_ENTRIES);
+        // 最旧的几个被删除, 最近的保留
+        assert!(st.last_seen.contains_key("user0") && !st.last_seen.contains_key(&format!("user{}", MAX_PRESENCE_ENTRIES)));
+        assert!(!st.last_seen.contains_key("old") && st.last_spoke.is_empty());
+    }
+
+    #[tokio::test]
+    async fn poll_and_away_texts_are_cleaned_and_filtered() {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        state.lock().await.filter = filter(&["darn"], FilterPolicy::Reject);
+        let mut alice = member("alice", &state).await;
+        let mut bob = member("bob", &state).await;
+
+        input("alice", raw("/poll \"Lunch\x1b[2J?\" pizza \"ramen\x07\""), &state).await;
+        let Some(Message::Servermsg(ServerMessage::System { content })) = pending(&mut bob) else { panic!("bob should see the poll") };
+        assert!(content.contains("poll #1: Lunch?") && content.contains("[2] ramen"), "{}", content);
+        assert!(!content.contains('\x1b') && !content.contains('\x07'), "{:?}", content);
+        let _ = pending(&mut alice);
+
+        // 被过滤的问题或选项和群发一样被拒绝, 不会创建投票
+        input("alice", raw("/poll \"darn it?\" yes no"), &state).await;
+        input("alice", raw("/poll why? yes darn"), &state).await;
+        let filtered = error_reply("alice", catalog::EN.filtered);
+        assert_eq!(pending(&mut alice), filtered);
+        assert_eq!(pending(&mut alice), filtered);
+        assert_eq!(state.lock().await.polls.len(), 1);
+
+        input("alice", raw("/afk darn meetings"), &state).await;
+        assert_eq!(pending(&mut alice), filtered);
+        assert!(!state.lock().await.afk.contains_key("alice"));
+        input("alice", raw("/afk lunch\x1b]0;pwned\x07"), &state).await;
+        assert_eq!(state.lock().await.afk["alice"].message.as_deref(), Some("lunch"));
+        assert!(pending(&mut bob).is_some_and(|msg| !format!("{:?}", msg).contains("pwned")));
+    }
+
+    #[test]
+    fn ago_uses_the_largest_whole_unit() {
+        assert_eq!(ago(Duration::from_secs(59), &catalog::EN), "just now");
+        assert_eq!(ago(Duration::from_secs(60), &catalog::EN), "1m ago");
+        assert_eq!(ago(Duration::from_secs(2 * 3600 + 59 * 60), &catalog::EN), "2h ago");
+        assert_eq!(ago(Duration::from_secs(3 * 86400), &catalog::EN), "3d ago");
+    }
+
+    #[tokio::test]
+    async fn whois_and_offline_errors_report_last_seen() {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let (alice_tx, mut alice_rx) = mpsc::channel(10);
+        let (bob_tx, _bob_rx) = mpsc::channel(10);
+        state.lock().await.clients.insert("alice".into(), alice_tx);
+        state.lock().await.clients.insert("bob".into(), bob_tx);
+        let whois = |target: &str| command("alice", ClientMessage::Command { command: Command::Whois(target.into()) }, &state);
+        let reply = |rx: &mut mpsc::Receiver<Message>| match rx.try_recv() {
+            Ok(Message::Servermsg(ServerMessage::System { content } | ServerMessage::Error { content, .. })) => content,
+            other => panic!("unexpected message: {:?}", other),
+        };
+
+        whois("bob").await;
+        assert_eq!(reply(&mut alice_rx), "bob is online");
+
+        unregister(&"bob".to_string(), "127.0.0.1:1".parse().unwrap(), &state).await;
+        state.lock().await.last_seen.insert("bob".into(), SystemTime::now() - Duration::from_secs(2 * 3600));
+        whois("bob").await;
+        assert_eq!(reply(&mut alice_rx), "bob is offline, last seen 2h ago");
+        whois("ghost").await;
+        assert_eq!(reply(&mut alice_rx), "ghost has not been seen");
+
+        let private = ClientMessage::Private { to: vec!["bob".into(), "ghost".into()], content: "hi".into(), ephemeral: false, ttl_secs: None, signature: None, encrypted: false, reply_to: None };
+        dispatch("alice", private, &state).await;
+        assert_eq!(reply(&mut alice_rx), "Not online or no such user: bob (last seen 2h ago), ghost");
+    }
+
+    #[tokio::test]
+    async fn dumpstate_is_admin_only_and_parses_back() {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let key = crate::common::signing::Identity::generate().public_key();
+        state.lock().await.admins.insert("root".into(), key.clone());
+        let mut root = member("root", &state).await;
+        state.lock().await.public_keys.insert("root".into(), key);
+        let mut alice = member("alice", &state).await;
+        state.lock().await.quiet_joins.insert("alice".into());
+        let run = |from: &'static str, text: &str| input(from, raw(text), &state);
+
+        run("alice", "/dumpstate").await;
+        match pending(&mut alice) {
+            Some(Message::Servermsg(ServerMessage::Error { content, .. })) => assert_eq!(content, "Only server admins can use /dumpstate"),
+            other => panic!("unexpected message: {:?}", other),
+        }
+
+        run("root", "/dumpstate json").await;
+        let Some(Message::Servermsg(ServerMessage::System { content })) = pending(&mut root) else { panic!("root should get a report") };
+        assert!(!content.contains('\n'));
+        let snapshot: StateSnapshot = serde_json::from_str(&content).unwrap();
+        assert_eq!(snapshot, state.lock().await.snapshot());
+        assert_eq!(snapshot.clients, vec!["alice", "root"]);
+        assert_eq!(snapshot.rooms[DEFAULT_ROOM], vec!["alice", "root"]);
+        assert_eq!(snapshot.quiet_joins, vec!["alice"]);
+
+        run("root", "/dumpstate").await;
+        let Some(Message::Servermsg(ServerMessage::System { content })) = pending(&mut root) else { panic!("root should get a report") };
+        assert!(content.contains("\n  \"clients\""), "{}", content);
+        run("root", "/dumpstate yaml").await;
+        assert!(matches!(pending(&mut root), Some(Message::Servermsg(ServerMessage::Error { .. }))));
+    }
+
+    // 以用户输入的一行发给服务器, 和客户端发送 Raw 相同
+    fn raw(text: &str) -> ClientMessage {
+        ClientMessage::Raw { text: text.into(), id: 0 }
+    }
+
+    async fn input(from: &str, msg: ClientMessage, state: &Arc<Mutex<ServerState>>) {
+        let _ = route(from, msg, state).await;
+    }
+
+    #[tokio::test]
+    async fn huge_ttls_are_capped_instead_of_overflowing() {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let mut alice = member("alice", &state).await;
+        let _bob = member("bob", &state).await;
+        input("alice", raw(&format!("/ttl {} x", u64::MAX)), &state).await;
+        let private = ClientMessage::Private { to: vec!["bob".into()], content: "hi".into(), ephemeral: false, ttl_secs: Some(u64::MAX), signature: None, encrypted: false, reply_to: None };
+        input("alice", private, &state).await;
+        assert!(matches!(pending(&mut alice), Some(Message::Servermsg(ServerMessage::BroadcastMessage { .. }))));
+
+        let st = state.lock().await;
+        let latest = Instant::now() + MAX_TTL;
+        let broadcast = st.room_history(DEFAULT_ROOM).last().unwrap();
+        assert!(broadcast.expires_at.is_some_and(|at| at <= latest));
+        assert!(st.private_history["bob"].back().unwrap().expires_at.is_some_and(|at| at <= latest));
+    }
+
+    #[tokio::test]
+    async fn actions_fill_their_template_for_everyone() {
+        let mut st = ServerState::default();
+        st.actions.insert("highfive".into(), ActionCommand { template: "{name} high-fives {1} and {2}".into(), args: 2 });
+        let state = Arc::new(Mutex::new(st));
+        let mut alice = member("alice", &state).await;
+        let mut bob = member("bob", &state).await;
+        let system = |msg: Option<Message>| match msg {
+            Some(Message::Servermsg(ServerMessage::System { content })) => content,
+            other => panic!("unexpected message: {:?}", other),
+        };
+
+        input("alice", raw("/slap bob"), &state).await;
+        assert_eq!(system(pending(&mut bob)), "alice slaps bob around with a large trout");
+        assert_eq!(system(pending(&mut alice)), "alice slaps bob around with a large trout");
+
+        input("bob", raw(r#"/highfive alice "the whole team""#), &state).await;
+        assert_eq!(system(pending(&mut alice)), "bob high-fives alice and the whole team");
+        let _ = pending(&mut bob);
+
+        // 参数个数不对时只告诉发送者
+        for wrong in ["/slap", "/slap bob carol", "/highfive alice"] {
+            input("alice", raw(wrong), &state).await;
+            assert!(matches!(pending(&mut alice), Some(Message::Servermsg(ServerMessage::Error { .. }))), "{}", wrong);
+            assert!(pending(&mut bob).is_none(), "{}", wrong);
+        }
+    }
+
+    #[test]
+    fn greeting_fills_in_known_placeholders_only() {
+        let greeting = |template: &str| Greeting { template: template.into() };
+        let welcome = greeting("Welcome {name}, {count} users online at {server}");
+        assert!(welcome.validate().is_ok());
+        assert_eq!(welcome.render("alice", 3, "rustchat"), "Welcome alice, 3 users online at rustchat");
+        // 单次替换: 用户名里的占位符不会再被展开
+        assert_eq!(greeting("hi {name}").render("{count}", 1, "lab"), "hi {count}");
+        assert!(greeting("no placeholders").validate().is_ok());
+        assert!(greeting("Welcome {nick}").validate().is_err());
+        assert!(greeting("{count} online, {1}").validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn new_users_are_greeted_but_resumed_sessions_are_not() {
+        let greeting = Some(Greeting { template: "Welcome {name}, {count} users online".into() });
+        let state = Arc::new(Mutex::new(ServerState { greeting, ..ServerState::default() }));
+        let _alice = member("alice", &state).await;
+        let mut bob = member("bob", &state).await;
+        let greeting = Message::Servermsg(ServerMessage::System { content: "Welcome bob, 2 users online".into() });
+        register(&"bob".to_string(), None, &state).await;
+        assert!(std::iter::from_fn(|| pending(&mut bob)).any(|msg| msg == greeting));
+
+        register(&"bob".to_string(), Some(0), &state).await;
+        assert!(!std::iter::from_fn(|| pending(&mut bob)).any(|msg| msg == greeting));
+    }
+
+    #[test]
+    fn actions_cannot_replace_built_in_commands_or_use_missing_arguments() {
+        let action = |template: &str, args| ActionCommand { template: template.into(), args };
+        assert!(action("{name} waves at {1}", 1).validate("wave", '/').is_ok());
+        assert!(action("{name} waves", 0).validate("wave", '/').is_ok());
+        assert!(action("{name} waves at {2}", 1).validate("wave", '/').is_err());
+        assert!(action("{name} waves at {0}", 1).validate("wave", '/').is_err());
+        for name in ["users", "w", "quit", "", "two words"] {
+            assert!(action("{name}", 0).validate(name, '/').is_err(), "{:?}", name);
+        }
+        assert!(Server::builder().action("slap", action("{name} pokes {1}", 1)).check().is_ok());
+        assert!(Server::builder().action("roll", action("{name} rolls over", 0)).check().is_err());
+    }
+
+    #[tokio::test]
+    async fn commands_are_matched_by_exact_name() {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let (alice_tx, mut alice_rx) = mpsc::channel(10);
+        state.lock().await.clients.insert("alice".into(), alice_tx);
+
+        for users in ["/users", "/users ", "  /users\t"] {
+            input("alice", raw(users), &state).await;
+            assert!(matches!(alice_rx.try_recv(), Ok(Message::Servermsg(ServerMessage::UserList { .. }))), "{:?}", users);
+        }
+
+        // 名字只是前缀相同的指令不会命中, 未知指令返回错误
+        for (unknown, name) in [("/usersx", "/usersx"), ("/nope now", "/nope")] {
+            input("alice", raw(unknown), &state).await;
+            match alice_rx.try_recv() {
+                Ok(Message::Servermsg(ServerMessage::Error { content, .. })) => assert_eq!(content, format!("Unknown command: {}", name)),
+                other => panic!("unexpected message: {:?}", other),
+            }
+        }
+
+        input("alice", raw("/block"), &state).await;
+        match alice_rx.try_recv() {
+            Ok(Message::Servermsg(ServerMessage::Error { content, .. })) => assert!(content.contains("Usage"), "{}", content),
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    fn spam_policy() -> SpamPolicy {
+        SpamPolicy { rate_limit: 3, rate_window: Duration::from_secs(10), max_repeats: 2, max_strikes: 2, mute_duration: Duration::from_secs(30) }
+    }
+
+    #[test]
+    fn rate_limit_strikes_escalate_to_a_mute() {
+        let policy = spam_policy();
+        let mut tracker = SpamTracker::default();
+        let now = Instant::now();
+        for i in 0..3 {
+            assert_eq!(tracker.check(&policy, &i.to_string(), now), Ok(()));
+        }
+        assert_eq!(tracker.check(&policy, "x", now + Duration::from_secs(4)), Err(SpamRefusal::TooFast(Duration::from_secs(6))));
+        assert_eq!(tracker.check(&policy, "y", now), Err(SpamRefusal::Muted(Duration::from_secs(30))));
+        let refusal = tracker.check(&policy, "z", now + Duration::from_millis(20_500)).unwrap_err();
+        assert_eq!(refusal.describe(&catalog::EN), "You are muted for 10s");
+        // 禁言结束后重新计数
+        assert_eq!(tracker.check(&policy, "back", now + Duration::from_secs(30)), Ok(()));
+    }
+
+    #[test]
+    fn rate_window_slides() {
+        let policy = spam_policy();
+        let mut tracker = SpamTracker::default();
+        let now = Instant::now();
+        for i in 0..3 {
+            assert_eq!(tracker.check(&policy, &i.to_string(), now + Duration::from_secs(i * 4)), Ok(()));
+        }
+        assert_eq!(tracker.check(&policy, "a", now + Duration::from_secs(10)), Ok(()));
+    }
+
+    #[test]
+    fn repeated_content_mutes_immediately() {
+        let policy = SpamPolicy { rate_limit: 0, ..spam_policy() };
+        let mut tracker = SpamTracker::default();
+        let now = Instant::now();
+        assert_eq!(tracker.check(&policy, "buy now", now), Ok(()));
+        assert_eq!(tracker.check(&policy, "buy now", now), Ok(()));
+        assert_eq!(tracker.check(&policy, "buy now", now), Err(SpamRefusal::Muted(Duration::from_secs(30))));
+
+        let mut other = SpamTracker::default();
+        for content in ["a", "b", "a", "b", "a"] {
+            assert_eq!(other.check(&policy, content, now), Ok(()));
+        }
+    }
+
+    #[test]
+    fn default_spam_policy_allows_everything() {
+        let policy = SpamPolicy::default();
+        let mut tracker = SpamTracker::default();
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert_eq!(tracker.check(&policy, "same", now), Ok(()));
+        }
+    }
+
+    fn nets(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn ip_access_applies_deny_before_allow() {
+        let access = IpAccess::parse(&nets(&["10.0.0.0/8", "192.168.1.7"]), &nets(&["10.1.0.0/16"])).unwrap();
+        assert!(access.permits("10.2.3.4".parse().unwrap()));
+        assert!(!access.permits("10.1.2.3".parse().unwrap()));
+        assert!(access.permits("192.168.1.7".parse().unwrap()));
+        assert!(!access.permits("192.168.1.8".parse().unwrap()));
+        assert!(!access.permits("::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ip_access_with_only_deny_accepts_the_rest() {
+        let access = IpAccess::parse(&[], &nets(&["203.0.113.0/24", "2001:db8::/32"])).unwrap();
+        assert!(!access.permits("203.0.113.9".parse().unwrap()));
+        assert!(!access.permits("2001:db8::1".parse().unwrap()));
+        assert!(access.permits("198.51.100.1".parse().unwrap()));
+        assert!(IpAccess::default().permits("203.0.113.9".parse().unwrap()));
+    }
+
+    #[test]
+    fn ip_access_matches_ipv4_mapped_addresses() {
+        let access = IpAccess::parse(&nets(&["127.0.0.0/8"]), &[]).unwrap();
+        assert!(access.permits("::ffff:127.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn invalid_ip_ranges_are_rejected() {
+        assert!(IpAccess::parse(&nets(&["10.0.0.0/33"]), &[]).is_err());
+        assert!(IpAccess::parse(&[], &nets(&["localhost"])).is_err());
+    }
+
+    #[test]
+    fn history_templates_are_filled_in_one_pass() {
+        let format = HistoryFormat { broadcast: "[{from}] {content} {other}".into(), ..HistoryFormat::default() };
+        assert_eq!(format.broadcast("alice", "hi {from}"), "[alice] hi {from} {other}");
+        assert_eq!(format.private_sent("bob, carol", "yo"), "You → bob, carol: yo");
+        assert_eq!(format.private_received("{content}", "x"), "{content} → You: x");
+        assert_eq!(format.private_sent("bob", "fn main() {\n}"), "You → bob: fn main() {\n    }");
+    }
+
+    #[test]
+    fn history_templates_need_their_placeholders() {
+        assert!(HistoryFormat::default().validate().is_ok());
+        let missing_content = HistoryFormat { broadcast: "{from} said something".into(), ..HistoryFormat::default() };
+        assert!(missing_content.validate().unwrap_err().to_string().contains("{content}"));
+        let missing_to = HistoryFormat { private_sent: "me: {content}".into(), ..HistoryFormat::default() };
+        assert!(missing_to.validate().unwrap_err().to_string().contains("{to}"));
+    }
+
+    #[test]
+    fn presence_templates_need_their_placeholders() {
+        assert!(PresenceFormat::default().validate().is_ok());
+        let missing_name = PresenceFormat { joined: Some("someone joined".into()), ..PresenceFormat::default() };
+        assert!(missing_name.validate().unwrap_err().to_string().contains("{name}"));
+        let missing_reason = PresenceFormat { left_with_reason: Some("{name} quit".into()), ..PresenceFormat::default() };
+        assert!(missing_reason.validate().unwrap_err().to_string().contains("{reason}"));
+    }
+
+    #[test]
+    fn recipients_resolve_by_unique_prefix() {
+        let mut st = ServerState::default();
+        for name in ["alice", "Albert", "bob", "bobby"] {
+            let (tx, _rx) = mpsc::channel(1);
+            st.clients.insert(name.into(), tx);
+        }
+        // 完整的名字优先, 即使它也是别人名字的前缀
+        assert_eq!(st.resolve_recipient("bob", true), Ok("bob".to_string()));
+        // 前缀不区分大小写
+        assert_eq!(st.resolve_recipient("ali", true), Ok("alice".to_string()));
+        assert_eq!(st.resolve_recipient("alB", true), Ok("Albert".to_string()));
+        assert_eq!(st.resolve_recipient("BOBB", true), Ok("bobby".to_string()));
+        assert_eq!(st.resolve_recipient("al", true), Err(vec!["Albert".to_string(), "alice".to_string()]));
+        assert_eq!(st.resolve_recipient("carol", true), Ok("carol".to_string()));
+        // 不按前缀匹配时只认完整的名字
+        assert_eq!(st.resolve_recipient("ali", false), Ok("ali".to_string()));
+
+        // 离线但来过的用户名不会被当成别人的前缀
+        st.clients.remove("bob");
+        st.last_seen.insert("bob".into(), SystemTime::now());
+        assert_eq!(st.resolve_recipient("bob", true), Ok("bob".to_string()));
+        st.last_seen.clear();
+        assert_eq!(st.resolve_recipient("bob", true), Ok("bobby".to_string()));
+    }
+
+    #[test]
+    fn ip_quota_limits_concurrent_names() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        let mut st = ServerState { max_names_per_ip: 2, ..ServerState::default() };
+        assert!(st.reserve_ip_slot(ip, "a"));
+        assert!(st.reserve_ip_slot(ip, "b"));
+        assert!(!st.reserve_ip_slot(ip, "c"));
+        assert!(st.reserve_ip_slot(other, "c"));
+
+        // 断开后名额归还
+        st.release_ip_slot(ip, "a");
+        assert!(st.reserve_ip_slot(ip, "c"));
+    }
+
+    #[test]
+    fn zero_ip_quota_means_unlimited() {
+        let ip: IpAddr = "::1".parse().unwrap();
+        let mut st = ServerState::default();
+        for i in 0..50 {
+            assert!(st.reserve_ip_slot(ip, &format!("user{}", i)));
+        }
+    }
+
+    #[test]
+    fn mask_does_not_rescan_its_own_replacement() {
+        let f = filter(&["*"], FilterPolicy::Mask);
+        assert_eq!(f.apply("a*b"), Some("a***b".to_string()));
+    }
+}
@@ -0,0 +1,419 @@
+use serde::Deserialize;
+
+/* 服务器发给用户的文字, 每种语言一份
+    带参数的条目是模板, 用 fill 把 {name} 这样的占位符换成实际内容
+    新增文字时在 Catalog 中加一个字段, 并在每种语言里都填上
+*/
+
+// 服务器使用的语言, 在配置中用 "en" / "zh" 选择
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Locale {
+    #[default]
+    En,
+    Zh,
+}
+
+impl Locale {
+    pub fn catalog(self) -> &'static Catalog {
+        match self {
+            Locale::En => &EN,
+            Locale::Zh => &ZH,
+        }
+    }
+}
+
+pub struct Catalog {
+    // 上下线
+    pub joined: &'static str,               // {name}
+    pub left: &'static str,                 // {name}
+    pub left_with_reason: &'static str,     // {name} {reason}
+    pub name_taken: &'static str,
+    pub name_reserved: &'static str,
+    pub invalid_name: &'static str,         // {max}
+    pub too_many_names: &'static str,
+
+    // 发送消息
+    pub rate_limited: &'static str,
+    pub muted: &'static str,                // {secs}
+    pub filtered: &'static str,
+    pub too_large: &'static str,            // {max}
+    pub not_online: &'static str,           // {names}
+    pub ambiguous_recipient: &'static str,  // {prefix} {names}
+    pub resolved_recipients: &'static str,  // {names}
+    pub encrypted_placeholder: &'static str,
+
+    // 用法提示
+    pub usage_whisper: &'static str,
+    pub usage_off_record: &'static str,
+    pub usage_ttl: &'static str,
+    pub usage_history: &'static str,
+    pub usage_block: &'static str,
+    pub usage_unblock: &'static str,
+    pub usage_roll: &'static str,           // {dice} {sides} {modifier}
+    pub usage_whois: &'static str,
+    pub usage_seen: &'static str,
+    pub usage_whoami: &'static str,
+    pub usage_color: &'static str,
+    pub usage_poll: &'static str,           // {min} {max}
+    pub usage_vote: &'static str,
+    pub usage_poll_close: &'static str,
+    pub usage_users: &'static str,
+    pub usage_quit: &'static str,
+    pub usage_quiet_joins: &'static str,
+    pub usage_afk: &'static str,
+    pub usage_stats: &'static str,
+    pub usage_dumpstate: &'static str,
+    pub usage_export: &'static str,
+    pub usage_dm_history: &'static str,
+    pub usage_import: &'static str,
+
+    // 帮助机器人
+    pub help_intro: &'static str,           // {name} {topics}
+    pub help_unknown: &'static str,         // {topic} {topics}
+
+    // 指令结果
+    pub no_user_online: &'static str,
+    pub unknown_command: &'static str,      // {command}
+    pub action_args: &'static str,          // {command} {count}
+    pub issued: &'static str,               // {command}
+    pub broadcast_history: &'static str,
+    pub private_history: &'static str,
+    pub no_history_page: &'static str,      // {page}
+    pub more_history: &'static str,         // {more} {next}
+    pub blocked: &'static str,              // {name}
+    pub unblocked: &'static str,            // {name}
+    pub not_blocked: &'static str,          // {name}
+    pub quiet_joins_on: &'static str,
+    pub quiet_joins_off: &'static str,
+    pub away: &'static str,                 // {name}
+    pub away_with_message: &'static str,    // {name} {message}
+    pub back: &'static str,                 // {name}
+    pub queue_depths: &'static str,         // {queues}
+    pub broadcast_lag: &'static str,        // {queued} {capacity} {missed}
+    pub missed_broadcasts: &'static str,    // {n}
+    pub session_resumed: &'static str,      // {count}
+    pub invalid_file_offer: &'static str,
+    pub invalid_attachments: &'static str,  // {max}
+    pub attachment_line: &'static str,      // {n} {name} {size} {type}, 历史中列在群发下面的一个附件
+    pub admin_only: &'static str,           // {command}
+    pub imported: &'static str,             // {count} {path}
+    pub dm_history_confirm: &'static str,   // {count} {command} {secs}
+    pub dm_history_cleared: &'static str,   // {count}
+    pub dm_history_unconfirmed: &'static str,   // {request} {secs}
+    pub import_failed: &'static str,        // {path} {error}
+    pub import_disabled: &'static str,
+    pub import_not_found: &'static str,     // {path}
+    pub import_too_large: &'static str,     // {path} {max}
+    pub rolled: &'static str,               // {name} {result}
+    pub slapped: &'static str,              // {name} {1}, 内置的 /slap 动作
+    pub online: &'static str,               // {name}
+    pub offline: &'static str,              // {name} {seen}
+    pub never_seen: &'static str,           // {name}
+    pub seen_now: &'static str,             // {name}
+    pub seen_spoke: &'static str,           // {name} {ago}
+    pub seen_silent: &'static str,          // {name} {seen}
+    pub whoami: &'static str,               // {name} {room} {status}
+    pub status_available: &'static str,
+    pub status_away: &'static str,
+    pub status_away_with_message: &'static str, // {message}
+    pub color_set: &'static str,            // {color}
+    pub color_cleared: &'static str,
+    pub last_seen: &'static str,            // {ago}
+    pub just_now: &'static str,
+    pub minutes_ago: &'static str,          // {n}
+    pub hours_ago: &'static str,            // {n}
+    pub days_ago: &'static str,             // {n}
+
+    // 投票
+    pub poll_started: &'static str,         // {name} {id} {question} {options}
+    pub no_open_poll: &'static str,         // {id}
+    pub no_such_option: &'static str,       // {question} {choice}
+    pub voted: &'static str,                // {option} {question}
+    pub poll_owner_only: &'static str,
+    pub poll_closed: &'static str,          // {id} {question} {results} {votes}
+    pub too_many_own_polls: &'static str,   // {max}
+    pub too_many_polls: &'static str,       // {max}
+}
+
+pub static EN: Catalog = Catalog {
+    joined: "{name} has joined the chat",
+    left: "{name} has left the chat",
+    left_with_reason: "{name} has left the chat ({reason})",
+    name_taken: "Name is already taken",
+    name_reserved: "Name is reserved",
+    invalid_name: "Names must be 1 to {max} characters without spaces or commas",
+    too_many_names: "Too many users registered from your address",
+
+    rate_limited: "You are sending messages too fast, slow down",
+    muted: "You are muted for {secs}s",
+    filtered: "Message rejected: it contains filtered words",
+    too_large: "Message not sent: it would exceed the {max} frame limit when delivered",
+    not_online: "Not online or no such user: {names}",
+    ambiguous_recipient: "\"{prefix}\" matches several users, message not sent: {names}",
+    resolved_recipients: "Sent to {names}",
+    encrypted_placeholder: "(encrypted)",
+
+    usage_whisper: "Usage: /w <user>[,<user>...] <message>",
+    usage_off_record: "Usage: /o <message>",
+    usage_ttl: "Usage: /ttl <seconds> <message>",
+    usage_history: "Usage: /history [--since <unix_seconds>] [page]",
+    usage_block: "Usage: /block <user>",
+    usage_unblock: "Usage: /unblock <user>",
+    usage_roll: "Usage: /roll [NdM+K], at most {dice} dice with {sides} sides, modifier within ±{modifier}",
+    usage_whois: "Usage: /whois <user>",
+    usage_seen: "Usage: /seen <user>, shows when the user last sent a message",
+    usage_whoami: "Usage: /whoami, shows the name, room and status the server has for you",
+    usage_color: "Usage: /color <red|green|yellow|blue|magenta|cyan|none>, picks the color others see your name in",
+    usage_poll: "Usage: /poll \"<question>\" <option> <option>... ({min} to {max} options)",
+    usage_vote: "Usage: /vote <poll_id> <option>",
+    usage_poll_close: "Usage: /poll-close <poll_id>",
+    usage_users: "Usage: /users, lists everyone online",
+    usage_quit: "Usage: /quit [reason], leaves the chat",
+    usage_quiet_joins: "Usage: /quiet-joins, hides or shows join and leave notices",
+    usage_afk: "Usage: /afk [message], marks you as away until you next send something",
+    usage_stats: "Usage: /stats, shows how many messages are waiting to be sent to each user and how far behind broadcasts are (admins only)",
+    usage_dumpstate: "Usage: /dumpstate [json|pretty]",
+    usage_export: "Usage: /export [path], saves the history you can see to a JSON file",
+    usage_dm_history: "Usage: /dm-history clear, deletes your private messages from the server after you confirm",
+    usage_import: "Usage: /import <path>, loads a file saved with /export on the server into the history",
+
+    help_intro: "Hi, I'm {name}. Send me a command name and I'll tell you how to use it. Commands: {topics}",
+    help_unknown: "I don't know a command called \"{topic}\". Commands: {topics}",
+
+    no_user_online: "No User Online",
+    unknown_command: "Unknown command: {command}",
+    action_args: "{command} takes exactly {count} argument(s)",
+    issued: "You issued: {command}",
+    broadcast_history: "=== Broadcast History ===",
+    private_history: "=== Your Private History ===",
+    no_history_page: "No history on page {page}",
+    more_history: "... {more} more, use /history {next}",
+    blocked: "You blocked {name}",
+    unblocked: "You unblocked {name}",
+    not_blocked: "{name} was not blocked",
+    quiet_joins_on: "Join and leave notices are now hidden",
+    quiet_joins_off: "Join and leave notices are now shown",
+    away: "{name} is away",
+    away_with_message: "{name} is away: {message}",
+    back: "{name} is back",
+    queue_depths: "Send queues (queued/capacity): {queues}",
+    broadcast_lag: "Broadcasts: slowest client {queued}/{capacity} behind, {missed} skipped since start",
+    missed_broadcasts: "You fell behind and missed {n} messages",
+    session_resumed: "Welcome back, resending {count} messages you missed",
+    invalid_file_offer: "Invalid file offer: the name must not contain directories and the checksum must be SHA-256 hex",
+    invalid_attachments: "Invalid attachments, message not sent: at most {max} per message, names must not contain directories or exceed 255 bytes, and types look like image/png",
+    attachment_line: "    [attachment {n}] {name} ({size}, {type})",
+    admin_only: "Only server admins can use {command}",
+    imported: "Imported {count} messages from {path}",
+    dm_history_confirm: "This deletes the {count} private messages the server keeps for you. The other side keeps their copy. Send {command} within {secs}s to go ahead",
+    dm_history_cleared: "Deleted {count} private messages from your history",
+    dm_history_unconfirmed: "Nothing to confirm: send {request} first, then confirm within {secs}s",
+    import_failed: "Cannot import {path}: {error}",
+    import_disabled: "Importing is turned off, set import_dir on the server to allow it",
+    import_not_found: "Cannot import {path}: no such file in the import directory",
+    import_too_large: "Cannot import {path}: the file is larger than {max}",
+    rolled: "{name} rolled {result}",
+    slapped: "{name} slaps {1} around with a large trout",
+    online: "{name} is online",
+    offline: "{name} is offline, {seen}",
+    never_seen: "{name} has not been seen",
+    seen_now: "{name} is here now",
+    seen_spoke: "{name} last sent a message {ago}",
+    seen_silent: "{name} has never sent a message ({seen})",
+    whoami: "You are {name} in {room}, {status}",
+    status_available: "available",
+    status_away: "away",
+    status_away_with_message: "away: {message}",
+    color_set: "Your name is now shown in {color}",
+    color_cleared: "Your name is shown in the default color again",
+    last_seen: "last seen {ago}",
+    just_now: "just now",
+    minutes_ago: "{n}m ago",
+    hours_ago: "{n}h ago",
+    days_ago: "{n}d ago",
+
+    poll_started: "{name} started poll #{id}: {question}  {options}  (vote with /vote {id} <option>)",
+    no_open_poll: "No open poll #{id}",
+    no_such_option: "Poll \"{question}\" has no option {choice}",
+    voted: "You voted for \"{option}\" in poll \"{question}\"",
+    poll_owner_only: "Only the creator can close a poll",
+    poll_closed: "Poll #{id} closed: {question}  {results}  ({votes} votes)",
+    too_many_own_polls: "You already have {max} open polls, close one with /poll-close first",
+    too_many_polls: "There are already {max} open polls, try again later",
+};
+
+pub static ZH: Catalog = Catalog {
+    joined: "{name} 加入了聊天",
+    left: "{name} 离开了聊天",
+    left_with_reason: "{name} 离开了聊天({reason})",
+    name_taken: "用户名已被占用",
+    name_reserved: "该用户名为保留名, 不能使用",
+    invalid_name: "用户名须为 1 到 {max} 个字符, 不能包含空格或逗号",
+    too_many_names: "你的地址注册的用户名过多",
+
+    rate_limited: "发言太快, 请稍后再发",
+    muted: "你已被禁言, 剩余 {secs} 秒",
+    filtered: "消息包含过滤词, 已被拒绝",
+    too_large: "消息未发送: 转发时会超过 {max} 的单帧上限",
+    not_online: "用户不在线或不存在: {names}",
+    ambiguous_recipient: "\"{prefix}\" 匹配到多个用户, 消息未发送: {names}",
+    resolved_recipients: "已发送给 {names}",
+    encrypted_placeholder: "(加密消息)",
+
+    usage_whisper: "用法: /w <用户>[,<用户>...] <消息>",
+    usage_off_record: "用法: /o <消息>",
+    usage_ttl: "用法: /ttl <秒数> <消息>",
+    usage_history: "用法: /history [--since <Unix 秒>] [页码]",
+    usage_block: "用法: /block <用户>",
+    usage_unblock: "用法: /unblock <用户>",
+    usage_roll: "用法: /roll [NdM+K], 最多 {dice} 个骰子、{sides} 面, 修正值在 ±{modifier} 以内",
+    usage_whois: "用法: /whois <用户>",
+    usage_seen: "用法: /seen <用户>, 查看该用户最近一次发言的时间",
+    usage_whoami: "用法: /whoami, 查看服务器记录的你的用户名、房间和状态",
+    usage_color: "用法: /color <red|green|yellow|blue|magenta|cyan|none>, 选择其他人看到的你的名字颜色",
+    usage_poll: "用法: /poll \"<问题>\" <选项> <选项>... ({min} 到 {max} 个选项)",
+    usage_vote: "用法: /vote <投票编号> <选项>",
+    usage_poll_close: "用法: /poll-close <投票编号>",
+    usage_users: "用法: /users, 列出在线用户",
+    usage_quit: "用法: /quit [原因], 离开聊天",
+    usage_quiet_joins: "用法: /quiet-joins, 隐藏或恢复上下线通知",
+    usage_afk: "用法: /afk [留言], 标记为暂时离开, 下次发送任何内容时自动取消",
+    usage_stats: "用法: /stats, 查看每个用户的发送队列和群发中积压了多少消息(仅管理员)",
+    usage_dumpstate: "用法: /dumpstate [json|pretty]",
+    usage_export: "用法: /export [路径], 把你能看到的历史保存为 JSON 文件",
+    usage_dm_history: "用法: /dm-history clear, 确认后删除服务器上你的私聊历史",
+    usage_import: "用法: /import <路径>, 把服务器上用 /export 保存的文件导入历史",
+
+    help_intro: "你好, 我是 {name}。发给我一个指令名, 我会告诉你它的用法。指令: {topics}",
+    help_unknown: "没有叫 \"{topic}\" 的指令。指令: {topics}",
+
+    no_user_online: "没有在线用户",
+    unknown_command: "未知指令: {command}",
+    action_args: "{command} 需要正好 {count} 个参数",
+    issued: "你执行了: {command}",
+    broadcast_history: "=== 广播历史 ===",
+    private_history: "=== 你的私聊历史 ===",
+    no_history_page: "历史记录没有第 {page} 页",
+    more_history: "... 还有 {more} 条, 使用 /history {next} 查看",
+    blocked: "你屏蔽了 {name}",
+    unblocked: "你取消了对 {name} 的屏蔽",
+    not_blocked: "你没有屏蔽 {name}",
+    quiet_joins_on: "已隐藏上下线通知",
+    quiet_joins_off: "已恢复显示上下线通知",
+    away: "{name} 暂时离开",
+    away_with_message: "{name} 暂时离开: {message}",
+    back: "{name} 回来了",
+    queue_depths: "发送队列(积压/容量): {queues}",
+    broadcast_lag: "群发: 最慢的客户端落后 {queued}/{capacity} 条, 启动以来共跳过 {missed} 条",
+    missed_broadcasts: "接收太慢, 错过了 {n} 条消息",
+    session_resumed: "欢迎回来, 补发你错过的 {count} 条消息",
+    invalid_file_offer: "文件信息无效: 文件名不能包含目录, 校验和必须是 SHA-256 的 hex",
+    invalid_attachments: "附件无效, 消息未发送: 每条最多 {max} 个, 文件名不能包含目录且不超过 255 字节, 类型形如 image/png",
+    attachment_line: "    [附件 {n}] {name} ({size}, {type})",
+    admin_only: "只有服务器管理员可以使用 {command}",
+    imported: "从 {path} 导入了 {count} 条消息",
+    dm_history_confirm: "这会删除服务器为你保存的 {count} 条私聊, 对方保存的不受影响。确定的话请在 {secs} 秒内发送 {command}",
+    dm_history_cleared: "已从你的历史中删除 {count} 条私聊",
+    dm_history_unconfirmed: "没有需要确认的操作: 请先发送 {request}, 再在 {secs} 秒内确认",
+    import_failed: "无法导入 {path}: {error}",
+    import_disabled: "导入功能未开启, 需要在服务器上设置 import_dir",
+    import_not_found: "无法导入 {path}: 导入目录中没有这个文件",
+    import_too_large: "无法导入 {path}: 文件超过 {max}",
+    rolled: "{name} 掷出了 {result}",
+    slapped: "{name} 抡起一条大鳟鱼拍了 {1} 一下",
+    online: "{name} 在线",
+    offline: "{name} 不在线, {seen}",
+    never_seen: "没有见过 {name}",
+    seen_now: "{name} 现在就在",
+    seen_spoke: "{name} 最近一次发言于 {ago}",
+    seen_silent: "{name} 从未发言({seen})",
+    whoami: "你是 {name}, 在 {room}, {status}",
+    status_available: "在线",
+    status_away: "暂时离开",
+    status_away_with_message: "暂时离开: {message}",
+    color_set: "你的名字现在显示为 {color}",
+    color_cleared: "你的名字恢复为默认颜色",
+    last_seen: "最近上线于 {ago}",
+    just_now: "刚刚",
+    minutes_ago: "{n} 分钟前",
+    hours_ago: "{n} 小时前",
+    days_ago: "{n} 天前",
+
+    poll_started: "{name} 发起了投票 #{id}: {question}  {options}  (用 /vote {id} <选项> 投票)",
+    no_open_poll: "没有进行中的投票 #{id}",
+    no_such_option: "投票 \"{question}\" 没有选项 {choice}",
+    voted: "你在投票 \"{question}\" 中选择了 \"{option}\"",
+    poll_owner_only: "只有发起者可以结束投票",
+    poll_closed: "投票 #{id} 已结束: {question}  {results}  (共 {votes} 票)",
+    too_many_own_polls: "你已经有 {max} 个进行中的投票, 请先用 /poll-close 结束一个",
+    too_many_polls: "已经有 {max} 个进行中的投票, 请稍后再试",
+};
+
+// 一次扫描替换模板中的 {key}, 替换进来的文本不会再被当作占位符; 不认识的占位符原样保留
+pub fn fill(template: &str, values: &[(&str, &str)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let value = after.find('}').and_then(|end| {
+            let key = &after[..end];
+            values.iter().find(|(k, _)| *k == key).map(|(_, v)| (*v, end))
+        });
+        match value {
+            Some((value, end)) => {
+                out.push_str(value);
+                rest = &after[end + 1..];
+            }
+            None => {
+                out.push('{');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 模板中出现的占位符
+    fn placeholders(template: &str) -> Vec<&str> {
+        let mut found: Vec<&str> = template.split('{').skip(1).filter_map(|s| s.split_once('}')).map(|(k, _)| k).collect();
+        found.sort();
+        found.dedup();
+        found
+    }
+
+    fn entries(c: &Catalog) -> Vec<&'static str> {
+        vec![
+            c.joined, c.left, c.left_with_reason, c.name_taken, c.name_reserved, c.invalid_name, c.too_many_names,
+            c.rate_limited, c.muted, c.filtered, c.too_large, c.not_online, c.ambiguous_recipient, c.resolved_recipients, c.encrypted_placeholder,
+            c.usage_whisper, c.usage_off_record, c.usage_ttl, c.usage_history, c.usage_block, c.usage_unblock,
+            c.usage_roll, c.usage_whois, c.usage_seen, c.usage_whoami, c.usage_color, c.usage_poll, c.usage_vote, c.usage_poll_close,
+            c.usage_users, c.usage_quit, c.usage_quiet_joins, c.usage_afk, c.usage_stats, c.usage_dumpstate, c.usage_export, c.usage_dm_history, c.usage_import, c.help_intro, c.help_unknown,
+            c.no_user_online, c.unknown_command, c.action_args, c.issued, c.broadcast_history, c.private_history, c.no_history_page, c.more_history,
+            c.blocked, c.unblocked, c.not_blocked, c.quiet_joins_on, c.quiet_joins_off, c.away, c.away_with_message, c.back, c.queue_depths, c.broadcast_lag, c.missed_broadcasts, c.session_resumed, c.invalid_file_offer, c.invalid_attachments, c.attachment_line, c.admin_only, c.imported, c.dm_history_confirm, c.dm_history_cleared, c.dm_history_unconfirmed, c.import_failed, c.import_disabled, c.import_not_found, c.import_too_large, c.rolled, c.slapped, c.online, c.offline, c.never_seen, c.seen_now, c.seen_spoke, c.seen_silent,
+            c.whoami, c.status_available, c.status_away, c.status_away_with_message, c.color_set, c.color_cleared,
+            c.last_seen, c.just_now, c.minutes_ago, c.hours_ago, c.days_ago,
+            c.poll_started, c.no_open_poll, c.no_such_option, c.voted, c.poll_owner_only, c.poll_closed, c.too_many_own_polls, c.too_many_polls,
+        ]
+    }
+
+    #[test]
+    fn translations_use_the_same_placeholders() {
+        for (en, zh) in entries(&EN).into_iter().zip(entries(&ZH)) {
+            assert_eq!(placeholders(en), placeholders(zh), "{:?} vs {:?}", en, zh);
+        }
+    }
+
+    #[test]
+    fn fill_replaces_known_keys_once() {
+        assert_eq!(fill("{name} joined", &[("name", "{name}")]), "{name} joined");
+        assert_eq!(fill("{a} {b} {", &[("a", "1")]), "1 {b} {");
+        assert_eq!(fill("{ {a}", &[("a", "x")]), "{ x");
+    }
+}
@@ -0,0 +1,158 @@
+use std::sync::Arc;
+use anyhow::Result;
+use tokio::sync::{mpsc, Mutex};
+use crate::common::{ClientMessage, Message, ServerMessage};
+use super::catalog::{fill, Catalog, Locale};
+use super::{route, ServerState};
+
+/* 服务用户: 由服务器内部处理消息、不对应网络连接的用户, 例如系统机器人
+    和普通客户端一样在 clients 中占用一个名字, 其他用户可以私聊它, 也会出现在 /users 中
+    clients 中保存的只是一个发送通道, 通道另一端可以是连接的写任务, 也可以是服务任务:
+    服务任务从收件箱(个人通道加上群发)中取出发给它的消息交给 handle, 返回的消息按它自己发出的处理
+*/
+pub trait Service: Send + 'static {
+    // name 是服务用户的名字, 返回要以该用户身份发出的消息
+    fn handle(&mut self, name: &str, msg: ServerMessage) -> Vec<ClientMessage>;
+}
+
+impl Service for Box<dyn Service> {
+    fn handle(&mut self, name: &str, msg: ServerMessage) -> Vec<ClientMessage> {
+        (**self).handle(name, msg)
+    }
+}
+
+// 示例服务: 把收到的私聊原样回复给发送者, 可以用来检查连接和压测
+pub struct Echo;
+
+impl Service for Echo {
+    fn handle(&mut self, _name: &str, msg: ServerMessage) -> Vec<ClientMessage> {
+        match msg {
+            ServerMessage::PrivateMessage { from, content, message_id, encrypted: false, .. } => vec![ClientMessage::Private {
+                to: vec![from], content, ephemeral: false, ttl_secs: None, signature: None, encrypted: false, reply_to: Some(message_id),
+            }],
+            _ => Vec::new(),
+        }
+    }
+}
+
+// 从消息目录中取出一条用法提示
+type UsageEntry = fn(&Catalog) -> &'static str;
+
+// 帮助机器人能解释的指令和对应的用法提示
+const HELP_TOPICS: &[(&str, UsageEntry)] = &[
+    ("w", |t| t.usage_whisper),
+    ("users", |t| t.usage_users),
+    ("whois", |t| t.usage_whois),
+    ("seen", |t| t.usage_seen),
+    ("whoami", |t| t.usage_whoami),
+    ("color", |t| t.usage_color),
+    ("history", |t| t.usage_history),
+    ("o", |t| t.usage_off_record),
+    ("ttl", |t| t.usage_ttl),
+    ("block", |t| t.usage_block),
+    ("unblock", |t| t.usage_unblock),
+    ("roll", |t| t.usage_roll),
+    ("poll", |t| t.usage_poll),
+    ("vote", |t| t.usage_vote),
+    ("poll-close", |t| t.usage_poll_close),
+    ("quiet-joins", |t| t.usage_quiet_joins),
+    ("afk", |t| t.usage_afk),
+    ("stats", |t| t.usage_stats),
+    ("export", |t| t.usage_export),
+    ("dm-history", |t| t.usage_dm_history),
+    ("quit", |t| t.usage_quit),
+];
+
+/* 帮助机器人: 私聊它一个指令名(带不带前缀都可以), 回复该指令的用法; 其他内容回复自我介绍和可以询问的指令
+    用法提示中的参数上限与 /roll、/poll 报错时给出的一致
+*/
+pub struct HelpBot {
+    text: &'static Catalog,
+    prefix: char,
+}
+
+impl HelpBot {
+    pub fn new(locale: Locale, prefix: char) -> Self {
+        HelpBot { text: locale.catalog(), prefix }
+    }
+
+    // 对一条私聊的回复
+    fn answer(&self, name: &str, question: &str) -> String {
+        let topics = HELP_TOPICS.iter().map(|(t, _)| format!("{}{}", self.prefix, t)).collect::<Vec<_>>().join(" ");
+        let question = question.trim();
+        let topic = question.strip_prefix(self.prefix).unwrap_or(question).split_whitespace().next().unwrap_or_default().to_lowercase();
+        if topic.is_empty() || ["help", "hi", "hello", "?"].contains(&topic.as_str()) {
+            return fill(self.text.help_intro, &[("name", name), ("topics", &topics)]);
+        }
+        match HELP_TOPICS.iter().find(|(t, _)| *t == topic) {
+            Some((_, usage)) => super::usage_text(usage(self.text)),
+            None => fill(self.text.help_unknown, &[("topic", &topic), ("topics", &topics)]),
+        }
+    }
+}
+
+impl Service for HelpBot {
+    fn handle(&mut self, name: &str, msg: ServerMessage) -> Vec<ClientMessage> {
+        match msg {
+            ServerMessage::PrivateMessage { from, content, message_id, encrypted: false, .. } => vec![ClientMessage::Private {
+                to: vec![from], content: self.answer(name, &content), ephemeral: false, ttl_secs: None, signature: None, encrypted: false,
+                reply_to: Some(message_id),
+            }],
+            _ => Vec::new(),
+        }
+    }
+}
+
+// 以 name 注册一个服务用户并启动它的任务, 名字已被占用时返回错误
+pub async fn spawn_service(name: &str, mut service: impl Service, state: &Arc<Mutex<ServerState>>) -> Result<()> {
+    let (tx, rx) = mpsc::channel(100);
+    let mut inbox = {
+        let mut st = state.lock().await;
+        if st.is_taken(name) {
+            anyhow::bail!("service user {:?}: name is already taken", name);
+        }
+        st.clients.insert(name.to_string(), tx);
+        st.inbox(name, rx)
+    };
+
+    // 服务发出的消息交给另一个任务处理, 避免发给自己的消息塞满通道时互相等待
+    let (out_tx, mut out_rx) = mpsc::unbounded_channel();
+    let router_state = state.clone();
+    let router_name = name.to_string();
+    tokio::spawn(async move {
+        while let Some(msg) = out_rx.recv().await {
+            let _ = route(&router_name, msg, &router_state).await;
+        }
+    });
+
+    // 收到关闭通知或通道被移除(服务器关闭)时结束
+    let name = name.to_string();
+    tokio::spawn(async move {
+        while let Some(Message::Servermsg(msg)) = inbox.recv().await {
+            if matches!(msg, ServerMessage::Exit { .. }) {
+                break;
+            }
+            for reply in service.handle(&name, msg) {
+                let _ = out_tx.send(reply);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn helpbot_explains_commands() {
+        let bot = HelpBot::new(Locale::En, '/');
+        assert_eq!(bot.answer("helpbot", "/block"), "Usage: /block <user>");
+        assert_eq!(bot.answer("helpbot", "  Poll-Close please"), "Usage: /poll-close <poll_id>");
+        assert!(bot.answer("helpbot", "roll").contains("at most 100 dice"));
+        assert!(bot.answer("helpbot", "hi").starts_with("Hi, I'm helpbot."));
+        assert!(bot.answer("helpbot", "").contains("/quiet-joins"));
+        assert!(bot.answer("helpbot", "dance").starts_with("I don't know a command called \"dance\""));
+        assert!(HelpBot::new(Locale::En, '!').answer("helpbot", "?").contains("!whois"));
+    }
+}
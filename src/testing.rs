@@ -0,0 +1,181 @@
+/* 测试工具(需要开启 testing feature): 在内存管道上运行服务器, 用脚本化的客户端驱动完整流程
+    let server = TestServer::new();
+    let mut alice = server.join("alice").await;
+    let mut bob = server.join("bob").await;
+    alice.say("hello").await;
+    bob.expect(|m| matches!(m, ServerMessage::BroadcastMessage { content, .. } if content == "hello")).await;
+    每个客户端走的是和真实连接相同的 handle_client, 只是把 TCP 换成了 tokio::io::duplex
+*/
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::Duration;
+use anyhow::Result;
+use futures::{SinkExt, StreamExt};
+use tokio::io::DuplexStream;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio_util::codec::Framed;
+use crate::common::codec::LengthCodec;
+use crate::common::{ClientMessage, Message, ServerMessage};
+use crate::server::{handle_client, ServerState};
+
+// expect 等待一条消息的最长时间
+pub const EXPECT_TIMEOUT: Duration = Duration::from_secs(2);
+// expect_none / collect 认为不会再有消息到达的静默时间
+pub const QUIET_PERIOD: Duration = Duration::from_millis(200);
+// 每条内存管道的缓冲区大小
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+// 在内存中运行的服务器, 只有共享状态, 不监听端口
+pub struct TestServer {
+    state: Arc<Mutex<ServerState>>,
+    next_port: AtomicU16,
+}
+
+impl Default for TestServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TestServer {
+    pub fn new() -> Self {
+        Self::with_state(ServerState::default())
+    }
+
+    // 使用预先配置好的状态, 例如设置了过滤词或反刷屏策略
+    pub fn with_state(state: ServerState) -> Self {
+        TestServer { state: Arc::new(Mutex::new(state)), next_port: AtomicU16::new(40000) }
+    }
+
+    pub fn state(&self) -> Arc<Mutex<ServerState>> {
+        self.state.clone()
+    }
+
+    // 从 127.0.0.1 建立一条连接并发送 Register, 不等待回复; 每条连接使用不同的端口
+    pub async fn connect(&self, name: &str) -> TestClient {
+        let port = self.next_port.fetch_add(1, Ordering::Relaxed);
+        self.connect_from(name, SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)).await
+    }
+
+    // 从指定地址建立连接, 用于测试按 IP 的限制
+    pub async fn connect_from(&self, name: &str, addr: SocketAddr) -> TestClient {
+        let (client_io, server_io) = tokio::io::duplex(PIPE_CAPACITY);
+        let task = tokio::spawn(handle_client(server_io, addr, self.state.clone()));
+        let mut client = TestClient { name: name.to_string(), framed: Framed::new(client_io, LengthCodec::new()), received: Vec::new(), next_id: 1, task };
+        client.send(ClientMessage::Register { name: name.to_string(), capabilities: vec![], public_key: None, encryption_key: None, session: None }).await;
+        client
+    }
+
+    // 连接并等到自己的加入通知, 之后收到的消息都是注册完成后产生的
+    pub async fn join(&self, name: &str) -> TestClient {
+        let mut client = self.connect(name).await;
+        client.expect(|m| matches!(m, ServerMessage::Welcome { .. })).await;
+        client.expect(|m| matches!(m, ServerMessage::System { content } if content.starts_with(name) && content.contains("joined"))).await;
+        client
+    }
+}
+
+/* 脚本化的测试客户端
+    received 按顺序记录收到的每一条消息, 包括 expect 跳过的, 方便测试结束后检查整个过程
+    不开启压缩, 所以收发的帧都是明文 JSON
+*/
+pub struct TestClient {
+    name: String,
+    framed: Framed<DuplexStream, LengthCodec>,
+    received: Vec<ServerMessage>,
+    next_id: u64,
+    task: JoinHandle<Result<()>>,
+}
+
+impl TestClient {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // 到目前为止收到的全部消息
+    pub fn received(&self) -> &[ServerMessage] {
+        &self.received
+    }
+
+    pub async fn send(&mut self, msg: ClientMessage) {
+        self.framed.send(Message::Clientmsg(msg)).await.expect("connection closed while sending");
+    }
+
+    // 像在终端里输入一行那样发送, 由服务器解析成指令、私聊或群发
+    pub async fn say(&mut self, text: &str) {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.send(ClientMessage::Raw { text: text.to_string(), id }).await;
+    }
+
+    // 依次发送多行输入, 然后收集直到静默的所有回复
+    pub async fn script(&mut self, lines: &[&str]) -> Vec<ServerMessage> {
+        for line in lines {
+            self.say(line).await;
+        }
+        self.collect().await
+    }
+
+    // 读取下一条消息, 连接关闭时返回 None
+    async fn next(&mut self) -> Option<ServerMessage> {
+        loop {
+            match self.framed.next().await {
+                Some(Ok(Message::Servermsg(msg))) => {
+                    self.received.push(msg.clone());
+                    return Some(msg);
+                }
+                Some(Ok(Message::Clientmsg(_))) => {}
+                Some(Err(e)) => panic!("{}: decode error: {}", self.name, e),
+                None => return None,
+            }
+        }
+    }
+
+    // 读取消息直到满足条件, 超时或连接关闭时测试失败
+    pub async fn expect(&mut self, pred: impl Fn(&ServerMessage) -> bool) -> ServerMessage {
+        let name = self.name.clone();
+        let wait = async {
+            loop {
+                match self.next().await {
+                    Some(msg) if pred(&msg) => return msg,
+                    Some(_) => {}
+                    None => panic!("{}: connection ended while waiting for a message", name),
+                }
+            }
+        };
+        tokio::time::timeout(EXPECT_TIMEOUT, wait).await.unwrap_or_else(|_| panic!("{}: timed out waiting for a message", name))
+    }
+
+    // 确认静默期内没有满足条件的消息到达
+    pub async fn expect_none(&mut self, pred: impl Fn(&ServerMessage) -> bool) {
+        for msg in self.collect().await {
+            if pred(&msg) {
+                panic!("{}: unexpected message: {:?}", self.name, msg);
+            }
+        }
+    }
+
+    // 收集消息直到 QUIET_PERIOD 内没有新消息或连接关闭
+    pub async fn collect(&mut self) -> Vec<ServerMessage> {
+        let mut messages = Vec::new();
+        while let Ok(Some(msg)) = tokio::time::timeout(QUIET_PERIOD, self.next()).await {
+            messages.push(msg);
+        }
+        messages
+    }
+
+    // 发送 Quit 并等待服务器端的连接处理结束, 返回 handle_client 的结果
+    pub async fn quit(mut self, reason: Option<&str>) -> Result<()> {
+        self.send(ClientMessage::Quit { reason: reason.map(str::to_string) }).await;
+        while self.next().await.is_some() {}
+        self.task.await?
+    }
+
+    // 不告别直接断开, 相当于客户端进程被杀掉
+    pub async fn disconnect(self) -> Result<()> {
+        drop(self.framed);
+        self.task.await?
+    }
+}
@@ -0,0 +1,260 @@
+// 通过内存管道用 ChatClient 与服务器交互, 确认库接口可以直接用来写机器人
+use futures::{SinkExt, StreamExt};
+use rustchat::client::{self, ChatClient, RefuseReason, Refused};
+use rustchat::common::codec::{LengthCodec, MAX_FRAME_LEN};
+use rustchat::common::command::Command;
+use rustchat::common::signing::{self, Identity};
+use rustchat::common::{ClientMessage, Message, ServerMessage, CAP_COMPRESS, CAP_E2E};
+use rustchat::server::{handle_client, ServerState, SpamPolicy};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::DuplexStream;
+use tokio::sync::Mutex;
+use tokio_util::codec::Framed;
+
+async fn connect(name: &str, state: &Arc<Mutex<ServerState>>) -> anyhow::Result<ChatClient<DuplexStream>> {
+    connect_with(name, &[CAP_COMPRESS], state).await
+}
+
+async fn connect_with(name: &str, capabilities: &[&str], state: &Arc<Mutex<ServerState>>) -> anyhow::Result<ChatClient<DuplexStream>> {
+    let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(handle_client(server_io, "127.0.0.1:40000".parse().unwrap(), state.clone()));
+    let capabilities = capabilities.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+    ChatClient::handshake(client_io, name, &capabilities).await
+}
+
+// 读取消息直到满足条件, 超时则测试失败
+async fn expect(client: &mut ChatClient<DuplexStream>, pred: impl Fn(&ServerMessage) -> bool) -> ServerMessage {
+    let wait = async {
+        loop {
+            match client.next_message().await {
+                Some(Ok(msg)) if pred(&msg) => return msg,
+                Some(Ok(_)) => {}
+                other => panic!("connection ended: {:?}", other),
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(2), wait).await.expect("timed out waiting for message")
+}
+
+#[tokio::test]
+async fn bot_can_broadcast_and_whisper() {
+    let state = Arc::new(Mutex::new(ServerState::default()));
+    let mut bot = connect("bot", &state).await.unwrap();
+    assert_eq!(bot.server_name(), "rustchat");
+    let mut alice = connect("alice", &state).await.unwrap();
+    expect(&mut bot, |m| matches!(m, ServerMessage::System { content } if content == "alice has joined the chat")).await;
+
+    let id = bot.send_broadcast("beep").await.unwrap();
+    let echo = expect(&mut bot, |m| matches!(m, ServerMessage::BroadcastMessage { .. })).await;
+    assert!(matches!(echo, ServerMessage::BroadcastMessage { id: got, content, .. } if got == id && content == "beep"));
+    expect(&mut alice, |m| matches!(m, ServerMessage::BroadcastMessage { from, .. } if from == "bot")).await;
+
+    bot.send_private(&["alice"], "boop").await.unwrap();
+    let msg = expect(&mut alice, |m| matches!(m, ServerMessage::PrivateMessage { .. })).await;
+    assert!(matches!(msg, ServerMessage::PrivateMessage { from, content, .. } if from == "bot" && content == "boop"));
+
+    bot.quit(Some("done")).await.unwrap();
+    expect(&mut alice, |m| matches!(m, ServerMessage::System { content } if content == "bot has left the chat (done)")).await;
+}
+
+#[tokio::test]
+async fn health_check_answers_without_registering() {
+    let state = Arc::new(Mutex::new(ServerState::default()));
+    let _bot = connect("bot", &state).await.unwrap();
+
+    let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+    let server = tokio::spawn(handle_client(server_io, "127.0.0.1:40001".parse().unwrap(), state.clone()));
+    assert_eq!(client::check_health_on(client_io).await.unwrap(), "rustchat");
+    // 服务器回复后就结束了这条连接, 在线用户不变
+    server.await.unwrap().unwrap();
+    let st = state.lock().await;
+    assert_eq!(st.clients.keys().collect::<Vec<_>>(), ["bot"]);
+}
+
+#[tokio::test]
+async fn oversized_message_is_refused_without_dropping_the_connection() {
+    let state = Arc::new(Mutex::new(ServerState::default()));
+    let mut bot = connect("bot", &state).await.unwrap();
+
+    let err = bot.send_broadcast(&"x".repeat(MAX_FRAME_LEN)).await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    // 什么也没有发出, 连接仍然可用
+    bot.send_broadcast("still here").await.unwrap();
+    expect(&mut bot, |m| matches!(m, ServerMessage::BroadcastMessage { content, .. } if content == "still here")).await;
+}
+
+#[tokio::test]
+async fn split_halves_work_independently() {
+    let state = Arc::new(Mutex::new(ServerState::default()));
+    let (mut sender, mut incoming) = connect("bot", &state).await.unwrap().split();
+    assert_eq!(sender.name(), "bot");
+
+    let reader = tokio::spawn(async move {
+        while let Some(Ok(msg)) = incoming.next().await {
+            if let ServerMessage::UserList { content, .. } = msg {
+                return content;
+            }
+        }
+        panic!("connection ended before the user list arrived");
+    });
+    sender.send_raw("/users").await.unwrap();
+    let users = tokio::time::timeout(Duration::from_secs(2), reader).await.unwrap().unwrap();
+    assert_eq!(users, vec!["bot".to_string()]);
+}
+
+#[tokio::test]
+async fn refused_registration_is_an_error() {
+    let state = Arc::new(Mutex::new(ServerState::default()));
+    let _first = connect("bot", &state).await.unwrap();
+    let err = connect("bot", &state).await.err().expect("duplicate name should be refused");
+    assert!(err.to_string().contains("registration refused"), "{}", err);
+    assert!(err.downcast_ref::<Refused>().is_some_and(Refused::is_name_taken));
+    let err = connect("system", &state).await.err().expect("reserved name should be refused");
+    let refused = err.downcast_ref::<Refused>().unwrap();
+    assert!(!refused.is_name_taken());
+    assert_eq!(refused.reason, RefuseReason::NameReserved);
+}
+
+#[tokio::test]
+async fn signed_private_messages_can_be_verified() {
+    let state = Arc::new(Mutex::new(ServerState::default()));
+    let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(handle_client(server_io, "127.0.0.1:40000".parse().unwrap(), state.clone()));
+    let identity = Identity::generate();
+    let mut bot = ChatClient::handshake_signed(client_io, "bot", &[], identity.clone()).await.unwrap();
+    let mut alice = connect("alice", &state).await.unwrap();
+    assert!(bot.is_signing());
+    assert!(!alice.is_signing());
+
+    bot.send_private(&["alice"], "signed boop").await.unwrap();
+    let msg = expect(&mut alice, |m| matches!(m, ServerMessage::PrivateMessage { .. })).await;
+    assert_eq!(signing::verify_message(&msg), Some(true));
+    assert!(matches!(&msg, ServerMessage::PrivateMessage { public_key: Some(key), .. } if *key == identity.public_key()));
+
+    // 没有注册公钥的用户附上的签名无法通过验证
+    let forged = ClientMessage::Private {
+        to: vec!["bot".into()], content: "hi".into(), ephemeral: false, ttl_secs: None,
+        signature: Some(identity.sign("alice", "hi")), encrypted: false, reply_to: None,
+    };
+    alice.send(forged).await.unwrap();
+    let msg = expect(&mut bot, |m| matches!(m, ServerMessage::PrivateMessage { .. })).await;
+    assert_eq!(signing::verify_message(&msg), Some(false));
+}
+
+#[tokio::test]
+async fn encrypted_private_messages_are_opaque_to_the_server() {
+    let state = Arc::new(Mutex::new(ServerState::default()));
+    let mut alice = connect_with("alice", &[CAP_E2E], &state).await.unwrap();
+    let mut bob = connect_with("bob", &[CAP_E2E], &state).await.unwrap();
+    let mut carol = connect("carol", &state).await.unwrap();
+    // alice 收到 bob 的公钥后才能加密
+    expect(&mut alice, |m| matches!(m, ServerMessage::EncryptionKey { name, .. } if name == "bob")).await;
+    assert!(alice.can_encrypt_to("bob"));
+    assert!(!alice.can_encrypt_to("carol"));
+
+    // 没有 carol 的公钥, 默认整条都不发, 明文要明确允许
+    let err = alice.send_private(&["bob", "carol"], "the cake is a lie").await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("no encryption key for carol"), "{}", err);
+    alice.allow_plaintext(true);
+    alice.send_private(&["bob", "carol"], "the cake is a lie").await.unwrap();
+    let msg = expect(&mut bob, |m| matches!(m, ServerMessage::PrivateMessage { .. })).await;
+    assert!(matches!(msg, ServerMessage::PrivateMessage { content, encrypted: true, .. } if content == "the cake is a lie"));
+    let msg = expect(&mut carol, |m| matches!(m, ServerMessage::PrivateMessage { .. })).await;
+    assert!(matches!(msg, ServerMessage::PrivateMessage { content, encrypted: false, .. } if content == "the cake is a lie"));
+
+    // 服务器的历史里只有占位文字
+    bob.send_command(Command::History { page: None, since: None }).await.unwrap();
+    let msg = expect(&mut bob, |m| matches!(m, ServerMessage::History { .. })).await;
+    let ServerMessage::History { content, .. } = msg else { unreachable!() };
+    assert!(content.contains("alice → You: (encrypted)"), "{}", content);
+    assert!(!content.contains("cake"), "{}", content);
+}
+
+#[tokio::test]
+async fn a_changed_encryption_key_is_not_trusted() {
+    let state = Arc::new(Mutex::new(ServerState::default()));
+    let mut alice = connect_with("alice", &[CAP_E2E], &state).await.unwrap();
+    let bob = connect_with("bob", &[CAP_E2E], &state).await.unwrap();
+    expect(&mut alice, |m| matches!(m, ServerMessage::EncryptionKey { name, .. } if name == "bob")).await;
+    assert!(alice.can_encrypt_to("bob"));
+
+    // 换了一个 bob, 公钥也不同了: 不再给他加密, 也不退回明文
+    bob.quit(None).await.unwrap();
+    expect(&mut alice, |m| matches!(m, ServerMessage::System { content } if content == "bob has left the chat")).await;
+    let _bob = connect_with("bob", &[CAP_E2E], &state).await.unwrap();
+    expect(&mut alice, |m| matches!(m, ServerMessage::EncryptionKey { name, .. } if name == "bob")).await;
+    assert!(!alice.can_encrypt_to("bob"));
+    let err = alice.send_private(&["bob"], "still there?").await.unwrap_err();
+    assert!(err.to_string().contains("the encryption key of bob changed"), "{}", err);
+}
+
+#[tokio::test]
+async fn throttle_delays_the_next_send() {
+    let mut st = ServerState::default();
+    st.spam_policy = SpamPolicy { rate_limit: 1, rate_window: Duration::from_millis(300), max_strikes: 0, ..SpamPolicy::default() };
+    let state = Arc::new(Mutex::new(st));
+    let mut bot = connect("bot", &state).await.unwrap();
+
+    bot.send_broadcast("one").await.unwrap();
+    bot.send_broadcast("two").await.unwrap();
+    let msg = expect(&mut bot, |m| matches!(m, ServerMessage::Throttle { .. })).await;
+    assert!(matches!(msg, ServerMessage::Throttle { retry_after_ms } if retry_after_ms > 0 && retry_after_ms <= 300), "{:?}", msg);
+
+    // 下一条等到窗口空出来才发出, 因此不会再被拒绝
+    let started = Instant::now();
+    bot.send_broadcast("three").await.unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(150), "{:?}", started.elapsed());
+    expect(&mut bot, |m| matches!(m, ServerMessage::BroadcastMessage { content, .. } if content == "three")).await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn huge_throttles_are_capped_instead_of_overflowing() {
+    // 一个只会要求客户端等待很久的服务器
+    let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(async move {
+        let mut server = Framed::new(server_io, LengthCodec::new());
+        server.next().await;
+        let welcome = ServerMessage::Welcome { capabilities: Vec::new(), server_name: "slow".into(), session: String::new() };
+        server.send(Message::Servermsg(welcome)).await.unwrap();
+        server.send(Message::Servermsg(ServerMessage::Throttle { retry_after_ms: u64::MAX })).await.unwrap();
+        while server.next().await.is_some() {}
+    });
+    let mut bot = ChatClient::handshake(client_io, "bot", &[]).await.unwrap();
+    assert!(matches!(bot.next_message().await, Some(Ok(ServerMessage::Throttle { .. }))));
+
+    let started = tokio::time::Instant::now();
+    bot.send_broadcast("still here").await.unwrap();
+    assert!(started.elapsed() <= Duration::from_secs(60), "{:?}", started.elapsed());
+}
+
+#[tokio::test]
+async fn resumed_session_replays_only_unacknowledged_messages() {
+    let state = Arc::new(Mutex::new(ServerState::default()));
+    let mut alice = connect("alice", &state).await.unwrap();
+    let mut bob = connect("bob", &state).await.unwrap();
+    assert!(!alice.session().is_empty());
+
+    bob.send_broadcast("first").await.unwrap();
+    expect(&mut alice, |m| matches!(m, ServerMessage::BroadcastMessage { content, .. } if content == "first")).await;
+    alice.acknowledge().await.unwrap();
+    // alice 还没取出 second 就断线了
+    bob.send_broadcast("second").await.unwrap();
+    expect(&mut bob, |m| matches!(m, ServerMessage::BroadcastMessage { content, .. } if content == "second")).await;
+    let session = alice.session().to_string();
+    alice.quit(None).await.unwrap();
+    expect(&mut bob, |m| matches!(m, ServerMessage::System { content } if content == "alice has left the chat")).await;
+    bob.send_broadcast("third").await.unwrap();
+    expect(&mut bob, |m| matches!(m, ServerMessage::BroadcastMessage { content, .. } if content == "third")).await;
+
+    let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(handle_client(server_io, "127.0.0.1:40000".parse().unwrap(), state.clone()));
+    let mut alice = ChatClient::handshake_resume(client_io, "alice", &[], None, &session).await.unwrap();
+    assert_eq!(alice.session(), session);
+    expect(&mut alice, |m| matches!(m, ServerMessage::System { content } if content == "Welcome back, resending 2 messages you missed")).await;
+    let msg = expect(&mut alice, |m| matches!(m, ServerMessage::History { .. })).await;
+    let ServerMessage::History { content, .. } = msg else { unreachable!() };
+    assert!(content.contains("second") && content.contains("third"), "{}", content);
+    assert!(!content.contains("first"), "{}", content);
+}
@@ -0,0 +1,216 @@
+// 消息序列化往返的性质测试: 任意消息经 encode 再 decode 后应与原消息完全相同
+use bytes::BytesMut;
+use proptest::prelude::*;
+use rustchat::common::codec::LengthCodec;
+use rustchat::common::command::{Command, Dice, DumpFormat, NameColor};
+use rustchat::common::export::{ExportKind, ExportedMessage};
+use rustchat::common::files::{AttachmentMeta, FileMeta};
+use rustchat::common::{ClientMessage, Message, RefuseReason, ServerMessage, ShutdownReason};
+use tokio_util::codec::{Decoder, Encoder};
+
+// 任意 unicode 字符串, 包括空串
+fn text() -> impl Strategy<Value = String> {
+    prop_oneof![
+        Just(String::new()),
+        any::<String>(),
+        // 较长的字符串
+        prop::collection::vec(any::<char>(), 0..4096).prop_map(|cs| cs.into_iter().collect()),
+    ]
+}
+
+fn names() -> impl Strategy<Value = Vec<String>> {
+    prop::collection::vec(text(), 0..5)
+}
+
+fn file_meta() -> impl Strategy<Value = FileMeta> {
+    (text(), any::<u64>(), text()).prop_map(|(name, size, sha256)| FileMeta { name, size, sha256 })
+}
+
+fn attachments() -> impl Strategy<Value = Vec<AttachmentMeta>> {
+    let attachment = (text(), any::<u64>(), text()).prop_map(|(name, size, content_type)| AttachmentMeta { name, size, content_type });
+    prop::collection::vec(attachment, 0..3)
+}
+
+fn name_color() -> impl Strategy<Value = NameColor> {
+    prop::sample::select(NameColor::ALL.to_vec())
+}
+
+fn exported_message() -> impl Strategy<Value = ExportedMessage> {
+    let kind = prop::sample::select(vec![ExportKind::Broadcast, ExportKind::Private, ExportKind::Command]);
+    (any::<u64>(), any::<u64>(), kind, text(), names(), text(), any::<Option<u64>>(), attachments()).prop_map(
+        |(message_id, sent_at, kind, from, to, content, reply_to, attachments)| {
+            ExportedMessage { message_id, sent_at, kind, from, to, content, reply_to, attachments }
+        }
+    )
+}
+
+fn shutdown_reason() -> impl Strategy<Value = ShutdownReason> {
+    prop_oneof![
+        Just(ShutdownReason::Shutdown),
+        Just(ShutdownReason::Restart),
+        any::<u64>().prop_map(|until| ShutdownReason::Maintenance { until }),
+    ]
+}
+
+fn refuse_reason() -> impl Strategy<Value = RefuseReason> {
+    prop_oneof![
+        Just(RefuseReason::InvalidName),
+        Just(RefuseReason::NameReserved),
+        Just(RefuseReason::NameTaken),
+        Just(RefuseReason::TooManyNames),
+        Just(RefuseReason::Other),
+    ]
+}
+
+fn command() -> impl Strategy<Value = Command> {
+    let dice = (any::<u32>(), any::<u32>(), any::<i64>()).prop_map(|(count, sides, modifier)| Dice { count, sides, modifier });
+    prop_oneof![
+        Just(Command::Users),
+        (any::<Option<usize>>(), any::<Option<u64>>()).prop_map(|(page, since)| Command::History { page, since }),
+        text().prop_map(Command::Block),
+        text().prop_map(Command::Unblock),
+        text().prop_map(Command::Whois),
+        text().prop_map(Command::Seen),
+        prop::option::of(dice).prop_map(Command::Roll),
+        (text(), names()).prop_map(|(question, options)| Command::Poll { question, options }),
+        (any::<u64>(), text()).prop_map(|(poll_id, choice)| Command::Vote { poll_id, choice }),
+        any::<u64>().prop_map(Command::PollClose),
+        Just(Command::Whoami),
+        prop::option::of(name_color()).prop_map(Command::Color),
+        Just(Command::QuietJoins),
+        Just(Command::Stats),
+        Just(Command::Export),
+        text().prop_map(Command::Import),
+        any::<bool>().prop_map(|confirm| Command::ClearDmHistory { confirm }),
+        (text(), names()).prop_map(|(name, args)| Command::Action { name, args }),
+        prop::option::of(prop_oneof![Just(DumpFormat::Pretty), Just(DumpFormat::Json)]).prop_map(Command::DumpState),
+    ]
+}
+
+fn client_message() -> impl Strategy<Value = ClientMessage> {
+    prop_oneof![
+        (text(), any::<u64>(), any::<bool>(), any::<Option<u64>>(), any::<Option<u64>>(), attachments()).prop_map(
+            |(content, id, ephemeral, ttl_secs, reply_to, attachments)| ClientMessage::Broadcast { content, id, ephemeral, ttl_secs, reply_to, attachments }
+        ),
+        (names(), text(), any::<bool>(), any::<Option<u64>>(), prop::option::of(text()), any::<bool>(), any::<Option<u64>>()).prop_map(
+            |(to, content, ephemeral, ttl_secs, signature, encrypted, reply_to)| {
+                ClientMessage::Private { to, content, ephemeral, ttl_secs, signature, encrypted, reply_to }
+            }
+        ),
+        command().prop_map(|command| ClientMessage::Command { command }),
+        (text(), names(), prop::option::of(text()), prop::option::of(text()), prop::option::of(text())).prop_map(
+            |(name, capabilities, public_key, encryption_key, session)| ClientMessage::Register { name, capabilities, public_key, encryption_key, session }
+        ),
+        any::<u64>().prop_map(|message_id| ClientMessage::ReadReceipt { message_id }),
+        any::<u64>().prop_map(|message_id| ClientMessage::Ack { message_id }),
+        prop::option::of(text()).prop_map(|reason| ClientMessage::Quit { reason }),
+        (text(), any::<u64>()).prop_map(|(text, id)| ClientMessage::Raw { text, id }),
+        file_meta().prop_map(|file| ClientMessage::FileOffer { file }),
+        Just(ClientMessage::Health),
+    ]
+}
+
+fn server_message() -> impl Strategy<Value = ServerMessage> {
+    prop_oneof![
+        (text(), text(), any::<u64>(), any::<u64>(), any::<Option<u64>>(), attachments()).prop_map(
+            |(from, content, id, message_id, reply_to, attachments)| ServerMessage::BroadcastMessage { from, content, id, message_id, reply_to, attachments }
+        ),
+        (text(), text(), text(), any::<u64>(), prop::option::of(text()), prop::option::of(text()), any::<bool>(), any::<Option<u64>>()).prop_map(
+            |(from, to, content, message_id, signature, public_key, encrypted, reply_to)| {
+                ServerMessage::PrivateMessage { from, to, content, message_id, signature, public_key, encrypted, reply_to }
+            }
+        ),
+        (names(), text()).prop_map(|(content, to)| ServerMessage::UserList { content, to }),
+        (text(), text()).prop_map(|(content, to)| ServerMessage::Error { content, to }),
+        text().prop_map(|content| ServerMessage::System { content }),
+        (text(), text()).prop_map(|(content, to)| ServerMessage::History { content, to }),
+        any::<u64>().prop_map(|message_id| ServerMessage::Deleted { message_id }),
+        (any::<u64>(), text()).prop_map(|(message_id, by)| ServerMessage::Read { message_id, by }),
+        (names(), text(), text()).prop_map(|(capabilities, server_name, session)| ServerMessage::Welcome { capabilities, server_name, session }),
+        (refuse_reason(), text()).prop_map(|(reason, content)| ServerMessage::RegisterRefused { reason, content }),
+        (text(), text()).prop_map(|(name, key)| ServerMessage::EncryptionKey { name, key }),
+        (text(), file_meta()).prop_map(|(from, file)| ServerMessage::FileOffer { from, file }),
+        any::<u64>().prop_map(|retry_after_ms| ServerMessage::Throttle { retry_after_ms }),
+        (text(), prop::option::of(name_color())).prop_map(|(name, color)| ServerMessage::NameColor { name, color }),
+        text().prop_map(|server_name| ServerMessage::Healthy { server_name }),
+        shutdown_reason().prop_map(|reason| ServerMessage::Exit { reason }),
+        (prop::collection::vec(exported_message(), 0..3), any::<bool>(), names()).prop_map(|(messages, done, blocked)| ServerMessage::Export { messages, done, blocked }),
+        any::<u64>().prop_map(|count| ServerMessage::UserCount { count }),
+    ]
+}
+
+fn message() -> impl Strategy<Value = Message> {
+    prop_oneof![
+        client_message().prop_map(Message::Clientmsg),
+        server_message().prop_map(Message::Servermsg),
+    ]
+}
+
+// 新增消息类型时这里会编译失败, 提醒同时补充上面的生成策略
+#[allow(dead_code)]
+fn all_variants_covered(msg: &Message) {
+    match msg {
+        Message::Clientmsg(m) => match m {
+            ClientMessage::Broadcast { .. }
+            | ClientMessage::Private { .. }
+            | ClientMessage::Command { .. }
+            | ClientMessage::Register { .. }
+            | ClientMessage::ReadReceipt { .. }
+            | ClientMessage::Ack { .. }
+            | ClientMessage::Quit { .. }
+            | ClientMessage::Raw { .. }
+            | ClientMessage::FileOffer { .. }
+            | ClientMessage::Health => {}
+        },
+        Message::Servermsg(m) => match m {
+            ServerMessage::BroadcastMessage { .. }
+            | ServerMessage::PrivateMessage { .. }
+            | ServerMessage::UserList { .. }
+            | ServerMessage::Error { .. }
+            | ServerMessage::System { .. }
+            | ServerMessage::History { .. }
+            | ServerMessage::Deleted { .. }
+            | ServerMessage::Read { .. }
+            | ServerMessage::Welcome { .. }
+            | ServerMessage::RegisterRefused { .. }
+            | ServerMessage::EncryptionKey { .. }
+            | ServerMessage::FileOffer { .. }
+            | ServerMessage::Throttle { .. }
+            | ServerMessage::NameColor { .. }
+            | ServerMessage::Healthy { .. }
+            | ServerMessage::Exit { .. }
+            | ServerMessage::Export { .. }
+            | ServerMessage::UserCount { .. } => {}
+        },
+    }
+}
+
+fn round_trip(msg: &Message, compress: bool) -> Message {
+    let mut codec = LengthCodec::new();
+    codec.set_compression(compress);
+    let mut buf = BytesMut::new();
+    codec.encode(msg.clone(), &mut buf).unwrap();
+    let decoded = codec.decode(&mut buf).unwrap().expect("a complete frame");
+    assert!(buf.is_empty(), "decoder left {} bytes behind", buf.len());
+    decoded
+}
+
+proptest! {
+    #[test]
+    fn encode_then_decode_is_identity(msg in message(), compress in any::<bool>()) {
+        prop_assert_eq!(round_trip(&msg, compress), msg);
+    }
+
+    #[test]
+    fn frames_decode_in_order_from_one_buffer(msgs in prop::collection::vec(message(), 1..8)) {
+        let mut codec = LengthCodec::new();
+        let mut buf = BytesMut::new();
+        for msg in &msgs {
+            codec.encode(msg.clone(), &mut buf).unwrap();
+        }
+        for msg in &msgs {
+            prop_assert_eq!(&codec.decode(&mut buf).unwrap().expect("a complete frame"), msg);
+        }
+        prop_assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+}
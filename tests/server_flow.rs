@@ -0,0 +1,930 @@
+// 通过内存管道驱动完整的注册 / 广播 / 私聊 / 命令流程
+use futures::{SinkExt, StreamExt};
+use rustchat::common::codec::LengthCodec;
+use rustchat::common::command::{self as cmdline, Command, NameColor};
+use rustchat::common::export::{ExportKind, ExportedMessage, HistoryExport};
+use rustchat::common::files::{AttachmentMeta, FileMeta};
+use rustchat::common::signing::Identity;
+use rustchat::common::{ClientMessage, Message, RefuseReason, ServerMessage, CAP_SIGN};
+use rustchat::server::{handle_client, spawn_service, Echo, PresenceFormat, ServerState, SpamPolicy};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, DuplexStream};
+use tokio::sync::Mutex;
+use tokio_util::codec::Framed;
+
+type Client = Framed<DuplexStream, LengthCodec>;
+
+fn new_state() -> Arc<Mutex<ServerState>> {
+    Arc::new(Mutex::new(ServerState::default()))
+}
+
+// 建立一条到服务器的内存连接并发送 Register, 不等待回复
+async fn register(name: &str, state: &Arc<Mutex<ServerState>>) -> Client {
+    register_with(name, None, state).await
+}
+
+// 注册, 给出签名密钥时同时声明 sign 能力并附上公钥
+async fn register_with(name: &str, identity: Option<&Identity>, state: &Arc<Mutex<ServerState>>) -> Client {
+    let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+    let addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    tokio::spawn(handle_client(server_io, addr, state.clone()));
+    let mut client = Framed::new(client_io, LengthCodec::new());
+    let capabilities = identity.map(|_| vec![CAP_SIGN.to_string()]).unwrap_or_default();
+    let public_key = identity.map(Identity::public_key);
+    client
+        .send(Message::Clientmsg(ClientMessage::Register { name: name.into(), capabilities, public_key, encryption_key: None, session: None }))
+        .await
+        .unwrap();
+    client
+}
+
+// 注册并等到自己的加入通知, 之后的消息都是注册完成后产生的
+async fn join(name: &str, state: &Arc<Mutex<ServerState>>) -> Client {
+    let mut client = register(name, state).await;
+    let joined = format!("{} has joined the chat", name);
+    expect(&mut client, |m| matches!(m, ServerMessage::System { content } if *content == joined)).await;
+    client
+}
+
+// 把名字配置成管理员, 用对应的签名密钥注册并等到加入通知
+async fn join_admin(name: &str, state: &Arc<Mutex<ServerState>>) -> Client {
+    let identity = Identity::generate();
+    state.lock().await.admins.insert(name.into(), identity.public_key());
+    let mut client = register_with(name, Some(&identity), state).await;
+    let joined = format!("{} has joined the chat", name);
+    expect(&mut client, |m| matches!(m, ServerMessage::System { content } if *content == joined)).await;
+    client
+}
+
+// 读取消息直到满足条件, 超时则测试失败
+async fn expect(client: &mut Client, pred: impl Fn(&ServerMessage) -> bool) -> ServerMessage {
+    let wait = async {
+        loop {
+            match client.next().await {
+                Some(Ok(Message::Servermsg(msg))) if pred(&msg) => return msg,
+                Some(Ok(_)) => {}
+                other => panic!("connection ended: {:?}", other),
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(2), wait).await.expect("timed out waiting for message")
+}
+
+// 确认一段时间内没有满足条件的消息到达
+async fn expect_none(client: &mut Client, pred: impl Fn(&ServerMessage) -> bool) {
+    let wait = async {
+        while let Some(Ok(Message::Servermsg(msg))) = client.next().await {
+            if pred(&msg) {
+                panic!("unexpected message: {:?}", msg);
+            }
+        }
+    };
+    let _ = tokio::time::timeout(Duration::from_millis(200), wait).await;
+}
+
+async fn send(client: &mut Client, msg: ClientMessage) {
+    client.send(Message::Clientmsg(msg)).await.unwrap();
+}
+
+// 每条群发用不同的 id, 同一会话中重复的 id 会被服务器当作重复发送丢弃
+fn broadcast(content: &str) -> ClientMessage {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    ClientMessage::Broadcast { content: content.into(), id, ephemeral: false, ttl_secs: None, reply_to: None, attachments: Vec::new() }
+}
+
+fn raw(text: &str, id: u64) -> ClientMessage {
+    ClientMessage::Raw { text: text.into(), id }
+}
+
+fn private(to: &[&str], content: &str) -> ClientMessage {
+    ClientMessage::Private {
+        to: to.iter().map(|t| t.to_string()).collect(),
+        content: content.into(),
+        ephemeral: false,
+        ttl_secs: None,
+        signature: None,
+        encrypted: false,
+        reply_to: None,
+    }
+}
+
+// 从一行输入构造指令, 与客户端库发送的一样
+fn command(line: &str) -> ClientMessage {
+    let command = Command::parse(&cmdline::parse(line).unwrap()).unwrap();
+    ClientMessage::Command { command }
+}
+
+#[tokio::test]
+async fn broadcast_is_seen_by_other_clients() {
+    let state = new_state();
+    let mut alice = join("alice", &state).await;
+    let mut bob = join("bob", &state).await;
+
+    send(&mut alice, broadcast("hello all")).await;
+    let msg = expect(&mut bob, |m| matches!(m, ServerMessage::BroadcastMessage { .. })).await;
+    match msg {
+        ServerMessage::BroadcastMessage { from, content, .. } => {
+            assert_eq!(from, "alice");
+            assert_eq!(content, "hello all");
+        }
+        _ => unreachable!(),
+    }
+}
+
+#[tokio::test]
+async fn private_message_reaches_only_the_recipient() {
+    let state = new_state();
+    let mut alice = join("alice", &state).await;
+    let mut bob = join("bob", &state).await;
+    let mut carol = join("carol", &state).await;
+
+    send(&mut alice, private(&["bob"], "psst")).await;
+    let msg = expect(&mut bob, |m| matches!(m, ServerMessage::PrivateMessage { .. })).await;
+    assert!(matches!(msg, ServerMessage::PrivateMessage { from, content, .. } if from == "alice" && content == "psst"));
+    expect_none(&mut carol, |m| matches!(m, ServerMessage::PrivateMessage { .. })).await;
+}
+
+#[tokio::test]
+async fn whisper_to_a_unique_prefix_reaches_the_full_name() {
+    let state = new_state();
+    let mut alice = join("alice", &state).await;
+    let mut bob = join("bob", &state).await;
+    let mut bobby = join("bobby", &state).await;
+    let mut carol = join("Carol", &state).await;
+
+    send(&mut alice, raw("/w ca hi", 1)).await;
+    let msg = expect(&mut carol, |m| matches!(m, ServerMessage::PrivateMessage { .. })).await;
+    assert!(matches!(msg, ServerMessage::PrivateMessage { to, content, .. } if to == "Carol" && content == "hi"));
+    expect(&mut alice, |m| matches!(m, ServerMessage::System { content } if content == "Sent to Carol")).await;
+
+    // 有歧义时不发给任何人, 列出候选
+    send(&mut alice, raw("/w bo,carol hey", 2)).await;
+    let msg = expect(&mut alice, |m| matches!(m, ServerMessage::Error { .. })).await;
+    assert!(matches!(msg, ServerMessage::Error { content, .. } if content == "\"bo\" matches several users, message not sent: bob, bobby"));
+    expect_none(&mut carol, |m| matches!(m, ServerMessage::PrivateMessage { .. })).await;
+    expect_none(&mut bob, |m| matches!(m, ServerMessage::PrivateMessage { .. })).await;
+    expect_none(&mut bobby, |m| matches!(m, ServerMessage::PrivateMessage { .. })).await;
+
+    // 没有匹配的前缀按不在线处理
+    send(&mut alice, raw("/w dave yo", 3)).await;
+    expect(&mut alice, |m| matches!(m, ServerMessage::Error { content, .. } if content == "Not online or no such user: dave")).await;
+
+    // bob 离线后, 发给 bob 的私聊不会落到 bobby 那里
+    send(&mut bob, ClientMessage::Quit { reason: None }).await;
+    expect(&mut alice, |m| matches!(m, ServerMessage::System { content } if content == "bob has left the chat")).await;
+    send(&mut alice, raw("/w bob are you there", 4)).await;
+    expect(&mut alice, |m| matches!(m, ServerMessage::Error { content, .. } if content.starts_with("Not online or no such user: bob ("))).await;
+    expect_none(&mut bobby, |m| matches!(m, ServerMessage::PrivateMessage { .. })).await;
+}
+
+#[tokio::test]
+async fn users_lists_everyone_online() {
+    let state = new_state();
+    let mut alice = join("alice", &state).await;
+    let _bob = join("bob", &state).await;
+
+    send(&mut alice, command("/users")).await;
+    let msg = expect(&mut alice, |m| matches!(m, ServerMessage::UserList { .. })).await;
+    let ServerMessage::UserList { mut content, to } = msg else { unreachable!() };
+    content.sort();
+    assert_eq!(content, vec!["alice", "bob"]);
+    assert_eq!(to, "alice");
+}
+
+#[tokio::test]
+async fn history_contains_broadcasts_and_own_private_messages() {
+    let state = new_state();
+    let mut alice = join("alice", &state).await;
+    let mut bob = join("bob", &state).await;
+
+    send(&mut alice, broadcast("public words")).await;
+    send(&mut alice, private(&["bob"], "secret words")).await;
+    expect(&mut bob, |m| matches!(m, ServerMessage::PrivateMessage { .. })).await;
+
+    send(&mut alice, command("/history")).await;
+    let msg = expect(&mut alice, |m| matches!(m, ServerMessage::History { .. })).await;
+    let ServerMessage::History { content, .. } = msg else { unreachable!() };
+    assert!(content.contains("alice broadcast: public words"), "{}", content);
+    assert!(content.contains("secret words"), "{}", content);
+}
+
+// 请求 /history 并取出历史的每一行
+async fn history_lines(client: &mut Client) -> Vec<String> {
+    send(client, command("/history")).await;
+    let msg = expect(client, |m| matches!(m, ServerMessage::History { .. })).await;
+    let ServerMessage::History { content, .. } = msg else { unreachable!() };
+    content.lines().map(str::to_string).collect()
+}
+
+#[tokio::test]
+async fn private_history_is_written_from_each_side() {
+    let state = new_state();
+    let mut alice = join("alice", &state).await;
+    let mut bob = join("bob", &state).await;
+    let mut carol = join("carol", &state).await;
+
+    send(&mut alice, private(&["bob"], "ping")).await;
+    expect(&mut bob, |m| matches!(m, ServerMessage::PrivateMessage { content, .. } if content == "ping")).await;
+    send(&mut bob, private(&["alice"], "pong")).await;
+    expect(&mut alice, |m| matches!(m, ServerMessage::PrivateMessage { content, .. } if content == "pong")).await;
+
+    // 发送者看到 "You → 接收者", 接收者看到 "发送者 → You", 两边顺序一致
+    let alice_lines = history_lines(&mut alice).await;
+    let sent = alice_lines.iter().position(|l| l.ends_with("You → bob: ping")).expect("alice's sent message");
+    let received = alice_lines.iter().position(|l| l.ends_with("bob → You: pong")).expect("alice's received message");
+    assert!(sent < received, "{:?}", alice_lines);
+    assert!(!alice_lines.iter().any(|l| l.contains("alice → You") || l.contains("You → alice")), "{:?}", alice_lines);
+
+    let bob_lines = history_lines(&mut bob).await;
+    let received = bob_lines.iter().position(|l| l.ends_with("alice → You: ping")).expect("bob's received message");
+    let sent = bob_lines.iter().position(|l| l.ends_with("You → alice: pong")).expect("bob's sent message");
+    assert!(received < sent, "{:?}", bob_lines);
+    assert!(!bob_lines.iter().any(|l| l.contains("bob → You") || l.contains("You → bob")), "{:?}", bob_lines);
+
+    // 第三个人的历史里没有这段私聊
+    let carol_lines = history_lines(&mut carol).await;
+    assert!(!carol_lines.iter().any(|l| l.contains("ping") || l.contains("pong")), "{:?}", carol_lines);
+
+    // 发给多人时, 发送者记下全部接收者, 每个接收者都看到发送者
+    send(&mut carol, private(&["alice", "bob"], "both of you")).await;
+    expect(&mut alice, |m| matches!(m, ServerMessage::PrivateMessage { content, .. } if content == "both of you")).await;
+    expect(&mut bob, |m| matches!(m, ServerMessage::PrivateMessage { content, .. } if content == "both of you")).await;
+    assert!(history_lines(&mut carol).await.iter().any(|l| l.ends_with("You → alice, bob: both of you")));
+    assert!(history_lines(&mut alice).await.iter().any(|l| l.ends_with("carol → You: both of you")));
+    assert!(history_lines(&mut bob).await.iter().any(|l| l.ends_with("carol → You: both of you")));
+}
+
+#[tokio::test]
+async fn clearing_private_history_needs_confirmation() {
+    let state = new_state();
+    let mut alice = join("alice", &state).await;
+    let mut bob = join("bob", &state).await;
+    send(&mut alice, private(&["bob"], "ping")).await;
+    expect(&mut bob, |m| matches!(m, ServerMessage::PrivateMessage { content, .. } if content == "ping")).await;
+
+    // 没有先发出请求时, 确认不起作用
+    send(&mut alice, command("/dm-history clear confirm")).await;
+    expect(&mut alice, |m| matches!(m, ServerMessage::Error { content, .. } if content.starts_with("Nothing to confirm: send /dm-history clear first"))).await;
+    assert!(history_lines(&mut alice).await.iter().any(|l| l.ends_with("You → bob: ping")));
+
+    send(&mut alice, command("/dm-history clear")).await;
+    expect(&mut alice, |m| matches!(m, ServerMessage::System { content } if content.starts_with("This deletes the 2 private messages") && content.ends_with("Send /dm-history clear confirm within 60s to go ahead"))).await;
+    send(&mut alice, command("/dm-history clear confirm")).await;
+    expect(&mut alice, |m| matches!(m, ServerMessage::System { content } if content == "Deleted 2 private messages from your history")).await;
+
+    // 只删除自己的一份, 对方的历史不变; 确认只能用一次
+    let lines = history_lines(&mut alice).await;
+    assert!(!lines.iter().any(|l| l.contains("ping") || l.contains("/dm-history")), "{:?}", lines);
+    assert!(history_lines(&mut bob).await.iter().any(|l| l.ends_with("alice → You: ping")));
+    send(&mut alice, command("/dm-history clear confirm")).await;
+    expect(&mut alice, |m| matches!(m, ServerMessage::Error { content, .. } if content.starts_with("Nothing to confirm"))).await;
+}
+
+#[tokio::test]
+async fn export_returns_structured_history_the_user_can_see() {
+    let state = new_state();
+    let mut alice = join("alice", &state).await;
+    let mut bob = join("bob", &state).await;
+    let mut carol = join("carol", &state).await;
+
+    send(&mut alice, broadcast("public words")).await;
+    send(&mut alice, private(&["bob"], "for bob")).await;
+    send(&mut carol, private(&["alice"], "for alice")).await;
+    expect(&mut bob, |m| matches!(m, ServerMessage::PrivateMessage { .. })).await;
+    expect(&mut alice, |m| matches!(m, ServerMessage::PrivateMessage { .. })).await;
+
+    send(&mut bob, command("/block carol")).await;
+    expect(&mut bob, |m| matches!(m, ServerMessage::System { content } if content.contains("carol"))).await;
+    send(&mut bob, command("/export")).await;
+    let msg = expect(&mut bob, |m| matches!(m, ServerMessage::Export { .. })).await;
+    let ServerMessage::Export { messages, done, blocked } = msg else { unreachable!() };
+    assert!(done);
+    assert_eq!(blocked, ["carol"]);
+    // 所在房间的广播和自己的私聊, 按 message_id 排列; 别人之间的私聊不在其中, 最后是这次 /export 本身
+    let summary: Vec<(ExportKind, &str, Vec<String>, &str)> = messages.iter()
+        .map(|m| (m.kind, m.from.as_str(), m.to.clone(), m.content.as_str()))
+        .collect();
+    assert_eq!(summary, vec![
+        (ExportKind::Broadcast, "alice", vec![], "public words"),
+        (ExportKind::Private, "alice", vec!["bob".to_string()], "for bob"),
+        (ExportKind::Command, "bob", vec![], "/export"),
+    ]);
+    assert!(messages.windows(2).all(|w| w[0].message_id < w[1].message_id));
+    assert!(messages.iter().all(|m| m.sent_at > 0));
+}
+
+#[tokio::test]
+async fn admin_names_need_the_configured_signing_key() {
+    let state = new_state();
+    let identity = Identity::generate();
+    state.lock().await.admins.insert("root".into(), identity.public_key());
+    let reserved = |m: &ServerMessage| matches!(m, ServerMessage::RegisterRefused { reason: RefuseReason::NameReserved, content } if content == "Name is reserved");
+
+    let mut unsigned = register("root", &state).await;
+    expect(&mut unsigned, reserved).await;
+    let mut impostor = register_with("root", Some(&Identity::generate()), &state).await;
+    expect(&mut impostor, reserved).await;
+
+    let mut root = register_with("root", Some(&identity), &state).await;
+    expect(&mut root, |m| matches!(m, ServerMessage::System { content } if content == "root has joined the chat")).await;
+    send(&mut root, command("/dumpstate")).await;
+    expect(&mut root, |m| matches!(m, ServerMessage::System { content } if content.contains("\"clients\""))).await;
+}
+
+#[tokio::test]
+async fn admins_import_history_exported_elsewhere() {
+    let state = new_state();
+    let mut root = join_admin("root", &state).await;
+    let mut alice = join("alice", &state).await;
+
+    let export = HistoryExport {
+        user: "alice".into(),
+        server: "old".into(),
+        exported_at: 1_700_000_000,
+        messages: vec![ExportedMessage {
+            message_id: 500, sent_at: 1_600_000_000, kind: ExportKind::Broadcast, from: "dave".into(), to: vec![],
+            content: "from the old server".into(), reply_to: None,
+            attachments: vec![AttachmentMeta { name: "notes.txt".into(), size: 12, content_type: "text/plain".into() }],
+        }],
+        blocked: Vec::new(),
+    };
+    let dir = std::env::temp_dir().join(format!("rustchat-import-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("alice.json"), serde_json::to_string(&export).unwrap()).unwrap();
+
+    // 没有设置 import_dir 时 /import 不可用
+    send(&mut root, command("/import alice.json")).await;
+    expect(&mut root, |m| matches!(m, ServerMessage::Error { content, .. } if content.starts_with("Importing is turned off"))).await;
+    state.lock().await.import_dir = Some(dir.clone());
+
+    send(&mut alice, command("/import alice.json")).await;
+    expect(&mut alice, |m| matches!(m, ServerMessage::Error { content, .. } if content == "Only server admins can use /import")).await;
+
+    send(&mut root, command("/import alice.json")).await;
+    expect(&mut root, |m| matches!(m, ServerMessage::System { content } if content == "Imported 1 messages from alice.json")).await;
+
+    send(&mut alice, command("/history")).await;
+    let msg = expect(&mut alice, |m| matches!(m, ServerMessage::History { .. })).await;
+    assert!(matches!(msg, ServerMessage::History { content, .. } if content.contains("dave broadcast: from the old server\n    [attachment 1] notes.txt (12 B, text/plain)")));
+    // 新消息的 id 接在导入的之后
+    send(&mut alice, broadcast("new")).await;
+    let msg = expect(&mut root, |m| matches!(m, ServerMessage::BroadcastMessage { .. })).await;
+    assert!(matches!(msg, ServerMessage::BroadcastMessage { message_id, .. } if message_id > 500));
+
+    // 目录之外的文件和不存在的文件得到同样的回复
+    for path in ["/no/such/file.json", "/etc/hostname", "../alice.json", "."] {
+        send(&mut root, command(&format!("/import {}", path))).await;
+        let expected = format!("Cannot import {}: no such file in the import directory", path);
+        expect(&mut root, |m| matches!(m, ServerMessage::Error { content, .. } if *content == expected)).await;
+    }
+    // 超过大小上限的文件不读取
+    std::fs::File::create(dir.join("huge.json")).unwrap().set_len(64 * 1024 * 1024).unwrap();
+    send(&mut root, command("/import huge.json")).await;
+    expect(&mut root, |m| matches!(m, ServerMessage::Error { content, .. } if content == "Cannot import huge.json: the file is larger than 16.0 MiB")).await;
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn history_is_paginated() {
+    let state = new_state();
+    let mut alice = join("alice", &state).await;
+
+    for i in 0..30 {
+        send(&mut alice, broadcast(&format!("message {}", i))).await;
+        expect(&mut alice, |m| matches!(m, ServerMessage::BroadcastMessage { .. })).await;
+    }
+
+    send(&mut alice, command("/history")).await;
+    let msg = expect(&mut alice, |m| matches!(m, ServerMessage::History { .. })).await;
+    let ServerMessage::History { content, .. } = msg else { unreachable!() };
+    assert!(content.contains("message 0"), "{}", content);
+    assert!(!content.contains("message 29"), "{}", content);
+    assert!(content.ends_with("use /history 2"), "{}", content);
+
+    send(&mut alice, command("/history 2")).await;
+    let msg = expect(&mut alice, |m| matches!(m, ServerMessage::History { .. })).await;
+    let ServerMessage::History { content, .. } = msg else { unreachable!() };
+    assert!(content.contains("message 29"), "{}", content);
+    assert!(!content.contains("more, use"), "{}", content);
+
+    send(&mut alice, command("/history 9")).await;
+    expect(&mut alice, |m| matches!(m, ServerMessage::Error { .. })).await;
+}
+
+#[tokio::test]
+async fn raw_input_is_parsed_and_routed_by_the_server() {
+    let state = new_state();
+    let mut alice = join("alice", &state).await;
+    let mut bob = join("bob", &state).await;
+
+    // 普通文本按群发处理, 回显带回客户端的 id
+    send(&mut alice, raw("hello all", 7)).await;
+    let msg = expect(&mut bob, |m| matches!(m, ServerMessage::BroadcastMessage { .. })).await;
+    assert!(matches!(msg, ServerMessage::BroadcastMessage { content, id: 7, .. } if content == "hello all"));
+
+    send(&mut alice, raw("/w bob  psst there", 8)).await;
+    let msg = expect(&mut bob, |m| matches!(m, ServerMessage::PrivateMessage { .. })).await;
+    assert!(matches!(msg, ServerMessage::PrivateMessage { content, .. } if content == "psst there"));
+
+    send(&mut alice, raw("/users", 9)).await;
+    expect(&mut alice, |m| matches!(m, ServerMessage::UserList { .. })).await;
+
+    send(&mut alice, raw("/ttl soon bye", 10)).await;
+    let msg = expect(&mut alice, |m| matches!(m, ServerMessage::Error { .. })).await;
+    assert!(matches!(msg, ServerMessage::Error { content, .. } if content.starts_with("Usage: /ttl")));
+    expect_none(&mut bob, |m| matches!(m, ServerMessage::BroadcastMessage { .. })).await;
+}
+
+#[tokio::test]
+async fn unknown_command_is_reported_as_unknown() {
+    let state = new_state();
+    let mut alice = join("alice", &state).await;
+
+    send(&mut alice, raw("/frobnicate now", 1)).await;
+    let msg = expect(&mut alice, |m| matches!(m, ServerMessage::Error { .. })).await;
+    assert!(matches!(msg, ServerMessage::Error { content, .. } if content == "Unknown command: /frobnicate"));
+}
+
+#[tokio::test]
+async fn custom_command_prefix_marks_commands() {
+    let state = new_state();
+    state.lock().await.command_prefix = '!';
+    let mut alice = join("alice", &state).await;
+    let mut bob = join("bob", &state).await;
+
+    send(&mut alice, raw("!users", 1)).await;
+    expect(&mut alice, |m| matches!(m, ServerMessage::UserList { .. })).await;
+    send(&mut alice, raw("!w bob psst", 2)).await;
+    expect(&mut bob, |m| matches!(m, ServerMessage::PrivateMessage { content, .. } if content == "psst")).await;
+
+    // 换了前缀后 / 开头的输入是普通群发
+    send(&mut alice, raw("/users", 3)).await;
+    let msg = expect(&mut bob, |m| matches!(m, ServerMessage::BroadcastMessage { .. })).await;
+    assert!(matches!(msg, ServerMessage::BroadcastMessage { content, .. } if content == "/users"));
+}
+
+#[tokio::test]
+async fn doubled_prefix_broadcasts_the_escaped_text() {
+    let state = new_state();
+    let mut alice = join("alice", &state).await;
+    let mut bob = join("bob", &state).await;
+
+    for (id, (input, content)) in (1..).zip([("//tmp/log", "/tmp/log"), ("///", "//"), ("/", "/")]) {
+        send(&mut alice, raw(input, id)).await;
+        let msg = expect(&mut bob, |m| matches!(m, ServerMessage::BroadcastMessage { .. })).await;
+        assert!(matches!(&msg, ServerMessage::BroadcastMessage { content: got, .. } if got == content), "{:?}", msg);
+    }
+}
+
+#[tokio::test]
+async fn quiet_joins_hides_presence_notices() {
+    let state = new_state();
+    let mut alice = join("alice", &state).await;
+    send(&mut alice, command("/quiet-joins")).await;
+    let msg = expect(&mut alice, |m| matches!(m, ServerMessage::System { .. })).await;
+    assert!(matches!(msg, ServerMessage::System { content } if content.contains("hidden")));
+
+    let mut bob = join("bob", &state).await;
+    send(&mut bob, raw("/quit", 1)).await;
+    expect_none(&mut alice, |m| matches!(m, ServerMessage::System { .. })).await;
+
+    // 再次切换后恢复通知
+    send(&mut alice, command("/quiet-joins")).await;
+    expect(&mut alice, |m| matches!(m, ServerMessage::System { content } if content.contains("shown"))).await;
+    let _carol = join("carol", &state).await;
+    expect(&mut alice, |m| matches!(m, ServerMessage::System { content } if content == "carol has joined the chat")).await;
+}
+
+#[tokio::test]
+async fn user_count_is_pushed_on_join_and_leave() {
+    let state = new_state();
+    let mut alice = register("alice", &state).await;
+    expect(&mut alice, |m| *m == ServerMessage::UserCount { count: 1 }).await;
+
+    let mut bob = register("bob", &state).await;
+    expect(&mut bob, |m| *m == ServerMessage::UserCount { count: 2 }).await;
+    expect(&mut alice, |m| *m == ServerMessage::UserCount { count: 2 }).await;
+
+    // 关闭了上下线通知也照样收到人数
+    send(&mut alice, command("/quiet-joins")).await;
+    send(&mut bob, raw("/quit", 1)).await;
+    expect(&mut alice, |m| *m == ServerMessage::UserCount { count: 1 }).await;
+}
+
+#[tokio::test]
+async fn escape_sequences_are_stripped_before_delivery() {
+    let state = new_state();
+    let mut alice = join("alice", &state).await;
+    let mut bob = join("bob", &state).await;
+
+    send(&mut alice, broadcast("\u{1b}[2Jhi\r\nthere\u{7}")).await;
+    let msg = expect(&mut bob, |m| matches!(m, ServerMessage::BroadcastMessage { .. })).await;
+    assert!(matches!(&msg, ServerMessage::BroadcastMessage { content, .. } if content == "hi\nthere"), "{:?}", msg);
+
+    send(&mut alice, private(&["bob"], "\u{1b}]0;owned\u{7}psst")).await;
+    let msg = expect(&mut bob, |m| matches!(m, ServerMessage::PrivateMessage { .. })).await;
+    assert!(matches!(&msg, ServerMessage::PrivateMessage { content, .. } if content == "psst"), "{:?}", msg);
+}
+
+#[tokio::test]
+async fn afk_replies_once_per_sender_and_clears_on_activity() {
+    let state = new_state();
+    let mut alice = join("alice", &state).await;
+    let mut bob = join("bob", &state).await;
+
+    send(&mut alice, command("/afk lunch")).await;
+    expect(&mut bob, |m| matches!(m, ServerMessage::System { content } if content == "alice is away: lunch")).await;
+
+    // 同一次离开期间只自动回复一次
+    send(&mut bob, private(&["alice"], "ping")).await;
+    expect(&mut bob, |m| matches!(m, ServerMessage::System { content } if content == "alice is away: lunch")).await;
+    send(&mut bob, private(&["alice"], "ping again")).await;
+    expect_none(&mut bob, |m| matches!(m, ServerMessage::System { content } if content.contains("away"))).await;
+
+    // alice 一发言就回来了
+    send(&mut alice, broadcast("I'm back")).await;
+    expect(&mut bob, |m| matches!(m, ServerMessage::System { content } if content == "alice is back")).await;
+    send(&mut bob, private(&["alice"], "welcome back")).await;
+    expect_none(&mut bob, |m| matches!(m, ServerMessage::System { content } if content.contains("away"))).await;
+}
+
+#[tokio::test]
+async fn whoami_reports_name_room_and_status() {
+    let state = new_state();
+    let mut alice = join("alice", &state).await;
+    let mut bob = join("bob", &state).await;
+    send(&mut alice, command("/whoami")).await;
+    expect(&mut alice, |m| matches!(m, ServerMessage::System { content } if content == "You are alice in lobby, available")).await;
+
+    send(&mut alice, command("/afk brb")).await;
+    send(&mut alice, command("/whoami")).await;
+    expect(&mut alice, |m| matches!(m, ServerMessage::System { content } if content == "You are alice in lobby, away: brb")).await;
+
+    // 重新连接后仍是同一个名字, 离开状态随断线清除
+    send(&mut alice, raw("/quit", 1)).await;
+    expect(&mut bob, |m| matches!(m, ServerMessage::System { content } if content == "alice has left the chat")).await;
+    let mut alice = join("alice", &state).await;
+    send(&mut alice, command("/whoami")).await;
+    expect(&mut alice, |m| matches!(m, ServerMessage::System { content } if content == "You are alice in lobby, available")).await;
+}
+
+#[tokio::test]
+async fn seen_reports_when_a_user_last_spoke() {
+    let state = new_state();
+    let mut alice = join("alice", &state).await;
+    let mut bob = join("bob", &state).await;
+    let mut seen = async |target: &str, answer: &str| {
+        send(&mut alice, command(&format!("/seen {}", target))).await;
+        expect(&mut alice, |m| matches!(m, ServerMessage::System { content } if content == answer)).await;
+    };
+    seen("bob", "bob is here now").await;
+
+    send(&mut bob, broadcast("brb")).await;
+    send(&mut bob, raw("/quit", 1)).await;
+    // carol 连上后一句话没说就断开
+    drop(join("carol", &state).await);
+    // 等两人的离开都处理完
+    for _ in 0..50 {
+        if state.lock().await.clients.len() == 1 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    seen("bob", "bob last sent a message just now").await;
+    seen("carol", "carol has never sent a message (last seen just now)").await;
+    seen("ghost", "ghost has not been seen").await;
+}
+
+#[tokio::test]
+async fn name_colors_reach_everyone_including_later_joiners() {
+    let state = new_state();
+    let mut alice = join("alice", &state).await;
+    let mut bob = join("bob", &state).await;
+
+    send(&mut alice, command("/color cyan")).await;
+    expect(&mut alice, |m| matches!(m, ServerMessage::System { content } if content == "Your name is now shown in cyan")).await;
+    expect(&mut bob, |m| *m == ServerMessage::NameColor { name: "alice".into(), color: Some(NameColor::Cyan) }).await;
+
+    // 后加入的用户也会收到已选的颜色
+    let mut carol = join("carol", &state).await;
+    expect(&mut carol, |m| *m == ServerMessage::NameColor { name: "alice".into(), color: Some(NameColor::Cyan) }).await;
+
+    send(&mut alice, command("/color none")).await;
+    expect(&mut carol, |m| *m == ServerMessage::NameColor { name: "alice".into(), color: None }).await;
+
+    send(&mut bob, raw("/color pink", 1)).await;
+    expect(&mut bob, |m| matches!(m, ServerMessage::Error { content, .. } if content.starts_with("Usage: /color"))).await;
+}
+
+#[tokio::test]
+async fn replies_carry_the_id_of_the_message_they_answer() {
+    let state = new_state();
+    let mut alice = join("alice", &state).await;
+    let mut bob = join("bob", &state).await;
+
+    send(&mut alice, broadcast("lunch?")).await;
+    let msg = expect(&mut bob, |m| matches!(m, ServerMessage::BroadcastMessage { .. })).await;
+    let ServerMessage::BroadcastMessage { message_id: question, reply_to: None, .. } = msg else { panic!("{:?}", msg) };
+
+    send(&mut bob, ClientMessage::Broadcast { content: "sure".into(), id: 2, ephemeral: false, ttl_secs: None, reply_to: Some(question), attachments: Vec::new() }).await;
+    let msg = expect(&mut alice, |m| matches!(m, ServerMessage::BroadcastMessage { from, .. } if from == "bob")).await;
+    assert!(matches!(msg, ServerMessage::BroadcastMessage { reply_to: Some(id), .. } if id == question), "{:?}", msg);
+
+    // 私聊回复同样带上被回复的 id
+    let mut reply = private(&["bob"], "noon?");
+    if let ClientMessage::Private { reply_to, .. } = &mut reply {
+        *reply_to = Some(question);
+    }
+    send(&mut alice, reply).await;
+    let msg = expect(&mut bob, |m| matches!(m, ServerMessage::PrivateMessage { .. })).await;
+    assert!(matches!(msg, ServerMessage::PrivateMessage { reply_to: Some(id), .. } if id == question), "{:?}", msg);
+}
+
+#[tokio::test]
+async fn resent_messages_are_delivered_once() {
+    let state = new_state();
+    let mut alice = join("alice", &state).await;
+    let mut bob = join("bob", &state).await;
+
+    // 客户端重连后补发了已经送达的消息: 同一个 id 只处理一次
+    let hello = broadcast("hello");
+    send(&mut alice, hello.clone()).await;
+    send(&mut alice, hello).await;
+    send(&mut alice, raw("/w bob psst", 900)).await;
+    send(&mut alice, raw("/w bob psst", 900)).await;
+    expect(&mut bob, |m| matches!(m, ServerMessage::BroadcastMessage { content, .. } if content == "hello")).await;
+    expect(&mut bob, |m| matches!(m, ServerMessage::PrivateMessage { content, .. } if content == "psst")).await;
+    expect_none(&mut bob, |m| matches!(m, ServerMessage::BroadcastMessage { .. } | ServerMessage::PrivateMessage { .. })).await;
+
+    // 没有编号的原始输入每次都处理
+    send(&mut alice, raw("again", 0)).await;
+    send(&mut alice, raw("again", 0)).await;
+    expect(&mut bob, |m| matches!(m, ServerMessage::BroadcastMessage { content, .. } if content == "again")).await;
+    expect(&mut bob, |m| matches!(m, ServerMessage::BroadcastMessage { content, .. } if content == "again")).await;
+}
+
+#[tokio::test]
+async fn broadcast_attachments_are_forwarded_and_validated() {
+    let state = new_state();
+    let mut alice = join("alice", &state).await;
+    let mut bob = join("bob", &state).await;
+
+    let plan = AttachmentMeta { name: "plan.pdf".into(), size: 2048, content_type: "application/pdf".into() };
+    send(&mut alice, ClientMessage::Broadcast {
+        content: "see attached".into(), id: 1, ephemeral: false, ttl_secs: None, reply_to: None, attachments: vec![plan.clone()],
+    }).await;
+    let msg = expect(&mut bob, |m| matches!(m, ServerMessage::BroadcastMessage { .. })).await;
+    assert!(matches!(&msg, ServerMessage::BroadcastMessage { attachments, .. } if attachments == std::slice::from_ref(&plan)), "{:?}", msg);
+
+    // 附件随消息保存: 历史中列在消息下面, /export 原样带上
+    send(&mut bob, command("/history")).await;
+    let msg = expect(&mut bob, |m| matches!(m, ServerMessage::History { .. })).await;
+    assert!(matches!(&msg, ServerMessage::History { content, .. } if content.contains("alice broadcast: see attached\n    [attachment 1] plan.pdf (2.0 KiB, application/pdf)")), "{:?}", msg);
+    send(&mut bob, command("/export")).await;
+    let msg = expect(&mut bob, |m| matches!(m, ServerMessage::Export { .. })).await;
+    assert!(matches!(&msg, ServerMessage::Export { messages, .. } if messages[0].attachments == [plan]), "{:?}", msg);
+
+    // 带路径或过长的文件名不合法, 整条消息被拒绝
+    let sneaky = AttachmentMeta { name: "../etc/passwd".into(), size: 1, content_type: "text/plain".into() };
+    let long = AttachmentMeta { name: format!("{}.txt", "a".repeat(300)), size: 1, content_type: "text/plain".into() };
+    for (id, bad) in [(2, sneaky), (3, long)] {
+        send(&mut alice, ClientMessage::Broadcast {
+            content: "sneaky".into(), id, ephemeral: false, ttl_secs: None, reply_to: None, attachments: vec![bad],
+        }).await;
+        expect(&mut alice, |m| matches!(m, ServerMessage::Error { .. })).await;
+    }
+    expect_none(&mut bob, |m| matches!(m, ServerMessage::BroadcastMessage { .. })).await;
+}
+
+#[tokio::test]
+async fn service_user_answers_like_a_client() {
+    let state = new_state();
+    spawn_service("echo", Echo, &state).await.unwrap();
+    assert!(spawn_service("echo", Echo, &state).await.is_err());
+    let mut alice = join("alice", &state).await;
+
+    send(&mut alice, command("/users")).await;
+    let msg = expect(&mut alice, |m| matches!(m, ServerMessage::UserList { .. })).await;
+    assert!(matches!(msg, ServerMessage::UserList { content, .. } if content.contains(&"echo".to_string())));
+
+    send(&mut alice, private(&["echo"], "ping")).await;
+    let msg = expect(&mut alice, |m| matches!(m, ServerMessage::PrivateMessage { .. })).await;
+    assert!(matches!(msg, ServerMessage::PrivateMessage { from, content, .. } if from == "echo" && content == "ping"));
+
+    // 服务用户的名字不能再被客户端注册
+    let mut impostor = register("echo", &state).await;
+    expect(&mut impostor, |m| matches!(m, ServerMessage::RegisterRefused { reason: RefuseReason::NameTaken, .. })).await;
+}
+
+#[tokio::test]
+async fn presence_templates_replace_the_default_notices() {
+    let state = new_state();
+    state.lock().await.presence_format = PresenceFormat {
+        joined: Some("→ {name} is here".into()),
+        left: None,
+        left_with_reason: Some("← {name} went away: {reason}".into()),
+    };
+    let mut alice = register("alice", &state).await;
+    expect(&mut alice, |m| matches!(m, ServerMessage::System { content } if content == "→ alice is here")).await;
+    let mut bob = register("bob", &state).await;
+    expect(&mut alice, |m| matches!(m, ServerMessage::System { content } if content == "→ bob is here")).await;
+
+    // 退出原因中的转义序列和消息内容一样被清理
+    send(&mut bob, raw("/quit \x1b[2Jbye", 1)).await;
+    expect(&mut alice, |m| matches!(m, ServerMessage::System { content } if content == "← bob went away: bye")).await;
+    let mut carol = register("carol", &state).await;
+    expect(&mut carol, |m| matches!(m, ServerMessage::System { content } if content == "→ carol is here")).await;
+    send(&mut carol, ClientMessage::Quit { reason: None }).await;
+    expect(&mut alice, |m| matches!(m, ServerMessage::System { content } if content == "carol has left the chat")).await;
+}
+
+#[tokio::test]
+async fn quit_announces_the_reason_and_closes_the_connection() {
+    let state = new_state();
+    let mut alice = join("alice", &state).await;
+    let mut bob = join("bob", &state).await;
+
+    send(&mut alice, raw("/quit see you tomorrow", 1)).await;
+    let msg = expect(&mut bob, |m| matches!(m, ServerMessage::System { content } if content.contains("left"))).await;
+    assert!(matches!(msg, ServerMessage::System { content } if content == "alice has left the chat (see you tomorrow)"));
+    // 读完已经发出的消息后连接关闭
+    let drained = async { while let Some(Ok(_)) = alice.next().await {} };
+    tokio::time::timeout(Duration::from_secs(2), drained).await.expect("connection should close");
+    assert!(!state.lock().await.clients.contains_key("alice"));
+}
+
+#[tokio::test]
+async fn private_message_to_offline_user_returns_error() {
+    let state = new_state();
+    let mut alice = join("alice", &state).await;
+
+    send(&mut alice, private(&["ghost"], "anyone?")).await;
+    let msg = expect(&mut alice, |m| matches!(m, ServerMessage::Error { .. })).await;
+    assert!(matches!(msg, ServerMessage::Error { content, .. } if content.contains("ghost")));
+}
+
+#[tokio::test]
+async fn duplicate_name_is_rejected() {
+    let state = new_state();
+    let _alice = join("alice", &state).await;
+
+    let mut impostor = register("alice", &state).await;
+    let msg = expect(&mut impostor, |_| true).await;
+    assert!(matches!(msg, ServerMessage::RegisterRefused { reason: RefuseReason::NameTaken, content } if content.contains("taken")));
+    // 被拒绝的连接随后被关闭
+    assert!(impostor.next().await.is_none());
+    assert_eq!(state.lock().await.clients.len(), 1);
+}
+
+#[tokio::test]
+async fn reserved_and_malformed_names_are_rejected() {
+    let state = new_state();
+    let cases = [
+        ("system", RefuseReason::NameReserved, "reserved"),
+        ("Admin", RefuseReason::NameReserved, "reserved"),
+        ("two words", RefuseReason::InvalidName, "without spaces"),
+        ("", RefuseReason::InvalidName, "without spaces"),
+    ];
+    for (name, expected, text) in cases {
+        let mut client = register(name, &state).await;
+        let msg = expect(&mut client, |_| true).await;
+        assert!(matches!(&msg, ServerMessage::RegisterRefused { reason, content } if *reason == expected && content.contains(text)), "{:?}: {:?}", name, msg);
+        assert!(client.next().await.is_none());
+    }
+    assert!(state.lock().await.clients.is_empty());
+}
+
+// 先由 alice 发出三条广播, 再让 bob 加入, 返回 bob 加入时收到的历史(没有则为 None)
+async fn history_on_join(join_history: usize) -> Option<String> {
+    let state = new_state();
+    state.lock().await.join_history = join_history;
+    let mut alice = join("alice", &state).await;
+    for i in 1..=3 {
+        send(&mut alice, broadcast(&format!("line {}", i))).await;
+        expect(&mut alice, |m| matches!(m, ServerMessage::BroadcastMessage { .. })).await;
+    }
+
+    let mut bob = register("bob", &state).await;
+    let wait = async {
+        while let Some(Ok(Message::Servermsg(msg))) = bob.next().await {
+            if let ServerMessage::History { content, .. } = msg {
+                return Some(content);
+            }
+        }
+        None
+    };
+    tokio::time::timeout(Duration::from_millis(300), wait).await.ok().flatten()
+}
+
+#[tokio::test]
+async fn joining_client_receives_recent_broadcasts() {
+    let content = history_on_join(2).await.expect("bob should receive recent history");
+    assert!(!content.contains("line 1"), "{}", content);
+    assert!(content.contains("line 2") && content.contains("line 3"), "{}", content);
+
+    let content = history_on_join(100).await.expect("bob should receive recent history");
+    assert_eq!(content.lines().count(), 3, "{}", content);
+}
+
+#[tokio::test]
+async fn join_history_of_zero_sends_nothing() {
+    assert_eq!(history_on_join(0).await, None);
+}
+
+#[tokio::test]
+async fn repeated_broadcasts_get_the_sender_muted() {
+    let state = new_state();
+    state.lock().await.spam_policy = SpamPolicy { max_repeats: 2, ..SpamPolicy::default() };
+    let mut alice = join("alice", &state).await;
+    let mut bob = join("bob", &state).await;
+
+    for _ in 0..2 {
+        send(&mut alice, broadcast("buy now")).await;
+        expect(&mut bob, |m| matches!(m, ServerMessage::BroadcastMessage { .. })).await;
+    }
+    send(&mut alice, broadcast("buy now")).await;
+    let msg = expect(&mut alice, |m| matches!(m, ServerMessage::Error { .. })).await;
+    assert!(matches!(msg, ServerMessage::Error { content, .. } if content == "You are muted for 60s"));
+
+    // 禁言期间换了内容也发不出去
+    send(&mut alice, broadcast("something else")).await;
+    let msg = expect(&mut alice, |m| matches!(m, ServerMessage::Error { .. })).await;
+    assert!(matches!(msg, ServerMessage::Error { content, .. } if content.starts_with("You are muted for")));
+    expect_none(&mut bob, |m| matches!(m, ServerMessage::BroadcastMessage { .. })).await;
+}
+
+#[tokio::test]
+async fn actions_count_as_spam_and_are_dropped_while_muted() {
+    let state = new_state();
+    state.lock().await.spam_policy = SpamPolicy { max_repeats: 2, ..SpamPolicy::default() };
+    let mut alice = join("alice", &state).await;
+    let mut bob = join("bob", &state).await;
+
+    for _ in 0..2 {
+        send(&mut alice, raw("/slap bob", 0)).await;
+        expect(&mut bob, |m| matches!(m, ServerMessage::System { content } if content.starts_with("alice slaps bob"))).await;
+    }
+    send(&mut alice, raw("/slap bob", 0)).await;
+    expect(&mut alice, |m| matches!(m, ServerMessage::Error { content, .. } if content == "You are muted for 60s")).await;
+    expect(&mut alice, |m| matches!(m, ServerMessage::Throttle { .. })).await;
+
+    // 禁言期间换了参数也发不出去
+    send(&mut alice, raw("/slap carol", 0)).await;
+    expect(&mut alice, |m| matches!(m, ServerMessage::Error { content, .. } if content.starts_with("You are muted for"))).await;
+    expect_none(&mut bob, |m| matches!(m, ServerMessage::System { content } if content.starts_with("alice slaps"))).await;
+}
+
+#[tokio::test]
+async fn file_offers_reach_everyone_and_bad_metadata_is_refused() {
+    let state = new_state();
+    let mut alice = join("alice", &state).await;
+    let mut bob = join("bob", &state).await;
+
+    let file = FileMeta { name: "notes.txt".into(), size: 1234, sha256: "ab".repeat(32) };
+    send(&mut alice, ClientMessage::FileOffer { file: file.clone() }).await;
+    let offer = expect(&mut bob, |m| matches!(m, ServerMessage::FileOffer { .. })).await;
+    assert_eq!(offer, ServerMessage::FileOffer { from: "alice".into(), file });
+
+    let sneaky = FileMeta { name: "../../etc/passwd".into(), size: 1, sha256: "ab".repeat(32) };
+    send(&mut alice, ClientMessage::FileOffer { file: sneaky }).await;
+    let msg = expect(&mut alice, |m| matches!(m, ServerMessage::Error { .. })).await;
+    assert!(matches!(msg, ServerMessage::Error { content, .. } if content.starts_with("Invalid file offer")));
+    expect_none(&mut bob, |m| matches!(m, ServerMessage::FileOffer { .. })).await;
+}
+
+// 按旧协议附带 from 字段的消息, 直接写入一帧 JSON
+async fn send_legacy(client: &mut Client, json: &str) {
+    let mut frame = (json.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(json.as_bytes());
+    client.get_mut().write_all(&frame).await.unwrap();
+}
+
+#[tokio::test]
+async fn legacy_from_fields_are_ignored_in_favour_of_the_registered_name() {
+    let state = new_state();
+    state.lock().await.admins.insert("root".into(), Identity::generate().public_key());
+    let mut alice = join("alice", &state).await;
+    let mut bob = join("bob", &state).await;
+
+    send_legacy(&mut alice, r#"{"Clientmsg":{"Broadcast":{"from":"bob","content":"I am bob","id":1}}}"#).await;
+    let msg = expect(&mut bob, |m| matches!(m, ServerMessage::BroadcastMessage { .. })).await;
+    assert!(matches!(&msg, ServerMessage::BroadcastMessage { from, .. } if from == "alice"), "{:?}", msg);
+
+    send_legacy(&mut alice, r#"{"Clientmsg":{"Private":{"from":"carol","to":["bob"],"content":"trust me"}}}"#).await;
+    let msg = expect(&mut bob, |m| matches!(m, ServerMessage::PrivateMessage { .. })).await;
+    assert!(matches!(&msg, ServerMessage::PrivateMessage { from, .. } if from == "alice"), "{:?}", msg);
+
+    // 冒充管理员也没有用, 回复发给真正的发送者
+    send_legacy(&mut alice, r#"{"Clientmsg":{"Command":{"from":"root","command":{"DumpState":null}}}}"#).await;
+    let msg = expect(&mut alice, |m| matches!(m, ServerMessage::Error { .. })).await;
+    assert!(matches!(&msg, ServerMessage::Error { content, .. } if content.starts_with("Only server admins")), "{:?}", msg);
+
+    // 以别人的名字退出只会让自己下线
+    send_legacy(&mut alice, r#"{"Clientmsg":{"Quit":{"from":"bob"}}}"#).await;
+    expect(&mut bob, |m| matches!(m, ServerMessage::System { content } if content == "alice has left the chat")).await;
+    assert!(state.lock().await.clients.contains_key("bob"));
+}
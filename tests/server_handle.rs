@@ -0,0 +1,157 @@
+// 在进程内启动真实的 TCP 服务器, 通过 ServerHandle 控制其生命周期
+use rustchat::client::ChatClient;
+use rustchat::common::ServerMessage;
+use rustchat::server::{Drain, Server, ShutdownReason};
+use std::time::Duration;
+
+#[tokio::test]
+async fn embedded_server_accepts_clients_and_shuts_down() {
+    let handle = Server::builder().bind("127.0.0.1:0").run().await.unwrap();
+    let addr = handle.local_addr();
+    assert_ne!(addr.port(), 0);
+
+    let mut alice = ChatClient::connect(addr, "alice").await.unwrap();
+    alice.send_broadcast("hi").await.unwrap();
+    let wait_echo = async {
+        while let Some(Ok(msg)) = alice.next_message().await {
+            if matches!(msg, ServerMessage::BroadcastMessage { .. }) {
+                return;
+            }
+        }
+        panic!("connection ended before the echo arrived");
+    };
+    tokio::time::timeout(Duration::from_secs(2), wait_echo).await.unwrap();
+    assert!(handle.state().lock().await.clients.contains_key("alice"));
+
+    assert_eq!(handle.shutdown().await, Drain::Complete);
+
+    // 客户端收到关闭通知, 之后连接结束
+    let wait_exit = async {
+        let mut got_exit = false;
+        while let Some(Ok(msg)) = alice.next_message().await {
+            got_exit |= matches!(msg, ServerMessage::Exit { .. });
+        }
+        got_exit
+    };
+    assert!(tokio::time::timeout(Duration::from_secs(2), wait_exit).await.unwrap());
+
+    // 不再接受新连接
+    assert!(ChatClient::connect(addr, "bob").await.is_err());
+}
+
+#[tokio::test]
+async fn connections_over_the_limit_wait_for_a_free_slot() {
+    let handle = Server::builder().bind("127.0.0.1:0").max_connections(1).run().await.unwrap();
+    let addr = handle.local_addr();
+    let alice = ChatClient::connect(addr, "alice").await.unwrap();
+
+    // 名额被 alice 占用, bob 的连接还没有被接受, 收不到 Welcome
+    let bob = tokio::spawn(ChatClient::connect(addr, "bob"));
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(!bob.is_finished());
+    assert!(!handle.state().lock().await.clients.contains_key("bob"));
+
+    // alice 断开后名额归还, bob 完成注册
+    drop(alice);
+    let bob = tokio::time::timeout(Duration::from_secs(2), bob).await.unwrap().unwrap().unwrap();
+    assert!(handle.state().lock().await.clients.contains_key("bob"));
+    drop(bob);
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn shutdown_delivers_queued_messages_before_exit() {
+    let handle = Server::builder().bind("127.0.0.1:0").run().await.unwrap();
+    let mut alice = ChatClient::connect(handle.local_addr(), "alice").await.unwrap();
+    let mut bob = ChatClient::connect(handle.local_addr(), "bob").await.unwrap();
+
+    // bob 暂不读取, 让消息积压在写任务的队列和 socket 缓冲中
+    for i in 0..50 {
+        alice.send_broadcast(&format!("message {}", i)).await.unwrap();
+    }
+    let wait_last = async {
+        while let Some(Ok(msg)) = alice.next_message().await {
+            if matches!(msg, ServerMessage::BroadcastMessage { content, .. } if content == "message 49") {
+                return;
+            }
+        }
+        panic!("connection ended before the last echo arrived");
+    };
+    tokio::time::timeout(Duration::from_secs(2), wait_last).await.unwrap();
+
+    assert_eq!(handle.shutdown().await, Drain::Complete);
+
+    // 所有广播都在关闭通知之前送达
+    let mut received = 0;
+    let mut got_exit = false;
+    while let Some(Ok(msg)) = bob.next_message().await {
+        match msg {
+            ServerMessage::BroadcastMessage { .. } => {
+                assert!(!got_exit, "broadcast arrived after Exit");
+                received += 1;
+            }
+            ServerMessage::Exit { .. } => got_exit = true,
+            _ => {}
+        }
+    }
+    assert_eq!(received, 50);
+    assert!(got_exit);
+}
+
+#[tokio::test]
+async fn zero_broadcast_capacity_is_refused() {
+    let err = Server::builder().bind("127.0.0.1:0").broadcast_capacity(0).run().await.err().expect("capacity 0 should be refused");
+    assert!(err.to_string().contains("broadcast capacity"), "{}", err);
+}
+
+#[tokio::test]
+async fn shutdown_notice_carries_the_reason() {
+    let handle = Server::builder().bind("127.0.0.1:0").run().await.unwrap();
+    let mut alice = ChatClient::connect(handle.local_addr(), "alice").await.unwrap();
+
+    let reason = ShutdownReason::Maintenance { until: 1_700_000_000 };
+    assert_eq!(handle.shutdown_with(reason).await, Drain::Complete);
+    let wait_exit = async {
+        while let Some(Ok(msg)) = alice.next_message().await {
+            if let ServerMessage::Exit { reason } = msg {
+                return Some(reason);
+            }
+        }
+        None
+    };
+    assert_eq!(tokio::time::timeout(Duration::from_secs(2), wait_exit).await.unwrap(), Some(reason));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn unix_socket_clients_chat_with_tcp_clients() {
+    let path = std::env::temp_dir().join(format!("rustchat-test-{}.sock", std::process::id()));
+    // 路径上的普通文件不会被删掉, 服务器拒绝启动
+    std::fs::write(&path, b"not a socket").unwrap();
+    let Err(err) = Server::builder().bind("127.0.0.1:0").unix_socket(&path).run().await else { panic!("a regular file was replaced") };
+    assert!(err.to_string().contains("is not a unix socket"), "{err}");
+    assert_eq!(std::fs::read(&path).unwrap(), b"not a socket");
+    std::fs::remove_file(&path).unwrap();
+    // 上次运行留下的、已经没人监听的套接字不影响启动
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    let handle = Server::builder().bind("127.0.0.1:0").unix_socket(&path).run().await.unwrap();
+    assert_eq!(handle.unix_socket(), Some(path.as_path()));
+    assert!(Server::builder().bind("127.0.0.1:0").unix_socket(&path).run().await.is_err(), "the socket is in use");
+
+    let mut alice = ChatClient::connect_unix(&path, "alice", &[]).await.unwrap();
+    let mut bob = ChatClient::connect(handle.local_addr(), "bob").await.unwrap();
+    alice.send_broadcast("over the local socket").await.unwrap();
+    let wait = async {
+        while let Some(Ok(msg)) = bob.next_message().await {
+            if let ServerMessage::BroadcastMessage { from, content, .. } = msg {
+                return (from, content);
+            }
+        }
+        panic!("connection ended before the broadcast arrived");
+    };
+    let got = tokio::time::timeout(Duration::from_secs(2), wait).await.unwrap();
+    assert_eq!(got, ("alice".to_string(), "over the local socket".to_string()));
+
+    handle.shutdown().await;
+    assert!(!path.exists());
+}
@@ -0,0 +1,46 @@
+// rustchat::testing 的用法示例: 用脚本化的客户端驱动内存中的服务器
+use rustchat::common::ServerMessage;
+use rustchat::server::{ServerState, SpamPolicy};
+use rustchat::testing::TestServer;
+use std::time::Duration;
+
+#[tokio::test]
+async fn scripted_client_sees_its_broadcasts_and_command_replies() {
+    let server = TestServer::new();
+    let mut alice = server.join("alice").await;
+    let mut bob = server.join("bob").await;
+
+    let replies = alice.script(&["hello", "/users"]).await;
+    assert!(replies.iter().any(|m| matches!(m, ServerMessage::BroadcastMessage { content, .. } if content == "hello")), "{:?}", replies);
+    assert!(replies.iter().any(|m| matches!(m, ServerMessage::UserList { content, .. } if content.len() == 2)), "{:?}", replies);
+
+    bob.expect(|m| matches!(m, ServerMessage::BroadcastMessage { from, content, .. } if from == "alice" && content == "hello")).await;
+    // 指令的回复只发给发送者
+    bob.expect_none(|m| matches!(m, ServerMessage::UserList { .. })).await;
+}
+
+#[tokio::test]
+async fn quit_reason_reaches_the_others_and_ends_the_connection() {
+    let server = TestServer::new();
+    let alice = server.join("alice").await;
+    let mut bob = server.join("bob").await;
+
+    alice.quit(Some("lunch")).await.unwrap();
+    bob.expect(|m| matches!(m, ServerMessage::System { content } if content.contains("alice") && content.contains("lunch"))).await;
+    assert!(!server.state().lock().await.clients.contains_key("alice"));
+}
+
+#[tokio::test]
+async fn configured_state_applies_to_scripted_clients() {
+    let mut state = ServerState::default();
+    state.spam_policy = SpamPolicy { rate_limit: 2, rate_window: Duration::from_secs(60), ..SpamPolicy::default() };
+    let server = TestServer::with_state(state);
+    let mut alice = server.join("alice").await;
+
+    let replies = alice.script(&["one", "two", "three"]).await;
+    let broadcasts = replies.iter().filter(|m| matches!(m, ServerMessage::BroadcastMessage { .. })).count();
+    assert_eq!(broadcasts, 2);
+    // 被拒绝时先收到原因, 再收到要求放慢的 Throttle
+    assert!(matches!(alice.received(), [.., ServerMessage::Error { .. }, ServerMessage::Throttle { .. }]), "{:?}", alice.received());
+    alice.disconnect().await.unwrap();
+}